@@ -0,0 +1,82 @@
+use clap::Parser as ClapParser;
+use serde::{Deserialize, Serialize};
+use vpp_stat_client::otlp::OtlpExporter;
+use vpp_stat_client::*;
+
+/// Periodically push the VPP statistics to an OpenTelemetry collector over OTLP/HTTP
+#[derive(Debug, Clone, ClapParser, Serialize, Deserialize)]
+#[clap(version = "0.0", author = "Andrew Yourtchenko <ayourtch@gmail.com>")]
+struct Opts {
+    /// VPP stats socket as supplied in the "statseg { socket-name /path/to/socket }" config
+    #[clap(short, long, default_value = "/tmp/stats.sock")]
+    socket: String,
+
+    /// Pattern to match
+    #[clap(short, long, default_value = ".*")]
+    pattern: Vec<String>,
+
+    /// OTLP/HTTP collector endpoint
+    #[clap(short, long, default_value = "http://127.0.0.1:4318")]
+    endpoint: String,
+
+    /// Value of the "service.instance.id" resource attribute, the socket path by default
+    #[clap(long)]
+    instance: Option<String>,
+
+    /// Push interval, in seconds
+    #[clap(short, long, default_value = "10")]
+    interval: u64,
+
+    /// A level of verbosity, and can be used multiple times
+    #[clap(short, long, parse(from_occurrences))]
+    verbose: i32,
+}
+
+fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    let rv = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if rv != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|&c| c == 0).unwrap_or(buf.len());
+    Some(String::from_utf8_lossy(&buf[..len]).into_owned())
+}
+
+fn main() {
+    let opts: Opts = Opts::parse();
+
+    let c = VppStatClient::connect(&opts.socket).unwrap();
+
+    let mut patterns = VppStringVec::new();
+    for s in &opts.pattern {
+        patterns.push(s);
+    }
+    /* The interface names are needed to label the interface counters */
+    patterns.push("^/if/names$");
+
+    let instance = opts.instance.clone().unwrap_or_else(|| opts.socket.clone());
+    let mut exporter =
+        OtlpExporter::new(&opts.endpoint).with_resource_attribute("service.instance.id", &instance);
+    if let Some(host) = hostname() {
+        exporter = exporter.with_resource_attribute("host.name", &host);
+    }
+    println!(
+        "Pushing to {} every {} seconds",
+        exporter.url(),
+        opts.interval
+    );
+
+    loop {
+        match c.snapshot(Some(&patterns)) {
+            Ok(snap) => {
+                if let Err(e) = exporter.push(&snap) {
+                    eprintln!("Could not push metrics: {:?}", e);
+                } else if opts.verbose > 0 {
+                    println!("Pushed {} entries", snap.entries.len());
+                }
+            }
+            Err(e) => eprintln!("Could not dump the stats: {:?}", e),
+        }
+        std::thread::sleep(std::time::Duration::from_secs(opts.interval));
+    }
+}
@@ -0,0 +1,125 @@
+/*
+ * The --alert-rules evaluated by the collector every --alert-interval,
+ * with a rule engine per instance, and their state served on
+ * "/api/v1/alerts" as JSON.
+ *
+ * With --history-dir, the history the collector keeps of the instance
+ * is dumped into a file there whenever a rule of the instance fires.
+ */
+
+use crate::history::Histories;
+use crate::http::Page;
+use crate::targets::Targets;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use vpp_stat_client::alerts::{AlertConfig, AlertState, AlertStatus, RuleEngine};
+use vpp_stat_client::prometheus::prom_str;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AlertsResponse {
+    pub alerts: Vec<AlertStatus>,
+}
+
+pub struct Alerting {
+    config: AlertConfig,
+    interval: Duration,
+    due: Instant,
+    engines: BTreeMap<String, RuleEngine>,
+    dump_dir: Option<String>,
+}
+
+impl Alerting {
+    /// Check the rules up front, so the bad ones are reported at the start
+    pub fn new(config: AlertConfig, interval: Duration) -> Result<Self, String> {
+        RuleEngine::new(&config).map_err(|e| format!("{:?}", e))?;
+        Ok(Alerting {
+            config,
+            interval,
+            due: Instant::now(),
+            engines: BTreeMap::new(),
+            dump_dir: None,
+        })
+    }
+
+    /// Dump the history of the instance into the directory when a rule fires
+    pub fn with_dump_dir(mut self, dir: &str) -> Self {
+        self.dump_dir = Some(dir.to_string());
+        self
+    }
+
+    /// The stats the rules read
+    pub fn patterns(&self) -> Vec<String> {
+        RuleEngine::new(&self.config).unwrap().patterns()
+    }
+
+    /// Evaluate the rules on every instance, if the interval is up
+    pub fn poll(
+        &mut self,
+        targets: &mut Targets,
+        histories: Option<&Histories>,
+        stale_after: Duration,
+        multi: bool,
+        verbose: bool,
+    ) {
+        if Instant::now() < self.due {
+            return;
+        }
+        self.due = Instant::now() + self.interval;
+        /* The instances which are gone take their alerts with them */
+        self.engines
+            .retain(|name, _| targets.targets.contains_key(name));
+        for target in targets.targets.values_mut() {
+            let config = &self.config;
+            let engine = self.engines.entry(target.name.clone()).or_insert_with(|| {
+                let engine = RuleEngine::new(config).unwrap();
+                /* With a single instance there is no "instance" label either */
+                if multi {
+                    engine.with_instance(&target.name)
+                } else {
+                    engine
+                }
+            });
+            let snap = match target.fetch(&engine.patterns(), stale_after) {
+                Ok(snap) => snap,
+                Err(e) => {
+                    if verbose {
+                        eprintln!("Could not read {} for the alerts: {:?}", target.name, e);
+                    }
+                    continue;
+                }
+            };
+            let timestamp = snap.timestamp;
+            let fired = engine
+                .evaluate(snap)
+                .into_iter()
+                .find(|e| e.state == AlertState::Firing);
+            let history = histories.and_then(|h| h.get(&target.name));
+            if let (Some(event), Some(dir), Some(h)) = (fired, &self.dump_dir, history) {
+                let path = format!(
+                    "{}/{}-{}-{}.json",
+                    dir,
+                    prom_str(&target.name),
+                    prom_str(&event.rule),
+                    timestamp as u64
+                );
+                match h.dump(&path) {
+                    Ok(()) if verbose => println!("Saved the history into {}", path),
+                    Ok(()) => {}
+                    Err(e) => eprintln!("Could not save the history: {:?}", e),
+                }
+            }
+        }
+    }
+
+    pub fn page(&self) -> Page {
+        let response = AlertsResponse {
+            alerts: self.engines.values().flat_map(|e| e.status()).collect(),
+        };
+        Page::new(
+            200,
+            "application/json",
+            serde_json::to_vec(&response).unwrap(),
+        )
+    }
+}
@@ -0,0 +1,666 @@
+/*
+ * The JSON query API, for the automation that wants ad-hoc reads:
+ *
+ *   /api/v1/stats?pattern=^/if/rx&pattern=^/err/&aggregate=threads&names=true
+ *
+ * The vector counters come out as one value per object (and per thread,
+ * unless aggregated), with the interface names resolved if asked for.
+ *
+ * The history kept in memory is queried over a window, the whole of it
+ * by default, with the start and end in seconds since the epoch:
+ *
+ *   /api/v1/history?pattern=^/if/rx$&interface=eth0&query=percentile&measure=rate&p=99
+ *
+ * The query is one of range (the samples), rate (the average over the
+ * window), summary (min, max, avg) or percentile, taken over the values
+ * or over the per-second rates between the samples.
+ */
+
+use crate::http::{query_param, query_params};
+use regex::Regex;
+use serde::Serialize;
+use vpp_stat_client::delta::SeriesKey;
+use vpp_stat_client::history::{History, Measure, Sample, Summary};
+use vpp_stat_client::snapshot::{is_interface_stat, CombinedCounter, SnapshotValue, StatSnapshot};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApiQuery {
+    pub patterns: Vec<String>,
+    /// Sum the vector counters across the threads
+    pub aggregate_threads: bool,
+    /// Add the interface names to the "/if/" counters
+    pub names: bool,
+    pub instance: Option<String>,
+}
+
+fn flag(url: &str, name: &str) -> bool {
+    matches!(
+        query_param(url, name).as_deref(),
+        Some("true") | Some("1") | Some("yes")
+    )
+}
+
+/* The "pattern" parameters, everything if none */
+fn patterns(url: &str) -> Result<Vec<String>, String> {
+    let mut patterns = query_params(url, "pattern");
+    if patterns.is_empty() {
+        patterns.push(".*".to_string());
+    }
+    for p in &patterns {
+        Regex::new(p).map_err(|e| format!("Bad pattern {}: {}", p, e))?;
+    }
+    Ok(patterns)
+}
+
+fn number(url: &str, name: &str) -> Result<Option<f64>, String> {
+    match query_param(url, name) {
+        Some(v) => v
+            .parse()
+            .map(Some)
+            .map_err(|_| format!("Bad {}: {}", name, v)),
+        None => Ok(None),
+    }
+}
+
+impl ApiQuery {
+    pub fn parse(url: &str) -> Result<Self, String> {
+        let patterns = patterns(url)?;
+        let aggregate_threads = match query_param(url, "aggregate").as_deref() {
+            None | Some("none") => false,
+            Some("threads") => true,
+            Some(other) => return Err(format!("Can not aggregate by {}", other)),
+        };
+        Ok(ApiQuery {
+            patterns,
+            aggregate_threads,
+            names: flag(url, "names"),
+            instance: query_param(url, "instance"),
+        })
+    }
+
+    /// The key for caching the results, the same for the same query in any order
+    pub fn cache_key(&self) -> String {
+        let mut patterns = self.patterns.clone();
+        patterns.sort();
+        patterns.dedup();
+        format!(
+            "api:{:?}:{}:{}:{:?}",
+            patterns, self.aggregate_threads, self.names, self.instance
+        )
+    }
+
+    /// The patterns to read the stats with, including "/if/names" if needed
+    pub fn fetch_patterns(&self) -> Vec<String> {
+        let mut patterns = self.patterns.clone();
+        if self.names {
+            patterns.push("^/if/names$".to_string());
+        }
+        patterns
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ApiValue {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thread: Option<usize>,
+    pub index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interface: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub packets: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ApiEntryValue {
+    Scalar { value: f64 },
+    Simple { values: Vec<ApiValue> },
+    Combined { values: Vec<ApiValue> },
+    Names { names: Vec<Option<String>> },
+    Empty,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ApiEntry {
+    pub name: String,
+    #[serde(flatten)]
+    pub value: ApiEntryValue,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ApiResponse {
+    pub instance: String,
+    /// When the stats were read, in seconds since the epoch
+    pub timestamp: f64,
+    pub heartbeat: f64,
+    pub entries: Vec<ApiEntry>,
+}
+
+fn value_rows<T: Copy>(
+    query: &ApiQuery,
+    snap: &StatSnapshot,
+    name: &str,
+    threads: &[Vec<T>],
+    sum: impl Fn(T, T) -> T,
+    make: impl Fn(T) -> ApiValue,
+) -> Vec<ApiValue> {
+    let interface = |i: usize| {
+        if query.names && is_interface_stat(name) {
+            snap.interface_name(i).map(|n| n.to_string())
+        } else {
+            None
+        }
+    };
+    let mut rows = vec![];
+    if query.aggregate_threads {
+        let count = threads.iter().map(|t| t.len()).max().unwrap_or(0);
+        for i in 0..count {
+            let total = threads
+                .iter()
+                .filter_map(|t| t.get(i).copied())
+                .reduce(&sum);
+            if let Some(total) = total {
+                rows.push(ApiValue {
+                    index: i,
+                    interface: interface(i),
+                    ..make(total)
+                });
+            }
+        }
+    } else {
+        for (t, values) in threads.iter().enumerate() {
+            for (i, v) in values.iter().enumerate() {
+                rows.push(ApiValue {
+                    thread: Some(t),
+                    index: i,
+                    interface: interface(i),
+                    ..make(*v)
+                });
+            }
+        }
+    }
+    rows
+}
+
+impl ApiResponse {
+    pub fn new(query: &ApiQuery, instance: &str, snap: &StatSnapshot) -> Self {
+        let patterns: Vec<Regex> = query
+            .patterns
+            .iter()
+            .filter_map(|p| Regex::new(p).ok())
+            .collect();
+        let blank = ApiValue {
+            thread: None,
+            index: 0,
+            interface: None,
+            value: None,
+            packets: None,
+            bytes: None,
+        };
+        let entries = snap
+            .entries
+            .iter()
+            /* "/if/names" might only be there for resolving the names */
+            .filter(|e| {
+                !query.names
+                    || e.name != "/if/names"
+                    || patterns.iter().any(|re| re.is_match(&e.name))
+            })
+            .map(|e| {
+                let value = match &e.value {
+                    SnapshotValue::Scalar(v) => ApiEntryValue::Scalar { value: *v },
+                    SnapshotValue::Simple(threads) => ApiEntryValue::Simple {
+                        values: value_rows(
+                            query,
+                            snap,
+                            &e.name,
+                            threads,
+                            |a, b| a.wrapping_add(b),
+                            |v| ApiValue {
+                                value: Some(v),
+                                ..blank.clone()
+                            },
+                        ),
+                    },
+                    SnapshotValue::Combined(threads) => ApiEntryValue::Combined {
+                        values: value_rows(
+                            query,
+                            snap,
+                            &e.name,
+                            threads,
+                            |a, b| CombinedCounter {
+                                packets: a.packets.wrapping_add(b.packets),
+                                bytes: a.bytes.wrapping_add(b.bytes),
+                            },
+                            |c| ApiValue {
+                                packets: Some(c.packets),
+                                bytes: Some(c.bytes),
+                                ..blank.clone()
+                            },
+                        ),
+                    },
+                    SnapshotValue::Names(names) => ApiEntryValue::Names {
+                        names: names.clone(),
+                    },
+                    SnapshotValue::Empty => ApiEntryValue::Empty,
+                };
+                ApiEntry {
+                    name: e.name.clone(),
+                    value,
+                }
+            })
+            .collect();
+        ApiResponse {
+            instance: instance.to_string(),
+            timestamp: snap.timestamp,
+            heartbeat: snap.heartbeat,
+            entries,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HistoryFunction {
+    Range,
+    Rate,
+    Summary,
+    /// From 0 to 100
+    Percentile(f64),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryQuery {
+    pub patterns: Vec<String>,
+    /// Only the series of this interface
+    pub interface: Option<String>,
+    pub function: HistoryFunction,
+    pub measure: Measure,
+    /// In seconds since the epoch
+    pub start: Option<f64>,
+    pub end: Option<f64>,
+    pub instance: Option<String>,
+}
+
+impl HistoryQuery {
+    pub fn parse(url: &str) -> Result<Self, String> {
+        let function = match query_param(url, "query").as_deref() {
+            None | Some("range") => HistoryFunction::Range,
+            Some("rate") => HistoryFunction::Rate,
+            Some("summary") => HistoryFunction::Summary,
+            Some("percentile") => match number(url, "p")? {
+                Some(p) if (0.0..=100.0).contains(&p) => HistoryFunction::Percentile(p),
+                Some(p) => return Err(format!("The percentile {} is not within 0 to 100", p)),
+                None => return Err("The \"p\" parameter is missing".to_string()),
+            },
+            Some(other) => return Err(format!("No such query: {}", other)),
+        };
+        let measure = match query_param(url, "measure").as_deref() {
+            None | Some("value") => Measure::Value,
+            Some("rate") => Measure::Rate,
+            Some(other) => return Err(format!("Can not measure the {}", other)),
+        };
+        Ok(HistoryQuery {
+            patterns: patterns(url)?,
+            interface: query_param(url, "interface"),
+            function,
+            measure,
+            start: number(url, "start")?,
+            end: number(url, "end")?,
+            instance: query_param(url, "instance"),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum HistoryResult {
+    Samples { samples: Vec<Sample> },
+    Rate { rate: Option<f64> },
+    Summary { summary: Option<Summary> },
+    Percentile { percentile: Option<f64> },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HistorySeries {
+    #[serde(flatten)]
+    pub key: SeriesKey,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interface: Option<String>,
+    #[serde(flatten)]
+    pub result: HistoryResult,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HistoryResponse {
+    pub instance: String,
+    pub start: f64,
+    pub end: f64,
+    pub series: Vec<HistorySeries>,
+}
+
+impl HistoryResponse {
+    /// The window ends now unless given, and goes back over the whole history
+    pub fn new(
+        query: &HistoryQuery,
+        instance: &str,
+        history: &History,
+        names: &[Option<String>],
+        now: f64,
+    ) -> Self {
+        let patterns: Vec<Regex> = query
+            .patterns
+            .iter()
+            .filter_map(|p| Regex::new(p).ok())
+            .collect();
+        let end = query.end.unwrap_or(now);
+        let start = query.start.unwrap_or(end - history.retention());
+        let interface = |key: &SeriesKey| match key.index {
+            Some(i) if is_interface_stat(&key.name) => names.get(i).cloned().flatten(),
+            _ => None,
+        };
+        let series = history
+            .keys()
+            .filter(|key| patterns.iter().any(|re| re.is_match(&key.name)))
+            .map(|key| (key, interface(key)))
+            .filter(|(_, name)| query.interface.is_none() || *name == query.interface)
+            .map(|(key, interface)| {
+                let result = match query.function {
+                    HistoryFunction::Range => HistoryResult::Samples {
+                        samples: match query.measure {
+                            Measure::Value => history.range(key, start, end),
+                            Measure::Rate => history.rates(key, start, end),
+                        },
+                    },
+                    HistoryFunction::Rate => HistoryResult::Rate {
+                        rate: history.rate(key, start, end),
+                    },
+                    HistoryFunction::Summary => HistoryResult::Summary {
+                        summary: history.summary(key, start, end, query.measure),
+                    },
+                    HistoryFunction::Percentile(p) => HistoryResult::Percentile {
+                        percentile: history.percentile(key, start, end, query.measure, p),
+                    },
+                };
+                HistorySeries {
+                    key: key.clone(),
+                    interface,
+                    result,
+                }
+            })
+            .collect();
+        HistoryResponse {
+            instance: instance.to_string(),
+            start,
+            end,
+            series,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use vpp_stat_client::snapshot::SnapshotEntry;
+
+    fn snap() -> StatSnapshot {
+        let entry = |name: &str, value| SnapshotEntry {
+            name: name.to_string(),
+            value,
+        };
+        let c = |packets, bytes| CombinedCounter { packets, bytes };
+        StatSnapshot {
+            timestamp: 100.0,
+            heartbeat: 7.0,
+            entries: vec![
+                entry(
+                    "/if/rx",
+                    SnapshotValue::Combined(vec![
+                        vec![c(1, 60), c(2, 120)],
+                        vec![c(10, 600), c(20, 1200)],
+                    ]),
+                ),
+                entry(
+                    "/err/ip4-input/drops",
+                    SnapshotValue::Simple(vec![vec![3], vec![4]]),
+                ),
+                entry(
+                    "/if/names",
+                    SnapshotValue::Names(vec![Some("local0".to_string()), None]),
+                ),
+            ],
+        }
+    }
+
+    fn values(response: &ApiResponse, name: &str) -> Vec<ApiValue> {
+        match &response
+            .entries
+            .iter()
+            .find(|e| e.name == name)
+            .unwrap()
+            .value
+        {
+            ApiEntryValue::Simple { values } | ApiEntryValue::Combined { values } => values.clone(),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    fn row(thread: Option<usize>, index: usize, interface: Option<&str>) -> ApiValue {
+        ApiValue {
+            thread,
+            index,
+            interface: interface.map(|s| s.to_string()),
+            value: None,
+            packets: None,
+            bytes: None,
+        }
+    }
+
+    #[test]
+    fn parse() {
+        let q = ApiQuery::parse("/api/v1/stats").unwrap();
+        assert_eq!(q.patterns, vec![".*"]);
+        assert!(!q.aggregate_threads && !q.names);
+        assert_eq!(q.instance, None);
+
+        let q = ApiQuery::parse(
+            "/api/v1/stats?pattern=%5E/if/rx&pattern=^/err/&aggregate=threads&names=yes\
+             &instance=/run/vpp1/stats.sock",
+        )
+        .unwrap();
+        assert_eq!(q.patterns, vec!["^/if/rx", "^/err/"]);
+        assert!(q.aggregate_threads && q.names);
+        assert_eq!(q.instance.as_deref(), Some("/run/vpp1/stats.sock"));
+        assert_eq!(q.fetch_patterns(), vec!["^/if/rx", "^/err/", "^/if/names$"]);
+
+        assert!(!ApiQuery::parse("/api/v1/stats?names=no").unwrap().names);
+        assert!(ApiQuery::parse("/api/v1/stats?pattern=(").is_err());
+        assert!(ApiQuery::parse("/api/v1/stats?aggregate=nodes").is_err());
+    }
+
+    #[test]
+    fn cache_key() {
+        let key = |url| ApiQuery::parse(url).unwrap().cache_key();
+        assert_eq!(
+            key("/api/v1/stats?pattern=a&pattern=b"),
+            key("/api/v1/stats?pattern=b&pattern=a&pattern=b")
+        );
+        let keys = [
+            key("/api/v1/stats?pattern=a"),
+            key("/api/v1/stats?pattern=a&aggregate=threads"),
+            key("/api/v1/stats?pattern=a&names=1"),
+            key("/api/v1/stats?pattern=a&instance=b"),
+            key("/api/v1/stats?pattern=b"),
+        ];
+        for (i, a) in keys.iter().enumerate() {
+            assert!(a.starts_with("api:"));
+            assert!(keys[i + 1..].iter().all(|b| a != b));
+        }
+    }
+
+    #[test]
+    fn per_thread_rows() {
+        let q = ApiQuery::parse("/api/v1/stats").unwrap();
+        let r = ApiResponse::new(&q, "vpp1", &snap());
+        assert_eq!(
+            (r.instance.as_str(), r.timestamp, r.heartbeat),
+            ("vpp1", 100.0, 7.0)
+        );
+        /* Nothing resolved, and "/if/names" is there as asked for */
+        assert_eq!(r.entries.len(), 3);
+        assert_eq!(
+            values(&r, "/if/rx"),
+            vec![
+                ApiValue {
+                    packets: Some(1),
+                    bytes: Some(60),
+                    ..row(Some(0), 0, None)
+                },
+                ApiValue {
+                    packets: Some(2),
+                    bytes: Some(120),
+                    ..row(Some(0), 1, None)
+                },
+                ApiValue {
+                    packets: Some(10),
+                    bytes: Some(600),
+                    ..row(Some(1), 0, None)
+                },
+                ApiValue {
+                    packets: Some(20),
+                    bytes: Some(1200),
+                    ..row(Some(1), 1, None)
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn aggregated_and_named() {
+        let q = ApiQuery::parse(
+            "/api/v1/stats?pattern=^/if/rx&pattern=^/err/&aggregate=threads&names=1",
+        )
+        .unwrap();
+        let r = ApiResponse::new(&q, "vpp1", &snap());
+        /* "/if/names" was only read to resolve the names */
+        let names: Vec<&str> = r.entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, vec!["/if/rx", "/err/ip4-input/drops"]);
+        assert_eq!(
+            values(&r, "/if/rx"),
+            vec![
+                ApiValue {
+                    packets: Some(11),
+                    bytes: Some(660),
+                    ..row(None, 0, Some("local0"))
+                },
+                ApiValue {
+                    packets: Some(22),
+                    bytes: Some(1320),
+                    ..row(None, 1, None)
+                },
+            ]
+        );
+        /* Not an interface counter */
+        assert_eq!(
+            values(&r, "/err/ip4-input/drops"),
+            vec![ApiValue {
+                value: Some(7),
+                ..row(None, 0, None)
+            }]
+        );
+
+        let q = ApiQuery::parse("/api/v1/stats?pattern=^/if/&names=1").unwrap();
+        let r = ApiResponse::new(&q, "vpp1", &snap());
+        assert!(r.entries.iter().any(|e| e.name == "/if/names"));
+    }
+
+    #[test]
+    fn history_parse() {
+        let q = HistoryQuery::parse("/api/v1/history").unwrap();
+        assert_eq!(q.patterns, vec![".*"]);
+        assert_eq!(
+            (q.function, q.measure, q.start, q.end),
+            (HistoryFunction::Range, Measure::Value, None, None)
+        );
+
+        let q = HistoryQuery::parse(
+            "/api/v1/history?pattern=^/if/rx$&interface=eth0&query=percentile&p=99.9\
+             &measure=rate&start=100&end=160.5",
+        )
+        .unwrap();
+        assert_eq!(q.interface.as_deref(), Some("eth0"));
+        assert_eq!(q.function, HistoryFunction::Percentile(99.9));
+        assert_eq!(
+            (q.measure, q.start, q.end),
+            (Measure::Rate, Some(100.0), Some(160.5))
+        );
+
+        for bad in [
+            "query=percentile",
+            "query=percentile&p=101",
+            "query=median",
+            "measure=bytes",
+            "start=yesterday",
+            "pattern=(",
+        ] {
+            assert!(HistoryQuery::parse(&format!("/api/v1/history?{}", bad)).is_err());
+        }
+    }
+
+    #[test]
+    fn history_series() {
+        let mut history = History::new(Duration::from_secs(1), Duration::from_secs(60));
+        for t in 0..=4u64 {
+            let entry = |name: &str, value| SnapshotEntry {
+                name: name.to_string(),
+                value,
+            };
+            history.record(&StatSnapshot {
+                timestamp: 100.0 + t as f64,
+                heartbeat: 0.0,
+                entries: vec![
+                    entry("/if/drops", SnapshotValue::Simple(vec![vec![t, t * 10]])),
+                    entry("/sys/vector_rate", SnapshotValue::Scalar(2.0)),
+                ],
+            });
+        }
+        let names = vec![Some("local0".to_string()), Some("eth0".to_string())];
+        let response = |url| {
+            let q = HistoryQuery::parse(url).unwrap();
+            HistoryResponse::new(&q, "vpp1", &history, &names, 104.0)
+        };
+
+        let r = response("/api/v1/history?pattern=^/if/drops$&interface=eth0&query=rate");
+        assert_eq!((r.start, r.end), (44.0, 104.0));
+        assert_eq!(r.series.len(), 1);
+        assert_eq!(r.series[0].key.index, Some(1));
+        assert_eq!(r.series[0].interface.as_deref(), Some("eth0"));
+        assert_eq!(r.series[0].result, HistoryResult::Rate { rate: Some(10.0) });
+
+        let r = response("/api/v1/history?pattern=^/if/drops$&measure=rate&start=102");
+        let rates: Vec<f64> = r
+            .series
+            .iter()
+            .flat_map(|s| match &s.result {
+                HistoryResult::Samples { samples } => {
+                    samples.iter().map(|s| s.value).collect::<Vec<f64>>()
+                }
+                other => panic!("{:?}", other),
+            })
+            .collect();
+        assert_eq!(rates, vec![1.0, 1.0, 10.0, 10.0]);
+
+        let r = response("/api/v1/history?pattern=^/sys/&query=summary");
+        let json = serde_json::to_string(&r.series).unwrap();
+        assert_eq!(
+            json,
+            "[{\"name\":\"/sys/vector_rate\",\"thread\":null,\"index\":null,\
+             \"field\":\"Value\",\"summary\":{\"count\":5,\"min\":2.0,\"max\":2.0,\
+             \"avg\":2.0}}]"
+        );
+    }
+}
@@ -0,0 +1,237 @@
+/*
+ * A small live dashboard served on "/": the interface rates, their peaks
+ * over the history and the error counters which are incrementing, pushed
+ * to the browser as Server-Sent Events on "/events". The page is
+ * self-contained, no external assets.
+ *
+ * All the event streams are fed by one hub thread, which asks the collector
+ * for the tables once per --dashboard-interval, for every instance that
+ * is being watched, and queues them to the subscribers. Every subscriber
+ * has a thread of its own doing the writes, so a stalled client holds up
+ * nobody else; one which falls too far behind is dropped, and the browser
+ * reconnects.
+ */
+
+use crate::http::percent_encode;
+use crate::{collect, Job};
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::sync::mpsc::{sync_channel, Receiver, Sender, SyncSender, TryRecvError};
+use std::time::Duration;
+use vpp_stat_client::top::Table;
+
+pub const DASHBOARD_PATTERNS: &[&str] = &["^/if/", "^/err/"];
+
+/// What every event carries
+#[derive(Debug, Clone, Serialize)]
+pub struct DashboardData {
+    pub instance: String,
+    pub timestamp: f64,
+    pub interfaces: Table,
+    pub errors: Table,
+    /// The peak interface rates over the history, if it is kept
+    pub peaks: Option<Table>,
+}
+
+/* How many events can wait for a slow client before it is dropped */
+const SUBSCRIBER_BACKLOG: usize = 4;
+
+pub struct Subscriber {
+    /* None for the default instance */
+    pub instance: Option<String>,
+    /* To the thread writing the events out */
+    events: SyncSender<String>,
+}
+
+impl Subscriber {
+    /// Send the headers of the event stream, before handing it over to the hub
+    pub fn start(instance: Option<String>, mut writer: Box<dyn Write + Send>) -> Option<Self> {
+        let headers = "HTTP/1.1 200 OK\r\n\
+                       Content-Type: text/event-stream\r\n\
+                       Cache-Control: no-cache\r\n\
+                       Connection: close\r\n\r\n\
+                       retry: 3000\n\n";
+        writer.write_all(headers.as_bytes()).ok()?;
+        writer.flush().ok()?;
+        let (events, rx) = sync_channel::<String>(SUBSCRIBER_BACKLOG);
+        std::thread::spawn(move || {
+            for event in rx {
+                if writer.write_all(event.as_bytes()).is_err() || writer.flush().is_err() {
+                    return;
+                }
+            }
+        });
+        Some(Subscriber { instance, events })
+    }
+
+    /* False once the client went away, or is too far behind */
+    fn send(&self, event: &str) -> bool {
+        self.events.try_send(event.to_string()).is_ok()
+    }
+}
+
+fn data_url(instance: &Option<String>) -> String {
+    match instance {
+        Some(i) => format!("/dashboard/data?instance={}", percent_encode(i)),
+        None => "/dashboard/data".to_string(),
+    }
+}
+
+/* One "data:" line per line of the payload */
+fn event(name: &str, payload: &str) -> String {
+    let mut out = format!("event: {}\n", name);
+    for line in payload.lines() {
+        out.push_str(&format!("data: {}\n", line));
+    }
+    out.push('\n');
+    out
+}
+
+/// Feed all the subscribers until the collector goes away
+pub fn hub(
+    jobs: Sender<Job>,
+    subscribers: Receiver<Subscriber>,
+    interval: Duration,
+    timeout: Duration,
+) {
+    let mut subs: Vec<Subscriber> = vec![];
+    loop {
+        /* Nothing to do without anyone watching */
+        if subs.is_empty() {
+            match subscribers.recv() {
+                Ok(s) => subs.push(s),
+                Err(_) => return,
+            }
+        }
+        loop {
+            match subscribers.try_recv() {
+                Ok(s) => subs.push(s),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return,
+            }
+        }
+
+        let instances: BTreeSet<Option<String>> = subs.iter().map(|s| s.instance.clone()).collect();
+        for instance in instances {
+            let page = match collect(&jobs, data_url(&instance), timeout) {
+                Some(p) => p,
+                None => return,
+            };
+            let body = String::from_utf8_lossy(&page.body);
+            let ev = if page.status == 200 {
+                event("stats", &body)
+            } else {
+                event("failure", &body)
+            };
+            subs.retain(|s| s.instance != instance || s.send(&ev));
+        }
+        std::thread::sleep(interval);
+    }
+}
+
+pub static DASHBOARD_PAGE: &str = r#"<!DOCTYPE html>
+<html><head><meta charset="utf-8"><title>VPP dashboard</title>
+<style>
+body { font-family: sans-serif; margin: 1em 2em; color: #222; }
+h1 { font-size: 1.3em; } h2 { font-size: 1.1em; margin-top: 1.5em; }
+table { border-collapse: collapse; min-width: 40em; }
+th, td { padding: 0.2em 0.8em; border-bottom: 1px solid #ddd; }
+th { text-align: left; background: #f0f0f0; } td.n { text-align: right; font-family: monospace; }
+#status { color: #888; } #status.bad { color: #c00; }
+nav a { margin-right: 1em; }
+</style></head>
+<body>
+<h1>VPP <span id="instance"></span></h1>
+<nav><a href="/metrics">metrics</a><a href="/healthz">healthz</a></nav>
+<p id="status">Connecting...</p>
+<h2>Interfaces</h2><table id="interfaces"></table>
+<div id="peaks-section" hidden><h2>Peaks over the history</h2><table id="peaks"></table></div>
+<h2>Errors incrementing</h2><table id="errors"></table>
+<script>
+function human(v) {
+  if (v === null) return "-";
+  var units = ["", "k", "M", "G", "T"], i = 0;
+  while (Math.abs(v) >= 1000 && i < units.length - 1) { v /= 1000; i++; }
+  return (i == 0 && Number.isInteger(v) ? v : v.toFixed(2)) + units[i];
+}
+function render(id, table, sortColumn) {
+  var rows = table.rows.slice();
+  if (sortColumn > 0) rows.sort(function (a, b) { return b.values[sortColumn - 1] - a.values[sortColumn - 1]; });
+  var el = document.getElementById(id);
+  el.textContent = "";
+  var head = el.insertRow();
+  table.columns.forEach(function (c) { var th = document.createElement("th"); th.textContent = c; head.appendChild(th); });
+  rows.forEach(function (r) {
+    var tr = el.insertRow();
+    tr.insertCell().textContent = r.label;
+    r.values.forEach(function (v) { var td = tr.insertCell(); td.className = "n"; td.textContent = human(v); });
+  });
+}
+var statusLine = document.getElementById("status");
+var source = new EventSource("/events" + location.search);
+source.addEventListener("stats", function (e) {
+  var d = JSON.parse(e.data);
+  document.getElementById("instance").textContent = d.instance;
+  render("interfaces", d.interfaces, 0);
+  render("errors", d.errors, 3);
+  document.getElementById("peaks-section").hidden = !d.peaks;
+  if (d.peaks) render("peaks", d.peaks, 0);
+  statusLine.className = "";
+  statusLine.textContent = "Updated " + new Date(d.timestamp * 1000).toLocaleTimeString();
+});
+source.addEventListener("failure", function (e) {
+  statusLine.className = "bad";
+  statusLine.textContent = e.data;
+});
+source.onerror = function () {
+  statusLine.className = "bad";
+  statusLine.textContent = "Disconnected, retrying...";
+};
+</script>
+</body></html>
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn event_lines() {
+        assert_eq!(event("stats", "{}"), "event: stats\ndata: {}\n\n");
+        assert_eq!(
+            event("failure", "Could not read\nthe stats\n"),
+            "event: failure\ndata: Could not read\ndata: the stats\n\n"
+        );
+    }
+
+    /* Never done with a write, until the test is over */
+    struct Stalled(Receiver<()>);
+
+    impl Write for Stalled {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            match self.0.recv() {
+                Ok(()) => Ok(buf.len()),
+                Err(_) => Err(std::io::ErrorKind::BrokenPipe.into()),
+            }
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn stalled_client_dropped() {
+        let (unblock, rx) = channel();
+        /* Let the headers through */
+        unblock.send(()).unwrap();
+        let s = Subscriber::start(None, Box::new(Stalled(rx))).unwrap();
+        /* One taken by the writer thread at most, the rest wait */
+        let sent = (0..SUBSCRIBER_BACKLOG + 2)
+            .take_while(|_| s.send("event: stats\n\n"))
+            .count();
+        assert!(sent >= SUBSCRIBER_BACKLOG && sent <= SUBSCRIBER_BACKLOG + 1);
+    }
+}
@@ -0,0 +1,115 @@
+/*
+ * The last --history-minutes of the stats of every instance, read by the
+ * collector every --history-resolution seconds. The history is queried
+ * on "/api/v1/history", gives the dashboard its peak interface rates,
+ * and is what the alerting dumps into --history-dir when a rule fires.
+ *
+ * The interface counters of the peaks are always kept, along with the
+ * --history-pattern stats and the ones the alerting rules read.
+ */
+
+use crate::api::{HistoryQuery, HistoryResponse};
+use crate::http::{text_response, Page};
+use crate::targets::Targets;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use vpp_stat_client::history::History;
+use vpp_stat_client::snapshot::unix_time_now;
+use vpp_stat_client::top::PEAK_PATTERNS;
+
+struct Kept {
+    history: History,
+    /* The latest ones, for the interface names of the series */
+    names: Vec<Option<String>>,
+}
+
+pub struct Histories {
+    resolution: Duration,
+    retention: Duration,
+    patterns: Vec<String>,
+    due: Instant,
+    kept: BTreeMap<String, Kept>,
+}
+
+impl Histories {
+    pub fn new(resolution: Duration, retention: Duration, patterns: &[String]) -> Self {
+        let mut patterns: Vec<String> = PEAK_PATTERNS
+            .iter()
+            .map(|p| p.to_string())
+            .chain(patterns.iter().cloned())
+            .collect();
+        patterns.sort();
+        patterns.dedup();
+        Histories {
+            resolution,
+            retention,
+            patterns,
+            due: Instant::now(),
+            kept: BTreeMap::new(),
+        }
+    }
+
+    /// Record the stats of every instance, if the resolution is up
+    pub fn poll(&mut self, targets: &mut Targets, stale_after: Duration, verbose: bool) {
+        if Instant::now() < self.due {
+            return;
+        }
+        self.due = Instant::now() + self.resolution;
+        /* The instances which are gone take their history with them */
+        self.kept
+            .retain(|name, _| targets.targets.contains_key(name));
+        let mut patterns = self.patterns.clone();
+        patterns.push("^/if/names$".to_string());
+        for target in targets.targets.values_mut() {
+            let snap = match target.fetch(&patterns, stale_after) {
+                Ok(snap) => snap,
+                Err(e) => {
+                    if verbose {
+                        eprintln!("Could not read {} for the history: {:?}", target.name, e);
+                    }
+                    continue;
+                }
+            };
+            let (resolution, retention, kept_patterns) =
+                (self.resolution, self.retention, &self.patterns);
+            let kept = self
+                .kept
+                .entry(target.name.clone())
+                .or_insert_with(|| Kept {
+                    history: History::new(resolution, retention).with_patterns(kept_patterns),
+                    names: vec![],
+                });
+            kept.history.record(&snap);
+            kept.names = snap.interface_names().to_vec();
+        }
+    }
+
+    pub fn get(&self, instance: &str) -> Option<&History> {
+        self.kept.get(instance).map(|k| &k.history)
+    }
+
+    /// The answer to a query, of the first instance unless it names one
+    pub fn page(&self, query: &HistoryQuery) -> Page {
+        let kept = match &query.instance {
+            Some(i) => self.kept.get_key_value(i),
+            None => self.kept.iter().next(),
+        };
+        match kept {
+            Some((instance, kept)) => {
+                let response = HistoryResponse::new(
+                    query,
+                    instance,
+                    &kept.history,
+                    &kept.names,
+                    unix_time_now(),
+                );
+                Page::new(
+                    200,
+                    "application/json",
+                    serde_json::to_vec(&response).unwrap(),
+                )
+            }
+            None => text_response(404, "No history of such an instance yet\n"),
+        }
+    }
+}
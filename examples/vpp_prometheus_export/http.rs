@@ -0,0 +1,369 @@
+/*
+ * The listening side of the exporter: TCP and unix socket listeners,
+ * optional TLS and the basic authentication of the requests.
+ */
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::io::{Cursor, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::OnceLock;
+use tiny_http::{ConfigListenAddr, Header, Request, Response, Server, ServerConfig, SslConfig};
+
+pub type HttpResponse = Response<Cursor<Vec<u8>>>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ListenAddr {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    /// Either "host:port", or "unix:/path/to/socket"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some("") => Err("empty unix socket path".to_string()),
+            Some(path) => Ok(ListenAddr::Unix(PathBuf::from(path))),
+            None => Ok(ListenAddr::Tcp(s.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TlsFiles {
+    pub cert: String,
+    pub key: String,
+}
+
+impl TlsFiles {
+    fn load(&self) -> Result<SslConfig, String> {
+        let read = |path: &str| std::fs::read(path).map_err(|e| format!("{}: {}", path, e));
+        Ok(SslConfig {
+            certificate: read(&self.cert)?,
+            private_key: read(&self.key)?,
+        })
+    }
+}
+
+/// Start listening; the TLS only applies to the TCP listeners
+pub fn bind(addr: &ListenAddr, tls: Option<&TlsFiles>) -> Result<Server, String> {
+    match addr {
+        ListenAddr::Tcp(a) => {
+            let ssl = match tls {
+                Some(t) => Some(t.load()?),
+                None => None,
+            };
+            let addr =
+                ConfigListenAddr::from_socket_addrs(a.as_str()).map_err(|e| e.to_string())?;
+            Server::new(ServerConfig { addr, ssl }).map_err(|e| e.to_string())
+        }
+        ListenAddr::Unix(path) => {
+            /* A socket left over from the previous run would fail the bind */
+            if std::fs::symlink_metadata(path).is_ok() {
+                std::fs::remove_file(path).map_err(|e| e.to_string())?;
+            }
+            Server::http_unix(path).map_err(|e| e.to_string())
+        }
+    }
+}
+
+/// Feed the requests from all the listeners into one queue
+pub fn incoming(servers: Vec<Server>) -> Receiver<Request> {
+    let (tx, rx) = channel();
+    for server in servers {
+        let tx = tx.clone();
+        std::thread::spawn(move || {
+            for request in server.incoming_requests() {
+                if tx.send(request).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    rx
+}
+
+pub fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[derive(Debug, Clone)]
+pub struct BasicAuth {
+    expected: String,
+}
+
+impl BasicAuth {
+    pub fn new(user: &str, password: &str) -> Self {
+        BasicAuth {
+            expected: format!(
+                "Basic {}",
+                base64(format!("{}:{}", user, password).as_bytes())
+            ),
+        }
+    }
+
+    pub fn check(&self, request: &Request) -> bool {
+        let given = request
+            .headers()
+            .iter()
+            .find(|h| h.field.equiv("Authorization"))
+            .map(|h| h.value.as_str())
+            .unwrap_or("");
+        /* Do not give away how much of the credentials matched */
+        given.len() == self.expected.len()
+            && given
+                .bytes()
+                .zip(self.expected.bytes())
+                .fold(0, |acc, (a, b)| acc | (a ^ b))
+                == 0
+    }
+}
+
+pub fn header(field: &str, value: &str) -> Header {
+    Header::from_bytes(field.as_bytes(), value.as_bytes()).unwrap()
+}
+
+/* Not worth compressing below this */
+const GZIP_MIN_SIZE: usize = 1024;
+
+/// A response which can be cached and sent from any of the worker threads;
+/// the compressed body is made on the first request that accepts it.
+#[derive(Debug)]
+pub struct Page {
+    pub status: u16,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: Vec<u8>,
+    gzipped: OnceLock<Vec<u8>>,
+}
+
+impl Page {
+    pub fn new(status: u16, content_type: &str, body: Vec<u8>) -> Self {
+        Page {
+            status,
+            headers: vec![("Content-Type", content_type.to_string())],
+            body,
+            gzipped: OnceLock::new(),
+        }
+    }
+
+    pub fn with_header(mut self, field: &'static str, value: &str) -> Self {
+        self.headers.push((field, value.to_string()));
+        self
+    }
+
+    fn gzipped(&self) -> &[u8] {
+        self.gzipped.get_or_init(|| {
+            let mut encoder = GzEncoder::new(vec![], Compression::default());
+            encoder.write_all(&self.body).unwrap();
+            encoder.finish().unwrap()
+        })
+    }
+
+    pub fn response(&self, accept_gzip: bool) -> HttpResponse {
+        let gzip = accept_gzip && self.body.len() >= GZIP_MIN_SIZE;
+        let body = if gzip {
+            self.gzipped().to_vec()
+        } else {
+            self.body.clone()
+        };
+        let mut response = Response::from_data(body).with_status_code(self.status);
+        for (field, value) in &self.headers {
+            response.add_header(header(field, value));
+        }
+        if gzip {
+            response.add_header(header("Content-Encoding", "gzip"));
+        }
+        response.with_header(header("Vary", "Accept-Encoding"))
+    }
+}
+
+/// Whether the client takes the gzip encoded responses
+pub fn accepts_gzip(request: &Request) -> bool {
+    request
+        .headers()
+        .iter()
+        .filter(|h| h.field.equiv("Accept-Encoding"))
+        .flat_map(|h| h.value.as_str().split(','))
+        .any(|e| {
+            let mut parts = e.split(';').map(str::trim);
+            parts.next() == Some("gzip") && !parts.any(|q| q == "q=0" || q == "q=0.0")
+        })
+}
+
+pub fn text_response(status: u16, body: &str) -> Page {
+    Page::new(
+        status,
+        "text/plain; charset=utf-8",
+        body.as_bytes().to_vec(),
+    )
+}
+
+pub fn html_response(status: u16, body: &str) -> Page {
+    Page::new(status, "text/html; charset=utf8", body.as_bytes().to_vec())
+}
+
+pub fn unauthorized() -> Page {
+    text_response(401, "Unauthorized\n")
+        .with_header("WWW-Authenticate", "Basic realm=\"vpp exporter\"")
+}
+
+/* Undo the %XX and '+' escaping of a query string component */
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' => match s.get(i + 1..i + 3).map(|h| u8::from_str_radix(h, 16)) {
+                Some(Ok(b)) => {
+                    out.push(b);
+                    i += 2;
+                }
+                _ => out.push(b'%'),
+            },
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Escape a query string component
+pub fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'/' | b'-' | b'_' | b'.' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// All the values of a parameter in the query string of the request URL
+pub fn query_params(url: &str, name: &str) -> Vec<String> {
+    let query = match url.split_once('?') {
+        Some((_, q)) => q,
+        None => return vec![],
+    };
+    query
+        .split('&')
+        .filter_map(|kv| {
+            let (k, v) = kv.split_once('=').unwrap_or((kv, ""));
+            if percent_decode(k) == name {
+                Some(percent_decode(v))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// The value of a parameter in the query string of the request URL
+pub fn query_param(url: &str, name: &str) -> Option<String> {
+    query_params(url, name).into_iter().next()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+    use tiny_http::TestRequest;
+
+    fn request(accept_encoding: Option<&str>) -> Request {
+        let mut r = TestRequest::new().with_path("/metrics");
+        if let Some(a) = accept_encoding {
+            r = r.with_header(header("Accept-Encoding", a));
+        }
+        r.into()
+    }
+
+    fn header_value(response: &HttpResponse, field: &'static str) -> Option<String> {
+        response
+            .headers()
+            .iter()
+            .find(|h| h.field.equiv(field))
+            .map(|h| h.value.to_string())
+    }
+
+    #[test]
+    fn gzip_accepted() {
+        assert!(!accepts_gzip(&request(None)));
+        assert!(!accepts_gzip(&request(Some("deflate, br"))));
+        assert!(accepts_gzip(&request(Some("deflate, gzip"))));
+        assert!(accepts_gzip(&request(Some("br;q=1.0, gzip;q=0.5"))));
+        assert!(!accepts_gzip(&request(Some("br, gzip;q=0"))));
+        assert!(!accepts_gzip(&request(Some("gzip; q=0.0"))));
+        /* Not a prefix match */
+        assert!(!accepts_gzip(&request(Some("x-gzip"))));
+    }
+
+    #[test]
+    fn percent_encoding() {
+        assert_eq!(
+            percent_encode("/run/vpp1/stats.sock"),
+            "/run/vpp1/stats.sock"
+        );
+        assert_eq!(percent_encode("a b&c=d+é"), "a%20b%26c%3Dd%2B%C3%A9");
+        for s in ["/run/vpp 1/stats.sock", "a&b=c", "100%", "é+"] {
+            let url = format!("/probe?target={}", percent_encode(s));
+            assert_eq!(query_param(&url, "target").as_deref(), Some(s));
+        }
+    }
+
+    #[test]
+    fn page_response() {
+        let small = text_response(503, "Busy\n").with_header("Retry-After", "1");
+        let response = small.response(true);
+        assert_eq!(response.status_code().0, 503);
+        assert_eq!(header_value(&response, "Content-Encoding"), None);
+        assert_eq!(header_value(&response, "Retry-After").as_deref(), Some("1"));
+        assert_eq!(
+            header_value(&response, "Vary").as_deref(),
+            Some("Accept-Encoding")
+        );
+        let mut body = String::new();
+        response.into_reader().read_to_string(&mut body).unwrap();
+        assert_eq!(body, "Busy\n");
+
+        let text = "vpp_up 1\n".repeat(200);
+        let large = text_response(200, &text);
+        let response = large.response(false);
+        assert_eq!(header_value(&response, "Content-Encoding"), None);
+        assert_eq!(response.data_length(), Some(text.len()));
+        let response = large.response(true);
+        assert_eq!(
+            header_value(&response, "Content-Encoding").as_deref(),
+            Some("gzip")
+        );
+        assert!(response.data_length().unwrap() < text.len());
+        let mut body = String::new();
+        GzDecoder::new(response.into_reader())
+            .read_to_string(&mut body)
+            .unwrap();
+        assert_eq!(body, text);
+    }
+}
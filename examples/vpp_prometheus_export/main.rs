@@ -0,0 +1,916 @@
+use clap::Parser as ClapParser;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tiny_http::Request;
+use vpp_stat_client::alerts::AlertConfig;
+use vpp_stat_client::discovery::SocketDiscovery;
+use vpp_stat_client::exporter_config::{ExporterConfig, FamilyEncoder};
+use vpp_stat_client::monotonic::MONOTONIC_PATTERNS;
+use vpp_stat_client::prometheus::{encode_text, PromFamily};
+use vpp_stat_client::recording::Replay;
+use vpp_stat_client::snapshot::StatSnapshot;
+use vpp_stat_client::source::{PatternFilter, SourceError, StatSource};
+use vpp_stat_client::top::{errors_table, interfaces_table, peaks_table};
+use vpp_stat_client::*;
+
+mod alerting;
+mod api;
+mod dashboard;
+mod history;
+mod http;
+mod push;
+mod self_metrics;
+mod targets;
+
+use alerting::Alerting;
+use api::{ApiQuery, ApiResponse, HistoryQuery};
+use dashboard::*;
+use history::Histories;
+use http::*;
+use self_metrics::self_families;
+use targets::*;
+
+/// Prometheus exporter for the VPP statistics
+#[derive(Debug, Clone, ClapParser, Serialize, Deserialize)]
+#[clap(version = "0.0", author = "Andrew Yourtchenko <ayourtch@gmail.com>")]
+pub struct Opts {
+    /// VPP stats socket as supplied in the "statseg { socket-name /path/to/socket }" config
+    #[clap(short, long, default_value = "/tmp/stats.sock")]
+    pub socket: String,
+
+    /// Stats socket of one of several VPP instances to scrape on /metrics, with an "instance"
+    /// label; can be given multiple times, instead of --socket
+    #[clap(long, conflicts_with_all = &["replay", "push-url"])]
+    pub target: Vec<String>,
+
+    /// Glob of the stats sockets to scrape like the --target ones, watched for the sockets
+    /// which appear and disappear, e.g. "/run/vpp/*/stats.sock"
+    #[clap(long, conflicts_with_all = &["replay", "push-url"])]
+    pub discover: Option<String>,
+
+    /// Regex of the sockets which can be probed with /probe?target=..., besides the --target ones
+    #[clap(long)]
+    pub probe_allow: Option<String>,
+
+    /// Pattern to match
+    #[clap(short, long, default_value = ".*")]
+    pub pattern: Vec<String>,
+
+    /// YAML file with the include/exclude, rename and aggregation rules, instead of --pattern
+    #[clap(short, long)]
+    pub config: Option<String>,
+
+    /// Address to listen on, "host:port" or "unix:/path/to/socket"; can be given multiple times
+    #[clap(short, long, default_value = "0.0.0.0:8000")]
+    pub listen: Vec<String>,
+
+    /// PEM certificate file, to serve HTTPS on the TCP listeners
+    #[clap(long, requires = "tls-key")]
+    pub tls_cert: Option<String>,
+
+    /// PEM private key file for the --tls-cert
+    #[clap(long, requires = "tls-cert")]
+    pub tls_key: Option<String>,
+
+    /// Require the HTTP basic authentication with this user name
+    #[clap(long, requires = "basic-auth-password-file")]
+    pub basic_auth_user: Option<String>,
+
+    /// File with the password for the --basic-auth-user
+    #[clap(long, requires = "basic-auth-user")]
+    pub basic_auth_password_file: Option<String>,
+
+    /// Report unhealthy on /healthz if the VPP heartbeat has not moved for this many seconds;
+    /// it is reported unknown until it has been seen moving
+    #[clap(long, default_value = "30")]
+    pub heartbeat_timeout: u64,
+
+    /// Serve the stats from a recording made with vpp_stats_record instead of the live VPP
+    #[clap(long)]
+    pub replay: Option<String>,
+
+    /// Replay speed: 1.0 is real time, 0 gives the next recorded frame on every scrape
+    #[clap(long, default_value = "1.0")]
+    pub replay_speed: f64,
+
+    /// Push the metrics to this remote_write URL instead of serving them
+    #[clap(long)]
+    pub push_url: Option<String>,
+
+    /// Interval between the remote_write pushes, in seconds
+    #[clap(long, default_value = "15")]
+    pub push_interval: u64,
+
+    /// How many batches to hold in memory while the remote_write URL is unreachable
+    #[clap(long, default_value = "100")]
+    pub push_queue: usize,
+
+    /// Value of the "instance" label added to the pushed series
+    #[clap(long)]
+    pub instance: Option<String>,
+
+    /// Add a "_per_second" gauge next to every counter, with the rate since the previous scrape
+    #[clap(long)]
+    pub rates: bool,
+
+    /// Keep the counters going up across the VPP restarts, with the offsets saved in this file.
+    /// The interface series get a "name" label, as their offsets follow the interface name
+    #[clap(long)]
+    pub monotonic_state: Option<String>,
+
+    /// Serve the scrapes coming within this many milliseconds of a successful one from the
+    /// cache, rather than reading the stats again
+    #[clap(long, default_value = "1000")]
+    pub min_interval: u64,
+
+    /// Number of the threads serving the HTTP requests
+    #[clap(long, default_value = "4")]
+    pub workers: usize,
+
+    /// Give up on a scrape that takes longer than this many seconds, with a 503; the instances
+    /// not read by then are skipped
+    #[clap(long, default_value = "10")]
+    pub scrape_timeout: u64,
+
+    /// Interval between the updates of the live dashboard, in milliseconds
+    #[clap(long, default_value = "1000")]
+    pub dashboard_interval: u64,
+
+    /// YAML file with the alerting rules, their state is served on /api/v1/alerts
+    #[clap(long)]
+    pub alert_rules: Option<String>,
+
+    /// Interval between the evaluations of the alerting rules, in seconds
+    #[clap(long, default_value = "10")]
+    pub alert_interval: u64,
+
+    /// Dump the history kept in memory into this directory when an alert fires
+    #[clap(long, requires = "alert-rules")]
+    pub history_dir: Option<String>,
+
+    /// How many minutes of the stats to keep in memory, for /api/v1/history, the peaks on
+    /// the dashboard and the --history-dir dumps; 0 keeps none
+    #[clap(long, default_value = "10")]
+    pub history_minutes: u64,
+
+    /// Seconds between the samples of the history
+    #[clap(long, default_value = "10")]
+    pub history_resolution: u64,
+
+    /// Pattern of the stats to keep in the history, can be given multiple times; the interface
+    /// counters and the stats the alerting rules read are always kept
+    #[clap(long)]
+    pub history_pattern: Vec<String>,
+
+    /// A level of verbosity, and can be used multiple times
+    #[clap(short, long, parse(from_occurrences))]
+    pub verbose: i32,
+}
+
+/* Every distinct API query gets an entry, so the cache is kept at most this big */
+const CACHE_MAX: usize = 100;
+
+/* A probed instance which is not scraped for this long is dropped, unmapping its segment */
+const PROBE_IDLE: Duration = Duration::from_secs(300);
+
+static NOT_FOUND_PAGE: &str = "<html><head><title>Document not found</title></head><body><h1>404 - Document not found</h1></body></html>\n";
+
+/* The settings shared by the collector and the HTTP workers */
+struct Exporter {
+    opts: Opts,
+    encoder: FamilyEncoder,
+    auth: Option<BasicAuth>,
+    probe_allow: Option<Regex>,
+    /* With a single instance the series carry no "instance" label */
+    multi: bool,
+}
+
+/* A request for the collector, which owns all the stat clients */
+struct Job {
+    url: String,
+    /* The worker stops waiting for the reply then, so do not bother */
+    deadline: Instant,
+    reply: Sender<Arc<Page>>,
+}
+
+/* Runs on the main thread, as the clients can not be moved to the other threads */
+struct Collector {
+    exporter: Arc<Exporter>,
+    /* The /healthz page, refreshed on every round so the workers need not wait for a job */
+    health: Arc<Mutex<Arc<Page>>>,
+    targets: Targets,
+    probed: Targets,
+    discovery: Option<SocketDiscovery>,
+    /* The last successful scrapes, shared until --min-interval passes */
+    cache: HashMap<String, (Instant, Arc<Page>)>,
+    /* The previous dashboard snapshot of every instance, for the rates */
+    dashboard_prev: HashMap<String, StatSnapshot>,
+    /* Only with --alert-rules */
+    alerting: Option<Alerting>,
+    /* None with --history-minutes 0 */
+    histories: Option<Histories>,
+}
+
+impl Collector {
+    fn stale_after(&self) -> Duration {
+        Duration::from_secs(self.exporter.opts.heartbeat_timeout)
+    }
+
+    /* Read and encode the stats of one instance, keeping the tally for the self metrics */
+    fn scrape(
+        exporter: &Exporter,
+        target: &mut Target,
+        stale_after: Duration,
+        deadline: Instant,
+    ) -> Result<Vec<PromFamily>, TargetError> {
+        /* A dump can not be stopped halfway, but the next one need not be started */
+        if Instant::now() > deadline {
+            return Err(TargetError::TimedOut);
+        }
+        let start = Instant::now();
+        let patterns = exporter.encoder.patterns();
+        let res = target
+            .fetch(&monotonic_patterns(target, &patterns), stale_after)
+            .map(|snap| {
+                let names = snap.interface_names().to_vec();
+                /* The stats read only for the reset detection are not exported */
+                let snap = match target.monotonic.as_mut() {
+                    Some(m) => PatternFilter::new(&patterns).apply(&m.update(&snap)),
+                    None => snap,
+                };
+                let mut families = exporter.encoder.families_with_names(&snap, &names);
+                if exporter.opts.rates {
+                    let rates = target.rates.update(&snap);
+                    families.append(&mut exporter.encoder.rate_families(&rates));
+                }
+                families
+            });
+        target.stats.scrapes += 1;
+        match &res {
+            Ok(families) => {
+                target.stats.last_series = families.iter().map(|f| f.samples.len()).sum()
+            }
+            Err(_) => target.stats.errors += 1,
+        }
+        target.stats.last_duration = start.elapsed();
+        res
+    }
+
+    fn metrics(
+        exporter: &Exporter,
+        target: &mut Target,
+        stale_after: Duration,
+        deadline: Instant,
+    ) -> Page {
+        match Collector::scrape(exporter, target, stale_after, deadline) {
+            Ok(mut families) => {
+                families.append(&mut self_families(&[target], false));
+                text_response(200, &encode_text(&families))
+            }
+            Err(TargetError::Dump(SourceError::EndOfRecording)) => {
+                text_response(410, "End of the recording\n")
+            }
+            Err(TargetError::Dump(SourceError::Dump(VppStatDumpError::ObsoleteDirData))) => {
+                text_response(
+                    503,
+                    "The stats directory kept changing while reading it, try again\n",
+                )
+                .with_header("Retry-After", "1")
+            }
+            Err(TargetError::TimedOut) => timed_out(),
+            Err(e) => text_response(503, &format!("Could not read the stats: {:?}\n", e)),
+        }
+    }
+
+    /* The instances which can not be read only get their "vpp_up" set to 0 */
+    fn multi_metrics<'a>(
+        exporter: &Exporter,
+        targets: impl Iterator<Item = &'a mut Target>,
+        stale_after: Duration,
+        deadline: Instant,
+    ) -> Page {
+        let mut targets: Vec<&mut Target> = targets.collect();
+        let mut families = vec![];
+        let mut up = vec![];
+        for target in targets.iter_mut() {
+            match Collector::scrape(exporter, target, stale_after, deadline) {
+                Ok(mut f) => {
+                    add_instance(&mut f, &target.name);
+                    families.append(&mut f);
+                    up.push((target.name.clone(), true));
+                }
+                /* Nobody is waiting for the rest */
+                Err(TargetError::TimedOut) => return timed_out(),
+                Err(e) => {
+                    if exporter.opts.verbose > 0 {
+                        eprintln!("Could not read {}: {:?}", target.name, e);
+                    }
+                    up.push((target.name.clone(), false));
+                }
+            }
+        }
+        families.push(up_family(&up));
+        let targets: Vec<&Target> = targets.iter().map(|t| &**t).collect();
+        families.append(&mut self_families(&targets, true));
+        text_response(200, &encode_text(&merge_families(families)))
+    }
+
+    fn probe(&mut self, socket: &str, deadline: Instant) -> Page {
+        let exporter = &*self.exporter;
+        let stale_after = self.stale_after();
+        let target = match self.targets.get(socket) {
+            Some(t) => t,
+            None if exporter
+                .probe_allow
+                .as_ref()
+                .map(|re| re.is_match(socket))
+                .unwrap_or(false) =>
+            {
+                self.probed.probe(socket)
+            }
+            None => return text_response(403, "This target can not be probed\n"),
+        };
+        Collector::multi_metrics(exporter, std::iter::once(target), stale_after, deadline)
+    }
+
+    fn healthz(&mut self) -> Page {
+        let stale_after = self.stale_after();
+        let mut healthy = true;
+        let mut body = String::new();
+        for target in self.targets.targets.values_mut() {
+            let line = match target.heartbeat() {
+                Ok((heartbeat, age)) if age > stale_after => {
+                    healthy = false;
+                    format!(
+                        "VPP heartbeat {} has not changed for {} seconds",
+                        heartbeat,
+                        age.as_secs()
+                    )
+                }
+                Ok((heartbeat, _)) if !target.health.seen_moving() => {
+                    healthy = false;
+                    format!("Unknown, VPP heartbeat {} has not changed yet", heartbeat)
+                }
+                Ok((heartbeat, _)) => format!("OK, VPP heartbeat {}", heartbeat),
+                Err(e) => {
+                    healthy = false;
+                    format!("Could not connect: {:?}", e)
+                }
+            };
+            if self.exporter.multi {
+                body.push_str(&format!("{}: {}\n", target.name, line));
+            } else {
+                body.push_str(&format!("{}\n", line));
+            }
+        }
+        text_response(if healthy { 200 } else { 503 }, &body)
+    }
+
+    /* The tables for the live dashboard, from the change since the previous call */
+    fn dashboard(&mut self, instance: Option<String>) -> Page {
+        let stale_after = self.stale_after();
+        let target = match &instance {
+            Some(i) => self.targets.get(i),
+            None => self.targets.targets.values_mut().next(),
+        };
+        let target = match target {
+            Some(t) => t,
+            None => return text_response(404, "No such instance\n"),
+        };
+        let patterns: Vec<String> = DASHBOARD_PATTERNS.iter().map(|p| p.to_string()).collect();
+        let cur = match target.fetch(&patterns, stale_after) {
+            Ok(snap) => snap,
+            Err(e) => return text_response(503, &format!("Could not read the stats: {:?}\n", e)),
+        };
+        let prev = self
+            .dashboard_prev
+            .insert(target.name.clone(), cur.clone())
+            .unwrap_or_else(|| cur.clone());
+        let peaks = self
+            .histories
+            .as_ref()
+            .and_then(|h| h.get(&target.name))
+            .map(|h| peaks_table(h, &cur, cur.timestamp - h.retention(), cur.timestamp));
+        let data = DashboardData {
+            instance: target.name.clone(),
+            timestamp: cur.timestamp,
+            interfaces: interfaces_table(&prev, &cur),
+            errors: errors_table(&prev, &cur),
+            peaks,
+        };
+        Page::new(200, "application/json", serde_json::to_vec(&data).unwrap())
+    }
+
+    fn api(&mut self, query: &ApiQuery) -> Page {
+        let stale_after = self.stale_after();
+        let target = match &query.instance {
+            Some(i) => self.targets.get(i),
+            None => self.targets.targets.values_mut().next(),
+        };
+        let target = match target {
+            Some(t) => t,
+            None => return text_response(404, "No such instance\n"),
+        };
+        match target.fetch(&query.fetch_patterns(), stale_after) {
+            Ok(snap) => Page::new(
+                200,
+                "application/json",
+                serde_json::to_vec(&ApiResponse::new(query, &target.name, &snap)).unwrap(),
+            ),
+            Err(e) => text_response(503, &format!("Could not read the stats: {:?}\n", e)),
+        }
+    }
+
+    fn scrape_page(&mut self, key: &str, url: &str, deadline: Instant) -> Page {
+        let exporter = &*self.exporter;
+        let stale_after = self.stale_after();
+        if key.starts_with("api:") {
+            return match ApiQuery::parse(url) {
+                Ok(query) => self.api(&query),
+                Err(e) => text_response(400, &format!("{}\n", e)),
+            };
+        }
+        let page = match key.strip_prefix("probe:") {
+            Some(socket) => self.probe(socket, deadline),
+            None if exporter.multi => Collector::multi_metrics(
+                exporter,
+                self.targets.targets.values_mut(),
+                stale_after,
+                deadline,
+            ),
+            None => match self.targets.targets.values_mut().next() {
+                Some(target) => Collector::metrics(exporter, target, stale_after, deadline),
+                None => html_response(404, NOT_FOUND_PAGE),
+            },
+        };
+        if let Err(e) = self.targets.save_monotonic() {
+            eprintln!("Could not save the monotonic counters: {:?}", e);
+        }
+        page
+    }
+
+    /* The scrapes are served from the cache for --min-interval after a successful one */
+    fn cached(&mut self, key: &str, url: &str, deadline: Instant) -> Arc<Page> {
+        let min_interval = Duration::from_millis(self.exporter.opts.min_interval);
+        self.cache.retain(|_, (at, _)| at.elapsed() < min_interval);
+        if let Some((_, page)) = self.cache.get(key) {
+            return page.clone();
+        }
+        let page = Arc::new(self.scrape_page(key, url, deadline));
+        if page.status == 200 {
+            if self.cache.len() >= CACHE_MAX {
+                let oldest = self
+                    .cache
+                    .iter()
+                    .min_by_key(|(_, (at, _))| *at)
+                    .map(|(k, _)| k.clone());
+                if let Some(k) = oldest {
+                    self.cache.remove(&k);
+                }
+            }
+            self.cache
+                .insert(key.to_string(), (Instant::now(), page.clone()));
+        } else {
+            self.cache.remove(key);
+        }
+        page
+    }
+
+    fn run_job(&mut self, job: Job) {
+        if Instant::now() > job.deadline {
+            return;
+        }
+        let path = job.url.split('?').next().unwrap_or("");
+        let page = match path {
+            "/dashboard/data" => Arc::new(self.dashboard(query_param(&job.url, "instance"))),
+            "/api/v1/alerts" => Arc::new(match &self.alerting {
+                Some(a) => a.page(),
+                None => text_response(404, "No --alert-rules given\n"),
+            }),
+            "/api/v1/history" => Arc::new(match (&self.histories, HistoryQuery::parse(&job.url)) {
+                (None, _) => text_response(404, "No history kept, --history-minutes is 0\n"),
+                (Some(h), Ok(query)) => h.page(&query),
+                (Some(_), Err(e)) => text_response(400, &format!("{}\n", e)),
+            }),
+            "/api/v1/stats" => match ApiQuery::parse(&job.url) {
+                Ok(query) => self.cached(&query.cache_key(), &job.url, job.deadline),
+                Err(e) => Arc::new(text_response(400, &format!("{}\n", e))),
+            },
+            "/probe" => match query_param(&job.url, "target") {
+                Some(s) if !s.is_empty() => {
+                    self.cached(&format!("probe:{}", s), &job.url, job.deadline)
+                }
+                _ => Arc::new(text_response(400, "The \"target\" parameter is missing\n")),
+            },
+            _ => self.cached("metrics", &job.url, job.deadline),
+        };
+        let _ = job.reply.send(page);
+    }
+
+    fn run(&mut self, jobs: Receiver<Job>) {
+        loop {
+            if let Some(d) = self.discovery.as_mut() {
+                match d.poll(Duration::from_millis(0)) {
+                    Ok(events) => self.targets.update(events, self.exporter.opts.verbose > 0),
+                    Err(e) => eprintln!("Socket discovery failed: {:?}", e),
+                }
+            }
+            self.probed.drop_idle(PROBE_IDLE);
+            *self.health.lock().unwrap() = Arc::new(self.healthz());
+            let stale_after = self.stale_after();
+            let verbose = self.exporter.opts.verbose > 0;
+            if let Some(h) = self.histories.as_mut() {
+                h.poll(&mut self.targets, stale_after, verbose);
+            }
+            if let Some(a) = self.alerting.as_mut() {
+                let histories = self.histories.as_ref();
+                a.poll(
+                    &mut self.targets,
+                    histories,
+                    stale_after,
+                    self.exporter.multi,
+                    verbose,
+                );
+            }
+            match jobs.recv_timeout(Duration::from_secs(1)) {
+                Ok(job) => self.run_job(job),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+    }
+}
+
+/* The stats needed to detect the resets are read along, if the counters are kept monotonic */
+fn monotonic_patterns(target: &Target, patterns: &[String]) -> Vec<String> {
+    let mut patterns = patterns.to_vec();
+    if target.monotonic.is_some() {
+        patterns.extend(MONOTONIC_PATTERNS.iter().map(|p| p.to_string()));
+    }
+    patterns
+}
+
+fn timed_out() -> Page {
+    text_response(503, "The scrape timed out\n").with_header("Retry-After", "1")
+}
+
+/* Hand the request over to the collector, None if it is gone */
+fn collect(jobs: &Sender<Job>, url: String, timeout: Duration) -> Option<Arc<Page>> {
+    let (tx, rx) = channel();
+    let job = Job {
+        url,
+        deadline: Instant::now() + timeout,
+        reply: tx,
+    };
+    jobs.send(job).ok()?;
+    Some(
+        rx.recv_timeout(timeout)
+            .unwrap_or_else(|_| Arc::new(timed_out())),
+    )
+}
+
+/* One of the --workers threads, taking the requests as they come */
+fn worker(
+    exporter: Arc<Exporter>,
+    requests: Arc<Mutex<Receiver<Request>>>,
+    jobs: Sender<Job>,
+    subscribers: Sender<Subscriber>,
+    health: Arc<Mutex<Arc<Page>>>,
+) {
+    let timeout = Duration::from_secs(exporter.opts.scrape_timeout);
+    loop {
+        /* The lock is only held while waiting, not while handling the request */
+        let request = match requests.lock().unwrap().recv() {
+            Ok(r) => r,
+            Err(_) => return,
+        };
+        let url = request.url().to_string();
+        let path = url.split('?').next().unwrap_or("").to_string();
+        let authorized = exporter
+            .auth
+            .as_ref()
+            .map(|a| a.check(&request))
+            .unwrap_or(true);
+        /* The health checks usually come with no credentials */
+        let page = match path.as_str() {
+            "/healthz" => Some(health.lock().unwrap().clone()),
+            _ if !authorized => Some(Arc::new(unauthorized())),
+            "/metrics" | "/probe" | "/api/v1/stats" | "/api/v1/alerts" | "/api/v1/history" => {
+                collect(&jobs, url, timeout)
+            }
+            "/" => Some(Arc::new(html_response(200, DASHBOARD_PAGE))),
+            "/events" => {
+                let instance = query_param(&url, "instance");
+                if let Some(s) = Subscriber::start(instance, request.into_writer()) {
+                    let _ = subscribers.send(s);
+                }
+                continue;
+            }
+            _ => Some(Arc::new(html_response(404, NOT_FOUND_PAGE))),
+        };
+        /* Still answered, the other requests may be ones the worker can serve */
+        let page = match page {
+            Some(p) => p,
+            None => Arc::new(text_response(503, "The collector is gone\n")),
+        };
+        let response = page.response(accepts_gzip(&request));
+        if let Err(e) = request.respond(response) {
+            if exporter.opts.verbose > 0 {
+                eprintln!("Could not send the response: {}", e);
+            }
+        }
+    }
+}
+
+fn main() {
+    let opts: Opts = Opts::parse();
+
+    let config = match &opts.config {
+        Some(path) => ExporterConfig::load(path).unwrap_or_else(|e| {
+            eprintln!("Could not load the config: {:?}", e);
+            std::process::exit(1);
+        }),
+        None => ExporterConfig::with_patterns(&opts.pattern),
+    };
+    /* The monotonic counters follow the interfaces, and so must their series */
+    let encoder = FamilyEncoder::new(config)
+        .map(|e| e.with_interface_names(opts.monotonic_state.is_some()))
+        .unwrap_or_else(|e| {
+            eprintln!("Bad config: {:?}", e);
+            std::process::exit(1);
+        });
+    if opts.verbose > 0 {
+        println!("Patterns: {:?}", encoder.patterns());
+    }
+    let replay = opts.replay.as_ref().map(|r| {
+        let replay = Replay::open(r).unwrap_or_else(|e| {
+            eprintln!("Could not open the recording {}: {:?}", r, e);
+            std::process::exit(1);
+        });
+        Box::new(replay.with_speed(opts.replay_speed))
+    });
+    if let Some(url) = &opts.push_url {
+        let source: Box<dyn StatSource> = match replay {
+            Some(r) => r,
+            None => Box::new(VppStatClient::connect(&opts.socket).unwrap_or_else(|e| {
+                eprintln!("Could not connect to {}: {:?}", opts.socket, e);
+                std::process::exit(1);
+            })),
+        };
+        push::push_loop(&opts, &encoder, url, source);
+    }
+    let multi = !opts.target.is_empty() || opts.discover.is_some();
+    let mut targets = Targets::new(match replay {
+        Some(r) => vec![Target::with_source(opts.replay.as_ref().unwrap(), r)],
+        None if multi => opts.target.iter().map(|t| Target::socket(t)).collect(),
+        None => vec![Target::socket(&opts.socket)],
+    });
+    if let Some(path) = &opts.monotonic_state {
+        targets = targets.with_monotonic_state(path).unwrap_or_else(|e| {
+            eprintln!("Could not load the monotonic state: {:?}", e);
+            std::process::exit(1);
+        });
+    }
+    let probed = Targets::new(vec![]);
+    let alerting = opts.alert_rules.as_ref().map(|path| {
+        let config = AlertConfig::load(path).unwrap_or_else(|e| {
+            eprintln!("Could not load the alert rules: {:?}", e);
+            std::process::exit(1);
+        });
+        let interval = Duration::from_secs(opts.alert_interval.max(1));
+        let alerting = Alerting::new(config, interval).unwrap_or_else(|e| {
+            eprintln!("Bad alert rules: {}", e);
+            std::process::exit(1);
+        });
+        match &opts.history_dir {
+            Some(dir) => alerting.with_dump_dir(dir),
+            None => alerting,
+        }
+    });
+    let histories = if opts.history_minutes > 0 {
+        let mut patterns = opts.history_pattern.clone();
+        if let Some(a) = &alerting {
+            patterns.extend(a.patterns());
+        }
+        Some(Histories::new(
+            Duration::from_secs(opts.history_resolution.max(1)),
+            Duration::from_secs(opts.history_minutes * 60),
+            &patterns,
+        ))
+    } else {
+        None
+    };
+    let discovery = opts.discover.as_ref().map(|d| {
+        SocketDiscovery::new(d).unwrap_or_else(|e| {
+            eprintln!("Could not watch {}: {:?}", d, e);
+            std::process::exit(1);
+        })
+    });
+    let probe_allow = opts.probe_allow.as_ref().map(|p| {
+        Regex::new(p).unwrap_or_else(|e| {
+            eprintln!("Bad --probe-allow: {}", e);
+            std::process::exit(1);
+        })
+    });
+
+    let tls = match (&opts.tls_cert, &opts.tls_key) {
+        (Some(cert), Some(key)) => Some(TlsFiles {
+            cert: cert.clone(),
+            key: key.clone(),
+        }),
+        _ => None,
+    };
+    let auth = match (&opts.basic_auth_user, &opts.basic_auth_password_file) {
+        (Some(user), Some(file)) => {
+            let password = std::fs::read_to_string(file).unwrap_or_else(|e| {
+                eprintln!("Could not read the password file {}: {}", file, e);
+                std::process::exit(1);
+            });
+            Some(BasicAuth::new(
+                user,
+                password.trim_end_matches(&['\r', '\n'][..]),
+            ))
+        }
+        _ => None,
+    };
+
+    let mut servers = vec![];
+    for listen in &opts.listen {
+        let addr: ListenAddr = listen.parse().unwrap_or_else(|e| {
+            eprintln!("Bad --listen {}: {}", listen, e);
+            std::process::exit(1);
+        });
+        match bind(&addr, tls.as_ref()) {
+            Ok(server) => {
+                println!("Listening on {}", listen);
+                servers.push(server);
+            }
+            Err(e) => {
+                eprintln!("Could not listen on {}: {}", listen, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let exporter = Arc::new(Exporter {
+        opts,
+        encoder,
+        auth,
+        probe_allow,
+        multi,
+    });
+    let requests = Arc::new(Mutex::new(incoming(servers)));
+    let (jobs_tx, jobs) = channel();
+    let (subscribers_tx, subscribers) = channel();
+    let health = Arc::new(Mutex::new(Arc::new(text_response(
+        503,
+        "Unknown, not checked yet\n",
+    ))));
+    for _i in 0..exporter.opts.workers.max(1) {
+        let exporter = exporter.clone();
+        let requests = requests.clone();
+        let jobs_tx = jobs_tx.clone();
+        let subscribers_tx = subscribers_tx.clone();
+        let health = health.clone();
+        std::thread::spawn(move || worker(exporter, requests, jobs_tx, subscribers_tx, health));
+    }
+    /* The hub, and with it its jobs sender, goes away along with the workers */
+    drop(subscribers_tx);
+    let interval = Duration::from_millis(exporter.opts.dashboard_interval);
+    let timeout = Duration::from_secs(exporter.opts.scrape_timeout);
+    std::thread::spawn(move || hub(jobs_tx, subscribers, interval, timeout));
+    Collector {
+        exporter,
+        health,
+        targets,
+        probed,
+        discovery,
+        cache: HashMap::new(),
+        dashboard_prev: HashMap::new(),
+        alerting,
+        histories,
+    }
+    .run(jobs);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use vpp_stat_client::snapshot::{SnapshotEntry, SnapshotValue};
+
+    /* Counts the dumps */
+    struct FakeSource(Rc<Cell<u32>>);
+
+    impl StatSource for FakeSource {
+        fn heartbeat(&self) -> f64 {
+            1.0
+        }
+
+        fn fetch(&mut self, _patterns: &[String]) -> Result<StatSnapshot, SourceError> {
+            self.0.set(self.0.get() + 1);
+            Ok(StatSnapshot {
+                timestamp: 0.0,
+                heartbeat: 1.0,
+                entries: vec![SnapshotEntry {
+                    name: "/sys/vector_rate".to_string(),
+                    value: SnapshotValue::Scalar(self.0.get() as f64),
+                }],
+            })
+        }
+    }
+
+    fn collector(args: &[&str], dumps: &Rc<Cell<u32>>) -> Collector {
+        let opts = Opts::parse_from(std::iter::once("vpp_prometheus_export").chain(args.to_vec()));
+        let encoder = FamilyEncoder::new(ExporterConfig::with_patterns(&opts.pattern)).unwrap();
+        let source = Box::new(FakeSource(dumps.clone()));
+        Collector {
+            exporter: Arc::new(Exporter {
+                opts,
+                encoder,
+                auth: None,
+                probe_allow: None,
+                multi: false,
+            }),
+            health: Arc::new(Mutex::new(Arc::new(text_response(503, "\n")))),
+            targets: Targets::new(vec![Target::with_source("fake", source)]),
+            probed: Targets::new(vec![]),
+            discovery: None,
+            cache: HashMap::new(),
+            dashboard_prev: HashMap::new(),
+            alerting: None,
+            histories: None,
+        }
+    }
+
+    fn body(page: &Page) -> String {
+        String::from_utf8(page.body.clone()).unwrap()
+    }
+
+    #[test]
+    fn scrapes_cached() {
+        let dumps = Rc::new(Cell::new(0));
+        let mut c = collector(&["--min-interval", "100"], &dumps);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let first = c.cached("metrics", "/metrics", deadline);
+        assert!(body(&first).contains("_sys_vector_rate 1.00\n"));
+        let again = c.cached("metrics", "/metrics", deadline);
+        assert!(Arc::ptr_eq(&first, &again));
+        assert_eq!(dumps.get(), 1);
+        std::thread::sleep(Duration::from_millis(150));
+        let later = c.cached("metrics", "/metrics", deadline);
+        assert!(body(&later).contains("_sys_vector_rate 2.00\n"));
+        assert_eq!(dumps.get(), 2);
+    }
+
+    #[test]
+    fn cache_evicted() {
+        let dumps = Rc::new(Cell::new(0));
+        let mut c = collector(&["--min-interval", "100"], &dumps);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        c.cached("metrics", "/metrics", deadline);
+        c.cached("api:x", "/api/v1/stats", deadline);
+        assert_eq!(c.cache.len(), 2);
+        std::thread::sleep(Duration::from_millis(150));
+        c.cached("api:y", "/api/v1/stats?names=1", deadline);
+        assert_eq!(c.cache.keys().collect::<Vec<_>>(), vec!["api:y"]);
+    }
+
+    #[test]
+    fn cache_capped() {
+        let dumps = Rc::new(Cell::new(0));
+        let mut c = collector(&["--min-interval", "60000"], &dumps);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        for i in 0..CACHE_MAX + 10 {
+            let url = format!("/api/v1/stats?pattern=^/sys/{}", i);
+            let query = ApiQuery::parse(&url).unwrap();
+            assert_eq!(c.cached(&query.cache_key(), &url, deadline).status, 200);
+        }
+        assert_eq!(c.cache.len(), CACHE_MAX);
+        let first = ApiQuery::parse("/api/v1/stats?pattern=^/sys/0").unwrap();
+        let last = format!("/api/v1/stats?pattern=^/sys/{}", CACHE_MAX + 9);
+        let last = ApiQuery::parse(&last).unwrap();
+        assert!(!c.cache.contains_key(&first.cache_key()));
+        assert!(c.cache.contains_key(&last.cache_key()));
+    }
+
+    #[test]
+    fn nothing_read_past_the_deadline() {
+        let dumps = Rc::new(Cell::new(0));
+        let mut c = collector(&["--min-interval", "0"], &dumps);
+        let page = c.cached(
+            "metrics",
+            "/metrics",
+            Instant::now() - Duration::from_millis(1),
+        );
+        assert_eq!(page.status, 503);
+        assert_eq!(body(&page), "The scrape timed out\n");
+        assert_eq!(dumps.get(), 0);
+        assert!(c.cache.is_empty());
+    }
+}
@@ -0,0 +1,70 @@
+/*
+ * Prometheus remote_write mode of the exporter, a Pipeline with the
+ * RemoteWriter as its sink
+ */
+
+use crate::Opts;
+use std::time::{Duration, Instant};
+use vpp_stat_client::exporter_config::FamilyEncoder;
+use vpp_stat_client::monotonic::MonotonicState;
+use vpp_stat_client::pipeline::{Pipeline, PipelineError, Rates};
+use vpp_stat_client::remote_write::RemoteWriter;
+use vpp_stat_client::source::StatSource;
+
+pub fn push_loop(
+    opts: &Opts,
+    encoder: &FamilyEncoder,
+    url: &str,
+    source: Box<dyn StatSource>,
+) -> ! {
+    let instance = opts.instance.clone().unwrap_or_else(|| opts.socket.clone());
+    let writer = RemoteWriter::new(url).with_external_label("instance", &instance);
+    let mut pipeline = Pipeline::new(source)
+        .with_encoder(encoder.clone())
+        .with_sink(Box::new(writer))
+        .with_max_queue(opts.push_queue);
+    if opts.rates {
+        pipeline =
+            pipeline.with_transform(Box::new(Rates::new(true).with_encoder(encoder.clone())));
+    }
+    let mut monotonic = opts.monotonic_state.as_ref().map(|path| {
+        let state = MonotonicState::load(path).unwrap_or_else(|e| {
+            eprintln!("Could not load the monotonic state: {:?}", e);
+            std::process::exit(1);
+        });
+        (path, state)
+    });
+    if let Some((_, state)) = monotonic.as_mut() {
+        pipeline = pipeline.with_monotonic(state.take(&instance));
+    }
+    println!("Pushing to {} every {} seconds", url, opts.push_interval);
+
+    let interval = Duration::from_secs(opts.push_interval);
+    loop {
+        let start = Instant::now();
+        match pipeline.poll() {
+            Ok(()) if opts.verbose > 0 => println!("Pushed poll {}", pipeline.stats().polls),
+            Ok(()) => {}
+            Err(PipelineError::EndOfSource) => {
+                if let Err(e) = pipeline.flush() {
+                    eprintln!("Push failed: {:?}", e);
+                }
+                println!("End of the recording");
+                std::process::exit(0);
+            }
+            Err(PipelineError::Send(e)) => eprintln!(
+                "Push failed: {:?}, {} batches dropped so far",
+                e,
+                pipeline.stats().dropped
+            ),
+            Err(_) => eprintln!("Could not acquire soft lock!"),
+        }
+        if let (Some((path, state)), Some(counters)) = (monotonic.as_mut(), pipeline.monotonic()) {
+            state.instances.insert(instance.clone(), counters.clone());
+            if let Err(e) = state.save(path) {
+                eprintln!("Could not save the monotonic counters: {:?}", e);
+            }
+        }
+        std::thread::sleep(interval.saturating_sub(start.elapsed()));
+    }
+}
@@ -0,0 +1,67 @@
+/*
+ * The exporter's own metrics, in the "vpp_exporter_" namespace, to tell
+ * the exporter struggling apart from the VPP itself.
+ */
+
+use crate::targets::Target;
+use vpp_stat_client::prometheus::{MetricType, PromFamily, PromSample, SampleValue};
+
+type Getter = fn(&Target) -> SampleValue;
+
+const SELF_METRICS: &[(&str, MetricType, Getter)] = &[
+    ("scrapes_total", MetricType::Counter, |t| {
+        SampleValue::Int(t.stats.scrapes)
+    }),
+    ("scrape_errors_total", MetricType::Counter, |t| {
+        SampleValue::Int(t.stats.errors)
+    }),
+    ("scrape_duration_seconds", MetricType::Gauge, |t| {
+        SampleValue::Precise(t.stats.last_duration.as_secs_f64())
+    }),
+    /* How many times the last scrape had to redo the dump */
+    ("scrape_obsolete_dir_retries", MetricType::Gauge, |t| {
+        SampleValue::Int(t.stats.last_retries)
+    }),
+    ("obsolete_dir_retries_total", MetricType::Counter, |t| {
+        SampleValue::Int(t.read_stats().obsolete_retries)
+    }),
+    ("ls_total", MetricType::Counter, |t| {
+        SampleValue::Int(t.read_stats().ls_count)
+    }),
+    ("reconnects_total", MetricType::Counter, |t| {
+        SampleValue::Int(t.stats.reconnects)
+    }),
+    ("last_heartbeat", MetricType::Gauge, |t| {
+        SampleValue::Precise(t.health.heartbeat())
+    }),
+    /* Since when the heartbeat has not moved */
+    ("heartbeat_age_seconds", MetricType::Gauge, |t| {
+        SampleValue::Precise(t.health.age().as_secs_f64())
+    }),
+    ("series", MetricType::Gauge, |t| {
+        SampleValue::Int(t.stats.last_series as u64)
+    }),
+];
+
+/// One family per metric, with a sample per instance; the "instance" label
+/// is only there when serving several instances
+pub fn self_families(targets: &[&Target], with_instance: bool) -> Vec<PromFamily> {
+    SELF_METRICS
+        .iter()
+        .map(|(name, metric_type, get)| PromFamily {
+            name: format!("vpp_exporter_{}", name),
+            metric_type: *metric_type,
+            samples: targets
+                .iter()
+                .map(|t| PromSample {
+                    labels: if with_instance {
+                        vec![("instance".to_string(), t.name.clone())]
+                    } else {
+                        vec![]
+                    },
+                    value: get(t),
+                })
+                .collect(),
+        })
+        .collect()
+}
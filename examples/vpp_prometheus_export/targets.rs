@@ -0,0 +1,370 @@
+/*
+ * The VPP instances the exporter reads from. Every instance has its own
+ * client, connected on the first use and dropped again once reading from
+ * it fails, so an instance which is down or restarting only loses its
+ * own series and comes back by itself.
+ */
+
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+use vpp_stat_client::discovery::DiscoveryEvent;
+use vpp_stat_client::monotonic::{MonotonicCounters, MonotonicError, MonotonicState};
+use vpp_stat_client::prometheus::{MetricType, PromFamily, PromSample, SampleValue};
+use vpp_stat_client::rates::RateTracker;
+use vpp_stat_client::snapshot::StatSnapshot;
+use vpp_stat_client::source::{ReadStats, SourceError, StatSource};
+use vpp_stat_client::*;
+
+/* When the heartbeat was last seen moving */
+pub struct Health {
+    heartbeat: f64,
+    changed_at: Instant,
+    /* The first heartbeat read tells nothing about whether VPP is alive */
+    seen_moving: bool,
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Health {
+            heartbeat: f64::NAN,
+            changed_at: Instant::now(),
+            seen_moving: false,
+        }
+    }
+
+    pub fn update(&mut self, heartbeat: f64) -> Duration {
+        if heartbeat != self.heartbeat {
+            self.seen_moving |= !self.heartbeat.is_nan();
+            self.heartbeat = heartbeat;
+            self.changed_at = Instant::now();
+        }
+        self.changed_at.elapsed()
+    }
+
+    /// Whether the heartbeat has changed at all since the first read
+    pub fn seen_moving(&self) -> bool {
+        self.seen_moving
+    }
+
+    /// The last heartbeat seen, NaN if none yet
+    pub fn heartbeat(&self) -> f64 {
+        self.heartbeat
+    }
+
+    pub fn age(&self) -> Duration {
+        self.changed_at.elapsed()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TargetError {
+    Connect(VppStatError),
+    Dump(SourceError),
+    /* The request was given up on before the instance was read */
+    TimedOut,
+}
+
+/// How the scrapes of one instance went, for the exporter's own metrics
+#[derive(Debug, Clone, Default)]
+pub struct ScrapeStats {
+    pub scrapes: u64,
+    pub errors: u64,
+    pub reconnects: u64,
+    /* The totals of the clients dropped so far */
+    reads: ReadStats,
+    pub last_duration: Duration,
+    pub last_retries: u64,
+    pub last_series: usize,
+}
+
+pub struct Target {
+    pub name: String,
+    /* None for a source which can not be reconnected, e.g. a replay */
+    socket: Option<String>,
+    source: Option<Box<dyn StatSource>>,
+    connected: bool,
+    pub health: Health,
+    pub stats: ScrapeStats,
+    pub rates: RateTracker,
+    /* Only with --monotonic-state */
+    pub monotonic: Option<MonotonicCounters>,
+    last_used: Instant,
+}
+
+impl Target {
+    /// A VPP instance behind this stats socket, connected on the first fetch
+    pub fn socket(path: &str) -> Self {
+        Target {
+            name: path.to_string(),
+            socket: Some(path.to_string()),
+            source: None,
+            connected: false,
+            health: Health::new(),
+            stats: Default::default(),
+            rates: RateTracker::new(),
+            monotonic: None,
+            last_used: Instant::now(),
+        }
+    }
+
+    pub fn with_source(name: &str, source: Box<dyn StatSource>) -> Self {
+        Target {
+            name: name.to_string(),
+            socket: None,
+            source: Some(source),
+            connected: true,
+            health: Health::new(),
+            stats: Default::default(),
+            rates: RateTracker::new(),
+            monotonic: None,
+            last_used: Instant::now(),
+        }
+    }
+
+    fn source(&mut self) -> Result<&mut Box<dyn StatSource>, TargetError> {
+        if self.source.is_none() {
+            if let Some(socket) = &self.socket {
+                let client = VppStatClient::connect(socket).map_err(TargetError::Connect)?;
+                self.source = Some(Box::new(client));
+                /* A new segment, its heartbeat starts over */
+                self.health = Health::new();
+                if self.connected {
+                    self.stats.reconnects += 1;
+                }
+                self.connected = true;
+            }
+        }
+        Ok(self.source.as_mut().unwrap())
+    }
+
+    fn disconnect(&mut self) {
+        if let Some(source) = self.source.take() {
+            let r = source.read_stats();
+            self.stats.reads.ls_count += r.ls_count;
+            self.stats.reads.obsolete_retries += r.obsolete_retries;
+        }
+    }
+
+    /// The read totals across all the clients of this instance so far
+    pub fn read_stats(&self) -> ReadStats {
+        let mut r = self.stats.reads;
+        if let Some(source) = &self.source {
+            r.ls_count += source.read_stats().ls_count;
+            r.obsolete_retries += source.read_stats().obsolete_retries;
+        }
+        r
+    }
+
+    /// The heartbeat, and for how long it has not moved
+    pub fn heartbeat(&mut self) -> Result<(f64, Duration), TargetError> {
+        let heartbeat = self.source()?.heartbeat();
+        Ok((heartbeat, self.health.update(heartbeat)))
+    }
+
+    /// Snapshot the matching stats. A VPP which was restarted leaves the old
+    /// segment mapped with the heartbeat frozen, so a client whose heartbeat
+    /// has not moved for stale_after is reconnected first.
+    pub fn fetch(
+        &mut self,
+        patterns: &[String],
+        stale_after: Duration,
+    ) -> Result<StatSnapshot, TargetError> {
+        self.last_used = Instant::now();
+        let retries = self.read_stats().obsolete_retries;
+        let res = self.try_fetch(patterns, stale_after);
+        self.stats.last_retries = self.read_stats().obsolete_retries - retries;
+        if let Ok(snap) = &res {
+            self.health.update(snap.heartbeat);
+        }
+        res
+    }
+
+    fn try_fetch(
+        &mut self,
+        patterns: &[String],
+        stale_after: Duration,
+    ) -> Result<StatSnapshot, TargetError> {
+        if self.socket.is_some() && self.source.is_some() {
+            if let Ok((_, age)) = self.heartbeat() {
+                if age > stale_after {
+                    self.disconnect();
+                }
+            }
+        }
+        let res = self.source()?.fetch(patterns);
+        if res.is_err() && self.socket.is_some() {
+            self.disconnect();
+        }
+        res.map_err(TargetError::Dump)
+    }
+}
+
+/// The configured instances, keyed by the socket path
+pub struct Targets {
+    pub targets: BTreeMap<String, Target>,
+    /* The state file, with the counters of the instances which are not there now */
+    monotonic: Option<(String, MonotonicState)>,
+}
+
+impl Targets {
+    pub fn new(targets: Vec<Target>) -> Self {
+        Targets {
+            targets: targets.into_iter().map(|t| (t.name.clone(), t)).collect(),
+            monotonic: None,
+        }
+    }
+
+    /// Keep the counters of every instance going up across the VPP restarts,
+    /// carrying on from the offsets saved in the state file
+    pub fn with_monotonic_state(mut self, path: &str) -> Result<Self, MonotonicError> {
+        let mut state = MonotonicState::load(path)?;
+        for target in self.targets.values_mut() {
+            target.monotonic = Some(state.take(&target.name));
+        }
+        self.monotonic = Some((path.to_string(), state));
+        Ok(self)
+    }
+
+    /// Write out the counters of all the instances, including the ones gone for now
+    pub fn save_monotonic(&self) -> Result<(), MonotonicError> {
+        let (path, saved) = match &self.monotonic {
+            Some(m) => m,
+            None => return Ok(()),
+        };
+        let mut state = saved.clone();
+        for target in self.targets.values() {
+            if let Some(m) = &target.monotonic {
+                state.instances.insert(target.name.clone(), m.clone());
+            }
+        }
+        state.save(path)
+    }
+
+    pub fn get(&mut self, name: &str) -> Option<&mut Target> {
+        self.targets.get_mut(name)
+    }
+
+    /// Attach the clients to the discovered sockets, and drop the ones which are gone
+    pub fn update(&mut self, events: Vec<DiscoveryEvent>, verbose: bool) {
+        for event in events {
+            match event {
+                DiscoveryEvent::Added(socket) => {
+                    if verbose {
+                        println!("Found {}", socket);
+                    }
+                    self.probe(&socket);
+                }
+                DiscoveryEvent::Removed(socket) => {
+                    if verbose {
+                        println!("Gone {}", socket);
+                    }
+                    self.remove(&socket);
+                }
+            }
+        }
+    }
+
+    fn remove(&mut self, socket: &str) {
+        let gone = self.targets.remove(socket).and_then(|t| t.monotonic);
+        if let (Some((_, state)), Some(m)) = (&mut self.monotonic, gone) {
+            state.instances.insert(socket.to_string(), m);
+        }
+    }
+
+    /// Drop the targets which have not been read for this long, with their clients
+    pub fn drop_idle(&mut self, idle: Duration) {
+        let gone: Vec<String> = self
+            .targets
+            .values()
+            .filter(|t| t.last_used.elapsed() > idle)
+            .map(|t| t.name.clone())
+            .collect();
+        for socket in gone {
+            self.remove(&socket);
+        }
+    }
+
+    /// Get the target, adding it if it is not there yet
+    pub fn probe(&mut self, socket: &str) -> &mut Target {
+        let monotonic = &mut self.monotonic;
+        self.targets.entry(socket.to_string()).or_insert_with(|| {
+            let mut target = Target::socket(socket);
+            if let Some((_, state)) = monotonic {
+                target.monotonic = Some(state.take(socket));
+            }
+            target
+        })
+    }
+}
+
+/// Add the "instance" label in front of the other labels of every sample
+pub fn add_instance(families: &mut [PromFamily], instance: &str) {
+    for family in families.iter_mut() {
+        for sample in family.samples.iter_mut() {
+            sample
+                .labels
+                .insert(0, ("instance".to_string(), instance.to_string()));
+        }
+    }
+}
+
+/// Merge the families with the same name, coming from the different instances
+pub fn merge_families(families: Vec<PromFamily>) -> Vec<PromFamily> {
+    let mut out: Vec<PromFamily> = vec![];
+    let mut by_name: HashMap<String, usize> = HashMap::new();
+    for mut family in families {
+        match by_name.get(&family.name) {
+            Some(i) => out[*i].samples.append(&mut family.samples),
+            None => {
+                by_name.insert(family.name.clone(), out.len());
+                out.push(family);
+            }
+        }
+    }
+    out
+}
+
+/// The "vpp_up" gauge, 1 for every instance which could be read
+pub fn up_family(up: &[(String, bool)]) -> PromFamily {
+    PromFamily {
+        name: "vpp_up".to_string(),
+        metric_type: MetricType::Gauge,
+        samples: up
+            .iter()
+            .map(|(instance, ok)| PromSample {
+                labels: vec![("instance".to_string(), instance.clone())],
+                value: SampleValue::Int(*ok as u64),
+            })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn health_needs_a_change() {
+        let mut health = Health::new();
+        health.update(10.0);
+        assert!(!health.seen_moving());
+        health.update(10.0);
+        assert!(!health.seen_moving());
+        health.update(11.0);
+        assert!(health.seen_moving());
+        assert_eq!(health.heartbeat(), 11.0);
+    }
+
+    #[test]
+    fn idle_targets_dropped() {
+        let mut targets = Targets::new(vec![]);
+        targets.probe("/run/vpp1/stats.sock");
+        std::thread::sleep(Duration::from_millis(200));
+        targets.probe("/run/vpp2/stats.sock");
+        targets.drop_idle(Duration::from_millis(100));
+        assert_eq!(
+            targets.targets.keys().collect::<Vec<_>>(),
+            vec!["/run/vpp2/stats.sock"]
+        );
+    }
+}
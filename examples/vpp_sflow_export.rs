@@ -0,0 +1,83 @@
+use clap::Parser as ClapParser;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use vpp_stat_client::interfaces::INTERFACE_PATTERNS;
+use vpp_stat_client::sflow::{SflowAgent, SFLOW_DEFAULT_PORT};
+use vpp_stat_client::*;
+
+/// Periodically send the VPP interface counters to an sFlow collector
+#[derive(Debug, Clone, ClapParser, Serialize, Deserialize)]
+#[clap(version = "0.0", author = "Andrew Yourtchenko <ayourtch@gmail.com>")]
+struct Opts {
+    /// VPP stats socket as supplied in the "statseg { socket-name /path/to/socket }" config
+    #[clap(short, long, default_value = "/tmp/stats.sock")]
+    socket: String,
+
+    /// sFlow collector, as host or host:port
+    #[clap(short, long, default_value = "127.0.0.1")]
+    collector: String,
+
+    /// Agent address to report in the datagrams
+    #[clap(short, long, default_value = "127.0.0.1")]
+    agent_address: IpAddr,
+
+    /// sFlow sub-agent id, to tell apart several VPP instances on the same agent address
+    #[clap(long, default_value = "0")]
+    sub_agent_id: u32,
+
+    /// Counter polling interval, in seconds
+    #[clap(short, long, default_value = "20")]
+    interval: u64,
+
+    /// A level of verbosity, and can be used multiple times
+    #[clap(short, long, parse(from_occurrences))]
+    verbose: i32,
+}
+
+fn resolve_collector(collector: &str) -> SocketAddr {
+    if let Ok(ip) = collector.parse::<IpAddr>() {
+        return SocketAddr::new(ip, SFLOW_DEFAULT_PORT);
+    }
+    let with_port = if collector.contains(':') {
+        collector.to_string()
+    } else {
+        format!("{}:{}", collector, SFLOW_DEFAULT_PORT)
+    };
+    with_port
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut a| a.next())
+        .unwrap_or_else(|| panic!("Could not resolve the collector address {}", collector))
+}
+
+fn main() {
+    let opts: Opts = Opts::parse();
+
+    let c = VppStatClient::connect(&opts.socket).unwrap();
+
+    let mut patterns = VppStringVec::new();
+    for s in INTERFACE_PATTERNS {
+        patterns.push(s);
+    }
+
+    let collector = resolve_collector(&opts.collector);
+    let mut agent = SflowAgent::new(collector, opts.agent_address)
+        .unwrap()
+        .with_sub_agent_id(opts.sub_agent_id);
+    println!(
+        "Sending counter samples to {} every {} seconds",
+        collector, opts.interval
+    );
+
+    loop {
+        match c.snapshot(Some(&patterns)) {
+            Ok(snap) => match agent.send(&snap) {
+                Ok(n) if opts.verbose > 0 => println!("Sent counters of {} interfaces", n),
+                Ok(_) => {}
+                Err(e) => eprintln!("Could not send the counter samples: {}", e),
+            },
+            Err(e) => eprintln!("Could not dump the stats: {:?}", e),
+        }
+        std::thread::sleep(std::time::Duration::from_secs(opts.interval));
+    }
+}
@@ -0,0 +1,78 @@
+use clap::Parser as ClapParser;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+use vpp_stat_client::agentx::*;
+use vpp_stat_client::interfaces::{InterfaceCounters, INTERFACE_PATTERNS};
+use vpp_stat_client::*;
+
+/// AgentX subagent serving the IF-MIB ifTable/ifXTable from the VPP interface counters
+#[derive(Debug, Clone, ClapParser, Serialize, Deserialize)]
+#[clap(version = "0.0", author = "Andrew Yourtchenko <ayourtch@gmail.com>")]
+struct Opts {
+    /// VPP stats socket as supplied in the "statseg { socket-name /path/to/socket }" config
+    #[clap(short, long, default_value = "/tmp/stats.sock")]
+    socket: String,
+
+    /// AgentX master agent socket
+    #[clap(short, long, default_value = AGENTX_DEFAULT_SOCKET)]
+    master: String,
+
+    /// Registration priority, lower values override the other registrations of the same tables
+    #[clap(long, default_value = "127")]
+    priority: u8,
+
+    /// How long to reuse the counters between the requests, in milliseconds
+    #[clap(long, default_value = "1000")]
+    cache_ms: u64,
+
+    /// A level of verbosity, and can be used multiple times
+    #[clap(short, long, parse(from_occurrences))]
+    verbose: i32,
+}
+
+fn main() {
+    let opts: Opts = Opts::parse();
+
+    let c = VppStatClient::connect(&opts.socket).unwrap();
+
+    let mut patterns = VppStringVec::new();
+    for s in INTERFACE_PATTERNS {
+        patterns.push(s);
+    }
+
+    let cache_time = Duration::from_millis(opts.cache_ms);
+    let mut cached: Option<(Instant, MibView)> = None;
+
+    /* Keep coming back if the master agent restarts */
+    loop {
+        let res = AgentxSubagent::connect(&opts.master).and_then(|mut agent| {
+            agent.open("VPP interface statistics", 5)?;
+            agent.register(IF_TABLE_ENTRY, opts.priority)?;
+            agent.register(IF_X_TABLE_ENTRY, opts.priority)?;
+            println!("Registered with the master agent at {}", &opts.master);
+            agent.serve(|| {
+                if let Some((when, view)) = &cached {
+                    if when.elapsed() < cache_time {
+                        return view.clone();
+                    }
+                }
+                let view = match c.snapshot(Some(&patterns)) {
+                    Ok(snap) => MibView::from_interfaces(&InterfaceCounters::collect(&snap)),
+                    Err(e) => {
+                        eprintln!("Could not dump the stats: {:?}", e);
+                        MibView::new()
+                    }
+                };
+                if opts.verbose > 0 {
+                    println!("Refreshed {} values", view.len());
+                }
+                cached = Some((Instant::now(), view.clone()));
+                view
+            })
+        });
+        if let Err(e) = res {
+            eprintln!("AgentX session ended: {:?}", e);
+        }
+        std::thread::sleep(Duration::from_secs(5));
+    }
+}
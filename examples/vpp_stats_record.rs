@@ -0,0 +1,56 @@
+use clap::Parser as ClapParser;
+use serde::{Deserialize, Serialize};
+use vpp_stat_client::recording::Recorder;
+use vpp_stat_client::source::StatSource;
+use vpp_stat_client::*;
+
+/// Record the snapshots of the VPP statistics into a file, for the later replay
+#[derive(Debug, Clone, ClapParser, Serialize, Deserialize)]
+#[clap(version = "0.0", author = "Andrew Yourtchenko <ayourtch@gmail.com>")]
+struct Opts {
+    /// VPP stats socket as supplied in the "statseg { socket-name /path/to/socket }" config
+    #[clap(short, long, default_value = "/tmp/stats.sock")]
+    socket: String,
+
+    /// Pattern to match
+    #[clap(short, long, default_value = ".*")]
+    pattern: Vec<String>,
+
+    /// File to record into; an existing recording is appended to
+    #[clap(short, long)]
+    output: String,
+
+    /// Interval between the snapshots, in milliseconds
+    #[clap(short, long, default_value = "1000")]
+    interval: u64,
+
+    /// Stop after this many snapshots
+    #[clap(short, long)]
+    count: Option<u64>,
+
+    /// A level of verbosity, and can be used multiple times
+    #[clap(short, long, parse(from_occurrences))]
+    verbose: i32,
+}
+
+fn main() {
+    let opts: Opts = Opts::parse();
+
+    let mut c = VppStatClient::connect(&opts.socket).unwrap();
+    let mut recorder = Recorder::append(&opts.output).unwrap();
+
+    let mut recorded = 0;
+    while opts.count.map(|n| recorded < n).unwrap_or(true) {
+        match c.fetch(&opts.pattern) {
+            Ok(snap) => {
+                recorder.record(&snap).unwrap();
+                recorded += 1;
+                if opts.verbose > 0 {
+                    println!("Recorded {} entries", snap.entries.len());
+                }
+            }
+            Err(e) => eprintln!("Could not dump the stats: {:?}", e),
+        }
+        std::thread::sleep(std::time::Duration::from_millis(opts.interval));
+    }
+}
@@ -0,0 +1,751 @@
+/*
+ * AgentX (RFC 2741) subagent, serving the IF-MIB ifTable and ifXTable
+ * rows from the interface counters, so the operators can snmpwalk VPP
+ * through the master agent (e.g. snmpd with "master agentx").
+ *
+ * Only the read side is implemented; the set requests are refused
+ * with notWritable. The ifIndex is sw_if_index + 1, as in the sFlow export.
+ *
+ * The stats segment has no MTU, speed, MAC address or link state, so those
+ * ifTable columns are filled with the "unknown" values the MIB allows:
+ * zero MTU and speed, an empty ifPhysAddress, admin up, oper unknown and
+ * no ifLastChange. Likewise zero ifInUnknownProtos and ifOutDiscards, and
+ * in the ifXTable zero ifHighSpeed, the link traps disabled, not
+ * promiscuous, no connector, an empty ifAlias and no discontinuity.
+ * The deprecated ifTable columns (ifIn/OutNUcastPkts, ifOutQLen and
+ * ifSpecific) are left out.
+ */
+
+use crate::interfaces::InterfaceCounters;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{Read, Write};
+use std::ops::Bound;
+use std::os::unix::net::UnixStream;
+use std::time::Instant;
+
+pub const AGENTX_DEFAULT_SOCKET: &str = "/var/agentx/master";
+
+pub const IF_TABLE_ENTRY: &[u32] = &[1, 3, 6, 1, 2, 1, 2, 2, 1];
+pub const IF_X_TABLE_ENTRY: &[u32] = &[1, 3, 6, 1, 2, 1, 31, 1, 1, 1];
+
+const AGENTX_VERSION: u8 = 1;
+
+const PDU_OPEN: u8 = 1;
+const PDU_CLOSE: u8 = 2;
+const PDU_REGISTER: u8 = 3;
+const PDU_GET: u8 = 5;
+const PDU_GETNEXT: u8 = 6;
+const PDU_GETBULK: u8 = 7;
+const PDU_TESTSET: u8 = 8;
+const PDU_COMMITSET: u8 = 9;
+const PDU_UNDOSET: u8 = 10;
+const PDU_CLEANUPSET: u8 = 11;
+const PDU_RESPONSE: u8 = 18;
+
+const FLAG_NON_DEFAULT_CONTEXT: u8 = 0x08;
+const FLAG_NETWORK_BYTE_ORDER: u8 = 0x10;
+
+const CLOSE_REASON_SHUTDOWN: u8 = 5;
+
+const IF_TYPE_ETHERNET_CSMACD: i32 = 6;
+const IF_STATUS_UP: i32 = 1;
+const IF_STATUS_UNKNOWN: i32 = 4;
+const TRAP_DISABLED: i32 = 2;
+const TRUTH_FALSE: i32 = 2;
+
+const ERR_NONE: u16 = 0;
+const ERR_NOT_WRITABLE: u16 = 17;
+const ERR_UNSUPPORTED_CONTEXT: u16 = 262;
+const ERR_PARSE: u16 = 266;
+const ERR_PROCESSING: u16 = 268;
+
+pub type Oid = Vec<u32>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum VarValue {
+    Integer(i32),
+    OctetString(Vec<u8>),
+    Counter32(u32),
+    Gauge32(u32),
+    TimeTicks(u32),
+    Counter64(u64),
+    NoSuchObject,
+    NoSuchInstance,
+    EndOfMibView,
+}
+
+impl VarValue {
+    fn type_code(&self) -> u16 {
+        match self {
+            VarValue::Integer(_) => 2,
+            VarValue::OctetString(_) => 4,
+            VarValue::Counter32(_) => 65,
+            VarValue::Gauge32(_) => 66,
+            VarValue::TimeTicks(_) => 67,
+            VarValue::Counter64(_) => 70,
+            VarValue::NoSuchObject => 128,
+            VarValue::NoSuchInstance => 129,
+            VarValue::EndOfMibView => 130,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AgentxError {
+    Io(String),
+    Parse(String),
+    /* The master agent answered with this error code */
+    Refused(u16),
+    ClosedByMaster(u8),
+}
+
+impl From<std::io::Error> for AgentxError {
+    fn from(e: std::io::Error) -> Self {
+        AgentxError::Io(e.to_string())
+    }
+}
+
+/// The values served by the subagent, ordered by OID for the GetNext walks
+#[derive(Debug, Clone, Default)]
+pub struct MibView {
+    values: BTreeMap<Oid, VarValue>,
+}
+
+fn oid_with(prefix: &[u32], column: u32, index: u32) -> Oid {
+    let mut oid = prefix.to_vec();
+    oid.push(column);
+    oid.push(index);
+    oid
+}
+
+impl MibView {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn insert(&mut self, oid: Oid, value: VarValue) {
+        self.values.insert(oid, value);
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Fill in the ifTable and ifXTable rows, with the defaults for the
+    /// columns the stats segment knows nothing about
+    pub fn from_interfaces(ifs: &[InterfaceCounters]) -> Self {
+        let mut view = MibView::new();
+        for ifc in ifs {
+            let idx = crate::sflow::sflow_if_index(ifc.sw_if_index);
+            let name = ifc.display_name().into_bytes();
+            let mut t = |column: u32, value: VarValue| {
+                view.insert(oid_with(IF_TABLE_ENTRY, column, idx), value)
+            };
+            t(1, VarValue::Integer(idx as i32));
+            t(2, VarValue::OctetString(name.clone()));
+            t(3, VarValue::Integer(IF_TYPE_ETHERNET_CSMACD));
+            t(4, VarValue::Integer(0));
+            t(5, VarValue::Gauge32(0));
+            t(6, VarValue::OctetString(vec![]));
+            t(7, VarValue::Integer(IF_STATUS_UP));
+            t(8, VarValue::Integer(IF_STATUS_UNKNOWN));
+            t(9, VarValue::TimeTicks(0));
+            t(10, VarValue::Counter32(ifc.rx.bytes as u32));
+            t(11, VarValue::Counter32(ifc.rx_unicast.packets as u32));
+            t(13, VarValue::Counter32(ifc.drops as u32));
+            t(14, VarValue::Counter32(ifc.rx_error as u32));
+            t(15, VarValue::Counter32(0));
+            t(16, VarValue::Counter32(ifc.tx.bytes as u32));
+            t(17, VarValue::Counter32(ifc.tx_unicast.packets as u32));
+            t(19, VarValue::Counter32(0));
+            t(20, VarValue::Counter32(ifc.tx_error as u32));
+
+            let mut x = |column: u32, value: VarValue| {
+                view.insert(oid_with(IF_X_TABLE_ENTRY, column, idx), value)
+            };
+            x(1, VarValue::OctetString(name));
+            x(2, VarValue::Counter32(ifc.rx_multicast.packets as u32));
+            x(3, VarValue::Counter32(ifc.rx_broadcast.packets as u32));
+            x(4, VarValue::Counter32(ifc.tx_multicast.packets as u32));
+            x(5, VarValue::Counter32(ifc.tx_broadcast.packets as u32));
+            x(6, VarValue::Counter64(ifc.rx.bytes));
+            x(7, VarValue::Counter64(ifc.rx_unicast.packets));
+            x(8, VarValue::Counter64(ifc.rx_multicast.packets));
+            x(9, VarValue::Counter64(ifc.rx_broadcast.packets));
+            x(10, VarValue::Counter64(ifc.tx.bytes));
+            x(11, VarValue::Counter64(ifc.tx_unicast.packets));
+            x(12, VarValue::Counter64(ifc.tx_multicast.packets));
+            x(13, VarValue::Counter64(ifc.tx_broadcast.packets));
+            x(14, VarValue::Integer(TRAP_DISABLED));
+            x(15, VarValue::Gauge32(0));
+            x(16, VarValue::Integer(TRUTH_FALSE));
+            x(17, VarValue::Integer(TRUTH_FALSE));
+            x(18, VarValue::OctetString(vec![]));
+            x(19, VarValue::TimeTicks(0));
+        }
+        view
+    }
+
+    pub fn get(&self, oid: &[u32]) -> VarValue {
+        match self.values.get(oid) {
+            Some(v) => v.clone(),
+            None if oid.starts_with(IF_TABLE_ENTRY) || oid.starts_with(IF_X_TABLE_ENTRY) => {
+                VarValue::NoSuchInstance
+            }
+            None => VarValue::NoSuchObject,
+        }
+    }
+
+    /// The first value after start (or at start, if included) and before end, if end is not empty
+    pub fn get_next(&self, start: &[u32], include: bool, end: &[u32]) -> Option<(Oid, VarValue)> {
+        let lower = if include {
+            Bound::Included(start)
+        } else {
+            Bound::Excluded(start)
+        };
+        self.values
+            .range::<[u32], _>((lower, Bound::Unbounded))
+            .next()
+            .filter(|(oid, _)| end.is_empty() || oid.as_slice() < end)
+            .map(|(oid, v)| (oid.clone(), v.clone()))
+    }
+}
+
+struct PduHeader {
+    pdu_type: u8,
+    flags: u8,
+    session_id: u32,
+    transaction_id: u32,
+    packet_id: u32,
+}
+
+struct PduReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    big_endian: bool,
+}
+
+impl<'a> PduReader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], AgentxError> {
+        if self.pos + n > self.data.len() {
+            return Err(AgentxError::Parse("truncated PDU".to_string()));
+        }
+        let out = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(out)
+    }
+
+    fn u8(&mut self) -> Result<u8, AgentxError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, AgentxError> {
+        let b: [u8; 2] = self.take(2)?.try_into().unwrap();
+        Ok(if self.big_endian {
+            u16::from_be_bytes(b)
+        } else {
+            u16::from_le_bytes(b)
+        })
+    }
+
+    fn u32(&mut self) -> Result<u32, AgentxError> {
+        let b: [u8; 4] = self.take(4)?.try_into().unwrap();
+        Ok(if self.big_endian {
+            u32::from_be_bytes(b)
+        } else {
+            u32::from_le_bytes(b)
+        })
+    }
+
+    fn oid(&mut self) -> Result<(Oid, bool), AgentxError> {
+        let n_subid = self.u8()?;
+        let prefix = self.u8()?;
+        let include = self.u8()? != 0;
+        self.u8()?;
+        let mut oid = if prefix != 0 {
+            vec![1, 3, 6, 1, prefix as u32]
+        } else {
+            vec![]
+        };
+        for _i in 0..n_subid {
+            oid.push(self.u32()?);
+        }
+        Ok((oid, include))
+    }
+
+    fn octets(&mut self) -> Result<&'a [u8], AgentxError> {
+        let len = self.u32()? as usize;
+        let data = self.take(len)?;
+        self.take((4 - len % 4) % 4)?;
+        Ok(data)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+}
+
+/* Everything we send is in the network byte order */
+#[derive(Default)]
+struct PduWriter {
+    buf: Vec<u8>,
+}
+
+impl PduWriter {
+    fn u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    fn u16(&mut self, v: u16) {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+    }
+
+    fn oid(&mut self, oid: &[u32], include: bool) {
+        let (prefix, rest) =
+            if oid.len() > 5 && oid[..4] == [1, 3, 6, 1] && oid[4] > 0 && oid[4] < 256 {
+                (oid[4] as u8, &oid[5..])
+            } else {
+                (0, oid)
+            };
+        self.u8(rest.len() as u8);
+        self.u8(prefix);
+        self.u8(include as u8);
+        self.u8(0);
+        for s in rest {
+            self.u32(*s);
+        }
+    }
+
+    fn octets(&mut self, data: &[u8]) {
+        self.u32(data.len() as u32);
+        self.buf.extend_from_slice(data);
+        let pad = (4 - data.len() % 4) % 4;
+        self.buf.resize(self.buf.len() + pad, 0);
+    }
+
+    fn varbind(&mut self, oid: &[u32], value: &VarValue) {
+        self.u16(value.type_code());
+        self.u16(0);
+        self.oid(oid, false);
+        match value {
+            VarValue::Integer(v) => self.u32(*v as u32),
+            VarValue::OctetString(s) => self.octets(s),
+            VarValue::Counter32(v) | VarValue::Gauge32(v) | VarValue::TimeTicks(v) => self.u32(*v),
+            VarValue::Counter64(v) => {
+                self.u32((*v >> 32) as u32);
+                self.u32(*v as u32);
+            }
+            VarValue::NoSuchObject | VarValue::NoSuchInstance | VarValue::EndOfMibView => {}
+        }
+    }
+}
+
+pub struct AgentxSubagent {
+    stream: UnixStream,
+    session_id: u32,
+    packet_id: u32,
+    start: Instant,
+    /* The requests from the master which came while waiting for a response */
+    pending: VecDeque<(PduHeader, Vec<u8>)>,
+}
+
+impl AgentxSubagent {
+    /// Connect to the master agent socket
+    pub fn connect(path: &str) -> Result<Self, AgentxError> {
+        Ok(Self::from_stream(UnixStream::connect(path)?))
+    }
+
+    pub fn from_stream(stream: UnixStream) -> Self {
+        AgentxSubagent {
+            stream,
+            session_id: 0,
+            packet_id: 0,
+            start: Instant::now(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn uptime(&self) -> u32 {
+        (self.start.elapsed().as_millis() / 10) as u32
+    }
+
+    fn send_pdu(
+        &mut self,
+        pdu_type: u8,
+        flags: u8,
+        transaction_id: u32,
+        packet_id: u32,
+        payload: &[u8],
+    ) -> Result<(), AgentxError> {
+        let mut w = PduWriter::default();
+        w.u8(AGENTX_VERSION);
+        w.u8(pdu_type);
+        w.u8(flags | FLAG_NETWORK_BYTE_ORDER);
+        w.u8(0);
+        w.u32(self.session_id);
+        w.u32(transaction_id);
+        w.u32(packet_id);
+        w.u32(payload.len() as u32);
+        w.buf.extend_from_slice(payload);
+        self.stream.write_all(&w.buf)?;
+        Ok(())
+    }
+
+    fn read_pdu(&mut self) -> Result<(PduHeader, Vec<u8>), AgentxError> {
+        let mut raw = [0u8; 20];
+        self.stream.read_exact(&mut raw)?;
+        let mut r = PduReader {
+            data: &raw,
+            pos: 0,
+            big_endian: raw[2] & FLAG_NETWORK_BYTE_ORDER != 0,
+        };
+        r.u8()?;
+        let pdu_type = r.u8()?;
+        let flags = r.u8()?;
+        r.u8()?;
+        let hdr = PduHeader {
+            pdu_type,
+            flags,
+            session_id: r.u32()?,
+            transaction_id: r.u32()?,
+            packet_id: r.u32()?,
+        };
+        let mut payload = vec![0u8; r.u32()? as usize];
+        self.stream.read_exact(&mut payload)?;
+        Ok((hdr, payload))
+    }
+
+    /* Send a request and wait for its response, returning the error code from it.
+     * The requests from the master in the meantime are kept for serve(),
+     * the stale responses are dropped. */
+    fn request(&mut self, pdu_type: u8, flags: u8, payload: &[u8]) -> Result<u16, AgentxError> {
+        self.packet_id += 1;
+        let packet_id = self.packet_id;
+        self.send_pdu(pdu_type, flags, 0, packet_id, payload)?;
+        loop {
+            let (hdr, payload) = self.read_pdu()?;
+            if hdr.pdu_type != PDU_RESPONSE {
+                self.pending.push_back((hdr, payload));
+                continue;
+            }
+            if hdr.packet_id == packet_id {
+                if pdu_type == PDU_OPEN {
+                    self.session_id = hdr.session_id;
+                }
+                let mut r = PduReader {
+                    data: &payload,
+                    pos: 0,
+                    big_endian: hdr.flags & FLAG_NETWORK_BYTE_ORDER != 0,
+                };
+                r.u32()?;
+                return r.u16();
+            }
+        }
+    }
+
+    /// Open the session, the description shows up in the master agent logs
+    pub fn open(&mut self, descr: &str, timeout: u8) -> Result<(), AgentxError> {
+        let mut w = PduWriter::default();
+        w.u8(timeout);
+        w.u8(0);
+        w.u8(0);
+        w.u8(0);
+        w.oid(&[], false);
+        w.octets(descr.as_bytes());
+        match self.request(PDU_OPEN, 0, &w.buf)? {
+            ERR_NONE => Ok(()),
+            err => Err(AgentxError::Refused(err)),
+        }
+    }
+
+    /// Register a subtree, the lower priority value wins over the other registrations
+    pub fn register(&mut self, subtree: &[u32], priority: u8) -> Result<(), AgentxError> {
+        let mut w = PduWriter::default();
+        w.u8(0);
+        w.u8(priority);
+        w.u8(0);
+        w.u8(0);
+        w.oid(subtree, false);
+        match self.request(PDU_REGISTER, 0, &w.buf)? {
+            ERR_NONE => Ok(()),
+            err => Err(AgentxError::Refused(err)),
+        }
+    }
+
+    pub fn close(&mut self) -> Result<(), AgentxError> {
+        self.packet_id += 1;
+        let packet_id = self.packet_id;
+        self.send_pdu(
+            PDU_CLOSE,
+            0,
+            0,
+            packet_id,
+            &[CLOSE_REASON_SHUTDOWN, 0, 0, 0],
+        )
+    }
+
+    fn respond(
+        &mut self,
+        hdr: &PduHeader,
+        error: u16,
+        index: u16,
+        varbinds: &[(Oid, VarValue)],
+    ) -> Result<(), AgentxError> {
+        let mut w = PduWriter::default();
+        w.u32(self.uptime());
+        w.u16(error);
+        w.u16(index);
+        for (oid, value) in varbinds {
+            w.varbind(oid, value);
+        }
+        self.send_pdu(PDU_RESPONSE, 0, hdr.transaction_id, hdr.packet_id, &w.buf)
+    }
+
+    fn next_or_end(view: &MibView, start: &[u32], include: bool, end: &[u32]) -> (Oid, VarValue) {
+        view.get_next(start, include, end)
+            .unwrap_or_else(|| (start.to_vec(), VarValue::EndOfMibView))
+    }
+
+    fn answer(
+        hdr: &PduHeader,
+        r: &mut PduReader,
+        view: &MibView,
+    ) -> Result<Vec<(Oid, VarValue)>, AgentxError> {
+        let (non_repeaters, max_repetitions) = if hdr.pdu_type == PDU_GETBULK {
+            (r.u16()? as usize, r.u16()? as usize)
+        } else {
+            (0, 0)
+        };
+        let mut ranges = vec![];
+        while !r.is_empty() {
+            let (start, include) = r.oid()?;
+            let (end, _) = r.oid()?;
+            ranges.push((start, include, end));
+        }
+
+        let mut out = vec![];
+        match hdr.pdu_type {
+            PDU_GET => {
+                for (start, _, _) in &ranges {
+                    out.push((start.clone(), view.get(start)));
+                }
+            }
+            PDU_GETNEXT => {
+                for (start, include, end) in &ranges {
+                    out.push(Self::next_or_end(view, start, *include, end));
+                }
+            }
+            _ => {
+                let non_repeaters = non_repeaters.min(ranges.len());
+                for (start, include, end) in &ranges[..non_repeaters] {
+                    out.push(Self::next_or_end(view, start, *include, end));
+                }
+                let mut cursors: Vec<(Oid, bool)> = ranges[non_repeaters..]
+                    .iter()
+                    .map(|(start, include, _)| (start.clone(), *include))
+                    .collect();
+                for _rep in 0..max_repetitions {
+                    let mut all_done = true;
+                    for (i, (start, include)) in cursors.iter_mut().enumerate() {
+                        let end = &ranges[non_repeaters + i].2;
+                        let (oid, value) = Self::next_or_end(view, start, *include, end);
+                        if value != VarValue::EndOfMibView {
+                            all_done = false;
+                        }
+                        *start = oid.clone();
+                        *include = false;
+                        out.push((oid, value));
+                    }
+                    if all_done {
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    /// Serve the requests until the master closes the session. The closure
+    /// is called for every Get, GetNext and GetBulk to get the current values.
+    pub fn serve<F>(&mut self, mut refresh: F) -> Result<(), AgentxError>
+    where
+        F: FnMut() -> MibView,
+    {
+        loop {
+            let (hdr, payload) = match self.pending.pop_front() {
+                Some(pdu) => pdu,
+                None => self.read_pdu()?,
+            };
+            let mut r = PduReader {
+                data: &payload,
+                pos: 0,
+                big_endian: hdr.flags & FLAG_NETWORK_BYTE_ORDER != 0,
+            };
+            match hdr.pdu_type {
+                PDU_GET | PDU_GETNEXT | PDU_GETBULK => {
+                    if hdr.flags & FLAG_NON_DEFAULT_CONTEXT != 0 {
+                        self.respond(&hdr, ERR_UNSUPPORTED_CONTEXT, 0, &[])?;
+                        continue;
+                    }
+                    let view = refresh();
+                    match Self::answer(&hdr, &mut r, &view) {
+                        Ok(varbinds) => self.respond(&hdr, ERR_NONE, 0, &varbinds)?,
+                        Err(_) => self.respond(&hdr, ERR_PARSE, 0, &[])?,
+                    }
+                }
+                PDU_TESTSET => self.respond(&hdr, ERR_NOT_WRITABLE, 1, &[])?,
+                PDU_COMMITSET | PDU_UNDOSET => self.respond(&hdr, ERR_NONE, 0, &[])?,
+                PDU_CLEANUPSET | PDU_RESPONSE => {}
+                PDU_CLOSE => {
+                    let reason = r.u8().unwrap_or(0);
+                    return Err(AgentxError::ClosedByMaster(reason));
+                }
+                _ => self.respond(&hdr, ERR_PROCESSING, 0, &[])?,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::CombinedCounter;
+
+    fn test_view() -> MibView {
+        MibView::from_interfaces(&[
+            InterfaceCounters {
+                sw_if_index: 0,
+                name: Some("local0".to_string()),
+                ..Default::default()
+            },
+            InterfaceCounters {
+                sw_if_index: 1,
+                name: Some("eth0".to_string()),
+                rx: CombinedCounter {
+                    packets: 7,
+                    bytes: 0x1_0000_0010,
+                },
+                ..Default::default()
+            },
+        ])
+    }
+
+    fn col(entry: &[u32], column: u32, index: u32) -> Oid {
+        oid_with(entry, column, index)
+    }
+
+    #[test]
+    fn walk_order() {
+        let view = test_view();
+        /* the walk goes down the column first */
+        let (oid, v) = view
+            .get_next(&col(IF_TABLE_ENTRY, 2, 1), false, &[])
+            .unwrap();
+        assert_eq!(oid, col(IF_TABLE_ENTRY, 2, 2));
+        assert_eq!(v, VarValue::OctetString(b"eth0".to_vec()));
+        /* the 32 bit counters wrap, the HC ones do not */
+        assert_eq!(
+            view.get(&col(IF_TABLE_ENTRY, 10, 2)),
+            VarValue::Counter32(0x10)
+        );
+        assert_eq!(
+            view.get(&col(IF_X_TABLE_ENTRY, 6, 2)),
+            VarValue::Counter64(0x1_0000_0010)
+        );
+        assert_eq!(
+            view.get(&col(IF_TABLE_ENTRY, 10, 9)),
+            VarValue::NoSuchInstance
+        );
+        /* Every column of the IF-MIB conformance groups, the deprecated ones aside */
+        for column in (1..=11).chain(13..=17).chain(19..=20) {
+            assert!(view.values.contains_key(&col(IF_TABLE_ENTRY, column, 2)));
+        }
+        for column in 1..=19 {
+            assert!(view.values.contains_key(&col(IF_X_TABLE_ENTRY, column, 2)));
+        }
+        assert_eq!(
+            view.get(&col(IF_TABLE_ENTRY, 8, 2)),
+            VarValue::Integer(IF_STATUS_UNKNOWN)
+        );
+        assert!(view
+            .get_next(&col(IF_X_TABLE_ENTRY, 19, 2), false, &[])
+            .is_none());
+    }
+
+    fn master_read(s: &mut UnixStream) -> (u8, u32, Vec<u8>) {
+        let mut hdr = [0u8; 20];
+        s.read_exact(&mut hdr).unwrap();
+        let packet_id = u32::from_be_bytes(hdr[12..16].try_into().unwrap());
+        let len = u32::from_be_bytes(hdr[16..20].try_into().unwrap()) as usize;
+        let mut payload = vec![0u8; len];
+        s.read_exact(&mut payload).unwrap();
+        (hdr[1], packet_id, payload)
+    }
+
+    fn master_send(s: &mut UnixStream, pdu_type: u8, packet_id: u32, payload: &[u8]) {
+        let mut w = PduWriter::default();
+        w.u8(1);
+        w.u8(pdu_type);
+        w.u8(FLAG_NETWORK_BYTE_ORDER);
+        w.u8(0);
+        w.u32(42);
+        w.u32(0);
+        w.u32(packet_id);
+        w.u32(payload.len() as u32);
+        w.buf.extend_from_slice(payload);
+        s.write_all(&w.buf).unwrap();
+    }
+
+    #[test]
+    fn session_with_mock_master() {
+        let (sub, mut master) = UnixStream::pair().unwrap();
+        let handle = std::thread::spawn(move || {
+            let mut agent = AgentxSubagent::from_stream(sub);
+            agent.open("vpp", 5).unwrap();
+            agent.register(IF_TABLE_ENTRY, 127).unwrap();
+            agent.serve(test_view)
+        });
+
+        let ok = [0u8; 8];
+        let (t, id, _) = master_read(&mut master);
+        assert_eq!(t, PDU_OPEN);
+        master_send(&mut master, PDU_RESPONSE, id, &ok);
+        let (t, id, _) = master_read(&mut master);
+        assert_eq!(t, PDU_REGISTER);
+
+        /* A request before the response to the registration is served after it */
+        let mut req = PduWriter::default();
+        req.oid(&col(IF_TABLE_ENTRY, 2, 1), false);
+        req.oid(&[], false);
+        master_send(&mut master, PDU_GETNEXT, 7, &req.buf);
+        master_send(&mut master, PDU_RESPONSE, id, &ok);
+        let (t, id, payload) = master_read(&mut master);
+        assert_eq!((t, id), (PDU_RESPONSE, 7));
+        let mut r = PduReader {
+            data: &payload,
+            pos: 0,
+            big_endian: true,
+        };
+        r.u32().unwrap();
+        assert_eq!(r.u16().unwrap(), ERR_NONE);
+        r.u16().unwrap();
+        assert_eq!(r.u16().unwrap(), 4);
+        r.u16().unwrap();
+        assert_eq!(r.oid().unwrap().0, col(IF_TABLE_ENTRY, 2, 2));
+        assert_eq!(r.octets().unwrap(), b"eth0");
+
+        master_send(&mut master, PDU_CLOSE, 8, &[CLOSE_REASON_SHUTDOWN, 0, 0, 0]);
+        assert_eq!(
+            handle.join().unwrap(),
+            Err(AgentxError::ClosedByMaster(CLOSE_REASON_SHUTDOWN))
+        );
+    }
+}
@@ -0,0 +1,841 @@
+/*
+ * The alerting rules, evaluated over the stats every interval:
+ *
+ *   rules:
+ *     - name: eth0-drops
+ *       expr: "rate(/if/drops[eth0]) > 1000 for 30s"
+ *       actions:
+ *         - log
+ *         - exec: /usr/local/bin/page-oncall
+ *         - webhook: http://127.0.0.1:9000/alerts
+ *     - name: vector-rate
+ *       expr: "/sys/vector_rate >= 200"
+ *   anomalies:
+ *     - name: traffic
+ *       patterns: ["^/if/rx$", "^/err/"]
+ *       detector: { k: 4, warmup: 60 }
+ *       actions: [log]
+ *
+ * The expression is rate(stat) or value(stat), or just the stat for its
+ * value, compared with a number. The stat can be narrowed down to one
+ * index or interface name in the brackets, and to the ".packets" or
+ * ".bytes" of the combined counters; otherwise the indices are summed.
+ *
+ * A rule which becomes true is pending, and fires once it has stayed true
+ * for the "for" duration (at once without it). A firing rule which becomes
+ * false is resolved. The actions are told when a rule fires and when it
+ * is resolved.
+ *
+ * The anomaly rules fire for every series whose rate the detector flags,
+ * and resolve once it is back within the usual range. A resolved series
+ * is left in the status for RESOLVED_KEPT seconds, then forgotten.
+ */
+
+use crate::anomaly::{Anomaly, AnomalyConfig, AnomalyDetector, Subject, SUBJECT_PATTERNS};
+use crate::delta::{deltas, flatten, CounterField, SeriesKey};
+use crate::snapshot::StatSnapshot;
+use crate::source::exact_pattern;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Write;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AlertError {
+    Io(String),
+    Parse(String),
+    BadExpr(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ActionConfig {
+    /// Print the alert on the stderr
+    Log,
+    /// Run the program, with the alert in the VPP_ALERT_* environment variables
+    Exec(String),
+    /// POST the alert as JSON to the URL
+    Webhook(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleConfig {
+    pub name: String,
+    pub expr: String,
+    #[serde(default)]
+    pub actions: Vec<ActionConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AnomalyRuleConfig {
+    pub name: String,
+    /// The stats to watch, everything if empty
+    #[serde(default)]
+    pub patterns: Vec<String>,
+    #[serde(default)]
+    pub detector: AnomalyConfig,
+    #[serde(default)]
+    pub actions: Vec<ActionConfig>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AlertConfig {
+    pub rules: Vec<RuleConfig>,
+    pub anomalies: Vec<AnomalyRuleConfig>,
+}
+
+impl AlertConfig {
+    pub fn load(path: &str) -> Result<Self, AlertError> {
+        let data = std::fs::read_to_string(path)
+            .map_err(|e| AlertError::Io(format!("{}: {}", path, e)))?;
+        AlertConfig::from_yaml(&data)
+    }
+
+    pub fn from_yaml(data: &str) -> Result<Self, AlertError> {
+        serde_yaml::from_str(data).map_err(|e| AlertError::Parse(e.to_string()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Function {
+    Rate,
+    Value,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Gt,
+    Ge,
+    Lt,
+    Le,
+    Eq,
+    Ne,
+}
+
+impl Comparison {
+    fn holds(&self, a: f64, b: f64) -> bool {
+        match self {
+            Comparison::Gt => a > b,
+            Comparison::Ge => a >= b,
+            Comparison::Lt => a < b,
+            Comparison::Le => a <= b,
+            Comparison::Eq => a == b,
+            Comparison::Ne => a != b,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Selector {
+    Index(usize),
+    Interface(String),
+}
+
+/// Which series of the stats an expression looks at
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SeriesSelector {
+    pub stat: String,
+    pub selector: Option<Selector>,
+    /// The packets for the combined counters if not given
+    pub field: Option<CounterField>,
+}
+
+impl SeriesSelector {
+    pub fn parse(s: &str) -> Result<Self, AlertError> {
+        let re = Regex::new(r"^(.*?)(?:\[([^\]]*)\])?(?:\.(packets|bytes))?$").unwrap();
+        let caps = re
+            .captures(s.trim())
+            .ok_or_else(|| AlertError::BadExpr(format!("bad stat \"{}\"", s)))?;
+        let stat = caps[1].to_string();
+        if stat.is_empty() {
+            return Err(AlertError::BadExpr(format!("no stat in \"{}\"", s)));
+        }
+        let selector = caps.get(2).map(|m| match m.as_str().parse::<usize>() {
+            Ok(index) => Selector::Index(index),
+            Err(_) => Selector::Interface(m.as_str().to_string()),
+        });
+        let field = caps.get(3).map(|m| match m.as_str() {
+            "bytes" => CounterField::Bytes,
+            _ => CounterField::Packets,
+        });
+        Ok(SeriesSelector {
+            stat,
+            selector,
+            field,
+        })
+    }
+
+    /// The stats to read for it
+    pub fn patterns(&self) -> Vec<String> {
+        let mut patterns = vec![exact_pattern(&self.stat)];
+        if let Some(Selector::Interface(_)) = self.selector {
+            patterns.push("^/if/names$".to_string());
+        }
+        patterns
+    }
+
+    pub fn matches(&self, key: &SeriesKey, snap: &StatSnapshot) -> bool {
+        if key.name != self.stat {
+            return false;
+        }
+        let field = match self.field {
+            Some(f) => key.field == f,
+            None => key.field != CounterField::Bytes,
+        };
+        field
+            && match &self.selector {
+                None => true,
+                Some(Selector::Index(i)) => key.index == Some(*i),
+                Some(Selector::Interface(name)) => key.interface_name(snap) == Some(name.as_str()),
+            }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlertExpr {
+    pub function: Function,
+    pub series: SeriesSelector,
+    pub comparison: Comparison,
+    pub threshold: f64,
+    /// How long it has to stay true before firing, in seconds
+    pub for_secs: f64,
+}
+
+impl AlertExpr {
+    pub fn parse(s: &str) -> Result<Self, AlertError> {
+        /* The stat names can have the spaces and the brackets, the greedy match finds the last ")" */
+        let re = Regex::new(concat!(
+            r"^\s*(?:(rate|value)\((.*)\)|(.*?))",
+            r"\s*(>=|<=|==|!=|>|<)\s*([-+0-9.eE]+)",
+            r"\s*(?:for\s+([0-9.]+)(ms|s|m|h))?\s*$",
+        ))
+        .unwrap();
+        let bad = || AlertError::BadExpr(format!("bad expression \"{}\"", s));
+        let caps = re.captures(s).ok_or_else(bad)?;
+        let function = match caps.get(1).map(|m| m.as_str()) {
+            Some("rate") => Function::Rate,
+            _ => Function::Value,
+        };
+        let series = SeriesSelector::parse(caps.get(2).or_else(|| caps.get(3)).unwrap().as_str())?;
+        let comparison = match &caps[4] {
+            ">" => Comparison::Gt,
+            ">=" => Comparison::Ge,
+            "<" => Comparison::Lt,
+            "<=" => Comparison::Le,
+            "==" => Comparison::Eq,
+            _ => Comparison::Ne,
+        };
+        let threshold = caps[5].parse::<f64>().map_err(|_| bad())?;
+        let for_secs = match caps.get(6) {
+            Some(n) => {
+                let n = n.as_str().parse::<f64>().map_err(|_| bad())?;
+                n * match &caps[7] {
+                    "ms" => 0.001,
+                    "s" => 1.0,
+                    "m" => 60.0,
+                    _ => 3600.0,
+                }
+            }
+            None => 0.0,
+        };
+        Ok(AlertExpr {
+            function,
+            series,
+            comparison,
+            threshold,
+            for_secs,
+        })
+    }
+
+    /// The summed value of the matching series; None if there are none,
+    /// or for the rates on the first snapshot or with every series reset
+    pub fn value(&self, prev: Option<&StatSnapshot>, cur: &StatSnapshot) -> Option<f64> {
+        let values: Vec<f64> = match self.function {
+            Function::Value => flatten(cur, false)
+                .into_iter()
+                .filter(|(key, _)| self.series.matches(key, cur))
+                .map(|(_, v)| v.as_f64())
+                .collect(),
+            /* The ones reset in the interval give no rate, same as for the RateTracker */
+            Function::Rate => deltas(prev?, cur, false)
+                .into_iter()
+                .filter(|d| self.series.matches(&d.key, cur) && !d.reset)
+                .map(|d| d.rate)
+                .collect(),
+        };
+        if values.is_empty() {
+            None
+        } else {
+            Some(values.iter().sum())
+        }
+    }
+
+    pub fn holds(&self, value: f64) -> bool {
+        self.comparison.holds(value, self.threshold)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertState {
+    Inactive,
+    Pending,
+    Firing,
+    Resolved,
+}
+
+impl AlertState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AlertState::Inactive => "inactive",
+            AlertState::Pending => "pending",
+            AlertState::Firing => "firing",
+            AlertState::Resolved => "resolved",
+        }
+    }
+}
+
+/// A rule which started or stopped firing
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AlertEvent {
+    #[serde(skip_serializing_if = "String::is_empty", default)]
+    pub instance: String,
+    pub rule: String,
+    /// The series of an anomaly rule, e.g. "/if/rx[1].packets (eth0)"
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub series: Option<String>,
+    pub expr: String,
+    /// Firing or resolved
+    pub state: AlertState,
+    pub value: Option<f64>,
+    /// In seconds since the epoch
+    pub timestamp: f64,
+}
+
+/// Where a rule is at, for the API
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AlertStatus {
+    #[serde(skip_serializing_if = "String::is_empty", default)]
+    pub instance: String,
+    pub rule: String,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub series: Option<String>,
+    pub expr: String,
+    pub state: AlertState,
+    /// The last value seen, None if there was no data
+    pub value: Option<f64>,
+    /// When the rule entered the state, in seconds since the epoch
+    pub since: Option<f64>,
+}
+
+pub trait AlertAction {
+    fn notify(&mut self, event: &AlertEvent);
+}
+
+/// Write a line per event, to the stderr by default
+pub struct LogAction<W: Write> {
+    writer: W,
+}
+
+impl LogAction<std::io::Stderr> {
+    pub fn stderr() -> Self {
+        LogAction {
+            writer: std::io::stderr(),
+        }
+    }
+}
+
+impl<W: Write> LogAction<W> {
+    pub fn new(writer: W) -> Self {
+        LogAction { writer }
+    }
+}
+
+fn event_line(event: &AlertEvent) -> String {
+    let value = event
+        .value
+        .map(|v| v.to_string())
+        .unwrap_or_else(|| "none".to_string());
+    let instance = if event.instance.is_empty() {
+        String::new()
+    } else {
+        format!("{} ", event.instance)
+    };
+    let rule = match &event.series {
+        Some(series) => format!("{} {}", event.rule, series),
+        None => event.rule.clone(),
+    };
+    format!(
+        "{}ALERT {} {}: {} (value {})",
+        instance,
+        event.state.as_str().to_uppercase(),
+        rule,
+        event.expr,
+        value
+    )
+}
+
+impl<W: Write> AlertAction for LogAction<W> {
+    fn notify(&mut self, event: &AlertEvent) {
+        let _ = writeln!(self.writer, "{}", event_line(event));
+    }
+}
+
+/// Run a program per event, in the background, with the VPP_ALERT_INSTANCE,
+/// VPP_ALERT_RULE, VPP_ALERT_SERIES, VPP_ALERT_EXPR, VPP_ALERT_STATE and
+/// VPP_ALERT_VALUE set
+pub struct ExecAction {
+    program: String,
+}
+
+impl ExecAction {
+    pub fn new(program: &str) -> Self {
+        ExecAction {
+            program: program.to_string(),
+        }
+    }
+}
+
+impl AlertAction for ExecAction {
+    fn notify(&mut self, event: &AlertEvent) {
+        let mut cmd = std::process::Command::new(&self.program);
+        cmd.env("VPP_ALERT_INSTANCE", &event.instance)
+            .env("VPP_ALERT_RULE", &event.rule)
+            .env("VPP_ALERT_SERIES", event.series.as_deref().unwrap_or(""))
+            .env("VPP_ALERT_EXPR", &event.expr)
+            .env("VPP_ALERT_STATE", event.state.as_str())
+            .env(
+                "VPP_ALERT_VALUE",
+                event.value.map(|v| v.to_string()).unwrap_or_default(),
+            );
+        let program = self.program.clone();
+        /* Waited for on the side, so the evaluation is not held up */
+        std::thread::spawn(move || {
+            if let Err(e) = cmd.status() {
+                eprintln!("Could not run {}: {}", program, e);
+            }
+        });
+    }
+}
+
+/// POST the events as JSON, in the background
+pub struct WebhookAction {
+    url: String,
+    timeout: u64,
+}
+
+impl WebhookAction {
+    pub fn new(url: &str) -> Self {
+        WebhookAction {
+            url: url.to_string(),
+            timeout: 5,
+        }
+    }
+}
+
+impl AlertAction for WebhookAction {
+    fn notify(&mut self, event: &AlertEvent) {
+        let req = match minreq::post(self.url.as_str()).with_json(event) {
+            Ok(req) => req.with_timeout(self.timeout),
+            Err(e) => {
+                eprintln!("Could not encode the alert: {}", e);
+                return;
+            }
+        };
+        let url = self.url.clone();
+        std::thread::spawn(move || match req.send() {
+            Ok(resp) if (200..300).contains(&resp.status_code) => {}
+            Ok(resp) => eprintln!("Webhook {} returned {}", url, resp.status_code),
+            Err(e) => eprintln!("Webhook {} failed: {}", url, e),
+        });
+    }
+}
+
+pub fn action(config: &ActionConfig) -> Box<dyn AlertAction> {
+    match config {
+        ActionConfig::Log => Box::new(LogAction::stderr()),
+        ActionConfig::Exec(program) => Box::new(ExecAction::new(program)),
+        ActionConfig::Webhook(url) => Box::new(WebhookAction::new(url)),
+    }
+}
+
+struct Rule {
+    name: String,
+    text: String,
+    expr: AlertExpr,
+    actions: Vec<Box<dyn AlertAction>>,
+    state: AlertState,
+    value: Option<f64>,
+    since: Option<f64>,
+    /* When it became true, while pending or firing */
+    true_since: f64,
+}
+
+/// How long the resolved anomalies stay in the status, in seconds
+pub const RESOLVED_KEPT: f64 = 600.0;
+
+struct AnomalyRule {
+    name: String,
+    patterns: Vec<String>,
+    detector: AnomalyDetector,
+    actions: Vec<Box<dyn AlertAction>>,
+    /* The series flagged, and the recently resolved ones, by their label */
+    series: BTreeMap<String, AlertStatus>,
+}
+
+/* The series with the name of what it is about, if there is one */
+fn series_label(anomaly: &Anomaly) -> String {
+    match &anomaly.subject {
+        Subject::Interface { name } | Subject::Node { name } => {
+            format!("{} ({})", anomaly.key, name)
+        }
+        Subject::Error { .. } | Subject::Stat => anomaly.key.to_string(),
+    }
+}
+
+pub struct RuleEngine {
+    instance: String,
+    rules: Vec<Rule>,
+    anomalies: Vec<AnomalyRule>,
+    /* Told about the events of all the rules */
+    actions: Vec<Box<dyn AlertAction>>,
+    prev: Option<StatSnapshot>,
+}
+
+impl RuleEngine {
+    pub fn new(config: &AlertConfig) -> Result<Self, AlertError> {
+        let rules = config
+            .rules
+            .iter()
+            .map(|r| {
+                let expr = AlertExpr::parse(&r.expr)
+                    .map_err(|e| AlertError::BadExpr(format!("rule {}: {:?}", r.name, e)))?;
+                Ok(Rule {
+                    name: r.name.clone(),
+                    text: r.expr.clone(),
+                    expr,
+                    actions: r.actions.iter().map(action).collect(),
+                    state: AlertState::Inactive,
+                    value: None,
+                    since: None,
+                    true_since: 0.0,
+                })
+            })
+            .collect::<Result<_, AlertError>>()?;
+        let anomalies = config
+            .anomalies
+            .iter()
+            .map(|a| {
+                for p in &a.patterns {
+                    Regex::new(p)
+                        .map_err(|e| AlertError::BadExpr(format!("anomalies {}: {}", a.name, e)))?;
+                }
+                Ok(AnomalyRule {
+                    name: a.name.clone(),
+                    patterns: a.patterns.clone(),
+                    detector: AnomalyDetector::new(a.detector.clone()).with_patterns(&a.patterns),
+                    actions: a.actions.iter().map(action).collect(),
+                    series: BTreeMap::new(),
+                })
+            })
+            .collect::<Result<_, AlertError>>()?;
+        Ok(RuleEngine {
+            instance: String::new(),
+            rules,
+            anomalies,
+            actions: vec![],
+            prev: None,
+        })
+    }
+
+    /// Name of the VPP instance, carried by the events and the status
+    pub fn with_instance(mut self, instance: &str) -> Self {
+        self.instance = instance.to_string();
+        self
+    }
+
+    /// An action for the events of every rule
+    pub fn with_action(mut self, action: Box<dyn AlertAction>) -> Self {
+        self.actions.push(action);
+        self
+    }
+
+    /// The stats to read for all the rules
+    pub fn patterns(&self) -> Vec<String> {
+        let mut patterns: Vec<String> = self
+            .rules
+            .iter()
+            .flat_map(|r| r.expr.series.patterns())
+            .collect();
+        for a in &self.anomalies {
+            if a.patterns.is_empty() {
+                patterns.push(".*".to_string());
+            }
+            patterns.extend(a.patterns.iter().cloned());
+            patterns.extend(SUBJECT_PATTERNS.iter().map(|p| p.to_string()));
+        }
+        patterns.sort();
+        patterns.dedup();
+        patterns
+    }
+
+    /// Evaluate the rules on the new snapshot, and notify the actions
+    /// of the rules which started or stopped firing
+    pub fn evaluate(&mut self, snap: StatSnapshot) -> Vec<AlertEvent> {
+        let now = snap.timestamp;
+        let mut events = vec![];
+        for rule in self.rules.iter_mut() {
+            let value = rule.expr.value(self.prev.as_ref(), &snap);
+            /* The rates need two snapshots, so the first one says nothing */
+            if value.is_none() && rule.expr.function == Function::Rate && self.prev.is_none() {
+                continue;
+            }
+            rule.value = value;
+            let holds = value.map(|v| rule.expr.holds(v)).unwrap_or(false);
+            let state = match (rule.state, holds) {
+                (AlertState::Pending, true) | (AlertState::Firing, true) => {
+                    if now - rule.true_since >= rule.expr.for_secs {
+                        AlertState::Firing
+                    } else {
+                        AlertState::Pending
+                    }
+                }
+                (_, true) => {
+                    rule.true_since = now;
+                    if rule.expr.for_secs <= 0.0 {
+                        AlertState::Firing
+                    } else {
+                        AlertState::Pending
+                    }
+                }
+                (AlertState::Firing, false) => AlertState::Resolved,
+                (AlertState::Resolved, false) => AlertState::Resolved,
+                (_, false) => AlertState::Inactive,
+            };
+            if state == rule.state {
+                continue;
+            }
+            rule.state = state;
+            rule.since = Some(now);
+            if state == AlertState::Firing || state == AlertState::Resolved {
+                let event = AlertEvent {
+                    instance: self.instance.clone(),
+                    rule: rule.name.clone(),
+                    series: None,
+                    expr: rule.text.clone(),
+                    state,
+                    value,
+                    timestamp: now,
+                };
+                for action in rule.actions.iter_mut().chain(self.actions.iter_mut()) {
+                    action.notify(&event);
+                }
+                events.push(event);
+            }
+        }
+        for rule in self.anomalies.iter_mut() {
+            let flagged: BTreeMap<String, Anomaly> = rule
+                .detector
+                .update(&snap)
+                .into_iter()
+                .map(|a| (series_label(&a), a))
+                .collect();
+            let mut changes = vec![];
+            for (label, anomaly) in &flagged {
+                let status = rule
+                    .series
+                    .entry(label.clone())
+                    .or_insert_with(|| AlertStatus {
+                        instance: self.instance.clone(),
+                        rule: rule.name.clone(),
+                        series: Some(label.clone()),
+                        expr: String::new(),
+                        state: AlertState::Inactive,
+                        value: None,
+                        since: None,
+                    });
+                status.expr = anomaly.to_string();
+                status.value = Some(anomaly.rate);
+                if status.state != AlertState::Firing {
+                    status.state = AlertState::Firing;
+                    status.since = Some(now);
+                    changes.push(status.clone());
+                }
+            }
+            for (label, status) in rule.series.iter_mut() {
+                if status.state == AlertState::Firing && !flagged.contains_key(label) {
+                    status.state = AlertState::Resolved;
+                    status.since = Some(now);
+                    changes.push(status.clone());
+                }
+            }
+            rule.series.retain(|_, status| {
+                status.state == AlertState::Firing
+                    || now - status.since.unwrap_or(now) < RESOLVED_KEPT
+            });
+            for status in changes {
+                let event = AlertEvent {
+                    instance: status.instance,
+                    rule: status.rule,
+                    series: status.series,
+                    expr: status.expr,
+                    state: status.state,
+                    value: status.value,
+                    timestamp: now,
+                };
+                for action in rule.actions.iter_mut().chain(self.actions.iter_mut()) {
+                    action.notify(&event);
+                }
+                events.push(event);
+            }
+        }
+        self.prev = Some(snap);
+        events
+    }
+
+    pub fn status(&self) -> Vec<AlertStatus> {
+        self.rules
+            .iter()
+            .map(|r| AlertStatus {
+                instance: self.instance.clone(),
+                rule: r.name.clone(),
+                series: None,
+                expr: r.text.clone(),
+                state: r.state,
+                value: r.value,
+                since: r.since,
+            })
+            .chain(
+                self.anomalies
+                    .iter()
+                    .flat_map(|a| a.series.values().cloned()),
+            )
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::SnapshotBuilder;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn snap(timestamp: f64, drops: u64) -> StatSnapshot {
+        SnapshotBuilder::new(timestamp)
+            .with_interfaces(&["local0", "eth0"])
+            .with_simple("/if/drops", vec![vec![5, drops]])
+            .build()
+    }
+
+    struct Recorder(Rc<RefCell<Vec<String>>>);
+
+    impl AlertAction for Recorder {
+        fn notify(&mut self, event: &AlertEvent) {
+            self.0.borrow_mut().push(event_line(event));
+        }
+    }
+
+    #[test]
+    fn parse_expressions() {
+        let e = AlertExpr::parse("rate(/if/drops[eth0]) > 1000 for 30s").unwrap();
+        assert_eq!(e.function, Function::Rate);
+        assert_eq!(e.series.stat, "/if/drops");
+        assert_eq!(
+            e.series.selector,
+            Some(Selector::Interface("eth0".to_string()))
+        );
+        assert_eq!(e.comparison, Comparison::Gt);
+        assert_eq!(e.for_secs, 30.0);
+
+        let e = AlertExpr::parse("/err/ip4-input/ip4 ttl <= 1 >= 5").unwrap();
+        assert_eq!(e.series.stat, "/err/ip4-input/ip4 ttl <= 1");
+        assert_eq!(e.comparison, Comparison::Ge);
+
+        let e = AlertExpr::parse("value(/if/rx[1].bytes) < 2e3 for 2m").unwrap();
+        assert_eq!(e.series.selector, Some(Selector::Index(1)));
+        assert_eq!(e.series.field, Some(CounterField::Bytes));
+        assert_eq!(e.for_secs, 120.0);
+
+        assert!(AlertExpr::parse("rate(/if/drops)").is_err());
+    }
+
+    #[test]
+    fn resets_give_no_rate() {
+        let eth0 = AlertExpr::parse("rate(/if/drops[eth0]) > 100").unwrap();
+        let all = AlertExpr::parse("rate(/if/drops) > 100").unwrap();
+        let (prev, cur) = (snap(10.0, 1000), snap(12.0, 200));
+        assert_eq!(eth0.value(Some(&prev), &cur), None);
+        /* The drops of local0 did not change */
+        assert_eq!(all.value(Some(&prev), &cur), Some(0.0));
+        assert_eq!(eth0.value(Some(&cur), &snap(14.0, 400)), Some(100.0));
+    }
+
+    #[test]
+    fn pending_firing_resolved() {
+        let config = AlertConfig::from_yaml(
+            "rules:\n  - name: drops\n    expr: rate(/if/drops[eth0]) > 100 for 10s\n",
+        )
+        .unwrap();
+        let got = Rc::new(RefCell::new(vec![]));
+        let mut engine = RuleEngine::new(&config)
+            .unwrap()
+            .with_instance("vpp1")
+            .with_action(Box::new(Recorder(got.clone())));
+        assert_eq!(
+            engine.patterns(),
+            vec!["^/if/drops$".to_string(), "^/if/names$".to_string()]
+        );
+
+        let mut drops = 0;
+        let mut states = vec![];
+        for (t, rate) in [(0, 0), (5, 200), (10, 200), (15, 200), (20, 0), (25, 0)] {
+            drops += rate * 5;
+            engine.evaluate(snap(t as f64, drops));
+            states.push(engine.status()[0].state);
+        }
+        use AlertState::*;
+        assert_eq!(
+            states,
+            vec![Inactive, Pending, Pending, Firing, Resolved, Resolved]
+        );
+        assert_eq!(
+            *got.borrow(),
+            vec![
+                "vpp1 ALERT FIRING drops: rate(/if/drops[eth0]) > 100 for 10s (value 200)",
+                "vpp1 ALERT RESOLVED drops: rate(/if/drops[eth0]) > 100 for 10s (value 0)",
+            ]
+        );
+    }
+
+    #[test]
+    fn anomaly_rules() {
+        let config = AlertConfig::from_yaml(
+            "anomalies:\n  - name: drops\n    patterns: [\"^/if/drops$\"]\n    detector: { warmup: 3 }\n",
+        )
+        .unwrap();
+        let mut engine = RuleEngine::new(&config).unwrap();
+        let mut drops = 0;
+        let mut fired = vec![];
+        for (t, rate) in [(0, 0), (1, 0), (2, 0), (3, 0), (4, 0), (5, 1000), (6, 0)] {
+            drops += rate;
+            for e in engine.evaluate(snap(t as f64, drops)) {
+                fired.push((t, e.series.unwrap(), e.state));
+            }
+        }
+        let series = "/if/drops[1] (eth0)".to_string();
+        assert_eq!(
+            fired,
+            vec![
+                (5, series.clone(), AlertState::Firing),
+                (6, series, AlertState::Resolved)
+            ]
+        );
+        assert_eq!(engine.status().len(), 1);
+        engine.evaluate(snap(6.0 + RESOLVED_KEPT, drops));
+        assert!(engine.status().is_empty());
+    }
+}
@@ -0,0 +1,270 @@
+/*
+ * Anomaly detection on the counter rates, for the traffic which has no
+ * sensible static threshold. Every series keeps the exponentially weighted
+ * mean and variance of its rate:
+ *
+ *   mean += alpha * (rate - mean)
+ *   var = (1 - alpha) * (var + alpha * (rate - mean_before)^2)
+ *
+ * and a rate more than k standard deviations away from the mean is flagged,
+ * once the series has seen the warmup number of rates. The deviation is
+ * taken as at least min_stddev, so a series which was flat does not flag
+ * on the first small change.
+ *
+ * The series which were reset in the interval give no rate, and are
+ * left as they were.
+ */
+
+use crate::delta::{deltas, SeriesKey, SeriesValue};
+use crate::snapshot::{SnapshotValue, StatSnapshot};
+use crate::source::PatternFilter;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AnomalyConfig {
+    /// Weight of the newest rate, between 0 and 1
+    pub alpha: f64,
+    /// How many standard deviations away is anomalous
+    pub k: f64,
+    /// Rates to see before flagging anything
+    pub warmup: u64,
+    /// The smallest standard deviation assumed, in the units per second
+    pub min_stddev: f64,
+}
+
+impl Default for AnomalyConfig {
+    fn default() -> Self {
+        AnomalyConfig {
+            alpha: 0.1,
+            k: 4.0,
+            warmup: 30,
+            min_stddev: 1.0,
+        }
+    }
+}
+
+/// The exponentially weighted mean and variance of one series
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Ewma {
+    pub mean: f64,
+    pub var: f64,
+    pub count: u64,
+}
+
+impl Ewma {
+    pub fn update(&mut self, x: f64, alpha: f64) {
+        if self.count == 0 {
+            self.mean = x;
+            self.var = 0.0;
+        } else {
+            let diff = x - self.mean;
+            let incr = alpha * diff;
+            self.mean += incr;
+            self.var = (1.0 - alpha) * (self.var + diff * incr);
+        }
+        self.count += 1;
+    }
+
+    pub fn stddev(&self) -> f64 {
+        self.var.sqrt()
+    }
+}
+
+/// What the anomalous series is about
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Subject {
+    Interface { name: String },
+    Node { name: String },
+    Error { node: String, reason: String },
+    Stat,
+}
+
+impl Subject {
+    fn of(key: &SeriesKey, snap: &StatSnapshot) -> Self {
+        if let Some(name) = key.interface_name(snap) {
+            return Subject::Interface {
+                name: name.to_string(),
+            };
+        }
+        if let Some(rest) = key.name.strip_prefix("/err/") {
+            if let Some((node, reason)) = rest.split_once('/') {
+                return Subject::Error {
+                    node: node.to_string(),
+                    reason: reason.to_string(),
+                };
+            }
+        }
+        if key.name.starts_with("/sys/node/") {
+            let name = match (snap.get("/sys/node/names"), key.index) {
+                (Some(SnapshotValue::Names(names)), Some(i)) => names.get(i).cloned().flatten(),
+                _ => None,
+            };
+            if let Some(name) = name {
+                return Subject::Node { name };
+            }
+        }
+        Subject::Stat
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Anomaly {
+    pub key: SeriesKey,
+    pub subject: Subject,
+    pub rate: f64,
+    pub mean: f64,
+    pub stddev: f64,
+    /// How many standard deviations away, negative below the mean
+    pub sigmas: f64,
+}
+
+impl fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.key)?;
+        match &self.subject {
+            Subject::Interface { name } | Subject::Node { name } => write!(f, " ({})", name)?,
+            Subject::Error { .. } | Subject::Stat => {}
+        }
+        write!(
+            f,
+            " at {:.1}/s, usually {:.1} +- {:.1} ({:+.1} sigma)",
+            self.rate, self.mean, self.stddev, self.sigmas
+        )
+    }
+}
+
+/// The patterns to read along, to tell what the series are about
+pub const SUBJECT_PATTERNS: &[&str] = &["^/if/names$", "^/sys/node/names$"];
+
+pub struct AnomalyDetector {
+    config: AnomalyConfig,
+    filter: PatternFilter,
+    series: HashMap<SeriesKey, Ewma>,
+    prev: Option<StatSnapshot>,
+}
+
+impl AnomalyDetector {
+    pub fn new(config: AnomalyConfig) -> Self {
+        AnomalyDetector {
+            config,
+            filter: PatternFilter::default(),
+            series: HashMap::new(),
+            prev: None,
+        }
+    }
+
+    /// Only look at the stats matching any of the patterns, rather than everything
+    pub fn with_patterns(mut self, patterns: &[String]) -> Self {
+        self.filter = PatternFilter::new(patterns);
+        self
+    }
+
+    /// The baseline of the series, if it had any rates yet
+    pub fn baseline(&self, key: &SeriesKey) -> Option<&Ewma> {
+        self.series.get(key)
+    }
+
+    /// Take in the rates since the previous snapshot, and give the series
+    /// which were anomalous, the furthest away first
+    pub fn update(&mut self, snap: &StatSnapshot) -> Vec<Anomaly> {
+        let mut out = vec![];
+        if let Some(prev) = &self.prev {
+            for d in deltas(prev, snap, false) {
+                /* The rates of the gauges are not worth watching */
+                if !matches!(d.new, SeriesValue::Counter(_))
+                    || d.reset
+                    || !self.filter.matches(&d.key.name)
+                {
+                    continue;
+                }
+                let ewma = self.series.entry(d.key.clone()).or_default();
+                if ewma.count >= self.config.warmup {
+                    let stddev = ewma.stddev().max(self.config.min_stddev);
+                    let sigmas = (d.rate - ewma.mean) / stddev;
+                    if sigmas.abs() > self.config.k {
+                        out.push(Anomaly {
+                            subject: Subject::of(&d.key, snap),
+                            key: d.key.clone(),
+                            rate: d.rate,
+                            mean: ewma.mean,
+                            stddev: ewma.stddev(),
+                            sigmas,
+                        });
+                    }
+                }
+                ewma.update(d.rate, self.config.alpha);
+            }
+        }
+        self.prev = Some(snap.clone());
+        out.sort_by(|a, b| b.sigmas.abs().total_cmp(&a.sigmas.abs()));
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::SnapshotBuilder;
+
+    fn snap(timestamp: f64, rx: u64) -> StatSnapshot {
+        SnapshotBuilder::new(timestamp)
+            .with_interfaces(&["eth0"])
+            .with_simple("/if/rx-miss", vec![vec![rx]])
+            .with_simple("/err/ip4-input/ip4 ttl <= 1", vec![vec![0]])
+            .build()
+    }
+
+    #[test]
+    fn ewma_converges() {
+        let mut e = Ewma::default();
+        for x in [10.0, 10.0, 10.0, 10.0] {
+            e.update(x, 0.5);
+        }
+        assert_eq!(e.mean, 10.0);
+        assert_eq!(e.var, 0.0);
+        e.update(20.0, 0.5);
+        assert_eq!(e.mean, 15.0);
+        assert_eq!(e.var, 25.0);
+    }
+
+    #[test]
+    fn flags_the_spike() {
+        let mut detector = AnomalyDetector::new(AnomalyConfig {
+            warmup: 10,
+            ..Default::default()
+        })
+        .with_patterns(&["^/if/".to_string()]);
+        let mut rx = 0;
+        /* Around 100/s, give or take 10 */
+        for t in 0..20 {
+            rx += if t % 2 == 0 { 90 } else { 110 };
+            assert!(detector.update(&snap(t as f64, rx)).is_empty());
+        }
+        rx += 1000;
+        let anomalies = detector.update(&snap(20.0, rx));
+        assert_eq!(anomalies.len(), 1);
+        assert_eq!(
+            anomalies[0].subject,
+            Subject::Interface {
+                name: "eth0".to_string()
+            }
+        );
+        assert!(anomalies[0].sigmas > 4.0);
+        assert!(anomalies[0]
+            .to_string()
+            .starts_with("/if/rx-miss[0] (eth0) at 1000.0/s"));
+
+        let key = SeriesKey::scalar("/err/ip4-input/ip4 ttl <= 1");
+        assert_eq!(
+            Subject::of(&key, &snap(0.0, 0)),
+            Subject::Error {
+                node: "ip4-input".to_string(),
+                reason: "ip4 ttl <= 1".to_string()
+            }
+        );
+    }
+}
@@ -0,0 +1,546 @@
+use clap::{Parser as ClapParser, Subcommand};
+use std::process::exit;
+use std::time::Duration;
+use vpp_stat_client::check::{Check, CheckResult, Threshold};
+use vpp_stat_client::delta::*;
+use vpp_stat_client::diff::SnapshotDiff;
+use vpp_stat_client::discovery::{DiscoveryEvent, SocketDiscovery};
+use vpp_stat_client::prometheus::{encode_text, families};
+use vpp_stat_client::recording::Replay;
+use vpp_stat_client::snapshot::*;
+use vpp_stat_client::source::{exact_pattern, PatternFilter, SourceError, StatSource};
+use vpp_stat_client::*;
+
+/* Exit codes, on top of 0 for success and 2 that clap uses for the usage errors */
+const EXIT_FAILURE: i32 = 1;
+const EXIT_CONNECT: i32 = 3;
+const EXIT_DUMP: i32 = 4;
+const EXIT_FILE: i32 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ArgEnum)]
+enum ExportFormat {
+    Json,
+    Yaml,
+    Csv,
+    Prometheus,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ArgEnum)]
+enum DiffFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, clap::Args)]
+struct CounterCheck {
+    /// The stat, by its exact name
+    stat: String,
+    /// Only the counter of this interface, rather than the sum of all
+    #[clap(long)]
+    interface: Option<String>,
+    /// The bytes of the combined counters rather than the packets
+    #[clap(long)]
+    bytes: bool,
+    /// The warning range, e.g. "1000" to warn above 1000
+    #[clap(long)]
+    warning: Option<Threshold>,
+    /// The critical range
+    #[clap(long)]
+    critical: Option<Threshold>,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+enum CheckCommand {
+    /// The heartbeat goes up over the wait. VPP bumps it every 10 seconds,
+    /// so the wait is its own rather than --interval
+    Heartbeat {
+        /// Seconds between the two reads, at least two heartbeat updates
+        #[clap(long, default_value = "25")]
+        wait: u64,
+    },
+    /// The per-second rate of a counter over the interval
+    Rate(CounterCheck),
+    /// How much a counter goes up over the interval
+    Increase(CounterCheck),
+    /// The used percentage of the fullest buffer pool
+    Buffers {
+        /// Only this pool, e.g. "default-numa-0"
+        #[clap(long)]
+        pool: Option<String>,
+        #[clap(long)]
+        warning: Option<Threshold>,
+        #[clap(long)]
+        critical: Option<Threshold>,
+    },
+}
+
+impl CheckCommand {
+    fn to_check(&self) -> Check {
+        match self.clone() {
+            CheckCommand::Heartbeat { .. } => Check::Heartbeat,
+            CheckCommand::Rate(c) => Check::Rate {
+                stat: c.stat,
+                interface: c.interface,
+                bytes: c.bytes,
+                warn: c.warning,
+                crit: c.critical,
+            },
+            CheckCommand::Increase(c) => Check::Increase {
+                stat: c.stat,
+                interface: c.interface,
+                bytes: c.bytes,
+                warn: c.warning,
+                crit: c.critical,
+            },
+            CheckCommand::Buffers {
+                pool,
+                warning,
+                critical,
+            } => Check::BufferUsage {
+                pool,
+                warn: warning,
+                crit: critical,
+            },
+        }
+    }
+
+    /// How long to wait between the two reads
+    fn wait(&self, opts: &Opts) -> Duration {
+        match self {
+            CheckCommand::Heartbeat { wait } => Duration::from_secs(*wait),
+            _ => Duration::from_millis(opts.interval),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Subcommand)]
+enum Command {
+    /// List the names of the matching stats
+    Ls,
+    /// Print the values of the matching stats
+    Dump,
+    /// Print the value of a single stat, by its exact name
+    Get { name: String },
+    /// Periodically print the counters that have changed, with their rates
+    Watch,
+    /// Show which counters have changed between two snapshots. The snapshots are the files
+    /// saved with "export --format json" or "--format yaml", or read live if not given.
+    Diff {
+        /// The earlier snapshot; without it two live snapshots are taken an interval apart
+        old: Option<String>,
+        /// The later snapshot; without it the live stats are compared against the old one
+        new: Option<String>,
+        #[clap(short, long, arg_enum, default_value = "text")]
+        format: DiffFormat,
+    },
+    /// Save a copy of the whole stats segment, to be opened later with --segment
+    Save { file: String },
+    /// List the stats sockets matching a glob, with the heartbeat of each VPP behind them
+    Discover {
+        /// Glob of the sockets, e.g. "/run/vpp/*/stats.sock"
+        glob: String,
+        /// Keep watching, and print the sockets as they appear and disappear
+        #[clap(short, long)]
+        follow: bool,
+    },
+    /// Print the matching stats in a machine readable format
+    Export {
+        #[clap(short, long, arg_enum, default_value = "json")]
+        format: ExportFormat,
+    },
+    /// Run a check as a Nagios/Icinga plugin: print the status line with the perfdata,
+    /// and exit with 0 for OK, 1 for WARNING, 2 for CRITICAL or 3 for UNKNOWN
+    Check {
+        #[clap(subcommand)]
+        check: CheckCommand,
+    },
+}
+
+/// Query the VPP statistics segment
+#[derive(Debug, Clone, ClapParser)]
+#[clap(version = "0.0", author = "Andrew Yourtchenko <ayourtch@gmail.com>")]
+struct Opts {
+    /// VPP stats socket as supplied in the "statseg { socket-name /path/to/socket }" config
+    #[clap(short, long, global = true, default_value = "/tmp/stats.sock")]
+    socket: String,
+
+    /// Read the stats from a segment image saved with "save", instead of the live VPP
+    #[clap(long, global = true)]
+    segment: Option<String>,
+
+    /// Read the stats from a recording instead of the live VPP
+    #[clap(long, global = true)]
+    replay: Option<String>,
+
+    /// Replay speed: 1.0 is real time, 0 gives the next recorded frame on every read
+    #[clap(long, global = true, default_value = "0")]
+    replay_speed: f64,
+
+    /// Pattern to match, can be given multiple times
+    #[clap(short, long, global = true, default_value = ".*")]
+    pattern: Vec<String>,
+
+    /// Interval between the repeated reads, in milliseconds
+    #[clap(short, long, global = true, default_value = "1000")]
+    interval: u64,
+
+    /// How many times to read the stats; once by default, forever for watch
+    #[clap(short, long, global = true)]
+    count: Option<u64>,
+
+    /// Show the vector counters per thread rather than summed across the threads
+    #[clap(short = 't', long, global = true)]
+    per_thread: bool,
+
+    /// A level of verbosity, and can be used multiple times
+    #[clap(short, long, global = true, parse(from_occurrences))]
+    verbose: i32,
+
+    #[clap(subcommand)]
+    command: Command,
+}
+
+/* The exit code and the message for the failed opens */
+type OpenError = (i32, String);
+
+fn try_connect(opts: &Opts) -> Result<VppStatClient, OpenError> {
+    if let Some(path) = &opts.segment {
+        VppStatClient::open_segment(path).map_err(|e| {
+            (
+                EXIT_FILE,
+                format!("Could not open the segment image {}: {:?}", path, e),
+            )
+        })
+    } else {
+        VppStatClient::connect(&opts.socket).map_err(|e| {
+            (
+                EXIT_CONNECT,
+                format!("Could not connect to {}: {:?}", &opts.socket, e),
+            )
+        })
+    }
+}
+
+fn try_open_source(opts: &Opts) -> Result<Box<dyn StatSource>, OpenError> {
+    if let Some(path) = &opts.replay {
+        match Replay::open(path) {
+            Ok(r) => Ok(Box::new(r.with_speed(opts.replay_speed))),
+            Err(e) => Err((
+                EXIT_FILE,
+                format!("Could not open the recording {}: {:?}", path, e),
+            )),
+        }
+    } else {
+        Ok(Box::new(try_connect(opts)?))
+    }
+}
+
+fn exit_on_error<T>(res: Result<T, OpenError>) -> T {
+    res.unwrap_or_else(|(code, message)| {
+        eprintln!("{}", message);
+        exit(code)
+    })
+}
+
+fn connect(opts: &Opts) -> VppStatClient {
+    exit_on_error(try_connect(opts))
+}
+
+fn open_source(opts: &Opts) -> Box<dyn StatSource> {
+    exit_on_error(try_open_source(opts))
+}
+
+fn fetch(source: &mut dyn StatSource, patterns: &[String]) -> StatSnapshot {
+    match source.fetch(patterns) {
+        Ok(snap) => snap,
+        Err(SourceError::EndOfRecording) => exit(0),
+        Err(e) => {
+            eprintln!("Could not dump the stats: {:?}", e);
+            exit(EXIT_DUMP);
+        }
+    }
+}
+
+fn load_snapshot(path: &str) -> StatSnapshot {
+    let data = match std::fs::read_to_string(path) {
+        Ok(d) => d,
+        Err(e) => {
+            eprintln!("Could not read {}: {}", path, e);
+            exit(EXIT_FILE);
+        }
+    };
+    /* YAML would take the JSON as well, but the JSON errors are more helpful */
+    let res = if data.trim_start().starts_with('{') {
+        serde_json::from_str(&data).map_err(|e| e.to_string())
+    } else {
+        serde_yaml::from_str(&data).map_err(|e| e.to_string())
+    };
+    match res {
+        Ok(snap) => snap,
+        Err(e) => {
+            eprintln!("Could not parse the snapshot in {}: {}", path, e);
+            exit(EXIT_FILE);
+        }
+    }
+}
+
+fn series_label(key: &SeriesKey, snap: &StatSnapshot) -> String {
+    match key.interface_name(snap) {
+        Some(ifname) => format!("{} ({})", key, ifname),
+        None => key.to_string(),
+    }
+}
+
+fn print_snapshot(snap: &StatSnapshot, per_thread: bool) {
+    let flat = flatten(snap, per_thread);
+    for entry in &snap.entries {
+        if let SnapshotValue::Names(names) = &entry.value {
+            for (i, name) in names.iter().enumerate() {
+                if let Some(name) = name {
+                    println!("{}[{}]: {}", entry.name, i, name);
+                }
+            }
+            continue;
+        }
+        /* The keys of one entry are adjacent, and the scalar key sorts first */
+        let first = SeriesKey::scalar(&entry.name);
+        for (key, value) in flat
+            .range(first..)
+            .take_while(|(k, _)| k.name == entry.name)
+        {
+            println!("{}: {}", series_label(key, snap), value);
+        }
+    }
+}
+
+fn print_changes(snap: &StatSnapshot, changes: &[SeriesDelta]) {
+    for d in changes {
+        if d.reset {
+            println!("{}: {} (reset)", series_label(&d.key, snap), d.new);
+        } else {
+            println!(
+                "{}: {} ({:+}, {:.1}/s)",
+                series_label(&d.key, snap),
+                d.new,
+                d.delta,
+                d.rate
+            );
+        }
+    }
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains(',') || s.contains('"') || s.contains('\n') {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+fn print_csv(snap: &StatSnapshot, per_thread: bool) {
+    let opt = |v: Option<usize>| v.map(|x| x.to_string()).unwrap_or_default();
+    for (key, value) in flatten(snap, per_thread) {
+        let field = match key.field {
+            CounterField::Value => "value",
+            CounterField::Packets => "packets",
+            CounterField::Bytes => "bytes",
+        };
+        println!(
+            "{},{},{},{},{},{},{}",
+            snap.timestamp,
+            csv_field(&key.name),
+            opt(key.thread),
+            opt(key.index),
+            csv_field(key.interface_name(snap).unwrap_or("")),
+            field,
+            value
+        );
+    }
+}
+
+fn export(snap: &StatSnapshot, format: ExportFormat, opts: &Opts, first: bool) {
+    match format {
+        /* A single snapshot is pretty-printed, the repeated ones go one per line */
+        ExportFormat::Json if opts.count.unwrap_or(1) == 1 => {
+            println!("{}", serde_json::to_string_pretty(snap).unwrap())
+        }
+        ExportFormat::Json => println!("{}", serde_json::to_string(snap).unwrap()),
+        ExportFormat::Yaml => print!("{}", serde_yaml::to_string(snap).unwrap()),
+        ExportFormat::Csv => {
+            if first {
+                println!("timestamp,name,thread,index,interface,field,value");
+            }
+            print_csv(snap, opts.per_thread);
+        }
+        ExportFormat::Prometheus => print!("{}", encode_text(&families(snap))),
+    }
+}
+
+/// Call f() for each of the "count" snapshots taken "interval" apart
+fn repeat(opts: &Opts, default_count: Option<u64>, mut f: impl FnMut(&mut dyn StatSource, u64)) {
+    let mut source = open_source(opts);
+    let count = opts.count.or(default_count);
+    let mut n = 0;
+    while count.map(|c| n < c).unwrap_or(true) {
+        if n > 0 {
+            std::thread::sleep(Duration::from_millis(opts.interval));
+        }
+        f(source.as_mut(), n);
+        n += 1;
+    }
+}
+
+/* Everything that goes wrong is UNKNOWN, the plugins do not use the other exit codes */
+fn check_result(opts: &Opts, check: &Check, wait: Duration) -> CheckResult {
+    let mut source = match try_open_source(opts) {
+        Ok(source) => source,
+        Err((_, message)) => return CheckResult::unknown(&message),
+    };
+    let patterns = check.patterns();
+    let mut read = || match source.fetch(&patterns) {
+        Ok(snap) => Ok(snap),
+        Err(e) => Err(CheckResult::unknown(&format!(
+            "Could not dump the stats: {:?}",
+            e
+        ))),
+    };
+    let prev = match read() {
+        Ok(snap) => snap,
+        Err(res) => return res,
+    };
+    if !check.needs_interval() {
+        return check.evaluate(&prev, &prev);
+    }
+    std::thread::sleep(wait);
+    match read() {
+        Ok(cur) => check.evaluate(&prev, &cur),
+        Err(res) => res,
+    }
+}
+
+fn socket_status(socket: &str) -> String {
+    match VppStatClient::connect(socket) {
+        Ok(c) => format!("heartbeat {}", c.heartbeat()),
+        Err(e) => format!("down: {:?}", e),
+    }
+}
+
+fn discover(glob: &str, follow: bool) {
+    let mut discovery = match SocketDiscovery::new(glob) {
+        Ok(d) => d,
+        Err(e) => {
+            eprintln!("Could not watch {}: {:?}", glob, e);
+            exit(EXIT_FILE);
+        }
+    };
+    let mut first = true;
+    loop {
+        let events = match discovery.poll(Duration::from_secs(1)) {
+            Ok(events) => events,
+            Err(e) => {
+                eprintln!("Socket discovery failed: {:?}", e);
+                exit(EXIT_FAILURE);
+            }
+        };
+        if first {
+            for socket in discovery.sockets() {
+                println!("{} {}", socket, socket_status(&socket));
+            }
+            if !follow {
+                return;
+            }
+            first = false;
+            continue;
+        }
+        for event in events {
+            match event {
+                DiscoveryEvent::Added(s) => println!("+ {} {}", s, socket_status(&s)),
+                DiscoveryEvent::Removed(s) => println!("- {}", s),
+            }
+        }
+    }
+}
+
+fn main() {
+    let opts: Opts = Opts::parse();
+    if opts.verbose > 0 {
+        eprintln!("Patterns: {:?}", &opts.pattern);
+    }
+
+    match &opts.command {
+        Command::Ls => repeat(&opts, Some(1), |source, _| {
+            match source.names(&opts.pattern) {
+                Ok(names) => names.iter().for_each(|n| println!("{}", n)),
+                Err(SourceError::EndOfRecording) => exit(0),
+                Err(e) => {
+                    eprintln!("Could not list the stats: {:?}", e);
+                    exit(EXIT_DUMP);
+                }
+            }
+        }),
+        Command::Dump => repeat(&opts, Some(1), |source, _| {
+            print_snapshot(&fetch(source, &opts.pattern), opts.per_thread);
+        }),
+        Command::Get { name } => {
+            let patterns = vec![exact_pattern(name)];
+            repeat(&opts, Some(1), |source, _| {
+                let snap = fetch(source, &patterns);
+                if snap.get(name).is_none() {
+                    eprintln!("No such stat: {}", name);
+                    exit(EXIT_FAILURE);
+                }
+                print_snapshot(&snap, opts.per_thread);
+            })
+        }
+        Command::Watch => {
+            let mut prev: Option<StatSnapshot> = None;
+            repeat(&opts, None, |source, _| {
+                let snap = fetch(source, &opts.pattern);
+                if let Some(prev) = &prev {
+                    println!("--- {:.3}", snap.timestamp);
+                    print_changes(&snap, &changed(prev, &snap, opts.per_thread));
+                }
+                prev = Some(snap);
+            })
+        }
+        Command::Diff { old, new, format } => {
+            let filter = PatternFilter::new(&opts.pattern);
+            let load = |path| filter.apply(&load_snapshot(path));
+            let (old, new) = match (old, new) {
+                (Some(old), Some(new)) => (load(old), load(new)),
+                (Some(old), None) => {
+                    let mut source = open_source(&opts);
+                    (load(old), fetch(source.as_mut(), &opts.pattern))
+                }
+                (None, _) => {
+                    let mut source = open_source(&opts);
+                    let old = fetch(source.as_mut(), &opts.pattern);
+                    std::thread::sleep(Duration::from_millis(opts.interval));
+                    (old, fetch(source.as_mut(), &opts.pattern))
+                }
+            };
+            let diff = SnapshotDiff::compare(&old, &new, opts.per_thread);
+            match format {
+                DiffFormat::Text => print!("{}", diff.to_text()),
+                DiffFormat::Json => println!("{}", serde_json::to_string_pretty(&diff).unwrap()),
+            }
+        }
+        Command::Save { file } => {
+            if let Err(e) = connect(&opts).save_segment(file) {
+                eprintln!("Could not save the segment into {}: {}", file, e);
+                exit(EXIT_FILE);
+            }
+        }
+        Command::Discover { glob, follow } => discover(glob, *follow),
+        Command::Export { format } => repeat(&opts, Some(1), |source, n| {
+            export(&fetch(source, &opts.pattern), *format, &opts, n == 0);
+        }),
+        Command::Check { check } => {
+            let wait = check.wait(&opts);
+            let check = check.to_check();
+            let res = check_result(&opts, &check, wait);
+            println!("{}", res.output(check.service()));
+            exit(res.state.exit_code());
+        }
+    }
+}
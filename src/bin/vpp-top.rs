@@ -0,0 +1,360 @@
+use clap::Parser as ClapParser;
+use regex::Regex;
+use std::io::{Read, Write};
+use std::process::exit;
+use std::time::{Duration, Instant};
+use vpp_stat_client::history::History;
+use vpp_stat_client::recording::Replay;
+use vpp_stat_client::snapshot::StatSnapshot;
+use vpp_stat_client::source::{SourceError, StatSource};
+use vpp_stat_client::top::*;
+use vpp_stat_client::*;
+
+/* Same exit codes as vpp-stats */
+const EXIT_CONNECT: i32 = 3;
+const EXIT_FILE: i32 = 5;
+
+/// Live top-style view of the interface, graph node and error counters
+#[derive(Debug, Clone, ClapParser)]
+#[clap(version = "0.0", author = "Andrew Yourtchenko <ayourtch@gmail.com>")]
+struct Opts {
+    /// VPP stats socket as supplied in the "statseg { socket-name /path/to/socket }" config
+    #[clap(short, long, default_value = "/tmp/stats.sock")]
+    socket: String,
+
+    /// Read the stats from a recording instead of the live VPP
+    #[clap(long)]
+    replay: Option<String>,
+
+    /// Replay speed: 1.0 is real time, 0 gives the next recorded frame on every refresh
+    #[clap(long, default_value = "1")]
+    replay_speed: f64,
+
+    /// Refresh interval, in milliseconds
+    #[clap(short, long, default_value = "1000")]
+    interval: u64,
+
+    /// How many minutes back the peaks tab looks
+    #[clap(long, default_value = "5")]
+    history_minutes: u64,
+
+    /// Seconds between the samples kept for the peaks tab
+    #[clap(long, default_value = "1")]
+    history_resolution: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Tab {
+    Interfaces,
+    Nodes,
+    Errors,
+    Peaks,
+}
+
+const TABS: [Tab; 4] = [Tab::Interfaces, Tab::Nodes, Tab::Errors, Tab::Peaks];
+
+impl Tab {
+    fn title(&self) -> &'static str {
+        match self {
+            Tab::Interfaces => "1:Interfaces",
+            Tab::Nodes => "2:Nodes",
+            Tab::Errors => "3:Errors",
+            Tab::Peaks => "4:Peaks",
+        }
+    }
+
+    fn table(&self, prev: &StatSnapshot, cur: &StatSnapshot, history: &History) -> Table {
+        match self {
+            Tab::Interfaces => interfaces_table(prev, cur),
+            Tab::Nodes => nodes_table(prev, cur),
+            Tab::Errors => errors_table(prev, cur),
+            Tab::Peaks => {
+                let start = cur.timestamp - history.retention();
+                peaks_table(history, cur, start, cur.timestamp)
+            }
+        }
+    }
+}
+
+/* Puts the terminal into the raw mode, and restores it when dropped */
+struct RawTerminal {
+    saved: libc::termios,
+}
+
+impl RawTerminal {
+    fn new() -> std::io::Result<Self> {
+        let mut saved: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut saved) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let mut raw = saved;
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG);
+        /* Reads return after 100ms even with no key pressed */
+        raw.c_cc[libc::VMIN] = 0;
+        raw.c_cc[libc::VTIME] = 1;
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        /* Alternate screen, hidden cursor */
+        print!("\x1b[?1049h\x1b[?25l");
+        Ok(RawTerminal { saved })
+    }
+
+    fn size(&self) -> (usize, usize) {
+        let mut ws: libc::winsize = unsafe { std::mem::zeroed() };
+        let rv = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut ws) };
+        if rv != 0 || ws.ws_row == 0 {
+            (80, 24)
+        } else {
+            (ws.ws_col as usize, ws.ws_row as usize)
+        }
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        print!("\x1b[?25h\x1b[?1049l");
+        let _ = std::io::stdout().flush();
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.saved) };
+    }
+}
+
+struct TopState {
+    tab: usize,
+    sort_column: usize,
+    descending: bool,
+    filter: Option<Regex>,
+    /* The filter being typed in, if any */
+    editing: Option<String>,
+    paused: bool,
+    message: String,
+}
+
+impl TopState {
+    fn new() -> Self {
+        TopState {
+            tab: 0,
+            sort_column: 1,
+            descending: true,
+            filter: None,
+            editing: None,
+            paused: false,
+            message: String::new(),
+        }
+    }
+
+    /// Returns false when it is time to quit
+    fn key(&mut self, k: u8, columns: usize) -> bool {
+        if let Some(text) = &mut self.editing {
+            match k {
+                b'\r' | b'\n' => {
+                    let text = self.editing.take().unwrap();
+                    if text.is_empty() {
+                        self.filter = None;
+                    } else {
+                        match Regex::new(&text) {
+                            Ok(re) => self.filter = Some(re),
+                            Err(e) => self.message = format!("Bad filter: {}", e),
+                        }
+                    }
+                }
+                0x1b => self.editing = None,
+                0x7f | 0x08 => {
+                    text.pop();
+                }
+                k if (0x20..0x7f).contains(&k) => text.push(k as char),
+                _ => {}
+            }
+            return true;
+        }
+        self.message.clear();
+        match k {
+            b'q' | 0x03 => return false,
+            b'\t' => {
+                self.tab = (self.tab + 1) % TABS.len();
+                self.sort_column = 1;
+            }
+            b'1'..=b'4' => {
+                self.tab = (k - b'1') as usize;
+                self.sort_column = 1;
+            }
+            b's' | b'>' => self.sort_column = (self.sort_column + 1) % columns.max(1),
+            b'<' => self.sort_column = (self.sort_column + columns.max(1) - 1) % columns.max(1),
+            b'r' => self.descending = !self.descending,
+            b'/' => self.editing = Some(String::new()),
+            b'p' | b' ' => self.paused = !self.paused,
+            _ => {}
+        }
+        true
+    }
+}
+
+fn render(term: &RawTerminal, state: &TopState, table: Option<&Table>, cur: Option<&StatSnapshot>) {
+    let (width, height) = term.size();
+    let mut out = String::from("\x1b[H\x1b[2J");
+
+    let tabs: Vec<String> = TABS
+        .iter()
+        .enumerate()
+        .map(|(i, t)| {
+            if i == state.tab {
+                format!("\x1b[7m {} \x1b[0m", t.title())
+            } else {
+                format!(" {} ", t.title())
+            }
+        })
+        .collect();
+    out.push_str(&tabs.join(""));
+    if state.paused {
+        out.push_str("  [paused]");
+    }
+    if let Some(snap) = cur {
+        out.push_str(&format!("  heartbeat {}", snap.heartbeat));
+    }
+    out.push_str("\r\n");
+
+    let status = if let Some(text) = &state.editing {
+        format!("Filter: {}_", text)
+    } else if !state.message.is_empty() {
+        state.message.clone()
+    } else {
+        format!(
+            "filter: {}   q:quit tab/1-4:view s/</>:sort column r:reverse /:filter p:pause",
+            state.filter.as_ref().map(|r| r.as_str()).unwrap_or("none")
+        )
+    };
+    out.push_str(&status);
+    out.push_str("\r\n\r\n");
+
+    match table {
+        None => out.push_str("Collecting the data..."),
+        Some(table) => {
+            let label_width = table
+                .rows
+                .iter()
+                .map(|r| r.label.len())
+                .max()
+                .unwrap_or(0)
+                .max(table.columns[0].len())
+                .min(width / 2);
+            for (i, c) in table.columns.iter().enumerate() {
+                let c = if i == state.sort_column {
+                    format!("{}{}", c, if state.descending { "▼" } else { "▲" })
+                } else {
+                    c.to_string()
+                };
+                if i == 0 {
+                    out.push_str(&format!("\x1b[1m{:<w$}\x1b[0m", c, w = label_width));
+                } else {
+                    out.push_str(&format!("\x1b[1m{:>14}\x1b[0m", c));
+                }
+            }
+            out.push_str("\r\n");
+            for row in table.rows.iter().take(height.saturating_sub(5)) {
+                let label: String = row.label.chars().take(label_width).collect();
+                out.push_str(&format!("{:<w$}", label, w = label_width));
+                for v in &row.values {
+                    out.push_str(&format!("{:>14}", human(*v)));
+                }
+                out.push_str("\r\n");
+            }
+        }
+    }
+    print!("{}", out);
+    let _ = std::io::stdout().flush();
+}
+
+fn main() {
+    let opts: Opts = Opts::parse();
+    let patterns: Vec<String> = TOP_PATTERNS.iter().map(|s| s.to_string()).collect();
+    let peak_patterns: Vec<String> = PEAK_PATTERNS.iter().map(|s| s.to_string()).collect();
+    let mut history = History::new(
+        Duration::from_secs(opts.history_resolution),
+        Duration::from_secs(opts.history_minutes * 60),
+    )
+    .with_patterns(&peak_patterns);
+
+    let mut source: Box<dyn StatSource> = if let Some(path) = &opts.replay {
+        match Replay::open(path) {
+            Ok(r) => Box::new(r.with_speed(opts.replay_speed)),
+            Err(e) => {
+                eprintln!("Could not open the recording {}: {:?}", path, e);
+                exit(EXIT_FILE);
+            }
+        }
+    } else {
+        match VppStatClient::connect(&opts.socket) {
+            Ok(c) => Box::new(c),
+            Err(e) => {
+                eprintln!("Could not connect to {}: {:?}", &opts.socket, e);
+                exit(EXIT_CONNECT);
+            }
+        }
+    };
+
+    let term = match RawTerminal::new() {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("Could not set up the terminal: {}", e);
+            exit(1);
+        }
+    };
+
+    let interval = Duration::from_millis(opts.interval);
+    let mut state = TopState::new();
+    let mut prev: Option<StatSnapshot> = None;
+    let mut cur: Option<StatSnapshot> = None;
+    let mut last_fetch: Option<Instant> = None;
+    let mut stdin = std::io::stdin();
+    let mut redraw = true;
+    /* Of the table last shown, for the sort keys */
+    let mut columns = 1;
+
+    loop {
+        let due = last_fetch.map(|t| t.elapsed() >= interval).unwrap_or(true);
+        if due && !state.paused {
+            redraw = true;
+            match source.fetch(&patterns) {
+                Ok(snap) => {
+                    history.record(&snap);
+                    prev = cur.take();
+                    cur = Some(snap);
+                }
+                Err(SourceError::EndOfRecording) => {
+                    state.message = "End of the recording".to_string();
+                    state.paused = true;
+                }
+                Err(e) => state.message = format!("Could not dump the stats: {:?}", e),
+            }
+            last_fetch = Some(Instant::now());
+        }
+
+        /* The tables are only built when there is something new to show */
+        if redraw {
+            let tab = TABS[state.tab];
+            let table = match (&prev, &cur) {
+                (Some(prev), Some(cur)) => {
+                    let mut table = tab.table(prev, cur, &history);
+                    if let Some(re) = &state.filter {
+                        table.filter(re);
+                    }
+                    table.sort_by(state.sort_column, state.descending);
+                    Some(table)
+                }
+                _ => None,
+            };
+            columns = table.as_ref().map(|t| t.columns.len()).unwrap_or(1);
+            render(&term, &state, table.as_ref(), cur.as_ref());
+            redraw = false;
+        }
+
+        let mut buf = [0u8; 16];
+        let n = stdin.read(&mut buf).unwrap_or(0);
+        for &k in &buf[..n] {
+            if !state.key(k, columns) {
+                return;
+            }
+            redraw = true;
+        }
+    }
+}
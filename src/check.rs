@@ -0,0 +1,517 @@
+/*
+ * The checks for Nagios/Icinga and the other monitoring plugin hosts.
+ *
+ * A check looks at two snapshots taken some time apart (or just the later
+ * one), and gives the state with a line of text and the perfdata:
+ *
+ *   VPP RATE WARNING - /if/drops eth0 at 120.0/s | 'eth0'=120;100;1000;0;
+ *
+ * The thresholds use the ranges of the monitoring plugin guidelines:
+ * "10" alerts outside of 0..10, "10:" below 10, "~:10" above 10,
+ * "10:20" outside of 10..20 and "@10:20" inside of it.
+ */
+
+use crate::delta::{deltas, CounterField};
+use crate::snapshot::{SnapshotValue, StatSnapshot};
+use crate::source::exact_pattern;
+use std::fmt;
+use std::str::FromStr;
+
+/// The states of the plugin, in the order of their exit codes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CheckState {
+    Ok,
+    Warning,
+    Critical,
+    Unknown,
+}
+
+impl CheckState {
+    pub fn exit_code(&self) -> i32 {
+        *self as i32
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CheckState::Ok => "OK",
+            CheckState::Warning => "WARNING",
+            CheckState::Critical => "CRITICAL",
+            CheckState::Unknown => "UNKNOWN",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Threshold {
+    pub start: f64,
+    pub end: f64,
+    /// Alert inside of the range rather than outside
+    pub inside: bool,
+    text: String,
+}
+
+impl FromStr for Threshold {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (inside, range) = match s.strip_prefix('@') {
+            Some(rest) => (true, rest),
+            None => (false, s),
+        };
+        let num = |v: &str, empty: f64| -> Result<f64, String> {
+            match v {
+                "" => Ok(empty),
+                "~" => Ok(f64::NEG_INFINITY),
+                _ => v
+                    .parse::<f64>()
+                    .map_err(|_| format!("bad threshold \"{}\"", s)),
+            }
+        };
+        let (start, end) = match range.split_once(':') {
+            Some((start, end)) => (num(start, 0.0)?, num(end, f64::INFINITY)?),
+            None => (0.0, num(range, 0.0)?),
+        };
+        if start > end {
+            return Err(format!(
+                "bad threshold \"{}\", the start is above the end",
+                s
+            ));
+        }
+        Ok(Threshold {
+            start,
+            end,
+            inside,
+            /* Kept for the perfdata, which wants them the way they were given */
+            text: s.to_string(),
+        })
+    }
+}
+
+impl Threshold {
+    pub fn alerts(&self, value: f64) -> bool {
+        let within = value >= self.start && value <= self.end;
+        within == self.inside
+    }
+}
+
+impl fmt::Display for Threshold {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+/// One 'label'=value[uom];warn;crit;min;max item
+#[derive(Debug, Clone, PartialEq)]
+pub struct Perfdata {
+    pub label: String,
+    pub value: f64,
+    pub uom: &'static str,
+    pub warn: Option<Threshold>,
+    pub crit: Option<Threshold>,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+}
+
+impl fmt::Display for Perfdata {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let opt = |v: Option<String>| v.unwrap_or_default();
+        write!(
+            f,
+            "'{}'={}{};{};{};{};{}",
+            self.label.replace('\'', "''"),
+            self.value,
+            self.uom,
+            opt(self.warn.as_ref().map(|t| t.to_string())),
+            opt(self.crit.as_ref().map(|t| t.to_string())),
+            opt(self.min.map(|v| v.to_string())),
+            opt(self.max.map(|v| v.to_string())),
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CheckResult {
+    pub state: CheckState,
+    pub message: String,
+    pub perfdata: Vec<Perfdata>,
+}
+
+impl CheckResult {
+    pub fn unknown(message: &str) -> Self {
+        CheckResult {
+            state: CheckState::Unknown,
+            message: message.to_string(),
+            perfdata: vec![],
+        }
+    }
+
+    /// The line to print, with the service name in front, e.g. "VPP RATE"
+    pub fn output(&self, service: &str) -> String {
+        let mut out = format!("{} {} - {}", service, self.state.as_str(), self.message);
+        if !self.perfdata.is_empty() {
+            out.push_str(" |");
+            for p in &self.perfdata {
+                out.push_str(&format!(" {}", p));
+            }
+        }
+        out
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Check {
+    /// The heartbeat went up between the snapshots, so VPP is alive
+    Heartbeat,
+    /// The per-second rate of a counter, summed over the indices unless
+    /// the interface is given. The packets of the combined counters,
+    /// or the bytes.
+    Rate {
+        stat: String,
+        interface: Option<String>,
+        bytes: bool,
+        warn: Option<Threshold>,
+        crit: Option<Threshold>,
+    },
+    /// Same as the rate, but how much the counter went up
+    Increase {
+        stat: String,
+        interface: Option<String>,
+        bytes: bool,
+        warn: Option<Threshold>,
+        crit: Option<Threshold>,
+    },
+    /// The used percentage of the buffer pools, the fullest one unless the pool is given
+    BufferUsage {
+        pool: Option<String>,
+        warn: Option<Threshold>,
+        crit: Option<Threshold>,
+    },
+}
+
+const BUFFER_POOLS: &str = "/buffer-pools/";
+
+fn state_for(value: f64, warn: &Option<Threshold>, crit: &Option<Threshold>) -> CheckState {
+    if crit.as_ref().map(|t| t.alerts(value)).unwrap_or(false) {
+        CheckState::Critical
+    } else if warn.as_ref().map(|t| t.alerts(value)).unwrap_or(false) {
+        CheckState::Warning
+    } else {
+        CheckState::Ok
+    }
+}
+
+impl Check {
+    /// The service name for the output line
+    pub fn service(&self) -> &'static str {
+        match self {
+            Check::Heartbeat => "VPP HEARTBEAT",
+            Check::Rate { .. } => "VPP RATE",
+            Check::Increase { .. } => "VPP INCREASE",
+            Check::BufferUsage { .. } => "VPP BUFFERS",
+        }
+    }
+
+    /// The stats the check needs to read
+    pub fn patterns(&self) -> Vec<String> {
+        match self {
+            Check::Heartbeat => vec!["^/sys/heartbeat$".to_string()],
+            Check::Rate { stat, .. } | Check::Increase { stat, .. } => {
+                vec![exact_pattern(stat), "^/if/names$".to_string()]
+            }
+            Check::BufferUsage { .. } => vec![format!("^{}", BUFFER_POOLS)],
+        }
+    }
+
+    /// Whether the check looks at the changes, and so needs the earlier snapshot
+    pub fn needs_interval(&self) -> bool {
+        !matches!(self, Check::BufferUsage { .. })
+    }
+
+    pub fn evaluate(&self, prev: &StatSnapshot, cur: &StatSnapshot) -> CheckResult {
+        match self {
+            Check::Heartbeat => heartbeat(prev, cur),
+            Check::Rate {
+                stat,
+                interface,
+                bytes,
+                warn,
+                crit,
+            } => counter(prev, cur, stat, interface, *bytes, true, warn, crit),
+            Check::Increase {
+                stat,
+                interface,
+                bytes,
+                warn,
+                crit,
+            } => counter(prev, cur, stat, interface, *bytes, false, warn, crit),
+            Check::BufferUsage { pool, warn, crit } => buffer_usage(cur, pool, warn, crit),
+        }
+    }
+}
+
+fn heartbeat(prev: &StatSnapshot, cur: &StatSnapshot) -> CheckResult {
+    let perfdata = vec![Perfdata {
+        label: "heartbeat".to_string(),
+        value: cur.heartbeat,
+        uom: "c",
+        warn: None,
+        crit: None,
+        min: Some(0.0),
+        max: None,
+    }];
+    let (state, message) = if cur.heartbeat > prev.heartbeat {
+        (CheckState::Ok, format!("heartbeat at {}", cur.heartbeat))
+    } else if cur.heartbeat < prev.heartbeat {
+        (
+            CheckState::Warning,
+            format!(
+                "heartbeat went back from {} to {}, VPP restarted",
+                prev.heartbeat, cur.heartbeat
+            ),
+        )
+    } else {
+        (
+            CheckState::Critical,
+            format!("heartbeat stuck at {}", cur.heartbeat),
+        )
+    };
+    CheckResult {
+        state,
+        message,
+        perfdata,
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn counter(
+    prev: &StatSnapshot,
+    cur: &StatSnapshot,
+    stat: &str,
+    interface: &Option<String>,
+    bytes: bool,
+    rate: bool,
+    warn: &Option<Threshold>,
+    crit: &Option<Threshold>,
+) -> CheckResult {
+    let value = match cur.get(stat) {
+        Some(value) => value,
+        None => return CheckResult::unknown(&format!("no stat {}", stat)),
+    };
+    let field = match value {
+        SnapshotValue::Combined(_) if bytes => CounterField::Bytes,
+        SnapshotValue::Combined(_) => CounterField::Packets,
+        _ => CounterField::Value,
+    };
+    let index = match interface {
+        Some(name) => match cur
+            .interface_names()
+            .iter()
+            .position(|n| n.as_deref() == Some(name.as_str()))
+        {
+            Some(index) => Some(index),
+            None => return CheckResult::unknown(&format!("no interface {}", name)),
+        },
+        None => None,
+    };
+    let mut changes: Vec<_> = deltas(prev, cur, false)
+        .into_iter()
+        .filter(|d| d.key.name == stat && d.key.field == field)
+        .filter(|d| index.is_none() || d.key.index == index)
+        .collect();
+    if changes.is_empty() {
+        return CheckResult::unknown(&format!("no earlier value of {}", stat));
+    }
+    /* Same as for the rates, an interface re-created in the slot counts as a reset */
+    changes.retain(|d| !d.reset && d.key.interface_name(prev) == d.key.interface_name(cur));
+    if changes.is_empty() {
+        return CheckResult::unknown(&format!("{} was reset since the earlier value", stat));
+    }
+    let value: f64 = changes
+        .iter()
+        .map(|d| if rate { d.rate } else { d.delta })
+        .sum();
+    let what = match interface {
+        Some(name) => format!("{} {}", stat, name),
+        None => stat.to_string(),
+    };
+    let label = interface.clone().unwrap_or_else(|| stat.to_string());
+    let state = state_for(value, warn, crit);
+    let message = if rate {
+        format!("{} at {:.1}/s", what, value)
+    } else {
+        format!("{} went up by {}", what, value)
+    };
+    CheckResult {
+        state,
+        message,
+        perfdata: vec![Perfdata {
+            label,
+            value,
+            uom: "",
+            warn: warn.clone(),
+            crit: crit.clone(),
+            min: Some(0.0),
+            max: None,
+        }],
+    }
+}
+
+fn buffer_usage(
+    cur: &StatSnapshot,
+    pool: &Option<String>,
+    warn: &Option<Threshold>,
+    crit: &Option<Threshold>,
+) -> CheckResult {
+    /* Each pool has the "cached", "used" and "available" scalars */
+    let mut pools: Vec<(String, f64)> = vec![];
+    for entry in &cur.entries {
+        let name = match entry
+            .name
+            .strip_prefix(BUFFER_POOLS)
+            .and_then(|n| n.strip_suffix("/used"))
+        {
+            Some(name) => name,
+            None => continue,
+        };
+        if pool.as_ref().map(|p| p != name).unwrap_or(false) {
+            continue;
+        }
+        let used = cur.scalar(&entry.name).unwrap_or(0.0);
+        let available = cur
+            .scalar(&format!("{}{}/available", BUFFER_POOLS, name))
+            .unwrap_or(0.0);
+        if used + available > 0.0 {
+            pools.push((name.to_string(), 100.0 * used / (used + available)));
+        }
+    }
+    let (fullest, usage) = match pools.iter().max_by(|a, b| a.1.total_cmp(&b.1)) {
+        Some(p) => p.clone(),
+        None => {
+            return CheckResult::unknown(match pool {
+                Some(_) => "no such buffer pool",
+                None => "no buffer pools",
+            })
+        }
+    };
+    CheckResult {
+        state: state_for(usage, warn, crit),
+        message: format!("{} at {:.1}% used", fullest, usage),
+        perfdata: pools
+            .into_iter()
+            .map(|(label, value)| Perfdata {
+                label,
+                value,
+                uom: "%",
+                warn: warn.clone(),
+                crit: crit.clone(),
+                min: Some(0.0),
+                max: Some(100.0),
+            })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::SnapshotBuilder;
+
+    fn snap_with(timestamp: f64, names: &[&str], drops: u64) -> StatSnapshot {
+        SnapshotBuilder::new(timestamp)
+            .with_interfaces(names)
+            .with_simple("/if/drops", vec![vec![0, drops], vec![0, drops]])
+            .with_scalar("/buffer-pools/default-numa-0/used", 920.0)
+            .with_scalar("/buffer-pools/default-numa-0/available", 80.0)
+            .build()
+    }
+
+    fn snap(timestamp: f64, drops: u64) -> StatSnapshot {
+        snap_with(timestamp, &["local0", "eth0"], drops)
+    }
+
+    #[test]
+    fn thresholds() {
+        let t = |s: &str| s.parse::<Threshold>().unwrap();
+        assert!(t("10").alerts(11.0));
+        assert!(t("10").alerts(-1.0));
+        assert!(!t("10").alerts(10.0));
+        assert!(t("10:").alerts(9.0));
+        assert!(!t("~:10").alerts(-100.0));
+        assert!(t("@10:20").alerts(15.0));
+        assert!(!t("@10:20").alerts(25.0));
+        assert!("20:10".parse::<Threshold>().is_err());
+        assert!("x".parse::<Threshold>().is_err());
+    }
+
+    #[test]
+    fn rate_on_interface() {
+        let check = Check::Rate {
+            stat: "/if/drops".to_string(),
+            interface: Some("eth0".to_string()),
+            bytes: false,
+            warn: Some("100".parse().unwrap()),
+            crit: Some("1000".parse().unwrap()),
+        };
+        let res = check.evaluate(&snap(10.0, 100), &snap(12.0, 220));
+        assert_eq!(res.state, CheckState::Warning);
+        assert_eq!(
+            res.output(check.service()),
+            "VPP RATE WARNING - /if/drops eth0 at 120.0/s | 'eth0'=120;100;1000;0;"
+        );
+
+        let missing = Check::Increase {
+            stat: "/if/drops".to_string(),
+            interface: Some("eth1".to_string()),
+            bytes: false,
+            warn: None,
+            crit: None,
+        };
+        let res = missing.evaluate(&snap(10.0, 100), &snap(12.0, 220));
+        assert_eq!(res.state.exit_code(), 3);
+    }
+
+    #[test]
+    fn resets_and_renames_give_no_value() {
+        let increase = |interface: &str| Check::Increase {
+            stat: "/if/drops".to_string(),
+            interface: Some(interface.to_string()),
+            bytes: false,
+            warn: None,
+            crit: None,
+        };
+        let res = increase("eth0").evaluate(&snap(10.0, 100), &snap(12.0, 50));
+        assert_eq!(res.state, CheckState::Unknown);
+        assert_eq!(res.message, "/if/drops was reset since the earlier value");
+
+        /* "eth0" deleted and "eth1" created in its slot */
+        let cur = snap_with(12.0, &["local0", "eth1"], 220);
+        let res = increase("eth1").evaluate(&snap(10.0, 100), &cur);
+        assert_eq!(res.state, CheckState::Unknown);
+
+        /* Only the interface which was there all along */
+        let all = Check::Increase {
+            stat: "/if/drops".to_string(),
+            interface: None,
+            bytes: false,
+            warn: None,
+            crit: None,
+        };
+        let res = all.evaluate(&snap(10.0, 100), &cur);
+        assert_eq!(res.message, "/if/drops went up by 0");
+    }
+
+    #[test]
+    fn heartbeat_and_buffers() {
+        let res = Check::Heartbeat.evaluate(&snap(10.0, 0), &snap(10.0, 0));
+        assert_eq!(res.state, CheckState::Critical);
+
+        let check = Check::BufferUsage {
+            pool: None,
+            warn: Some("80".parse().unwrap()),
+            crit: Some("90".parse().unwrap()),
+        };
+        let res = check.evaluate(&snap(10.0, 0), &snap(10.0, 0));
+        assert_eq!(res.state, CheckState::Critical);
+        assert_eq!(res.message, "default-numa-0 at 92.0% used");
+    }
+}
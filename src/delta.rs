@@ -0,0 +1,250 @@
+/*
+ * Flattening of the snapshots into the individual series, and the
+ * per-series differences between two snapshots of the same stats.
+ */
+
+use crate::snapshot::{is_interface_stat, SnapshotValue, StatSnapshot};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum CounterField {
+    Value,
+    Packets,
+    Bytes,
+}
+
+/// One number out of a snapshot. The scalars have neither thread nor index,
+/// the totals across the threads have only the index.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct SeriesKey {
+    pub name: String,
+    pub thread: Option<usize>,
+    pub index: Option<usize>,
+    pub field: CounterField,
+}
+
+impl SeriesKey {
+    pub fn scalar(name: &str) -> Self {
+        SeriesKey {
+            name: name.to_string(),
+            thread: None,
+            index: None,
+            field: CounterField::Value,
+        }
+    }
+
+    /// The interface name for the stats indexed by sw_if_index
+    pub fn interface_name<'a>(&self, snap: &'a StatSnapshot) -> Option<&'a str> {
+        match self.index {
+            Some(idx) if is_interface_stat(&self.name) => snap.interface_name(idx),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for SeriesKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        if let Some(t) = self.thread {
+            write!(f, "[t{}]", t)?;
+        }
+        if let Some(i) = self.index {
+            write!(f, "[{}]", i)?;
+        }
+        match self.field {
+            CounterField::Value => Ok(()),
+            CounterField::Packets => write!(f, ".packets"),
+            CounterField::Bytes => write!(f, ".bytes"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum SeriesValue {
+    Counter(u64),
+    Gauge(f64),
+}
+
+impl SeriesValue {
+    pub fn as_f64(&self) -> f64 {
+        match self {
+            SeriesValue::Counter(v) => *v as f64,
+            SeriesValue::Gauge(v) => *v,
+        }
+    }
+}
+
+impl fmt::Display for SeriesValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SeriesValue::Counter(v) => write!(f, "{}", v),
+            SeriesValue::Gauge(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+/// Turn the snapshot into the individual series. With per_thread unset
+/// the vector counters are summed across the threads.
+pub fn flatten(snap: &StatSnapshot, per_thread: bool) -> BTreeMap<SeriesKey, SeriesValue> {
+    let mut out = BTreeMap::new();
+    for entry in &snap.entries {
+        let key = |thread, index, field| SeriesKey {
+            name: entry.name.clone(),
+            thread,
+            index: Some(index),
+            field,
+        };
+        match &entry.value {
+            SnapshotValue::Scalar(v) => {
+                out.insert(SeriesKey::scalar(&entry.name), SeriesValue::Gauge(*v));
+            }
+            SnapshotValue::Simple(threads) if per_thread => {
+                for (t, vals) in threads.iter().enumerate() {
+                    for (i, v) in vals.iter().enumerate() {
+                        out.insert(
+                            key(Some(t), i, CounterField::Value),
+                            SeriesValue::Counter(*v),
+                        );
+                    }
+                }
+            }
+            SnapshotValue::Combined(threads) if per_thread => {
+                for (t, vals) in threads.iter().enumerate() {
+                    for (i, v) in vals.iter().enumerate() {
+                        out.insert(
+                            key(Some(t), i, CounterField::Packets),
+                            SeriesValue::Counter(v.packets),
+                        );
+                        out.insert(
+                            key(Some(t), i, CounterField::Bytes),
+                            SeriesValue::Counter(v.bytes),
+                        );
+                    }
+                }
+            }
+            SnapshotValue::Simple(_) => {
+                for i in 0..entry.value.index_count() {
+                    let v = entry.value.simple_total(i).unwrap_or(0);
+                    out.insert(key(None, i, CounterField::Value), SeriesValue::Counter(v));
+                }
+            }
+            SnapshotValue::Combined(_) => {
+                for i in 0..entry.value.index_count() {
+                    let v = entry.value.combined_total(i).unwrap_or_default();
+                    out.insert(
+                        key(None, i, CounterField::Packets),
+                        SeriesValue::Counter(v.packets),
+                    );
+                    out.insert(
+                        key(None, i, CounterField::Bytes),
+                        SeriesValue::Counter(v.bytes),
+                    );
+                }
+            }
+            SnapshotValue::Names(_) | SnapshotValue::Empty => {}
+        }
+    }
+    out
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SeriesDelta {
+    pub key: SeriesKey,
+    pub old: SeriesValue,
+    pub new: SeriesValue,
+    pub delta: f64,
+    /* Change per second, zero if the snapshots were taken at the same time */
+    pub rate: f64,
+    /* The counter went backwards, e.g. it was cleared or the VPP restarted */
+    pub reset: bool,
+}
+
+fn series_delta(key: &SeriesKey, old: SeriesValue, new: SeriesValue, elapsed: f64) -> SeriesDelta {
+    let (delta, reset) = match (old, new) {
+        (SeriesValue::Counter(o), SeriesValue::Counter(n)) if n < o => (n as f64, true),
+        (SeriesValue::Counter(o), SeriesValue::Counter(n)) => ((n - o) as f64, false),
+        _ => (new.as_f64() - old.as_f64(), false),
+    };
+    SeriesDelta {
+        key: key.clone(),
+        old,
+        new,
+        delta,
+        rate: if elapsed > 0.0 { delta / elapsed } else { 0.0 },
+        reset,
+    }
+}
+
+/// The differences for the series present in both snapshots.
+/// A counter which went backwards is assumed to have restarted from zero.
+pub fn deltas(prev: &StatSnapshot, cur: &StatSnapshot, per_thread: bool) -> Vec<SeriesDelta> {
+    let elapsed = cur.timestamp - prev.timestamp;
+    let old = flatten(prev, per_thread);
+    flatten(cur, per_thread)
+        .into_iter()
+        .filter_map(|(key, new)| old.get(&key).map(|o| series_delta(&key, *o, new, elapsed)))
+        .collect()
+}
+
+/// Same as deltas(), but only the series which have changed
+pub fn changed(prev: &StatSnapshot, cur: &StatSnapshot, per_thread: bool) -> Vec<SeriesDelta> {
+    deltas(prev, cur, per_thread)
+        .into_iter()
+        .filter(|d| d.delta != 0.0 || d.reset)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::SnapshotBuilder;
+
+    fn snap(timestamp: f64, rx: u64, drops: u64) -> StatSnapshot {
+        SnapshotBuilder::new(timestamp)
+            .with_combined("/if/rx", vec![vec![(rx, rx * 64)], vec![(1, 64)]])
+            .with_simple("/if/drops", vec![vec![drops]])
+            .with_scalar("/sys/vector_rate", timestamp)
+            .build()
+    }
+
+    #[test]
+    fn flatten_totals_and_threads() {
+        let s = snap(0.0, 10, 3);
+        let totals = flatten(&s, false);
+        let rx = SeriesKey {
+            name: "/if/rx".to_string(),
+            thread: None,
+            index: Some(0),
+            field: CounterField::Packets,
+        };
+        assert_eq!(totals.get(&rx), Some(&SeriesValue::Counter(11)));
+        assert_eq!(rx.to_string(), "/if/rx[0].packets");
+
+        let threads = flatten(&s, true);
+        let rx1 = SeriesKey {
+            thread: Some(1),
+            ..rx
+        };
+        assert_eq!(threads.get(&rx1), Some(&SeriesValue::Counter(1)));
+        assert_eq!(rx1.to_string(), "/if/rx[t1][0].packets");
+        assert_eq!(threads.len(), 6);
+    }
+
+    #[test]
+    fn rates_and_resets() {
+        let d = changed(&snap(10.0, 10, 5), &snap(12.0, 30, 2), false);
+        let get = |name: &str, field| {
+            d.iter()
+                .find(|d| d.key.name == name && d.key.field == field)
+                .unwrap()
+        };
+        assert_eq!(get("/if/rx", CounterField::Packets).rate, 10.0);
+        assert_eq!(get("/if/rx", CounterField::Bytes).delta, 1280.0);
+        let drops = get("/if/drops", CounterField::Value);
+        assert!(drops.reset);
+        assert_eq!(drops.delta, 2.0);
+        assert_eq!(get("/sys/vector_rate", CounterField::Value).delta, 2.0);
+    }
+}
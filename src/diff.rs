@@ -0,0 +1,208 @@
+/*
+ * Comparison of two snapshots, e.g. taken before and after a test run:
+ * which counters moved, per index (and per thread, if asked), and which stat
+ * entries have appeared or disappeared in between.
+ */
+
+use crate::delta::{flatten, CounterField, SeriesKey, SeriesValue};
+use crate::snapshot::{is_interface_stat, SnapshotValue, StatSnapshot};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryChange {
+    Created,
+    Removed,
+    Changed,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CounterChange {
+    pub thread: Option<usize>,
+    pub index: Option<usize>,
+    pub field: CounterField,
+    pub interface: Option<String>,
+    pub old: SeriesValue,
+    pub new: SeriesValue,
+    pub delta: f64,
+    /* Relative to the old value, none if that was zero */
+    pub relative: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EntryDiff {
+    pub name: String,
+    pub change: EntryChange,
+    /* Empty for the created and removed entries */
+    pub counters: Vec<CounterChange>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotDiff {
+    pub old_timestamp: f64,
+    pub new_timestamp: f64,
+    pub entries: Vec<EntryDiff>,
+}
+
+fn entry_names(snap: &StatSnapshot) -> BTreeSet<&str> {
+    snap.entries.iter().map(|e| e.name.as_str()).collect()
+}
+
+impl SnapshotDiff {
+    /// Compare the snapshots counter by counter, either per thread or summed
+    /// across the threads. Only the changed counters and the created or removed
+    /// entries are kept.
+    pub fn compare(old: &StatSnapshot, new: &StatSnapshot, per_thread: bool) -> Self {
+        let old_names = entry_names(old);
+        let new_names = entry_names(new);
+        let old_series = flatten(old, per_thread);
+        let new_series = flatten(new, per_thread);
+
+        let mut changed: BTreeMap<&str, Vec<CounterChange>> = BTreeMap::new();
+        for (key, n) in &new_series {
+            let o = match old_series.get(key) {
+                Some(o) => o,
+                /* The vectors grow when the objects are created, count from zero */
+                None if old_names.contains(key.name.as_str()) => &SeriesValue::Counter(0),
+                None => continue,
+            };
+            if o == n {
+                continue;
+            }
+            let delta = n.as_f64() - o.as_f64();
+            let interface = match key.index {
+                Some(i) if is_interface_stat(&key.name) => new.interface_name(i),
+                _ => None,
+            };
+            changed
+                .entry(key.name.as_str())
+                .or_default()
+                .push(CounterChange {
+                    thread: key.thread,
+                    index: key.index,
+                    field: key.field,
+                    interface: interface.map(|s| s.to_string()),
+                    old: *o,
+                    new: *n,
+                    delta,
+                    relative: if o.as_f64() != 0.0 {
+                        Some(delta / o.as_f64())
+                    } else {
+                        None
+                    },
+                });
+        }
+
+        let mut entries = vec![];
+        for name in old_names.union(&new_names) {
+            let change = match (old_names.contains(name), new_names.contains(name)) {
+                (false, true) => EntryChange::Created,
+                (true, false) => EntryChange::Removed,
+                _ => EntryChange::Changed,
+            };
+            let counters = match change {
+                EntryChange::Changed => match changed.remove(name) {
+                    Some(c) => c,
+                    None => continue,
+                },
+                _ => vec![],
+            };
+            entries.push(EntryDiff {
+                name: name.to_string(),
+                change,
+                counters,
+            });
+        }
+        SnapshotDiff {
+            old_timestamp: old.timestamp,
+            new_timestamp: new.timestamp,
+            entries,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// One line per created or removed entry, and per changed counter
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+        for e in &self.entries {
+            match e.change {
+                EntryChange::Created => writeln!(out, "+ {}", e.name).unwrap(),
+                EntryChange::Removed => writeln!(out, "- {}", e.name).unwrap(),
+                EntryChange::Changed => {
+                    for c in &e.counters {
+                        let key = SeriesKey {
+                            name: e.name.clone(),
+                            thread: c.thread,
+                            index: c.index,
+                            field: c.field,
+                        };
+                        write!(out, "  {}", key).unwrap();
+                        if let Some(ifname) = &c.interface {
+                            write!(out, " ({})", ifname).unwrap();
+                        }
+                        write!(out, ": {} -> {} ({:+}", c.old, c.new, c.delta).unwrap();
+                        if let Some(r) = c.relative {
+                            write!(out, ", {:+.1}%", r * 100.0).unwrap();
+                        }
+                        writeln!(out, ")").unwrap();
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::SnapshotBuilder;
+
+    #[test]
+    fn changed_created_removed() {
+        let c = |packets| (packets, packets * 100);
+        let old = SnapshotBuilder::new(1.0)
+            .with_interfaces(&["local0", "eth0"])
+            .with_combined("/if/rx", vec![vec![c(0), c(10)], vec![c(0), c(5)]])
+            .with_scalar("/bfd/sessions", 1.0)
+            .build();
+        let new = SnapshotBuilder::new(2.0)
+            .with_interfaces(&["local0", "eth0"])
+            .with_combined("/if/rx", vec![vec![c(0), c(15)], vec![c(0), c(5)]])
+            .with_simple("/err/ip4-input/ttl expired", vec![vec![1]])
+            .build();
+
+        let diff = SnapshotDiff::compare(&old, &new, true);
+        assert_eq!(diff.entries.len(), 3);
+        assert_eq!(diff.entries[0].name, "/bfd/sessions");
+        assert_eq!(diff.entries[0].change, EntryChange::Removed);
+        assert_eq!(diff.entries[1].change, EntryChange::Created);
+
+        let rx = &diff.entries[2];
+        assert_eq!(rx.change, EntryChange::Changed);
+        assert_eq!(rx.counters.len(), 2);
+        assert_eq!(rx.counters[0].thread, Some(0));
+        assert_eq!(rx.counters[0].index, Some(1));
+        assert_eq!(rx.counters[0].interface.as_deref(), Some("eth0"));
+        assert_eq!(rx.counters[0].relative, Some(0.5));
+
+        let text = diff.to_text();
+        assert!(text.contains("- /bfd/sessions\n"));
+        assert!(text.contains("  /if/rx[t0][1].packets (eth0): 10 -> 15 (+5, +50.0%)\n"));
+        assert!(SnapshotDiff::compare(&new, &new, true).is_empty());
+
+        let summed = SnapshotDiff::compare(&old, &new, false);
+        let rx = &summed.entries[2];
+        assert_eq!(rx.counters.len(), 2);
+        assert_eq!(rx.counters[0].thread, None);
+        assert_eq!(rx.counters[0].delta, 5.0);
+        assert!(summed
+            .to_text()
+            .contains("  /if/rx[1].packets (eth0): 15 -> 20 (+5, +33.3%)\n"));
+    }
+}
@@ -0,0 +1,281 @@
+/*
+ * Discovery of the stats sockets of the VPP instances which come and go,
+ * e.g. the pods sharing a host directory:
+ *
+ *   let mut discovery = SocketDiscovery::new("/run/vpp/vpp?/stats.sock")?;
+ *   loop {
+ *       for event in discovery.poll(Duration::from_secs(1))? { ... }
+ *   }
+ *
+ * The directories leading to the sockets are watched with inotify, and any
+ * change there triggers a rescan of the glob; the inotify events themselves
+ * are only used as the wakeups, so nothing is missed if they overflow.
+ */
+
+use std::collections::{BTreeSet, HashMap};
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiscoveryEvent {
+    Added(String),
+    Removed(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DiscoveryError {
+    BadPattern(String),
+    NoSuchDirectory(String),
+    InotifyFailed(i32),
+}
+
+const WATCH_MASK: u32 = libc::IN_CREATE
+    | libc::IN_DELETE
+    | libc::IN_MOVED_FROM
+    | libc::IN_MOVED_TO
+    | libc::IN_DELETE_SELF
+    | libc::IN_ONLYDIR;
+
+fn errno() -> i32 {
+    std::io::Error::last_os_error().raw_os_error().unwrap_or(0)
+}
+
+fn has_wildcards(s: &str) -> bool {
+    s.contains(['*', '?', '['])
+}
+
+pub struct SocketDiscovery {
+    pattern: String,
+    /* The directory above the first component with the wildcards */
+    base: PathBuf,
+    /* The patterns of the directories between the base and the sockets */
+    dir_patterns: Vec<String>,
+    fd: RawFd,
+    watches: HashMap<i32, PathBuf>,
+    sockets: BTreeSet<String>,
+    /* Whether to rescan on the next poll() even without any events */
+    dirty: bool,
+}
+
+impl SocketDiscovery {
+    /// Start watching for the sockets matching the glob. The sockets already
+    /// there are reported as added by the first poll().
+    pub fn new(pattern: &str) -> Result<Self, DiscoveryError> {
+        glob::Pattern::new(pattern).map_err(|e| DiscoveryError::BadPattern(e.to_string()))?;
+        let path = Path::new(pattern);
+        let mut base = PathBuf::new();
+        let mut dir_patterns = vec![];
+        let parent = path
+            .parent()
+            .ok_or_else(|| DiscoveryError::BadPattern(pattern.to_string()))?;
+        for component in parent.components() {
+            let s = component.as_os_str().to_string_lossy();
+            if dir_patterns.is_empty() && !has_wildcards(&s) {
+                base.push(component);
+            } else {
+                let prev = dir_patterns
+                    .last()
+                    .map(PathBuf::from)
+                    .unwrap_or_else(|| base.clone());
+                dir_patterns.push(prev.join(component).to_string_lossy().into_owned());
+            }
+        }
+        if !base.is_dir() {
+            return Err(DiscoveryError::NoSuchDirectory(
+                base.to_string_lossy().into_owned(),
+            ));
+        }
+
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(DiscoveryError::InotifyFailed(errno()));
+        }
+        let mut discovery = SocketDiscovery {
+            pattern: pattern.to_string(),
+            base,
+            dir_patterns,
+            fd,
+            watches: HashMap::new(),
+            sockets: BTreeSet::new(),
+            dirty: true,
+        };
+        discovery.watch(&discovery.base.clone())?;
+        Ok(discovery)
+    }
+
+    /// The sockets found by the last poll()
+    pub fn sockets(&self) -> Vec<String> {
+        self.sockets.iter().cloned().collect()
+    }
+
+    fn watch(&mut self, dir: &Path) -> Result<(), DiscoveryError> {
+        if self.watches.values().any(|d| d == dir) {
+            return Ok(());
+        }
+        let cpath = CString::new(dir.as_os_str().as_bytes())
+            .map_err(|_| DiscoveryError::BadPattern(self.pattern.clone()))?;
+        let wd = unsafe { libc::inotify_add_watch(self.fd, cpath.as_ptr(), WATCH_MASK) };
+        if wd < 0 {
+            let e = errno();
+            /* The directory can be gone already, the next rescan sorts it out */
+            if e == libc::ENOENT || e == libc::ENOTDIR {
+                return Ok(());
+            }
+            return Err(DiscoveryError::InotifyFailed(e));
+        }
+        self.watches.insert(wd, dir.to_path_buf());
+        Ok(())
+    }
+
+    fn glob(pattern: &str) -> Vec<PathBuf> {
+        match glob::glob(pattern) {
+            Ok(paths) => paths.filter_map(Result::ok).collect(),
+            Err(_) => vec![],
+        }
+    }
+
+    /* Watch the new directories, and compare the sockets with the last scan */
+    fn rescan(&mut self) -> Result<Vec<DiscoveryEvent>, DiscoveryError> {
+        for pattern in self.dir_patterns.clone() {
+            for dir in SocketDiscovery::glob(&pattern) {
+                if dir.is_dir() {
+                    self.watch(&dir)?;
+                }
+            }
+        }
+        let found: BTreeSet<String> = SocketDiscovery::glob(&self.pattern)
+            .into_iter()
+            .filter(|p| {
+                std::fs::metadata(p)
+                    .map(|m| m.file_type().is_socket())
+                    .unwrap_or(false)
+            })
+            .map(|p| p.to_string_lossy().into_owned())
+            .collect();
+        let mut events: Vec<DiscoveryEvent> = self
+            .sockets
+            .difference(&found)
+            .map(|s| DiscoveryEvent::Removed(s.clone()))
+            .collect();
+        events.extend(
+            found
+                .difference(&self.sockets)
+                .map(|s| DiscoveryEvent::Added(s.clone())),
+        );
+        self.sockets = found;
+        Ok(events)
+    }
+
+    /* Read out all the pending events, forgetting the watches which are gone */
+    fn drain(&mut self) -> Result<bool, DiscoveryError> {
+        let mut buf = [0u8; 4096];
+        let header = std::mem::size_of::<libc::inotify_event>();
+        let mut any = false;
+        loop {
+            let n = unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut _, buf.len()) };
+            if n < 0 {
+                let e = errno();
+                if e == libc::EAGAIN || e == libc::EINTR {
+                    return Ok(any);
+                }
+                return Err(DiscoveryError::InotifyFailed(e));
+            }
+            if n == 0 {
+                return Ok(any);
+            }
+            let n = n as usize;
+            let mut off = 0;
+            while off + header <= n {
+                let event: libc::inotify_event =
+                    unsafe { std::ptr::read_unaligned(buf[off..].as_ptr() as *const _) };
+                if event.mask & libc::IN_IGNORED != 0 {
+                    self.watches.remove(&event.wd);
+                }
+                off += header + event.len as usize;
+                any = true;
+            }
+        }
+    }
+
+    /// Wait up to the timeout for the changes, and return the sockets which
+    /// appeared or disappeared since the previous call
+    pub fn poll(&mut self, timeout: Duration) -> Result<Vec<DiscoveryEvent>, DiscoveryError> {
+        /* The sockets present at the start, or created before the watches were added */
+        if self.dirty {
+            self.dirty = false;
+            let events = self.rescan()?;
+            if !events.is_empty() {
+                return Ok(events);
+            }
+        }
+        let mut pfd = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let rv = unsafe { libc::poll(&mut pfd, 1, timeout.as_millis() as libc::c_int) };
+        if rv < 0 {
+            let e = errno();
+            if e == libc::EINTR {
+                return Ok(vec![]);
+            }
+            return Err(DiscoveryError::InotifyFailed(e));
+        }
+        if rv > 0 && self.drain()? {
+            /* Once more on the next call, for the sockets in the directories just watched */
+            self.dirty = true;
+            return self.rescan();
+        }
+        Ok(vec![])
+    }
+}
+
+impl Drop for SocketDiscovery {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn sockets_come_and_go() {
+        let dir = std::env::temp_dir().join(format!("vpp-discovery-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("pod1")).unwrap();
+        let _l1 = UnixListener::bind(dir.join("pod1/stats.sock")).unwrap();
+
+        let pattern = format!("{}/*/stats.sock", dir.display());
+        let mut d = SocketDiscovery::new(&pattern).unwrap();
+        let sock = |pod: &str| format!("{}/{}/stats.sock", dir.display(), pod);
+        assert_eq!(
+            d.poll(Duration::from_millis(0)).unwrap(),
+            vec![DiscoveryEvent::Added(sock("pod1"))]
+        );
+
+        std::fs::create_dir(dir.join("pod2")).unwrap();
+        std::fs::write(dir.join("pod2/stats.txt"), "").unwrap();
+        let _l2 = UnixListener::bind(dir.join("pod2/stats.sock")).unwrap();
+        std::fs::remove_file(dir.join("pod1/stats.sock")).unwrap();
+        let mut events = vec![];
+        for _i in 0..10 {
+            events.extend(d.poll(Duration::from_millis(100)).unwrap());
+        }
+        assert_eq!(
+            events,
+            vec![
+                DiscoveryEvent::Removed(sock("pod1")),
+                DiscoveryEvent::Added(sock("pod2"))
+            ]
+        );
+        assert_eq!(d.sockets(), vec![sock("pod2")]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
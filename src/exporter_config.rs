@@ -0,0 +1,533 @@
+/*
+ * Trimming and reshaping of what the exporter emits, driven by a YAML file:
+ *
+ *   include: ["^/if/", "^/err/"]
+ *   exclude: ["^/if/names$"]
+ *   rename:
+ *     - match: "^/err/(?P<node>[^/]+)/(?P<reason>.*)$"
+ *       name: vpp_errors_total
+ *   aggregate_threads: false
+ *   threads:
+ *     - family: "^vpp_errors_total$"
+ *       aggregate: true
+ *   drop_zero: true
+ *   labels:
+ *     site: ams1
+ *
+ * The include and exclude regexes are matched against the stat paths,
+ * the thread rules against the family names after the renaming.
+ *
+ * The config is rejected if it would give an invalid exposition: the names
+ * which are not valid metric or label names, two rules giving the same
+ * family, or the labels clashing with the ones the exporter adds. The stats
+ * renamed into one family with the same labels are summed up.
+ */
+
+use crate::delta::SeriesDelta;
+use crate::prometheus::{entry_families, prom_str, PromFamily, PromSample, SampleValue};
+use crate::rates::{entry_rates, rate_gauges};
+use crate::snapshot::{is_interface_stat, StatSnapshot};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/* The labels put on the samples by the exporter itself */
+const RESERVED_LABELS: &[&str] = &["thread", "interface", "index", "name", "instance"];
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RenameRule {
+    /// Regex on the stat path; the named groups become the labels
+    #[serde(rename = "match")]
+    pub pattern: String,
+    /// Family name for all the matching stats
+    pub name: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ThreadRule {
+    /// Regex on the family name
+    pub family: String,
+    /// Sum the samples across the threads and drop the "thread" label
+    pub aggregate: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExporterConfig {
+    /// Stat path regexes to export, everything if empty
+    pub include: Vec<String>,
+    /// Stat path regexes to leave out, even if included
+    pub exclude: Vec<String>,
+    pub rename: Vec<RenameRule>,
+    /// Whether to sum across the threads the families not matched by any thread rule
+    pub aggregate_threads: bool,
+    /// First matching rule wins
+    pub threads: Vec<ThreadRule>,
+    /// Leave out the samples which are zero
+    pub drop_zero: bool,
+    /// Labels added to every sample
+    pub labels: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExporterConfigError {
+    Io(String),
+    Parse(String),
+    BadRegex(String),
+    /// Not a valid metric or label name
+    BadName(String),
+    /// Two rules giving the same family, or the same label twice
+    Conflict(String),
+}
+
+/* [a-zA-Z_:][a-zA-Z0-9_:]* for the metric names, the same without ":" for the labels */
+fn valid_name(s: &str, metric: bool) -> bool {
+    let ok = |c: char| c.is_ascii_alphanumeric() || c == '_' || (metric && c == ':');
+    match s.chars().next() {
+        Some(c) if !c.is_ascii_digit() && ok(c) => s.chars().all(ok),
+        _ => false,
+    }
+}
+
+fn check_label(label: &str) -> Result<(), ExporterConfigError> {
+    if !valid_name(label, false) || label.starts_with("__") {
+        return Err(ExporterConfigError::BadName(format!(
+            "bad label name \"{}\"",
+            label
+        )));
+    }
+    if RESERVED_LABELS.contains(&label) {
+        return Err(ExporterConfigError::Conflict(format!(
+            "the label \"{}\" is added by the exporter",
+            label
+        )));
+    }
+    Ok(())
+}
+
+/* The name given by a rename rule, before the suffixes */
+pub(crate) fn check_family_name(name: &str) -> Result<(), ExporterConfigError> {
+    if !valid_name(name, true) {
+        return Err(ExporterConfigError::BadName(format!(
+            "bad metric name \"{}\"",
+            name
+        )));
+    }
+    /* The families which are not renamed all start with it */
+    if name.starts_with('_') {
+        return Err(ExporterConfigError::Conflict(format!(
+            "the renamed \"{}\" can clash with the stat paths",
+            name
+        )));
+    }
+    Ok(())
+}
+
+impl ExporterConfig {
+    pub fn load(path: &str) -> Result<Self, ExporterConfigError> {
+        let data = std::fs::read_to_string(path)
+            .map_err(|e| ExporterConfigError::Io(format!("{}: {}", path, e)))?;
+        ExporterConfig::from_yaml(&data)
+    }
+
+    pub fn from_yaml(data: &str) -> Result<Self, ExporterConfigError> {
+        let config: ExporterConfig =
+            serde_yaml::from_str(data).map_err(|e| ExporterConfigError::Parse(e.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Check that the renames and the labels give a valid exposition
+    pub fn validate(&self) -> Result<(), ExporterConfigError> {
+        for label in self.labels.keys() {
+            check_label(label)?;
+        }
+        /* Every family a rule can give, with the combined and the names suffixes */
+        let mut families: HashMap<String, &str> = HashMap::new();
+        for rule in &self.rename {
+            check_family_name(&rule.name)?;
+            let re = Regex::new(&rule.pattern)
+                .map_err(|e| ExporterConfigError::BadRegex(e.to_string()))?;
+            for label in re.capture_names().flatten() {
+                check_label(label)?;
+                if self.labels.contains_key(label) {
+                    return Err(ExporterConfigError::Conflict(format!(
+                        "the label \"{}\" is both a group of \"{}\" and a config label",
+                        label, rule.pattern
+                    )));
+                }
+            }
+            for suffix in ["", "_packets", "_bytes", "_info"] {
+                let family = format!("{}{}", rule.name, suffix);
+                if let Some(other) = families.insert(family.clone(), &rule.pattern) {
+                    return Err(ExporterConfigError::Conflict(format!(
+                        "both \"{}\" and \"{}\" give the family {}",
+                        other, rule.pattern, family
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The config with only the include patterns, the same as the exporter used to do
+    pub fn with_patterns(patterns: &[String]) -> Self {
+        ExporterConfig {
+            include: patterns.to_vec(),
+            ..Default::default()
+        }
+    }
+}
+
+fn compile(patterns: &[String]) -> Result<Vec<Regex>, ExporterConfigError> {
+    patterns
+        .iter()
+        .map(|p| Regex::new(p).map_err(|e| ExporterConfigError::BadRegex(e.to_string())))
+        .collect()
+}
+
+/// The config with the regexes compiled, ready to be applied to the snapshots
+#[derive(Debug, Clone)]
+pub struct FamilyEncoder {
+    config: ExporterConfig,
+    exclude: Vec<Regex>,
+    rename: Vec<(Regex, String)>,
+    threads: Vec<(Regex, bool)>,
+    interface_names: bool,
+}
+
+impl FamilyEncoder {
+    pub fn new(config: ExporterConfig) -> Result<Self, ExporterConfigError> {
+        config.validate()?;
+        /* The includes are checked here even though the C library does the matching */
+        compile(&config.include)?;
+        let exclude = compile(&config.exclude)?;
+        let rename = config
+            .rename
+            .iter()
+            .map(|r| {
+                Regex::new(&r.pattern)
+                    .map(|re| (re, r.name.clone()))
+                    .map_err(|e| ExporterConfigError::BadRegex(e.to_string()))
+            })
+            .collect::<Result<_, _>>()?;
+        let threads = config
+            .threads
+            .iter()
+            .map(|t| {
+                Regex::new(&t.family)
+                    .map(|re| (re, t.aggregate))
+                    .map_err(|e| ExporterConfigError::BadRegex(e.to_string()))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(FamilyEncoder {
+            config,
+            exclude,
+            rename,
+            threads,
+            interface_names: false,
+        })
+    }
+
+    /// Also label the samples of the "/if/" stats with the interface "name", so a series
+    /// follows the interface rather than its index, e.g. for the monotonic counters
+    pub fn with_interface_names(mut self, on: bool) -> Self {
+        self.interface_names = on;
+        self
+    }
+
+    /// The patterns to fetch the stats with
+    pub fn patterns(&self) -> Vec<String> {
+        if self.config.include.is_empty() {
+            vec![".*".to_string()]
+        } else {
+            self.config.include.clone()
+        }
+    }
+
+    fn aggregate(&self, family: &str) -> bool {
+        self.threads
+            .iter()
+            .find(|(re, _)| re.is_match(family))
+            .map(|(_, aggregate)| *aggregate)
+            .unwrap_or(self.config.aggregate_threads)
+    }
+
+    pub fn families(&self, snap: &StatSnapshot) -> Vec<PromFamily> {
+        self.families_with_names(snap, snap.interface_names())
+    }
+
+    /// The same, with the interface names from elsewhere, when "/if/names" is not exported
+    pub fn families_with_names(
+        &self,
+        snap: &StatSnapshot,
+        names: &[Option<String>],
+    ) -> Vec<PromFamily> {
+        self.encode(snap.entries.iter().map(|e| {
+            let mut families = entry_families(e);
+            if self.interface_names && is_interface_stat(&e.name) {
+                for family in families.iter_mut() {
+                    add_interface_names(&mut family.samples, names);
+                }
+            }
+            (e.name.as_str(), families)
+        }))
+    }
+
+    /// The "_per_second" gauges, from the rates reshaped like the counters they are of
+    pub fn rate_families(&self, rates: &[SeriesDelta]) -> Vec<PromFamily> {
+        rate_gauges(self.encode(entry_rates(rates)))
+    }
+
+    /* The families of every stat path, as entry_families() names them */
+    fn encode<S: AsRef<str>>(
+        &self,
+        entries: impl IntoIterator<Item = (S, Vec<PromFamily>)>,
+    ) -> Vec<PromFamily> {
+        let mut out: Vec<PromFamily> = vec![];
+        let mut by_name: HashMap<String, usize> = HashMap::new();
+        let mut renamed_families: HashSet<String> = HashSet::new();
+        for (path, families) in entries {
+            let path = path.as_ref();
+            if self.exclude.iter().any(|re| re.is_match(path)) {
+                continue;
+            }
+            let renamed = self.rename.iter().find_map(|(re, name)| {
+                re.captures(path).map(|caps| {
+                    let labels: Vec<(String, String)> = re
+                        .capture_names()
+                        .flatten()
+                        .filter_map(|n| {
+                            caps.name(n)
+                                .map(|m| (n.to_string(), m.as_str().to_string()))
+                        })
+                        .collect();
+                    (name, labels)
+                })
+            });
+            for mut family in families {
+                if let Some((name, labels)) = &renamed {
+                    /* Keep the "_packets" and "_bytes" of the combined counters */
+                    let suffix = family.name[prom_str(path).len()..].to_string();
+                    family.name = format!("{}{}", name, suffix);
+                    renamed_families.insert(family.name.clone());
+                    for sample in family.samples.iter_mut() {
+                        let mut l = labels.clone();
+                        l.append(&mut sample.labels);
+                        sample.labels = l;
+                    }
+                }
+                match by_name.get(&family.name) {
+                    Some(i) => out[*i].samples.append(&mut family.samples),
+                    None => {
+                        by_name.insert(family.name.clone(), out.len());
+                        out.push(family);
+                    }
+                }
+            }
+        }
+
+        for family in out.iter_mut() {
+            if renamed_families.contains(&family.name) {
+                family.samples = sum_samples(&family.samples, |_| true);
+            }
+            if self.aggregate(&family.name) {
+                family.samples = sum_threads(&family.samples);
+            }
+            if self.config.drop_zero {
+                family.samples.retain(|s| s.value.as_f64() != 0.0);
+            }
+            for sample in family.samples.iter_mut() {
+                for (k, v) in &self.config.labels {
+                    sample.labels.push((k.clone(), v.clone()));
+                }
+            }
+        }
+        out.retain(|f| !f.samples.is_empty());
+        out
+    }
+}
+
+/* The "name" label after the "interface" one, for the slots which have a name */
+fn add_interface_names(samples: &mut [PromSample], names: &[Option<String>]) {
+    for sample in samples.iter_mut() {
+        let pos = sample.labels.iter().position(|(k, _)| k == "interface");
+        let name = pos
+            .and_then(|p| sample.labels[p].1.parse::<usize>().ok())
+            .and_then(|i| names.get(i).cloned().flatten());
+        if let (Some(p), Some(name)) = (pos, name) {
+            sample.labels.insert(p + 1, ("name".to_string(), name));
+        }
+    }
+}
+
+pub(crate) fn sum_threads(samples: &[PromSample]) -> Vec<PromSample> {
+    sum_samples(samples, |label| label != "thread")
+}
+
+/* Sum up the samples which are the same on the labels kept */
+pub(crate) fn sum_samples(samples: &[PromSample], keep: impl Fn(&str) -> bool) -> Vec<PromSample> {
+    let mut out: Vec<PromSample> = vec![];
+    let mut index: HashMap<Vec<(String, String)>, usize> = HashMap::new();
+    for s in samples {
+        let labels: Vec<(String, String)> =
+            s.labels.iter().filter(|(k, _)| keep(k)).cloned().collect();
+        match index.get(&labels) {
+            Some(i) => {
+                let o = &mut out[*i];
+                o.value = match (o.value, s.value) {
+                    (SampleValue::Int(a), SampleValue::Int(b)) => {
+                        SampleValue::Int(a.wrapping_add(b))
+                    }
+                    (a, b) => SampleValue::Float(a.as_f64() + b.as_f64()),
+                }
+            }
+            None => {
+                index.insert(labels.clone(), out.len());
+                out.push(PromSample {
+                    labels,
+                    value: s.value,
+                });
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prometheus::encode_text;
+    use crate::rates::RateTracker;
+    use crate::snapshot::SnapshotBuilder;
+
+    #[test]
+    fn rename_aggregate_and_drop() {
+        let config = ExporterConfig::from_yaml(
+            r#"
+exclude: ["^/if/names$"]
+rename:
+  - match: "^/err/(?P<node>[^/]+)/(?P<reason>.*)$"
+    name: vpp_errors_total
+threads:
+  - family: "^vpp_errors_total$"
+    aggregate: true
+drop_zero: true
+labels:
+  site: ams1
+"#,
+        )
+        .unwrap();
+        let encoder = FamilyEncoder::new(config).unwrap();
+        let snap = SnapshotBuilder::new(0.0)
+            .with_interfaces(&["local0"])
+            .with_simple("/if/drops", vec![vec![0, 4]])
+            .with_simple("/err/ip4-input/ttl expired", vec![vec![2], vec![3]])
+            .with_simple("/err/ip4-input/bad checksum", vec![vec![0], vec![0]])
+            .with_simple("/err/ip6-input/ttl expired", vec![vec![1], vec![0]])
+            .build();
+        assert_eq!(
+            encode_text(&encoder.families(&snap)),
+            "# TYPE _if_drops counter\n\
+             _if_drops{thread=\"0\",interface=\"1\",site=\"ams1\"} 4\n\
+             # TYPE vpp_errors_total counter\n\
+             vpp_errors_total{node=\"ip4-input\",reason=\"ttl expired\",interface=\"0\",site=\"ams1\"} 5\n\
+             vpp_errors_total{node=\"ip6-input\",reason=\"ttl expired\",interface=\"0\",site=\"ams1\"} 1\n"
+        );
+        assert_eq!(encoder.patterns(), vec![".*".to_string()]);
+    }
+
+    #[test]
+    fn rates_reshaped_like_the_counters() {
+        let config = ExporterConfig::from_yaml(
+            r#"
+rename:
+  - match: "^/err/(?P<node>[^/]+)/(?P<reason>.*)$"
+    name: vpp_errors_total
+aggregate_threads: true
+"#,
+        )
+        .unwrap();
+        let encoder = FamilyEncoder::new(config).unwrap();
+        let snap = |timestamp, a, b| {
+            SnapshotBuilder::new(timestamp)
+                .with_simple("/err/ip4-input/ttl expired", vec![vec![a], vec![b]])
+                .build()
+        };
+        let mut tracker = RateTracker::new();
+        assert!(tracker.update(&snap(10.0, 0, 10)).is_empty());
+        let rates = tracker.update(&snap(12.0, 4, 12));
+        assert_eq!(
+            encode_text(&encoder.rate_families(&rates)),
+            "# TYPE vpp_errors_total_per_second gauge\n\
+             vpp_errors_total_per_second{node=\"ip4-input\",reason=\"ttl expired\",interface=\"0\"} 3.00\n"
+        );
+    }
+
+    #[test]
+    fn bad_config() {
+        assert!(matches!(
+            ExporterConfig::from_yaml("includes: []"),
+            Err(ExporterConfigError::Parse(_))
+        ));
+        let config = ExporterConfig::with_patterns(&["(".to_string()]);
+        assert!(matches!(
+            FamilyEncoder::new(config),
+            Err(ExporterConfigError::BadRegex(_))
+        ));
+
+        let rename = |rules: &[(&str, &str)]| {
+            let rules: Vec<String> = rules
+                .iter()
+                .map(|(m, n)| format!("  - match: \"{}\"\n    name: {}\n", m, n))
+                .collect();
+            ExporterConfig::from_yaml(&format!("rename:\n{}", rules.concat()))
+        };
+        assert!(rename(&[("^/if/rx$", "vpp_if_rx")]).is_ok());
+        assert!(matches!(
+            rename(&[("^/if/rx$", "vpp-if-rx")]),
+            Err(ExporterConfigError::BadName(_))
+        ));
+        assert!(matches!(
+            rename(&[("^/if/(?P<thread>.*)$", "vpp_if")]),
+            Err(ExporterConfigError::Conflict(_))
+        ));
+        assert!(matches!(
+            rename(&[("^/if/rx$", "vpp_if"), ("^/if/tx$", "vpp_if_bytes")]),
+            Err(ExporterConfigError::Conflict(_))
+        ));
+        assert!(matches!(
+            rename(&[("^/if/rx$", "_if_tx")]),
+            Err(ExporterConfigError::Conflict(_))
+        ));
+        assert!(matches!(
+            ExporterConfig::from_yaml("labels:\n  interface: x\n"),
+            Err(ExporterConfigError::Conflict(_))
+        ));
+    }
+
+    #[test]
+    fn renamed_into_one_family() {
+        let config = ExporterConfig::from_yaml(
+            r#"
+rename:
+  - match: "^/err/ip4-input/"
+    name: vpp_ip4_errors_total
+"#,
+        )
+        .unwrap();
+        let encoder = FamilyEncoder::new(config).unwrap();
+        let snap = SnapshotBuilder::new(0.0)
+            .with_simple("/err/ip4-input/ttl expired", vec![vec![2]])
+            .with_simple("/err/ip4-input/bad checksum", vec![vec![3]])
+            .build();
+        /* No two samples with the same labels */
+        assert_eq!(
+            encode_text(&encoder.families(&snap)),
+            "# TYPE vpp_ip4_errors_total counter\n\
+             vpp_ip4_errors_total{thread=\"0\",interface=\"0\"} 5\n"
+        );
+    }
+}
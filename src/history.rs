@@ -0,0 +1,428 @@
+/*
+ * The last few minutes of the selected series, kept in memory for the
+ * live views and for the post-mortem when something fires.
+ *
+ * Every series has a ring of a fixed number of samples, retention divided
+ * by resolution. A snapshot which comes within the resolution of the last
+ * recorded one is skipped, so the ring covers the retention however often
+ * the stats are read. The oldest samples are overwritten once it is full.
+ * A series which has not been in the snapshots for the whole retention,
+ * e.g. of a deleted interface, is dropped along with its ring.
+ *
+ * The queries take a window of [start, end] in seconds since the epoch,
+ * and look either at the values themselves or at the per-second rates
+ * between the neighbouring samples. A counter which went down is taken
+ * to have restarted from zero, the same way as the deltas do.
+ */
+
+use crate::delta::{flatten, SeriesKey, SeriesValue};
+use crate::snapshot::StatSnapshot;
+use crate::source::PatternFilter;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HistoryError {
+    Io(String),
+    Parse(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Sample {
+    /// In seconds since the epoch
+    pub timestamp: f64,
+    pub value: f64,
+}
+
+/// What the summaries and the percentiles are taken over
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Measure {
+    Value,
+    Rate,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Summary {
+    pub count: usize,
+    pub min: f64,
+    pub max: f64,
+    pub avg: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Ring {
+    counter: bool,
+    samples: Vec<Sample>,
+    /* Where the next sample goes, once the ring is full */
+    next: usize,
+}
+
+impl Ring {
+    fn new(counter: bool, capacity: usize) -> Self {
+        Ring {
+            counter,
+            samples: Vec::with_capacity(capacity),
+            next: 0,
+        }
+    }
+
+    fn push(&mut self, sample: Sample, capacity: usize) {
+        if self.samples.len() < capacity {
+            self.samples.push(sample);
+        } else {
+            self.samples[self.next] = sample;
+            self.next = (self.next + 1) % capacity;
+        }
+    }
+
+    /// Oldest first
+    fn iter(&self) -> impl Iterator<Item = &Sample> {
+        let (newer, older) = self.samples.split_at(self.next);
+        older.iter().chain(newer.iter())
+    }
+
+    fn newest(&self) -> Option<&Sample> {
+        match self.next {
+            0 => self.samples.last(),
+            n => self.samples.get(n - 1),
+        }
+    }
+
+    fn window(&self, start: f64, end: f64) -> Vec<Sample> {
+        self.iter()
+            .filter(|s| s.timestamp >= start && s.timestamp <= end)
+            .copied()
+            .collect()
+    }
+
+    fn increase(&self, old: f64, new: f64) -> f64 {
+        if self.counter && new < old {
+            new
+        } else {
+            new - old
+        }
+    }
+
+    /// The rate between every two neighbouring samples, at the later one
+    fn rates(&self, start: f64, end: f64) -> Vec<Sample> {
+        self.window(start, end)
+            .windows(2)
+            .filter(|w| w[1].timestamp > w[0].timestamp)
+            .map(|w| Sample {
+                timestamp: w[1].timestamp,
+                value: self.increase(w[0].value, w[1].value) / (w[1].timestamp - w[0].timestamp),
+            })
+            .collect()
+    }
+}
+
+/// How the history is written out by dump()
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DumpedSeries {
+    pub key: SeriesKey,
+    pub counter: bool,
+    pub samples: Vec<Sample>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryDump {
+    /// In seconds
+    pub resolution: f64,
+    pub retention: f64,
+    pub series: Vec<DumpedSeries>,
+}
+
+#[derive(Debug, Clone)]
+pub struct History {
+    resolution: f64,
+    retention: f64,
+    patterns: Vec<String>,
+    filter: PatternFilter,
+    last: Option<f64>,
+    series: BTreeMap<SeriesKey, Ring>,
+}
+
+impl History {
+    pub fn new(resolution: Duration, retention: Duration) -> Self {
+        History {
+            resolution: resolution.as_secs_f64(),
+            retention: retention.as_secs_f64(),
+            patterns: vec![],
+            filter: PatternFilter::default(),
+            last: None,
+            series: BTreeMap::new(),
+        }
+    }
+
+    /// Only keep the stats matching any of the patterns, rather than everything
+    pub fn with_patterns(mut self, patterns: &[String]) -> Self {
+        self.patterns = patterns.to_vec();
+        self.filter = PatternFilter::new(patterns);
+        self
+    }
+
+    /// The stats to read for it
+    pub fn patterns(&self) -> &[String] {
+        &self.patterns
+    }
+
+    /// How far back the samples go, in seconds
+    pub fn retention(&self) -> f64 {
+        self.retention
+    }
+
+    /// How many samples every series keeps
+    pub fn capacity(&self) -> usize {
+        if self.resolution > 0.0 {
+            ((self.retention / self.resolution).ceil() as usize).max(1)
+        } else {
+            1
+        }
+    }
+
+    /// Add the values of the snapshot, unless it is too close to the last one
+    pub fn record(&mut self, snap: &StatSnapshot) {
+        if let Some(last) = self.last {
+            if snap.timestamp < last + self.resolution {
+                return;
+            }
+        }
+        self.last = Some(snap.timestamp);
+        let capacity = self.capacity();
+        for (key, value) in flatten(snap, false) {
+            if !self.filter.matches(&key.name) {
+                continue;
+            }
+            let counter = matches!(value, SeriesValue::Counter(_));
+            self.series
+                .entry(key)
+                .or_insert_with(|| Ring::new(counter, capacity))
+                .push(
+                    Sample {
+                        timestamp: snap.timestamp,
+                        value: value.as_f64(),
+                    },
+                    capacity,
+                );
+        }
+        let oldest = snap.timestamp - self.retention;
+        self.series.retain(|_, ring| {
+            ring.newest()
+                .map(|s| s.timestamp >= oldest)
+                .unwrap_or(false)
+        });
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &SeriesKey> {
+        self.series.keys()
+    }
+
+    /// The samples within the window, oldest first
+    pub fn range(&self, key: &SeriesKey, start: f64, end: f64) -> Vec<Sample> {
+        self.series
+            .get(key)
+            .map(|r| r.window(start, end))
+            .unwrap_or_default()
+    }
+
+    /// The per-second rates between the samples within the window, oldest first
+    pub fn rates(&self, key: &SeriesKey, start: f64, end: f64) -> Vec<Sample> {
+        self.series
+            .get(key)
+            .map(|r| r.rates(start, end))
+            .unwrap_or_default()
+    }
+
+    /// The average rate over the window, from its first to its last sample
+    pub fn rate(&self, key: &SeriesKey, start: f64, end: f64) -> Option<f64> {
+        let ring = self.series.get(key)?;
+        let samples = ring.window(start, end);
+        let (first, last) = (samples.first()?, samples.last()?);
+        let elapsed = last.timestamp - first.timestamp;
+        if elapsed <= 0.0 {
+            return None;
+        }
+        let increase: f64 = samples
+            .windows(2)
+            .map(|w| ring.increase(w[0].value, w[1].value))
+            .sum();
+        Some(increase / elapsed)
+    }
+
+    fn measured(&self, key: &SeriesKey, start: f64, end: f64, measure: Measure) -> Vec<f64> {
+        let samples = match measure {
+            Measure::Value => self.range(key, start, end),
+            Measure::Rate => self.rates(key, start, end),
+        };
+        samples.into_iter().map(|s| s.value).collect()
+    }
+
+    /// The minimum, maximum and average over the window, None if it is empty
+    pub fn summary(
+        &self,
+        key: &SeriesKey,
+        start: f64,
+        end: f64,
+        measure: Measure,
+    ) -> Option<Summary> {
+        let values = self.measured(key, start, end, measure);
+        if values.is_empty() {
+            return None;
+        }
+        Some(Summary {
+            count: values.len(),
+            min: values.iter().copied().fold(f64::INFINITY, f64::min),
+            max: values.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+            avg: values.iter().sum::<f64>() / values.len() as f64,
+        })
+    }
+
+    /// The nearest-rank percentile, p from 0 to 100
+    pub fn percentile(
+        &self,
+        key: &SeriesKey,
+        start: f64,
+        end: f64,
+        measure: Measure,
+        p: f64,
+    ) -> Option<f64> {
+        let mut values = self.measured(key, start, end, measure);
+        if values.is_empty() {
+            return None;
+        }
+        values.sort_by(|a, b| a.total_cmp(b));
+        let rank = ((p.clamp(0.0, 100.0) / 100.0) * values.len() as f64).ceil() as usize;
+        Some(values[rank.max(1) - 1])
+    }
+
+    pub fn to_dump(&self) -> HistoryDump {
+        HistoryDump {
+            resolution: self.resolution,
+            retention: self.retention,
+            series: self
+                .series
+                .iter()
+                .map(|(key, ring)| DumpedSeries {
+                    key: key.clone(),
+                    counter: ring.counter,
+                    samples: ring.iter().copied().collect(),
+                })
+                .collect(),
+        }
+    }
+
+    pub fn from_dump(dump: &HistoryDump) -> Self {
+        let mut history = History {
+            resolution: dump.resolution,
+            retention: dump.retention,
+            patterns: vec![],
+            filter: PatternFilter::default(),
+            last: None,
+            series: BTreeMap::new(),
+        };
+        let capacity = history.capacity();
+        for s in &dump.series {
+            let mut ring = Ring::new(s.counter, capacity);
+            for sample in &s.samples {
+                ring.push(*sample, capacity);
+                let last = history.last.unwrap_or(sample.timestamp);
+                history.last = Some(last.max(sample.timestamp));
+            }
+            history.series.insert(s.key.clone(), ring);
+        }
+        history
+    }
+
+    /// Write out all the series as JSON, e.g. when an alert fires
+    pub fn dump(&self, path: &str) -> Result<(), HistoryError> {
+        let data =
+            serde_json::to_vec(&self.to_dump()).map_err(|e| HistoryError::Parse(e.to_string()))?;
+        std::fs::write(path, data).map_err(|e| HistoryError::Io(format!("{}: {}", path, e)))
+    }
+
+    /// Read back a dump, for the queries after the fact
+    pub fn load(path: &str) -> Result<Self, HistoryError> {
+        let data = std::fs::read_to_string(path)
+            .map_err(|e| HistoryError::Io(format!("{}: {}", path, e)))?;
+        let dump: HistoryDump =
+            serde_json::from_str(&data).map_err(|e| HistoryError::Parse(e.to_string()))?;
+        Ok(History::from_dump(&dump))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::SnapshotBuilder;
+
+    fn snap(timestamp: f64, rx: u64) -> StatSnapshot {
+        SnapshotBuilder::new(timestamp)
+            .with_simple("/if/rx-miss", vec![vec![rx]])
+            .with_scalar("/sys/vector_rate", timestamp)
+            .build()
+    }
+
+    fn key() -> SeriesKey {
+        SeriesKey {
+            index: Some(0),
+            ..SeriesKey::scalar("/if/rx-miss")
+        }
+    }
+
+    #[test]
+    fn ring_wraps_and_skips() {
+        let mut h = History::new(Duration::from_secs(10), Duration::from_secs(40))
+            .with_patterns(&["^/if/".to_string()]);
+        assert_eq!(h.capacity(), 4);
+        for t in 0..=12 {
+            /* Every 5 seconds, half of them too close to the previous one */
+            h.record(&snap(t as f64 * 5.0, t * 100));
+        }
+        let samples = h.range(&key(), 0.0, 100.0);
+        let times: Vec<f64> = samples.iter().map(|s| s.timestamp).collect();
+        assert_eq!(times, vec![30.0, 40.0, 50.0, 60.0]);
+        assert_eq!(h.keys().count(), 1);
+        assert!(h
+            .range(&SeriesKey::scalar("/sys/vector_rate"), 0.0, 100.0)
+            .is_empty());
+    }
+
+    #[test]
+    fn queries() {
+        let mut h = History::new(Duration::from_secs(1), Duration::from_secs(60));
+        /* 10/s, then a reset, then 30/s */
+        for (t, rx) in [(0, 0), (1, 10), (2, 20), (3, 5), (4, 35), (5, 65)] {
+            h.record(&snap(t as f64, rx));
+        }
+        let k = key();
+        assert_eq!(h.rate(&k, 0.0, 5.0), Some(17.0));
+        let rates = h.summary(&k, 0.0, 5.0, Measure::Rate).unwrap();
+        assert_eq!((rates.min, rates.max, rates.count), (5.0, 30.0, 5));
+        let values = h.summary(&k, 2.0, 5.0, Measure::Value).unwrap();
+        assert_eq!((values.min, values.max, values.avg), (5.0, 65.0, 31.25));
+        assert_eq!(h.percentile(&k, 0.0, 5.0, Measure::Rate, 50.0), Some(10.0));
+        assert_eq!(h.percentile(&k, 0.0, 5.0, Measure::Rate, 100.0), Some(30.0));
+
+        let back = History::from_dump(&h.to_dump());
+        assert_eq!(back.range(&k, 0.0, 5.0), h.range(&k, 0.0, 5.0));
+    }
+
+    #[test]
+    fn gone_series_dropped() {
+        let mut h = History::new(Duration::from_secs(1), Duration::from_secs(10));
+        h.record(&snap(0.0, 1));
+        let other = |t| {
+            SnapshotBuilder::new(t)
+                .with_scalar("/sys/vector_rate", t)
+                .build()
+        };
+        h.record(&other(5.0));
+        assert_eq!(h.keys().count(), 2);
+        h.record(&other(10.0));
+        assert_eq!(h.keys().count(), 2);
+        h.record(&other(11.0));
+        let keys: Vec<&SeriesKey> = h.keys().collect();
+        assert_eq!(keys, vec![&SeriesKey::scalar("/sys/vector_rate")]);
+    }
+}
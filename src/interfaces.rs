@@ -0,0 +1,139 @@
+/*
+ * Per-interface view of the "/if/..." counters, summed across the threads.
+ */
+
+use crate::snapshot::{CombinedCounter, SnapshotValue, StatSnapshot};
+
+/// The patterns needed to fill in the interface counters
+pub const INTERFACE_PATTERNS: &[&str] = &["^/if/"];
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InterfaceCounters {
+    pub sw_if_index: u32,
+    pub name: Option<String>,
+    pub rx: CombinedCounter,
+    pub rx_unicast: CombinedCounter,
+    pub rx_multicast: CombinedCounter,
+    pub rx_broadcast: CombinedCounter,
+    pub tx: CombinedCounter,
+    pub tx_unicast: CombinedCounter,
+    pub tx_multicast: CombinedCounter,
+    pub tx_broadcast: CombinedCounter,
+    pub drops: u64,
+    pub rx_error: u64,
+    pub tx_error: u64,
+    pub rx_no_buf: u64,
+    pub rx_miss: u64,
+    pub punt: u64,
+}
+
+fn combined(snap: &StatSnapshot, name: &str, index: usize) -> Option<CombinedCounter> {
+    snap.get(name).and_then(|v| v.combined_total(index))
+}
+
+fn simple(snap: &StatSnapshot, name: &str, index: usize) -> u64 {
+    snap.get(name)
+        .and_then(|v| v.simple_total(index))
+        .unwrap_or(0)
+}
+
+fn minus(a: CombinedCounter, b: CombinedCounter) -> CombinedCounter {
+    CombinedCounter {
+        packets: a.packets.saturating_sub(b.packets),
+        bytes: a.bytes.saturating_sub(b.bytes),
+    }
+}
+
+impl InterfaceCounters {
+    /// Collect the counters of all the interfaces present in the snapshot.
+    /// If "/if/names" is there, the slots of the deleted interfaces are skipped.
+    pub fn collect(snap: &StatSnapshot) -> Vec<InterfaceCounters> {
+        let names = snap.interface_names();
+        let count = snap
+            .entries
+            .iter()
+            .filter(|e| e.name.starts_with("/if/"))
+            .map(|e| e.value.index_count())
+            .max()
+            .unwrap_or(0);
+
+        let mut out = vec![];
+        for i in 0..count {
+            let name = names.get(i).cloned().flatten();
+            if !names.is_empty() && name.is_none() {
+                continue;
+            }
+            let get = |n| combined(snap, n, i).unwrap_or_default();
+            let rx = get("/if/rx");
+            let tx = get("/if/tx");
+            let rx_multicast = get("/if/rx-multicast");
+            let rx_broadcast = get("/if/rx-broadcast");
+            let tx_multicast = get("/if/tx-multicast");
+            let tx_broadcast = get("/if/tx-broadcast");
+            /* Older VPP versions do not have the unicast counters */
+            let rx_unicast = combined(snap, "/if/rx-unicast", i)
+                .unwrap_or_else(|| minus(minus(rx, rx_multicast), rx_broadcast));
+            let tx_unicast = combined(snap, "/if/tx-unicast", i)
+                .unwrap_or_else(|| minus(minus(tx, tx_multicast), tx_broadcast));
+            out.push(InterfaceCounters {
+                sw_if_index: i as u32,
+                name,
+                rx,
+                rx_unicast,
+                rx_multicast,
+                rx_broadcast,
+                tx,
+                tx_unicast,
+                tx_multicast,
+                tx_broadcast,
+                drops: simple(snap, "/if/drops", i),
+                rx_error: simple(snap, "/if/rx-error", i),
+                tx_error: simple(snap, "/if/tx-error", i),
+                rx_no_buf: simple(snap, "/if/rx-no-buf", i),
+                rx_miss: simple(snap, "/if/rx-miss", i),
+                punt: simple(snap, "/if/punt", i),
+            });
+        }
+        out
+    }
+
+    pub fn display_name(&self) -> String {
+        match &self.name {
+            Some(n) => n.clone(),
+            None => format!("sw_if_index {}", self.sw_if_index),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::SnapshotBuilder;
+
+    fn c(packets: u64, bytes: u64) -> CombinedCounter {
+        CombinedCounter { packets, bytes }
+    }
+
+    #[test]
+    fn collect_sums_threads_and_skips_deleted() {
+        let snap = SnapshotBuilder::new(0.0)
+            .with_interfaces(&["local0", "", "eth0"])
+            .with_combined(
+                "/if/rx",
+                vec![
+                    vec![(0, 0), (0, 0), (10, 1000)],
+                    vec![(0, 0), (0, 0), (5, 500)],
+                ],
+            )
+            .with_combined("/if/rx-broadcast", vec![vec![(0, 0), (0, 0), (1, 60)]])
+            .with_simple("/if/drops", vec![vec![0, 0, 3], vec![0, 0, 4]])
+            .build();
+        let ifs = InterfaceCounters::collect(&snap);
+        assert_eq!(ifs.len(), 2);
+        assert_eq!(ifs[1].sw_if_index, 2);
+        assert_eq!(ifs[1].name.as_deref(), Some("eth0"));
+        assert_eq!(ifs[1].rx, c(15, 1500));
+        assert_eq!(ifs[1].rx_unicast, c(14, 1440));
+        assert_eq!(ifs[1].drops, 7);
+    }
+}
@@ -7,6 +7,31 @@
 #[macro_use]
 pub mod macros; /* Handy macros */
 
+pub mod agentx;
+pub mod alerts;
+pub mod anomaly;
+pub mod check;
+pub mod delta;
+pub mod diff;
+pub mod discovery;
+pub mod exporter_config;
+pub mod history;
+pub mod interfaces;
+pub mod monotonic;
+pub mod otlp;
+pub mod pipeline;
+pub mod prometheus;
+pub mod protobuf;
+pub mod rates;
+pub mod recording;
+pub mod remote_write;
+pub mod segment;
+pub mod sflow;
+pub mod snapshot;
+pub mod source;
+pub mod testing;
+pub mod top;
+
 // use std;
 use std::fmt;
 use std::fmt::{Debug, Error, Formatter};
@@ -231,6 +256,9 @@ where
 
 pub struct VppStatClient {
     stat_client_ptr: *mut sys::stat_client_main_t,
+    read_stats: std::cell::Cell<source::ReadStats>,
+    /* Mapped by from_segment_fd(), with no socket connection behind it */
+    from_file: bool,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -240,6 +268,10 @@ pub enum VppStatError {
     ReceivingFdFailed,
     MmapFstatFailed,
     MmapMapFailed,
+    CouldNotOpenFile,
+    SegmentTooSmall,
+    SegmentUpdateInProgress,
+    BadDirectoryPointer,
 }
 
 pub struct VppStringVec {
@@ -431,15 +463,19 @@ impl VppStatClient {
             clib_mem_init(std::ptr::null_mut(), 64000000);
         }
     }
-    pub fn connect(path: &str) -> Result<Self, VppStatError> {
-        use crate::VppStatError::*;
-        use sys::*;
-
+    pub(crate) fn init_default() {
         static START: std::sync::Once = std::sync::Once::new();
 
         START.call_once(|| {
             VppStatClient::init_once(None);
         });
+    }
+
+    pub fn connect(path: &str) -> Result<Self, VppStatError> {
+        use crate::VppStatError::*;
+        use sys::*;
+
+        VppStatClient::init_default();
 
         let sc = unsafe { stat_client_get() };
         let cpath = format!("{}\0", path);
@@ -448,6 +484,8 @@ impl VppStatClient {
         match rv {
             0 => Ok(VppStatClient {
                 stat_client_ptr: sc,
+                read_stats: Default::default(),
+                from_file: false,
             }),
             -1 => Err(CouldNotOpenSocket),
             -2 => Err(CouldNotConnect),
@@ -462,6 +500,12 @@ impl VppStatClient {
         unsafe { stat_segment_heartbeat_r(self.stat_client_ptr) }
     }
 
+    pub(crate) fn count_read(&self, f: impl FnOnce(&mut source::ReadStats)) {
+        let mut stats = self.read_stats.get();
+        f(&mut stats);
+        self.read_stats.set(stats);
+    }
+
     pub fn ls(&self, patterns: Option<&VppStringVec>) -> VppStatDir {
         let patterns = if let Some(v) = patterns {
             v.vvec_ptr
@@ -469,6 +513,7 @@ impl VppStatClient {
             std::ptr::null_mut()
         };
         let dir_ptr = unsafe { stat_segment_ls_r(patterns, self.stat_client_ptr) };
+        self.count_read(|s| s.ls_count += 1);
         let dir = vv2slice(dir_ptr);
         VppStatDir {
             client: &self,
@@ -481,7 +526,13 @@ impl VppStatClient {
 impl Drop for VppStatClient {
     fn drop(&mut self) {
         unsafe {
-            stat_segment_disconnect_r(self.stat_client_ptr);
+            if self.from_file {
+                /* Only unmapped, the disconnect is for the clients which did connect */
+                let sm = &*self.stat_client_ptr;
+                libc::munmap(sm.shared_header as _, sm.memory_size as usize);
+            } else {
+                stat_segment_disconnect_r(self.stat_client_ptr);
+            }
             stat_client_free(self.stat_client_ptr);
         }
     }
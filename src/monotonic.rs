@@ -0,0 +1,334 @@
+/*
+ * Counters which keep going up across the VPP restarts, for the consumers
+ * which can not deal with the resets themselves, e.g. the billing.
+ *
+ * Every series keeps an offset, which is added to the value read from the
+ * stat segment. When the series is reset, the last value seen before the
+ * reset is added to the offset. A reset is either:
+ *   - a new stats segment, i.e. "/sys/boottime" changed,
+ *   - the heartbeat going back, i.e. VPP restarted,
+ *   - the counter going down, e.g. the interface was re-created.
+ * After a restart all the series known so far are treated as reset,
+ * including the ones which are not in the current snapshot.
+ *
+ * The "/if/" series are tracked by the interface name when "/if/names" is
+ * in the snapshot, so an interface which comes back under a different
+ * sw_if_index after the restart carries on from where it was. Such series
+ * are to be exported with the interface name among their labels, see
+ * FamilyEncoder::with_interface_names(), else a label set keyed by the index
+ * alone would jump between the interfaces.
+ *
+ * The offsets can be saved into a state file, such that the counters also
+ * survive the restarts of whatever is doing the reading.
+ */
+
+use crate::delta::CounterField;
+use crate::snapshot::{is_interface_stat, CombinedCounter, SnapshotValue, StatSnapshot};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// The stats needed for the reset detection, besides the counters themselves
+pub const MONOTONIC_PATTERNS: &[&str] = &["^/sys/boottime$", "^/if/names$"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MonotonicError {
+    Io(String),
+    Parse(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum CounterObject {
+    Index(usize),
+    Interface(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct CounterId {
+    name: String,
+    thread: usize,
+    object: CounterObject,
+    field: CounterField,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Offset {
+    /* The value read last time, before adding the offset */
+    last: u64,
+    offset: u64,
+}
+
+/* How a series is kept in the state file */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct SavedSeries {
+    name: String,
+    thread: usize,
+    object: CounterObject,
+    field: CounterField,
+    last: u64,
+    offset: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+struct SavedCounters {
+    heartbeat: Option<f64>,
+    boottime: Option<f64>,
+    series: Vec<SavedSeries>,
+}
+
+/// The offsets of all the series of one VPP instance
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(from = "SavedCounters", into = "SavedCounters")]
+pub struct MonotonicCounters {
+    heartbeat: Option<f64>,
+    boottime: Option<f64>,
+    series: BTreeMap<CounterId, Offset>,
+}
+
+impl From<SavedCounters> for MonotonicCounters {
+    fn from(saved: SavedCounters) -> Self {
+        MonotonicCounters {
+            heartbeat: saved.heartbeat,
+            boottime: saved.boottime,
+            series: saved
+                .series
+                .into_iter()
+                .map(|s| {
+                    let id = CounterId {
+                        name: s.name,
+                        thread: s.thread,
+                        object: s.object,
+                        field: s.field,
+                    };
+                    let offset = Offset {
+                        last: s.last,
+                        offset: s.offset,
+                    };
+                    (id, offset)
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<MonotonicCounters> for SavedCounters {
+    fn from(counters: MonotonicCounters) -> Self {
+        SavedCounters {
+            heartbeat: counters.heartbeat,
+            boottime: counters.boottime,
+            series: counters
+                .series
+                .into_iter()
+                .map(|(id, o)| SavedSeries {
+                    name: id.name,
+                    thread: id.thread,
+                    object: id.object,
+                    field: id.field,
+                    last: o.last,
+                    offset: o.offset,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl MonotonicCounters {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    fn adjust(&mut self, id: CounterId, value: u64) -> u64 {
+        let o = self.series.entry(id).or_default();
+        if value < o.last {
+            o.offset = o.offset.wrapping_add(o.last);
+        }
+        o.last = value;
+        value.wrapping_add(o.offset)
+    }
+
+    /// The snapshot with the offsets added to the counters; the scalars
+    /// and the names are left as they are.
+    pub fn update(&mut self, snap: &StatSnapshot) -> StatSnapshot {
+        let boottime = snap.scalar("/sys/boottime");
+        let new_segment = matches!((self.boottime, boottime), (Some(a), Some(b)) if a != b);
+        let went_back = matches!(self.heartbeat, Some(h) if snap.heartbeat < h);
+        if new_segment || went_back {
+            for o in self.series.values_mut() {
+                o.offset = o.offset.wrapping_add(o.last);
+                o.last = 0;
+            }
+        }
+        self.heartbeat = Some(snap.heartbeat);
+        if boottime.is_some() {
+            self.boottime = boottime;
+        }
+
+        let names = snap.interface_names().to_vec();
+        let mut out = snap.clone();
+        for entry in out.entries.iter_mut() {
+            let by_name = is_interface_stat(&entry.name);
+            let object = |index: usize| match names.get(index).cloned().flatten() {
+                Some(n) if by_name => CounterObject::Interface(n),
+                _ => CounterObject::Index(index),
+            };
+            let id = |thread: usize, index: usize, field: CounterField| CounterId {
+                name: entry.name.clone(),
+                thread,
+                object: object(index),
+                field,
+            };
+            match &mut entry.value {
+                SnapshotValue::Simple(threads) => {
+                    for (t, values) in threads.iter_mut().enumerate() {
+                        for (i, v) in values.iter_mut().enumerate() {
+                            *v = self.adjust(id(t, i, CounterField::Value), *v);
+                        }
+                    }
+                }
+                SnapshotValue::Combined(threads) => {
+                    for (t, values) in threads.iter_mut().enumerate() {
+                        for (i, c) in values.iter_mut().enumerate() {
+                            *c = CombinedCounter {
+                                packets: self.adjust(id(t, i, CounterField::Packets), c.packets),
+                                bytes: self.adjust(id(t, i, CounterField::Bytes), c.bytes),
+                            };
+                        }
+                    }
+                }
+                SnapshotValue::Scalar(_) | SnapshotValue::Names(_) | SnapshotValue::Empty => {}
+            }
+        }
+        out
+    }
+}
+
+/// The counters of all the instances, as kept in the state file
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MonotonicState {
+    pub instances: BTreeMap<String, MonotonicCounters>,
+}
+
+impl MonotonicState {
+    /// Read the state file; a file which is not there yet gives the empty state
+    pub fn load(path: &str) -> Result<Self, MonotonicError> {
+        let data = match std::fs::read_to_string(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Default::default()),
+            Err(e) => return Err(MonotonicError::Io(format!("{}: {}", path, e))),
+        };
+        serde_json::from_str(&data).map_err(|e| MonotonicError::Parse(e.to_string()))
+    }
+
+    /// Write the state file under a temporary name and then rename it into place,
+    /// so a crash in the middle does not lose the offsets
+    pub fn save(&self, path: &str) -> Result<(), MonotonicError> {
+        let data = serde_json::to_vec(self).map_err(|e| MonotonicError::Parse(e.to_string()))?;
+        let tmp_path = format!("{}.tmp", path);
+        std::fs::write(&tmp_path, data)
+            .and_then(|_| std::fs::rename(&tmp_path, path))
+            .map_err(|e| MonotonicError::Io(format!("{}: {}", path, e)))
+    }
+
+    /// Take out the counters of the instance, new ones if it is not known
+    pub fn take(&mut self, instance: &str) -> MonotonicCounters {
+        self.instances.remove(instance).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exporter_config::{ExporterConfig, FamilyEncoder};
+    use crate::prometheus::{MetricType, SampleValue};
+    use crate::snapshot::SnapshotBuilder;
+    use std::collections::HashMap;
+
+    fn snap(heartbeat: f64, boottime: f64, names: &[&str], drops: Vec<u64>) -> StatSnapshot {
+        SnapshotBuilder::new(heartbeat)
+            .with_scalar("/sys/boottime", boottime)
+            .with_interfaces(names)
+            .with_simple("/if/drops", vec![drops])
+            .build()
+    }
+
+    fn drops(m: &mut MonotonicCounters, s: &StatSnapshot) -> Vec<u64> {
+        match m.update(s).get("/if/drops") {
+            Some(SnapshotValue::Simple(v)) => v[0].clone(),
+            _ => vec![],
+        }
+    }
+
+    #[test]
+    fn counters_survive_resets() {
+        let mut m = MonotonicCounters::new();
+        assert_eq!(
+            drops(&mut m, &snap(10.0, 100.0, &["a", "b"], vec![5, 50])),
+            vec![5, 50]
+        );
+        /* "a" re-created, so its counter started over */
+        assert_eq!(
+            drops(&mut m, &snap(11.0, 100.0, &["a", "b"], vec![2, 60])),
+            vec![7, 60]
+        );
+        /* VPP restarted and already counted past the old values, with "b" at index 0 now */
+        assert_eq!(
+            drops(&mut m, &snap(50.0, 200.0, &["b", "a"], vec![70, 9])),
+            vec![130, 16]
+        );
+
+        /* The reader restarted, and so did VPP meanwhile */
+        let path = std::env::temp_dir().join(format!("vpp-monotonic-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut state = MonotonicState::default();
+        state.instances.insert("vpp1".to_string(), m);
+        state.save(path).unwrap();
+        let mut m = MonotonicState::load(path).unwrap().take("vpp1");
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(
+            drops(&mut m, &snap(3.0, 300.0, &["a", "b"], vec![1, 1])),
+            vec![17, 131]
+        );
+    }
+
+    /* Whether any series came out lower than it was before, over the snapshots */
+    fn any_decrease(encoder: &FamilyEncoder, snaps: &[StatSnapshot]) -> bool {
+        let mut m = MonotonicCounters::new();
+        let mut seen: HashMap<(String, Vec<(String, String)>), u64> = HashMap::new();
+        let mut decreased = false;
+        for s in snaps {
+            for family in encoder.families(&m.update(s)) {
+                if family.metric_type != MetricType::Counter {
+                    continue;
+                }
+                for sample in family.samples {
+                    if let SampleValue::Int(v) = sample.value {
+                        let prev = seen.insert((family.name.clone(), sample.labels), v);
+                        decreased |= prev.map(|p| v < p).unwrap_or(false);
+                    }
+                }
+            }
+        }
+        decreased
+    }
+
+    #[test]
+    fn encoded_series_never_decrease() {
+        let snaps = [
+            snap(10.0, 100.0, &["a", "b"], vec![5, 50]),
+            snap(11.0, 100.0, &["a", "b"], vec![2, 60]),
+            /* Restarted, with the interfaces at the other indices */
+            snap(50.0, 200.0, &["b", "a"], vec![70, 9]),
+            snap(51.0, 200.0, &["b", "a", "c"], vec![71, 10, 3]),
+            /* "a" deleted */
+            snap(52.0, 200.0, &["b", "", "c"], vec![72, 0, 4]),
+            snap(5.0, 300.0, &["a", "b"], vec![1, 1]),
+        ];
+        let encoder = FamilyEncoder::new(ExporterConfig::default()).unwrap();
+        assert!(!any_decrease(
+            &encoder.clone().with_interface_names(true),
+            &snaps
+        ));
+        /* By the index alone, the offsets of the interfaces which moved get mixed up */
+        assert!(any_decrease(&encoder, &snaps));
+    }
+}
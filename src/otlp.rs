@@ -0,0 +1,346 @@
+/*
+ * Export the stat segment data as OpenTelemetry metrics,
+ * pushed to a collector over OTLP/HTTP with the protobuf encoding.
+ *
+ * The counters become monotonic cumulative sums, the scalars
+ * (/sys/..., buffer pools, etc.) and the memory stats become gauges.
+ */
+
+use crate::protobuf::ProtoWriter;
+use crate::snapshot::{is_interface_stat, SnapshotValue, StatSnapshot};
+
+pub const OTLP_METRICS_PATH: &str = "/v1/metrics";
+
+const AGGREGATION_TEMPORALITY_CUMULATIVE: u64 = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OtlpError {
+    RequestFailed(String),
+    HttpStatus(i32),
+}
+
+enum AttrValue {
+    Str(String),
+    Int(i64),
+}
+
+enum PointValue {
+    Int(u64),
+    Double(f64),
+}
+
+struct DataPoint {
+    attrs: Vec<(&'static str, AttrValue)>,
+    value: PointValue,
+}
+
+#[derive(PartialEq)]
+enum MetricKind {
+    Gauge,
+    Sum,
+}
+
+pub fn otlp_metric_name(path: &str, suffix: Option<&str>) -> String {
+    let mut out = "vpp".to_string();
+    for c in path.chars() {
+        match c {
+            '/' => out.push('.'),
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-' => out.push(c),
+            _ => out.push('_'),
+        }
+    }
+    if let Some(suffix) = suffix {
+        out.push('.');
+        out.push_str(suffix);
+    }
+    out
+}
+
+fn write_key_value(w: &mut ProtoWriter, field: u32, key: &str, value: &AttrValue) {
+    w.message(field, |kv| {
+        kv.string(1, key);
+        kv.message(2, |any| match value {
+            AttrValue::Str(s) => any.string(1, s),
+            AttrValue::Int(i) => any.int64(3, *i),
+        });
+    });
+}
+
+pub struct OtlpExporter {
+    url: String,
+    resource: Vec<(String, String)>,
+    start_time: f64,
+    timeout: u64,
+}
+
+impl OtlpExporter {
+    /// The endpoint is the collector base URL, e.g. "http://127.0.0.1:4318"
+    pub fn new(endpoint: &str) -> Self {
+        let url = if endpoint.ends_with(OTLP_METRICS_PATH) {
+            endpoint.to_string()
+        } else {
+            format!("{}{}", endpoint.trim_end_matches('/'), OTLP_METRICS_PATH)
+        };
+        OtlpExporter {
+            url,
+            resource: vec![("service.name".to_string(), "vpp".to_string())],
+            start_time: crate::snapshot::unix_time_now(),
+            timeout: 10,
+        }
+    }
+
+    /// Add or replace a resource attribute, e.g. "service.instance.id"
+    pub fn with_resource_attribute(mut self, key: &str, value: &str) -> Self {
+        self.resource.retain(|(k, _)| k != key);
+        self.resource.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn with_timeout(mut self, seconds: u64) -> Self {
+        self.timeout = seconds;
+        self
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    fn vector_points<T, F>(
+        snap: &StatSnapshot,
+        name: &str,
+        threads: &[Vec<T>],
+        value: F,
+    ) -> Vec<DataPoint>
+    where
+        F: Fn(&T) -> PointValue,
+    {
+        let if_stat = is_interface_stat(name);
+        let mut points = vec![];
+        for (k, thread) in threads.iter().enumerate() {
+            for (j, v) in thread.iter().enumerate() {
+                let mut attrs = vec![("thread", AttrValue::Int(k as i64))];
+                if if_stat {
+                    attrs.push(("sw_if_index", AttrValue::Int(j as i64)));
+                    if let Some(ifname) = snap.interface_name(j) {
+                        attrs.push(("interface", AttrValue::Str(ifname.to_string())));
+                    }
+                } else {
+                    attrs.push(("index", AttrValue::Int(j as i64)));
+                }
+                points.push(DataPoint {
+                    attrs,
+                    value: value(v),
+                });
+            }
+        }
+        points
+    }
+
+    fn write_metric(
+        &self,
+        w: &mut ProtoWriter,
+        snap: &StatSnapshot,
+        start_ns: u64,
+        (name, description, unit): (&str, &str, &str),
+        kind: MetricKind,
+        points: &[DataPoint],
+    ) {
+        let time_ns = (snap.timestamp * 1e9) as u64;
+        let write_points = |data: &mut ProtoWriter| {
+            for p in points {
+                data.message(1, |dp| {
+                    for (key, value) in &p.attrs {
+                        write_key_value(dp, 7, key, value);
+                    }
+                    if kind == MetricKind::Sum {
+                        dp.fixed64(2, start_ns);
+                    }
+                    dp.fixed64(3, time_ns);
+                    match p.value {
+                        PointValue::Double(v) => dp.double(4, v),
+                        PointValue::Int(v) => dp.fixed64(6, v),
+                    }
+                });
+            }
+        };
+        w.message(2, |m| {
+            m.string(1, name);
+            m.string(2, description);
+            if !unit.is_empty() {
+                m.string(3, unit);
+            }
+            match kind {
+                MetricKind::Gauge => m.message(5, write_points),
+                MetricKind::Sum => m.message(7, |sum| {
+                    write_points(sum);
+                    sum.varint(2, AGGREGATION_TEMPORALITY_CUMULATIVE);
+                    sum.bool(3, true);
+                }),
+            }
+        });
+    }
+
+    /// Build the ExportMetricsServiceRequest message for the snapshot
+    pub fn encode(&self, snap: &StatSnapshot) -> Vec<u8> {
+        /* The counters start when VPP starts, if it tells us when that was */
+        let start_time = match snap.scalar("/sys/boottime") {
+            Some(boottime) if boottime > 0.0 => boottime,
+            _ => self.start_time,
+        };
+        let start_ns = (start_time * 1e9) as u64;
+
+        let mut req = ProtoWriter::new();
+        req.message(1, |rm| {
+            rm.message(1, |res| {
+                for (key, value) in &self.resource {
+                    write_key_value(res, 1, key, &AttrValue::Str(value.clone()));
+                }
+            });
+            rm.message(2, |sm| {
+                sm.message(1, |scope| {
+                    scope.string(1, env!("CARGO_PKG_NAME"));
+                    scope.string(2, env!("CARGO_PKG_VERSION"));
+                });
+                for entry in &snap.entries {
+                    let path = entry.name.as_str();
+                    let vector_kind = if path.starts_with("/mem/") {
+                        MetricKind::Gauge
+                    } else {
+                        MetricKind::Sum
+                    };
+                    match &entry.value {
+                        SnapshotValue::Scalar(val) => {
+                            let points = [DataPoint {
+                                attrs: vec![],
+                                value: PointValue::Double(*val),
+                            }];
+                            let name = otlp_metric_name(path, None);
+                            let desc = (name.as_str(), path, "");
+                            self.write_metric(sm, snap, start_ns, desc, MetricKind::Gauge, &points);
+                        }
+                        SnapshotValue::Simple(threads) => {
+                            let points =
+                                Self::vector_points(snap, path, threads, |v| PointValue::Int(*v));
+                            let name = otlp_metric_name(path, None);
+                            let desc = (name.as_str(), path, "");
+                            self.write_metric(sm, snap, start_ns, desc, vector_kind, &points);
+                        }
+                        SnapshotValue::Combined(threads) => {
+                            let points = Self::vector_points(snap, path, threads, |v| {
+                                PointValue::Int(v.packets)
+                            });
+                            let name = otlp_metric_name(path, Some("packets"));
+                            let desc = (name.as_str(), path, "{packets}");
+                            self.write_metric(sm, snap, start_ns, desc, MetricKind::Sum, &points);
+
+                            let points = Self::vector_points(snap, path, threads, |v| {
+                                PointValue::Int(v.bytes)
+                            });
+                            let name = otlp_metric_name(path, Some("bytes"));
+                            let desc = (name.as_str(), path, "By");
+                            self.write_metric(sm, snap, start_ns, desc, MetricKind::Sum, &points);
+                        }
+                        /* The names are only used to label the other metrics */
+                        SnapshotValue::Names(_) | SnapshotValue::Empty => {}
+                    }
+                }
+            });
+        });
+        req.into_bytes()
+    }
+
+    pub fn push(&self, snap: &StatSnapshot) -> Result<(), OtlpError> {
+        let body = self.encode(snap);
+        let resp = minreq::post(self.url.as_str())
+            .with_header("Content-Type", "application/x-protobuf")
+            .with_body(body)
+            .with_timeout(self.timeout)
+            .send()
+            .map_err(|e| OtlpError::RequestFailed(e.to_string()))?;
+        if (200..300).contains(&resp.status_code) {
+            Ok(())
+        } else {
+            Err(OtlpError::HttpStatus(resp.status_code))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::SnapshotBuilder;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    fn test_snapshot() -> StatSnapshot {
+        SnapshotBuilder::new(1000.0)
+            .with_heartbeat(42.0)
+            .with_interfaces(&["local0"])
+            .with_combined("/if/rx", vec![vec![(1, 64)]])
+            .with_scalar("/sys/vector_rate", 1.5)
+            .build()
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|w| w == needle)
+    }
+
+    #[test]
+    fn metric_names() {
+        assert_eq!(otlp_metric_name("/if/rx", Some("bytes")), "vpp.if.rx.bytes");
+        assert_eq!(
+            otlp_metric_name("/err/ip4-input/ip4 ttl <= 1", None),
+            "vpp.err.ip4-input.ip4_ttl____1"
+        );
+    }
+
+    #[test]
+    fn push_to_mock_collector() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let collector = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut content_type = String::new();
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim_end();
+                if line.is_empty() {
+                    break;
+                }
+                let (key, value) = line.split_once(':').unwrap();
+                match key.to_ascii_lowercase().as_str() {
+                    "content-type" => content_type = value.trim().to_string(),
+                    "content-length" => content_length = value.trim().parse().unwrap(),
+                    _ => {}
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            let mut stream = reader.into_inner();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            (request_line, content_type, body)
+        });
+
+        let exporter = OtlpExporter::new(&format!("http://{}", addr))
+            .with_resource_attribute("service.instance.id", "vpp-test");
+        let snap = test_snapshot();
+        exporter.push(&snap).unwrap();
+
+        let (request_line, content_type, body) = collector.join().unwrap();
+        assert!(request_line.starts_with("POST /v1/metrics "));
+        assert_eq!(content_type, "application/x-protobuf");
+        assert_eq!(body, exporter.encode(&snap));
+        assert!(contains(&body, b"vpp-test"));
+        assert!(contains(&body, b"vpp.if.rx.packets"));
+        assert!(contains(&body, b"local0"));
+        assert!(contains(&body, b"vpp.sys.vector_rate"));
+        assert!(!contains(&body, b"vpp.if.names"));
+    }
+}
@@ -0,0 +1,743 @@
+/*
+ * The polling loop shared by the exporters, so a new output only needs
+ * to implement the Sink:
+ *
+ *   let mut pipeline = Pipeline::new(Box::new(client))
+ *       .with_patterns(&["^/if/".to_string()])
+ *       .with_transform(Box::new(Rates::new(false)))
+ *       .with_transform(Box::new(InterfaceNames))
+ *       .with_sink(Box::new(TextSink::new(std::io::stdout(), TextFormat::Influx)));
+ *   pipeline.run(Duration::from_secs(10));
+ *
+ * Every poll reads a snapshot from the source, turns it into the metric
+ * families and runs them through the transforms, in the order they were
+ * added. The result is queued for every sink separately, and handed over
+ * once the batch size of them are there. A sink which fails temporarily gets
+ * the same batches again with the next ones, up to the max queue, after
+ * which the oldest are dropped; the other sinks are not held up by it.
+ *
+ * With the monotonic counters, the resets are detected on the stats read
+ * along, which are then left out again, same as the exporter does.
+ */
+
+use crate::exporter_config::{
+    check_family_name, sum_samples, sum_threads, ExporterConfigError, FamilyEncoder,
+};
+use crate::monotonic::{MonotonicCounters, MONOTONIC_PATTERNS};
+use crate::prometheus::{encode_text, families, prom_str, MetricType, PromFamily, SampleValue};
+use crate::rates::{self, RateTracker};
+use crate::snapshot::{is_interface_stat, StatSnapshot};
+use crate::source::{PatternFilter, SourceError, StatSource};
+use regex::Regex;
+use std::collections::{HashMap, VecDeque};
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_BATCH_SIZE: usize = 1;
+pub const DEFAULT_MAX_QUEUE: usize = 100;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PipelineError {
+    BadRegex(String),
+    /// A rename the exporter config would refuse as well
+    BadRename(ExporterConfigError),
+    /// The source has no more data, e.g. the end of a recording
+    EndOfSource,
+    Fetch(SourceError),
+    /// The stats were read and queued, but a sink failed
+    Send(SinkError),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SinkError {
+    /// Worth retrying, the batches are kept
+    Temporary(String),
+    /// The batches will never be accepted, they are dropped
+    Permanent(String),
+}
+
+/// The families of one poll, after the transforms
+#[derive(Debug, Clone, PartialEq)]
+pub struct Batch {
+    /// When the stats were read, in seconds since the epoch
+    pub timestamp: f64,
+    pub heartbeat: f64,
+    pub families: Vec<PromFamily>,
+}
+
+pub trait Transform {
+    /// Rework the families made from the snapshot, which is there for the context
+    fn apply(&mut self, snap: &StatSnapshot, families: Vec<PromFamily>) -> Vec<PromFamily>;
+}
+
+pub trait Sink {
+    /// Send out the batches, oldest first
+    fn send(&mut self, batches: &[Batch]) -> Result<(), SinkError>;
+}
+
+fn compile(patterns: &[String]) -> Result<Vec<Regex>, PipelineError> {
+    patterns
+        .iter()
+        .map(|p| Regex::new(p).map_err(|e| PipelineError::BadRegex(e.to_string())))
+        .collect()
+}
+
+/// Keep the families whose names match any of the include regexes
+/// (all if there are none) and none of the exclude ones
+pub struct Filter {
+    include: Vec<Regex>,
+    exclude: Vec<Regex>,
+}
+
+impl Filter {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self, PipelineError> {
+        Ok(Filter {
+            include: compile(include)?,
+            exclude: compile(exclude)?,
+        })
+    }
+}
+
+impl Transform for Filter {
+    fn apply(&mut self, _snap: &StatSnapshot, mut families: Vec<PromFamily>) -> Vec<PromFamily> {
+        families.retain(|f| {
+            (self.include.is_empty() || self.include.iter().any(|re| re.is_match(&f.name)))
+                && !self.exclude.iter().any(|re| re.is_match(&f.name))
+        });
+        families
+    }
+}
+
+/// Rename the families by the regex, the replacement can refer to the groups as $1 or $name.
+/// The first matching rule wins. The names are checked the same as by the exporter config:
+/// the plain ones up front, the ones using the groups once renamed, the family staying as it
+/// was if its new name does not pass. The families renamed into one are merged and summed.
+pub struct Rename {
+    rules: Vec<(Regex, String)>,
+}
+
+impl Rename {
+    pub fn new(rules: &[(String, String)]) -> Result<Self, PipelineError> {
+        let mut names: HashMap<&str, &str> = HashMap::new();
+        for (pattern, name) in rules.iter().filter(|(_, name)| !name.contains('$')) {
+            check_family_name(name).map_err(PipelineError::BadRename)?;
+            if let Some(other) = names.insert(name, pattern) {
+                return Err(PipelineError::BadRename(ExporterConfigError::Conflict(
+                    format!(
+                        "both \"{}\" and \"{}\" give the family {}",
+                        other, pattern, name
+                    ),
+                )));
+            }
+        }
+        let rules = rules
+            .iter()
+            .map(|(p, r)| {
+                Regex::new(p)
+                    .map(|re| (re, r.clone()))
+                    .map_err(|e| PipelineError::BadRegex(e.to_string()))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Rename { rules })
+    }
+}
+
+impl Transform for Rename {
+    fn apply(&mut self, _snap: &StatSnapshot, families: Vec<PromFamily>) -> Vec<PromFamily> {
+        let mut out: Vec<PromFamily> = vec![];
+        let mut renamed: HashMap<String, usize> = HashMap::new();
+        for mut family in families {
+            let name = self
+                .rules
+                .iter()
+                .find(|(re, _)| re.is_match(&family.name))
+                .map(|(re, replacement)| re.replace(&family.name, replacement.as_str()))
+                .filter(|name| check_family_name(name).is_ok())
+                .map(|name| name.into_owned());
+            match name {
+                Some(name) => match renamed.get(&name) {
+                    Some(i) => out[*i].samples.append(&mut family.samples),
+                    None => {
+                        renamed.insert(name.clone(), out.len());
+                        family.name = name;
+                        out.push(family);
+                    }
+                },
+                None => out.push(family),
+            }
+        }
+        for i in renamed.values() {
+            out[*i].samples = sum_samples(&out[*i].samples, |_| true);
+        }
+        out
+    }
+}
+
+/// Sum the matching families across the threads, dropping the "thread" label
+pub struct AggregateThreads {
+    families: Regex,
+}
+
+impl AggregateThreads {
+    pub fn new(families: &str) -> Result<Self, PipelineError> {
+        Ok(AggregateThreads {
+            families: Regex::new(families).map_err(|e| PipelineError::BadRegex(e.to_string()))?,
+        })
+    }
+}
+
+impl Transform for AggregateThreads {
+    fn apply(&mut self, _snap: &StatSnapshot, mut families: Vec<PromFamily>) -> Vec<PromFamily> {
+        for family in families.iter_mut() {
+            if self.families.is_match(&family.name) {
+                family.samples = sum_threads(&family.samples);
+            }
+        }
+        families
+    }
+}
+
+/// Add the per-second rate gauges, optionally instead of the counters. The rates are
+/// of all the counters in the snapshot, so only the transforms after this one apply to them.
+pub struct Rates {
+    tracker: RateTracker,
+    keep_counters: bool,
+    encoder: Option<FamilyEncoder>,
+}
+
+impl Rates {
+    pub fn new(keep_counters: bool) -> Self {
+        Rates {
+            tracker: RateTracker::new(),
+            keep_counters,
+            encoder: None,
+        }
+    }
+
+    /// Reshape the rates the same way the encoder does the counters
+    pub fn with_encoder(mut self, encoder: FamilyEncoder) -> Self {
+        self.encoder = Some(encoder);
+        self
+    }
+}
+
+impl Transform for Rates {
+    fn apply(&mut self, snap: &StatSnapshot, mut families: Vec<PromFamily>) -> Vec<PromFamily> {
+        let rates = self.tracker.update(snap);
+        if !self.keep_counters {
+            families.retain(|f| f.metric_type != MetricType::Counter);
+        }
+        families.append(&mut match &self.encoder {
+            Some(encoder) => encoder.rate_families(&rates),
+            None => rates::families(&rates),
+        });
+        families
+    }
+}
+
+/// Add the "name" label to the samples of the "/if/" families, from "/if/names"
+pub struct InterfaceNames;
+
+impl Transform for InterfaceNames {
+    fn apply(&mut self, snap: &StatSnapshot, mut families: Vec<PromFamily>) -> Vec<PromFamily> {
+        let interface_families: Vec<String> = snap
+            .entries
+            .iter()
+            .filter(|e| is_interface_stat(&e.name))
+            .map(|e| prom_str(&e.name))
+            .collect();
+        for family in families.iter_mut() {
+            if !interface_families
+                .iter()
+                .any(|f| family.name.starts_with(f))
+            {
+                continue;
+            }
+            for sample in family.samples.iter_mut() {
+                let name = sample
+                    .labels
+                    .iter()
+                    .find(|(k, _)| k == "interface")
+                    .and_then(|(_, v)| v.parse::<usize>().ok())
+                    .and_then(|i| snap.interface_name(i));
+                if let Some(name) = name {
+                    sample.labels.push(("name".to_string(), name.to_string()));
+                }
+            }
+        }
+        families
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextFormat {
+    /// The Prometheus text exposition format, one block per batch
+    Prometheus,
+    /// One JSON object per sample and line
+    JsonLines,
+    /// The InfluxDB line protocol, with the family as the measurement
+    Influx,
+}
+
+/// Write the batches out as text, e.g. to the stdout or to a file
+pub struct TextSink<W: Write> {
+    writer: W,
+    format: TextFormat,
+}
+
+impl<W: Write> TextSink<W> {
+    pub fn new(writer: W, format: TextFormat) -> Self {
+        TextSink { writer, format }
+    }
+}
+
+fn escape_influx(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, ',' | '=' | ' ' | '\\') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Encode the batch in one of the text formats
+pub fn encode_batch(batch: &Batch, format: TextFormat) -> String {
+    match format {
+        TextFormat::Prometheus => encode_text(&batch.families),
+        TextFormat::JsonLines => {
+            let mut out = String::new();
+            for family in &batch.families {
+                for sample in &family.samples {
+                    let labels: serde_json::Map<String, serde_json::Value> = sample
+                        .labels
+                        .iter()
+                        .map(|(k, v)| (k.clone(), serde_json::Value::from(v.as_str())))
+                        .collect();
+                    let value = match sample.value {
+                        SampleValue::Int(v) => serde_json::Value::from(v),
+                        SampleValue::Float(v) | SampleValue::Precise(v) => {
+                            serde_json::Value::from(v)
+                        }
+                    };
+                    let line = serde_json::json!({
+                        "timestamp": batch.timestamp,
+                        "name": family.name,
+                        "labels": labels,
+                        "value": value,
+                    });
+                    out.push_str(&line.to_string());
+                    out.push('\n');
+                }
+            }
+            out
+        }
+        TextFormat::Influx => {
+            let ns = (batch.timestamp * 1e9) as i64;
+            let mut out = String::new();
+            for family in &batch.families {
+                for sample in &family.samples {
+                    out.push_str(&escape_influx(&family.name));
+                    for (k, v) in &sample.labels {
+                        out.push_str(&format!(",{}={}", escape_influx(k), escape_influx(v)));
+                    }
+                    let value = match sample.value {
+                        SampleValue::Int(v) => format!("{}i", v),
+                        SampleValue::Float(v) | SampleValue::Precise(v) => format!("{}", v),
+                    };
+                    out.push_str(&format!(" value={} {}\n", value, ns));
+                }
+            }
+            out
+        }
+    }
+}
+
+impl<W: Write> Sink for TextSink<W> {
+    fn send(&mut self, batches: &[Batch]) -> Result<(), SinkError> {
+        for batch in batches {
+            self.writer
+                .write_all(encode_batch(batch, self.format).as_bytes())
+                .map_err(|e| SinkError::Temporary(e.to_string()))?;
+        }
+        self.writer
+            .flush()
+            .map_err(|e| SinkError::Temporary(e.to_string()))
+    }
+}
+
+/// How the polls and the sends went so far
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PipelineStats {
+    pub polls: u64,
+    pub fetch_errors: u64,
+    pub sink_errors: u64,
+    /// The batches given up on, because of the full queue or a permanent error
+    pub dropped: u64,
+}
+
+struct SinkQueue {
+    sink: Box<dyn Sink>,
+    queue: VecDeque<Batch>,
+}
+
+pub struct Pipeline {
+    source: Box<dyn StatSource>,
+    patterns: Vec<String>,
+    /* For the stats the monotonic counters read along */
+    filter: PatternFilter,
+    encoder: Option<FamilyEncoder>,
+    monotonic: Option<MonotonicCounters>,
+    transforms: Vec<Box<dyn Transform>>,
+    sinks: Vec<SinkQueue>,
+    batch_size: usize,
+    max_queue: usize,
+    stats: PipelineStats,
+}
+
+impl Pipeline {
+    pub fn new(source: Box<dyn StatSource>) -> Self {
+        Pipeline {
+            source,
+            patterns: vec![],
+            filter: PatternFilter::new(&[]),
+            encoder: None,
+            monotonic: None,
+            transforms: vec![],
+            sinks: vec![],
+            batch_size: DEFAULT_BATCH_SIZE,
+            max_queue: DEFAULT_MAX_QUEUE,
+            stats: Default::default(),
+        }
+    }
+
+    /// The stats to read, everything by default
+    pub fn with_patterns(mut self, patterns: &[String]) -> Self {
+        self.patterns = patterns.to_vec();
+        self.filter = PatternFilter::new(patterns);
+        self
+    }
+
+    /// Make the families the same way the exporter does, with its patterns
+    pub fn with_encoder(mut self, encoder: FamilyEncoder) -> Self {
+        self.patterns = encoder.patterns();
+        self.filter = PatternFilter::new(&self.patterns);
+        self.encoder = Some(encoder);
+        self
+    }
+
+    /// Keep the counters going up across the resets, starting from the given ones.
+    /// The encoder should then label the interface names, see with_interface_names().
+    pub fn with_monotonic(mut self, counters: MonotonicCounters) -> Self {
+        self.monotonic = Some(counters);
+        self
+    }
+
+    /// The monotonic counters so far, e.g. to save them
+    pub fn monotonic(&self) -> Option<&MonotonicCounters> {
+        self.monotonic.as_ref()
+    }
+
+    pub fn with_transform(mut self, transform: Box<dyn Transform>) -> Self {
+        self.transforms.push(transform);
+        self
+    }
+
+    pub fn with_sink(mut self, sink: Box<dyn Sink>) -> Self {
+        self.sinks.push(SinkQueue {
+            sink,
+            queue: VecDeque::new(),
+        });
+        self
+    }
+
+    /// How many polls to hand over to the sinks at once
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// How many batches to hold for a sink which is failing
+    pub fn with_max_queue(mut self, max_queue: usize) -> Self {
+        self.max_queue = max_queue.max(1);
+        self
+    }
+
+    pub fn stats(&self) -> PipelineStats {
+        self.stats
+    }
+
+    /// Read the stats once and queue the result for the sinks, sending
+    /// the queues which have filled up a batch
+    pub fn poll(&mut self) -> Result<(), PipelineError> {
+        self.stats.polls += 1;
+        let mut patterns = self.patterns.clone();
+        if self.monotonic.is_some() {
+            patterns.extend(MONOTONIC_PATTERNS.iter().map(|p| p.to_string()));
+        }
+        let snap = match self.source.fetch(&patterns) {
+            Ok(snap) => snap,
+            Err(SourceError::EndOfRecording) => return Err(PipelineError::EndOfSource),
+            Err(e) => {
+                self.stats.fetch_errors += 1;
+                return Err(PipelineError::Fetch(e));
+            }
+        };
+        let names = snap.interface_names().to_vec();
+        let snap = match self.monotonic.as_mut() {
+            Some(counters) => self.filter.apply(&counters.update(&snap)),
+            None => snap,
+        };
+        let mut out = match &self.encoder {
+            Some(encoder) => encoder.families_with_names(&snap, &names),
+            None => families(&snap),
+        };
+        for transform in self.transforms.iter_mut() {
+            out = transform.apply(&snap, out);
+        }
+        let batch = Batch {
+            timestamp: snap.timestamp,
+            heartbeat: snap.heartbeat,
+            families: out,
+        };
+        for s in self.sinks.iter_mut() {
+            while s.queue.len() >= self.max_queue {
+                s.queue.pop_front();
+                self.stats.dropped += 1;
+            }
+            s.queue.push_back(batch.clone());
+        }
+        self.send(self.batch_size).map_err(PipelineError::Send)
+    }
+
+    /// Send whatever is queued, even if short of a batch
+    pub fn flush(&mut self) -> Result<(), SinkError> {
+        self.send(1)
+    }
+
+    /* Every sink is tried, the first error is the one returned */
+    fn send(&mut self, min: usize) -> Result<(), SinkError> {
+        let mut res = Ok(());
+        for s in self.sinks.iter_mut() {
+            if s.queue.len() < min {
+                continue;
+            }
+            let batches = s.queue.make_contiguous();
+            match s.sink.send(batches) {
+                Ok(()) => s.queue.clear(),
+                Err(e) => {
+                    self.stats.sink_errors += 1;
+                    if let SinkError::Permanent(_) = e {
+                        self.stats.dropped += s.queue.len() as u64;
+                        s.queue.clear();
+                    }
+                    if res.is_ok() {
+                        res = Err(e);
+                    }
+                }
+            }
+        }
+        res
+    }
+
+    /// Poll every interval until the source runs out, then flush the sinks.
+    /// The failed reads and sends are only counted, the next poll tries again.
+    pub fn run(&mut self, interval: Duration) {
+        loop {
+            let start = Instant::now();
+            if let Err(PipelineError::EndOfSource) = self.poll() {
+                let _ = self.flush();
+                return;
+            }
+            std::thread::sleep(interval.saturating_sub(start.elapsed()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exporter_config::ExporterConfig;
+    use crate::snapshot::SnapshotBuilder;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /* Gives out the snapshots one by one, then ends */
+    struct Snapshots(VecDeque<StatSnapshot>);
+
+    impl StatSource for Snapshots {
+        fn heartbeat(&self) -> f64 {
+            0.0
+        }
+
+        fn fetch(&mut self, _patterns: &[String]) -> Result<StatSnapshot, SourceError> {
+            self.0.pop_front().ok_or(SourceError::EndOfRecording)
+        }
+    }
+
+    /* Fails the first sends, and records what it got */
+    struct Flaky {
+        failures: usize,
+        got: Rc<RefCell<Vec<usize>>>,
+    }
+
+    impl Sink for Flaky {
+        fn send(&mut self, batches: &[Batch]) -> Result<(), SinkError> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(SinkError::Temporary("down".to_string()));
+            }
+            self.got.borrow_mut().push(batches.len());
+            Ok(())
+        }
+    }
+
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn snap(timestamp: f64, rx: u64) -> StatSnapshot {
+        SnapshotBuilder::new(timestamp)
+            .with_interfaces(&["eth0"])
+            .with_simple("/if/drops", vec![vec![rx], vec![rx]])
+            .build()
+    }
+
+    #[test]
+    fn transforms_and_retries() {
+        let source = Snapshots((0..5).map(|i| snap(i as f64, i * 10)).collect());
+        let got = Rc::new(RefCell::new(vec![]));
+        let out = Rc::new(RefCell::new(vec![]));
+        let mut pipeline = Pipeline::new(Box::new(source))
+            .with_transform(Box::new(Rates::new(false)))
+            .with_transform(Box::new(
+                Filter::new(&["^_if_".to_string()], &["names".to_string()]).unwrap(),
+            ))
+            .with_transform(Box::new(AggregateThreads::new(".*").unwrap()))
+            .with_transform(Box::new(InterfaceNames))
+            .with_sink(Box::new(Flaky {
+                failures: 2,
+                got: got.clone(),
+            }))
+            .with_sink(Box::new(TextSink::new(
+                Shared(out.clone()),
+                TextFormat::Influx,
+            )))
+            .with_batch_size(2)
+            .with_max_queue(3);
+        pipeline.run(Duration::from_millis(0));
+
+        /*
+         * The first two sends fail, the queue overflows by the fourth poll
+         * and the fifth is left for the flush at the end of the source
+         */
+        assert_eq!(*got.borrow(), vec![3, 1]);
+        assert_eq!(pipeline.stats().sink_errors, 2);
+        assert_eq!(pipeline.stats().dropped, 1);
+        assert_eq!(
+            String::from_utf8(out.borrow().clone())
+                .unwrap()
+                .lines()
+                .last()
+                .unwrap(),
+            "_if_drops_per_second,interface=0,name=eth0 value=20 4000000000"
+        );
+    }
+
+    #[test]
+    fn monotonic_counters_and_send_errors() {
+        let snap = |heartbeat, boottime, drops| {
+            SnapshotBuilder::new(heartbeat)
+                .with_scalar("/sys/boottime", boottime)
+                .with_interfaces(&["eth0"])
+                .with_simple("/if/drops", vec![vec![drops]])
+                .build()
+        };
+        /* VPP restarted in between */
+        let source = Snapshots(vec![snap(1.0, 100.0, 5), snap(2.0, 200.0, 2)].into());
+        let encoder =
+            FamilyEncoder::new(ExporterConfig::with_patterns(&["^/if/drops$".to_string()]))
+                .unwrap()
+                .with_interface_names(true);
+        let got = Rc::new(RefCell::new(vec![]));
+        let out = Rc::new(RefCell::new(vec![]));
+        let mut pipeline = Pipeline::new(Box::new(source))
+            .with_encoder(encoder)
+            .with_monotonic(MonotonicCounters::new())
+            .with_sink(Box::new(Flaky {
+                failures: 1,
+                got: got.clone(),
+            }))
+            .with_sink(Box::new(TextSink::new(
+                Shared(out.clone()),
+                TextFormat::Prometheus,
+            )));
+        assert_eq!(
+            pipeline.poll(),
+            Err(PipelineError::Send(SinkError::Temporary(
+                "down".to_string()
+            )))
+        );
+        assert_eq!(pipeline.poll(), Ok(()));
+        assert_eq!(*got.borrow(), vec![2]);
+        /* The stats read for the resets are not exported */
+        assert_eq!(
+            String::from_utf8(out.borrow().clone()).unwrap(),
+            "# TYPE _if_drops counter\n\
+             _if_drops{thread=\"0\",interface=\"0\",name=\"eth0\"} 5\n\
+             # TYPE _if_drops counter\n\
+             _if_drops{thread=\"0\",interface=\"0\",name=\"eth0\"} 7\n"
+        );
+    }
+
+    #[test]
+    fn renames_checked_and_merged() {
+        let rename = |rules: &[(&str, &str)]| {
+            let rules: Vec<(String, String)> = rules
+                .iter()
+                .map(|(p, r)| (p.to_string(), r.to_string()))
+                .collect();
+            Rename::new(&rules)
+        };
+        assert!(matches!(
+            rename(&[("^_if_rx$", "vpp-if-rx")]),
+            Err(PipelineError::BadRename(ExporterConfigError::BadName(_)))
+        ));
+        assert!(matches!(
+            rename(&[("^_if_rx$", "_if_tx")]),
+            Err(PipelineError::BadRename(ExporterConfigError::Conflict(_)))
+        ));
+        assert!(matches!(
+            rename(&[("^_if_rx$", "vpp_if"), ("^_if_tx$", "vpp_if")]),
+            Err(PipelineError::BadRename(ExporterConfigError::Conflict(_)))
+        ));
+
+        let snap = SnapshotBuilder::new(0.0)
+            .with_simple("/err/ip4-input/ttl expired", vec![vec![2]])
+            .with_simple("/err/ip4-input/bad checksum", vec![vec![3]])
+            .with_simple("/err/ip6-input/ttl expired", vec![vec![4]])
+            .build();
+        let mut rename = rename(&[
+            ("^_err_ip4_input_.*$", "vpp_ip4_errors_total"),
+            /* Would clash with the stat paths, so left as it was */
+            ("^_err_ip6(_input_.*)$", "_err_ip4$1"),
+        ])
+        .unwrap();
+        assert_eq!(
+            encode_text(&rename.apply(&snap, families(&snap))),
+            "# TYPE vpp_ip4_errors_total counter\n\
+             vpp_ip4_errors_total{thread=\"0\",interface=\"0\"} 5\n\
+             # TYPE _err_ip6_input_ttl_expired counter\n\
+             _err_ip6_input_ttl_expired{thread=\"0\",interface=\"0\"} 4\n"
+        );
+    }
+}
@@ -0,0 +1,223 @@
+/*
+ * Mapping of the stat segment entries onto the Prometheus metric families.
+ *
+ * This is shared by the text exposition format served on /metrics
+ * and by the remote_write push mode, so both produce the same series.
+ */
+
+use crate::snapshot::{SnapshotEntry, SnapshotValue, StatSnapshot};
+use std::fmt::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricType {
+    Counter,
+    Gauge,
+}
+
+impl MetricType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MetricType::Counter => "counter",
+            MetricType::Gauge => "gauge",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SampleValue {
+    Int(u64),
+    Float(f64),
+    /// Written out in full, rather than rounded to the hundredths
+    Precise(f64),
+}
+
+impl SampleValue {
+    pub fn as_f64(&self) -> f64 {
+        match self {
+            SampleValue::Int(v) => *v as f64,
+            SampleValue::Float(v) | SampleValue::Precise(v) => *v,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PromSample {
+    pub labels: Vec<(String, String)>,
+    pub value: SampleValue,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PromFamily {
+    pub name: String,
+    pub metric_type: MetricType,
+    pub samples: Vec<PromSample>,
+}
+
+/// Replace the characters which are not valid in a metric name
+pub fn prom_str(s: &str) -> String {
+    s.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+fn escape_label_value(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            '\n' => out.push_str("\\n"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+fn vector_samples<T, F>(threads: &[Vec<T>], value: F) -> Vec<PromSample>
+where
+    F: Fn(&T) -> u64,
+{
+    let mut samples = vec![];
+    for (k, thread) in threads.iter().enumerate() {
+        for (j, v) in thread.iter().enumerate() {
+            samples.push(PromSample {
+                labels: vec![
+                    ("thread".to_string(), k.to_string()),
+                    ("interface".to_string(), j.to_string()),
+                ],
+                value: SampleValue::Int(value(v)),
+            });
+        }
+    }
+    samples
+}
+
+pub fn families(snap: &StatSnapshot) -> Vec<PromFamily> {
+    snap.entries.iter().flat_map(entry_families).collect()
+}
+
+/// The families for a single stat entry; the combined counters give two of them
+pub fn entry_families(entry: &SnapshotEntry) -> Vec<PromFamily> {
+    let mut out = vec![];
+    let name = prom_str(&entry.name);
+    match &entry.value {
+        SnapshotValue::Scalar(val) => out.push(PromFamily {
+            name,
+            metric_type: MetricType::Counter,
+            samples: vec![PromSample {
+                labels: vec![],
+                value: SampleValue::Float(*val),
+            }],
+        }),
+        SnapshotValue::Simple(threads) => out.push(PromFamily {
+            name,
+            metric_type: MetricType::Counter,
+            samples: vector_samples(threads, |v| *v),
+        }),
+        SnapshotValue::Combined(threads) => {
+            out.push(PromFamily {
+                name: format!("{}_packets", name),
+                metric_type: MetricType::Counter,
+                samples: vector_samples(threads, |v| v.packets),
+            });
+            out.push(PromFamily {
+                name: format!("{}_bytes", name),
+                metric_type: MetricType::Counter,
+                samples: vector_samples(threads, |v| v.bytes),
+            });
+        }
+        SnapshotValue::Names(names) => out.push(PromFamily {
+            name: format!("{}_info", name),
+            metric_type: MetricType::Gauge,
+            samples: names
+                .iter()
+                .enumerate()
+                .filter_map(|(k, n)| {
+                    n.as_ref().map(|n| PromSample {
+                        labels: vec![
+                            ("index".to_string(), k.to_string()),
+                            ("name".to_string(), n.clone()),
+                        ],
+                        value: SampleValue::Int(1),
+                    })
+                })
+                .collect(),
+        }),
+        SnapshotValue::Empty => {}
+    }
+    out
+}
+
+/// Encode the families in the Prometheus text exposition format
+pub fn encode_text(families: &[PromFamily]) -> String {
+    let mut out = "".to_string();
+    for family in families {
+        writeln!(
+            out,
+            "# TYPE {} {}",
+            family.name,
+            family.metric_type.as_str()
+        )
+        .unwrap();
+        for sample in &family.samples {
+            out.push_str(&family.name);
+            if !sample.labels.is_empty() {
+                let labels: Vec<String> = sample
+                    .labels
+                    .iter()
+                    .map(|(k, v)| format!("{}=\"{}\"", k, escape_label_value(v)))
+                    .collect();
+                write!(out, "{{{}}}", labels.join(",")).unwrap();
+            }
+            match sample.value {
+                SampleValue::Int(v) => writeln!(out, " {}", v).unwrap(),
+                SampleValue::Float(v) => writeln!(out, " {:.2}", v).unwrap(),
+                SampleValue::Precise(v) => writeln!(out, " {}", v).unwrap(),
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::SnapshotBuilder;
+
+    #[test]
+    fn text_exposition() {
+        let snap = SnapshotBuilder::new(0.0)
+            .with_scalar("/sys/vector_rate", 2.5)
+            .with_combined("/if/rx", vec![vec![(3, 180)]])
+            .with_interfaces(&["local0", ""])
+            .build();
+        let text = encode_text(&families(&snap));
+        assert_eq!(
+            text,
+            "# TYPE _sys_vector_rate counter\n\
+             _sys_vector_rate 2.50\n\
+             # TYPE _if_rx_packets counter\n\
+             _if_rx_packets{thread=\"0\",interface=\"0\"} 3\n\
+             # TYPE _if_rx_bytes counter\n\
+             _if_rx_bytes{thread=\"0\",interface=\"0\"} 180\n\
+             # TYPE _if_names_info gauge\n\
+             _if_names_info{index=\"0\",name=\"local0\"} 1\n"
+        );
+    }
+
+    #[test]
+    fn precise_not_rounded() {
+        let family = PromFamily {
+            name: "duration".to_string(),
+            metric_type: MetricType::Gauge,
+            samples: vec![PromSample {
+                labels: vec![],
+                value: SampleValue::Precise(0.000125),
+            }],
+        };
+        assert_eq!(
+            encode_text(&[family]),
+            "# TYPE duration gauge\nduration 0.000125\n"
+        );
+    }
+}
@@ -0,0 +1,104 @@
+/*
+ * A minimal protobuf encoder - just enough to build the few messages
+ * that the exporters need, without pulling in the whole code generation machinery.
+ */
+
+const WIRE_VARINT: u32 = 0;
+const WIRE_FIXED64: u32 = 1;
+const WIRE_LEN: u32 = 2;
+
+pub fn put_varint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct ProtoWriter {
+    buf: Vec<u8>,
+}
+
+impl ProtoWriter {
+    pub fn new() -> Self {
+        ProtoWriter { buf: vec![] }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    fn key(&mut self, field: u32, wire_type: u32) {
+        put_varint(&mut self.buf, ((field << 3) | wire_type) as u64);
+    }
+
+    pub fn varint(&mut self, field: u32, v: u64) {
+        self.key(field, WIRE_VARINT);
+        put_varint(&mut self.buf, v);
+    }
+
+    /* int64 fields are encoded as two's complement varints */
+    pub fn int64(&mut self, field: u32, v: i64) {
+        self.varint(field, v as u64);
+    }
+
+    pub fn bool(&mut self, field: u32, v: bool) {
+        self.varint(field, v as u64);
+    }
+
+    pub fn fixed64(&mut self, field: u32, v: u64) {
+        self.key(field, WIRE_FIXED64);
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn double(&mut self, field: u32, v: f64) {
+        self.fixed64(field, v.to_bits());
+    }
+
+    pub fn bytes(&mut self, field: u32, v: &[u8]) {
+        self.key(field, WIRE_LEN);
+        put_varint(&mut self.buf, v.len() as u64);
+        self.buf.extend_from_slice(v);
+    }
+
+    pub fn string(&mut self, field: u32, v: &str) {
+        self.bytes(field, v.as_bytes());
+    }
+
+    /// Encode an embedded message, filled in by the closure
+    pub fn message<F: FnOnce(&mut ProtoWriter)>(&mut self, field: u32, f: F) {
+        let mut sub = ProtoWriter::new();
+        f(&mut sub);
+        self.bytes(field, &sub.buf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_wire_format() {
+        let mut w = ProtoWriter::new();
+        w.varint(1, 300);
+        w.string(2, "hi");
+        w.message(3, |m| m.bool(1, true));
+        w.int64(4, -1);
+        assert_eq!(
+            w.into_bytes(),
+            vec![
+                0x08, 0xac, 0x02, 0x12, 0x02, b'h', b'i', 0x1a, 0x02, 0x08, 0x01, 0x20, 0xff, 0xff,
+                0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01
+            ]
+        );
+    }
+}
@@ -0,0 +1,163 @@
+/*
+ * The "_per_second" gauges next to the counters, for the consumers which
+ * can not do rate() themselves. Every snapshot is compared against the
+ * previous one, so the rates are over the interval between the scrapes.
+ *
+ * A series gives no rate for the interval in which it was reset: when the
+ * counter went down, when VPP was restarted (the heartbeat went back) or
+ * when the interface at its index was deleted and another one created.
+ * The latter needs "/if/names" in the snapshots.
+ *
+ * The rates come out as the families named and labelled the same as the
+ * counters they are of, so they can be reshaped the same way, and only
+ * then turned into the gauges.
+ */
+
+use crate::delta::{deltas, CounterField, SeriesDelta, SeriesValue};
+use crate::prometheus::{prom_str, MetricType, PromFamily, PromSample, SampleValue};
+use crate::snapshot::{is_interface_stat, StatSnapshot};
+
+pub const RATE_SUFFIX: &str = "_per_second";
+
+#[derive(Debug, Clone, Default)]
+pub struct RateTracker {
+    prev: Option<StatSnapshot>,
+}
+
+impl RateTracker {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// The per-thread changes of the counters since the previous call,
+    /// leaving out the ones which were reset. Nothing on the first call.
+    pub fn update(&mut self, snap: &StatSnapshot) -> Vec<SeriesDelta> {
+        let prev = match self.prev.replace(snap.clone()) {
+            Some(p) => p,
+            None => return vec![],
+        };
+        if snap.heartbeat < prev.heartbeat || snap.timestamp <= prev.timestamp {
+            return vec![];
+        }
+        let check_names = !snap.interface_names().is_empty();
+        deltas(&prev, snap, true)
+            .into_iter()
+            /* The scalars are not really counters */
+            .filter(|d| matches!(d.new, SeriesValue::Counter(_)) && !d.reset)
+            .filter(|d| {
+                !check_names
+                    || !is_interface_stat(&d.key.name)
+                    || d.key.interface_name(&prev) == d.key.interface_name(snap)
+            })
+            .collect()
+    }
+}
+
+/// The rates, as the families of every stat path, named and labelled
+/// the same as prometheus::entry_families() does the counters
+pub fn entry_rates(rates: &[SeriesDelta]) -> Vec<(String, Vec<PromFamily>)> {
+    let mut out: Vec<(String, Vec<PromFamily>)> = vec![];
+    /* The deltas come sorted by the stat path */
+    for d in rates {
+        if out.last().map(|(n, _)| n != &d.key.name).unwrap_or(true) {
+            out.push((d.key.name.clone(), vec![]));
+        }
+        let families = &mut out.last_mut().unwrap().1;
+        let name = match d.key.field {
+            CounterField::Value => prom_str(&d.key.name),
+            CounterField::Packets => format!("{}_packets", prom_str(&d.key.name)),
+            CounterField::Bytes => format!("{}_bytes", prom_str(&d.key.name)),
+        };
+        let sample = PromSample {
+            labels: vec![
+                ("thread".to_string(), d.key.thread.unwrap_or(0).to_string()),
+                (
+                    "interface".to_string(),
+                    d.key.index.unwrap_or(0).to_string(),
+                ),
+            ],
+            value: SampleValue::Float(d.rate),
+        };
+        match families.iter_mut().find(|f| f.name == name) {
+            Some(f) => f.samples.push(sample),
+            None => families.push(PromFamily {
+                name,
+                metric_type: MetricType::Gauge,
+                samples: vec![sample],
+            }),
+        }
+    }
+    out
+}
+
+/// Turn the families of the rates into the "_per_second" gauges
+pub fn rate_gauges(families: Vec<PromFamily>) -> Vec<PromFamily> {
+    families
+        .into_iter()
+        .map(|f| PromFamily {
+            name: format!("{}{}", f.name, RATE_SUFFIX),
+            metric_type: MetricType::Gauge,
+            samples: f.samples,
+        })
+        .collect()
+}
+
+/// The gauges of the rates, not reshaped in any way
+pub fn families(rates: &[SeriesDelta]) -> Vec<PromFamily> {
+    rate_gauges(
+        entry_rates(rates)
+            .into_iter()
+            .flat_map(|(_, f)| f)
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prometheus::encode_text;
+    use crate::snapshot::SnapshotBuilder;
+
+    fn snap(timestamp: f64, heartbeat: f64, names: &[&str], drops: Vec<u64>) -> StatSnapshot {
+        SnapshotBuilder::new(timestamp)
+            .with_heartbeat(heartbeat)
+            .with_interfaces(names)
+            .with_simple("/if/drops", vec![drops])
+            .build()
+    }
+
+    fn rates(t: &mut RateTracker, s: &StatSnapshot) -> String {
+        encode_text(&families(&t.update(s)))
+    }
+
+    #[test]
+    fn resets_give_no_rate() {
+        let mut t = RateTracker::new();
+        assert_eq!(
+            rates(&mut t, &snap(10.0, 1.0, &["a", "b"], vec![0, 100])),
+            ""
+        );
+        assert_eq!(
+            rates(&mut t, &snap(12.0, 3.0, &["a", "b"], vec![4, 150])),
+            "# TYPE _if_drops_per_second gauge\n\
+             _if_drops_per_second{thread=\"0\",interface=\"0\"} 2.00\n\
+             _if_drops_per_second{thread=\"0\",interface=\"1\"} 25.00\n"
+        );
+        /* "b" deleted and "c" created in its place, with the counter already past the old one */
+        assert_eq!(
+            rates(&mut t, &snap(14.0, 5.0, &["a", "c"], vec![6, 200])),
+            "# TYPE _if_drops_per_second gauge\n\
+             _if_drops_per_second{thread=\"0\",interface=\"0\"} 1.00\n"
+        );
+        /* VPP restarted */
+        assert_eq!(
+            rates(&mut t, &snap(16.0, 1.0, &["a", "c"], vec![8, 210])),
+            ""
+        );
+        assert_eq!(
+            rates(&mut t, &snap(18.0, 3.0, &["a", "c"], vec![4, 220])),
+            "# TYPE _if_drops_per_second gauge\n\
+             _if_drops_per_second{thread=\"0\",interface=\"1\"} 5.00\n"
+        );
+    }
+}
@@ -0,0 +1,567 @@
+/*
+ * Recording of the stats into a file, and replaying them back.
+ *
+ * The file is the magic followed by the frames, one per snapshot. Each frame
+ * is length-prefixed, so a frame cut short by a crash is simply ignored.
+ * The names are written once and then referred to by their id, and the
+ * counters are written as the zigzag varint of the difference from the same
+ * counter in the previous frame, which is mostly zero or small.
+ *
+ * All the integers are varints, the floats are little endian f64.
+ */
+
+use crate::protobuf::put_varint;
+use crate::snapshot::{CombinedCounter, SnapshotEntry, SnapshotValue, StatSnapshot};
+use crate::source::{PatternFilter, SourceError, StatSource};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::time::{Duration, Instant};
+
+const MAGIC: &[u8; 8] = b"VPPSREC1";
+
+const TAG_EMPTY: u8 = 0;
+const TAG_SCALAR: u8 = 1;
+const TAG_SIMPLE: u8 = 2;
+const TAG_COMBINED: u8 = 3;
+const TAG_NAMES: u8 = 4;
+const TAG_NAMES_UNCHANGED: u8 = 5;
+
+fn zigzag(v: i64) -> u64 {
+    ((v << 1) ^ (v >> 63)) as u64
+}
+
+fn unzigzag(v: u64) -> i64 {
+    ((v >> 1) as i64) ^ -((v & 1) as i64)
+}
+
+fn bad(msg: &str) -> SourceError {
+    SourceError::BadRecording(msg.to_string())
+}
+
+struct FrameReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> FrameReader<'a> {
+    fn u8(&mut self) -> Result<u8, SourceError> {
+        let b = *self
+            .data
+            .get(self.pos)
+            .ok_or_else(|| bad("truncated frame"))?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn varint(&mut self) -> Result<u64, SourceError> {
+        let mut v: u64 = 0;
+        for shift in (0..64).step_by(7) {
+            let b = self.u8()?;
+            v |= ((b & 0x7f) as u64) << shift;
+            if b & 0x80 == 0 {
+                return Ok(v);
+            }
+        }
+        Err(bad("varint too long"))
+    }
+
+    fn usize(&mut self) -> Result<usize, SourceError> {
+        let v = self.varint()? as usize;
+        /* Every element takes at least a byte, anything larger is garbage */
+        if v > self.data.len() {
+            return Err(bad("bad length"));
+        }
+        Ok(v)
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], SourceError> {
+        if self.pos + n > self.data.len() {
+            return Err(bad("truncated frame"));
+        }
+        let out = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(out)
+    }
+
+    fn f64(&mut self) -> Result<f64, SourceError> {
+        let b: [u8; 8] = self.bytes(8)?.try_into().unwrap();
+        Ok(f64::from_le_bytes(b))
+    }
+
+    fn string(&mut self) -> Result<String, SourceError> {
+        let len = self.usize()?;
+        Ok(String::from_utf8_lossy(self.bytes(len)?).into_owned())
+    }
+}
+
+/* The state shared by the encoder and the decoder to do the deltas */
+#[derive(Default)]
+struct CodecState {
+    names: Vec<String>,
+    ids: HashMap<String, u64>,
+    prev: HashMap<u64, SnapshotValue>,
+    last_ts_us: i64,
+}
+
+fn prev_at<T: Copy + Default>(prev: Option<&Vec<Vec<T>>>, t: usize, i: usize) -> T {
+    prev.and_then(|p| p.get(t))
+        .and_then(|p| p.get(i))
+        .copied()
+        .unwrap_or_default()
+}
+
+impl CodecState {
+    fn encode_frame(&mut self, snap: &StatSnapshot) -> Vec<u8> {
+        let mut buf = vec![];
+        let ts_us = (snap.timestamp * 1e6) as i64;
+        put_varint(&mut buf, zigzag(ts_us - self.last_ts_us));
+        self.last_ts_us = ts_us;
+        buf.extend_from_slice(&snap.heartbeat.to_le_bytes());
+        put_varint(&mut buf, snap.entries.len() as u64);
+
+        for entry in &snap.entries {
+            let id = match self.ids.get(&entry.name) {
+                Some(id) => {
+                    put_varint(&mut buf, *id);
+                    *id
+                }
+                None => {
+                    /* First use of the name, the id is implicitly the next one */
+                    let id = self.names.len() as u64;
+                    put_varint(&mut buf, id);
+                    put_varint(&mut buf, entry.name.len() as u64);
+                    buf.extend_from_slice(entry.name.as_bytes());
+                    self.names.push(entry.name.clone());
+                    self.ids.insert(entry.name.clone(), id);
+                    id
+                }
+            };
+            let prev = self.prev.get(&id);
+            match &entry.value {
+                SnapshotValue::Empty => buf.push(TAG_EMPTY),
+                SnapshotValue::Scalar(v) => {
+                    buf.push(TAG_SCALAR);
+                    buf.extend_from_slice(&v.to_le_bytes());
+                }
+                SnapshotValue::Simple(threads) => {
+                    let prev = match prev {
+                        Some(SnapshotValue::Simple(p)) => Some(p),
+                        _ => None,
+                    };
+                    buf.push(TAG_SIMPLE);
+                    put_varint(&mut buf, threads.len() as u64);
+                    for (t, thread) in threads.iter().enumerate() {
+                        put_varint(&mut buf, thread.len() as u64);
+                        for (i, v) in thread.iter().enumerate() {
+                            let d = v.wrapping_sub(prev_at(prev, t, i));
+                            put_varint(&mut buf, zigzag(d as i64));
+                        }
+                    }
+                }
+                SnapshotValue::Combined(threads) => {
+                    let prev = match prev {
+                        Some(SnapshotValue::Combined(p)) => Some(p),
+                        _ => None,
+                    };
+                    buf.push(TAG_COMBINED);
+                    put_varint(&mut buf, threads.len() as u64);
+                    for (t, thread) in threads.iter().enumerate() {
+                        put_varint(&mut buf, thread.len() as u64);
+                        for (i, v) in thread.iter().enumerate() {
+                            let p = prev_at(prev, t, i);
+                            put_varint(&mut buf, zigzag(v.packets.wrapping_sub(p.packets) as i64));
+                            put_varint(&mut buf, zigzag(v.bytes.wrapping_sub(p.bytes) as i64));
+                        }
+                    }
+                }
+                SnapshotValue::Names(names) => {
+                    if prev == Some(&entry.value) {
+                        buf.push(TAG_NAMES_UNCHANGED);
+                    } else {
+                        buf.push(TAG_NAMES);
+                        put_varint(&mut buf, names.len() as u64);
+                        for n in names {
+                            match n {
+                                None => put_varint(&mut buf, 0),
+                                Some(n) => {
+                                    put_varint(&mut buf, n.len() as u64 + 1);
+                                    buf.extend_from_slice(n.as_bytes());
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            self.prev.insert(id, entry.value.clone());
+        }
+        buf
+    }
+
+    fn decode_frame(&mut self, data: &[u8]) -> Result<StatSnapshot, SourceError> {
+        let mut r = FrameReader { data, pos: 0 };
+        self.last_ts_us += unzigzag(r.varint()?);
+        let heartbeat = r.f64()?;
+        let count = r.usize()?;
+        let mut entries = Vec::with_capacity(count);
+
+        for _e in 0..count {
+            let id = r.varint()?;
+            if id == self.names.len() as u64 {
+                let name = r.string()?;
+                self.ids.insert(name.clone(), id);
+                self.names.push(name);
+            }
+            let name = self
+                .names
+                .get(id as usize)
+                .ok_or_else(|| bad("unknown name id"))?
+                .clone();
+            let prev = self.prev.get(&id);
+            let value = match r.u8()? {
+                TAG_EMPTY => SnapshotValue::Empty,
+                TAG_SCALAR => SnapshotValue::Scalar(r.f64()?),
+                TAG_SIMPLE => {
+                    let prev = match prev {
+                        Some(SnapshotValue::Simple(p)) => Some(p),
+                        _ => None,
+                    };
+                    let mut threads = vec![];
+                    for t in 0..r.usize()? {
+                        let mut thread = vec![];
+                        for i in 0..r.usize()? {
+                            let d = unzigzag(r.varint()?) as u64;
+                            thread.push(prev_at(prev, t, i).wrapping_add(d));
+                        }
+                        threads.push(thread);
+                    }
+                    SnapshotValue::Simple(threads)
+                }
+                TAG_COMBINED => {
+                    let prev = match prev {
+                        Some(SnapshotValue::Combined(p)) => Some(p),
+                        _ => None,
+                    };
+                    let mut threads = vec![];
+                    for t in 0..r.usize()? {
+                        let mut thread = vec![];
+                        for i in 0..r.usize()? {
+                            let p = prev_at(prev, t, i);
+                            let dp = unzigzag(r.varint()?) as u64;
+                            let db = unzigzag(r.varint()?) as u64;
+                            thread.push(CombinedCounter {
+                                packets: p.packets.wrapping_add(dp),
+                                bytes: p.bytes.wrapping_add(db),
+                            });
+                        }
+                        threads.push(thread);
+                    }
+                    SnapshotValue::Combined(threads)
+                }
+                TAG_NAMES => {
+                    let mut names = vec![];
+                    for _i in 0..r.usize()? {
+                        let len = r.usize()?;
+                        if len == 0 {
+                            names.push(None);
+                        } else {
+                            let s = r.bytes(len - 1)?;
+                            names.push(Some(String::from_utf8_lossy(s).into_owned()));
+                        }
+                    }
+                    SnapshotValue::Names(names)
+                }
+                TAG_NAMES_UNCHANGED => match prev {
+                    Some(v @ SnapshotValue::Names(_)) => v.clone(),
+                    _ => return Err(bad("unchanged names without the previous ones")),
+                },
+                _ => return Err(bad("unknown value tag")),
+            };
+            self.prev.insert(id, value.clone());
+            entries.push(SnapshotEntry { name, value });
+        }
+        Ok(StatSnapshot {
+            timestamp: self.last_ts_us as f64 / 1e6,
+            heartbeat,
+            entries,
+        })
+    }
+}
+
+/* Read one length-prefixed frame, None on the end of data or on a partial frame */
+fn read_frame<R: Read>(input: &mut R) -> Result<Option<Vec<u8>>, SourceError> {
+    let mut len: u64 = 0;
+    for shift in (0..64).step_by(7) {
+        let mut b = [0u8; 1];
+        match input.read(&mut b) {
+            Ok(0) => return Ok(None),
+            Ok(_) => {}
+            Err(e) => return Err(SourceError::BadRecording(e.to_string())),
+        }
+        len |= ((b[0] & 0x7f) as u64) << shift;
+        if b[0] & 0x80 == 0 {
+            break;
+        }
+    }
+    let mut frame = vec![];
+    input
+        .take(len)
+        .read_to_end(&mut frame)
+        .map_err(|e| SourceError::BadRecording(e.to_string()))?;
+    if (frame.len() as u64) < len {
+        return Ok(None);
+    }
+    Ok(Some(frame))
+}
+
+pub struct RecordingReader<R: Read> {
+    input: R,
+    state: CodecState,
+    /* Bytes of the complete frames consumed so far, including the magic */
+    consumed: u64,
+}
+
+impl RecordingReader<BufReader<File>> {
+    pub fn open(path: &str) -> Result<Self, SourceError> {
+        let f = File::open(path).map_err(|e| SourceError::BadRecording(e.to_string()))?;
+        RecordingReader::new(BufReader::new(f))
+    }
+}
+
+impl<R: Read> RecordingReader<R> {
+    pub fn new(mut input: R) -> Result<Self, SourceError> {
+        let mut magic = [0u8; 8];
+        input
+            .read_exact(&mut magic)
+            .map_err(|_| bad("not a stats recording"))?;
+        if &magic != MAGIC {
+            return Err(bad("not a stats recording"));
+        }
+        Ok(RecordingReader {
+            input,
+            state: Default::default(),
+            consumed: MAGIC.len() as u64,
+        })
+    }
+
+    pub fn next_frame(&mut self) -> Result<Option<StatSnapshot>, SourceError> {
+        match read_frame(&mut self.input)? {
+            None => Ok(None),
+            Some(frame) => {
+                let snap = self.state.decode_frame(&frame)?;
+                let mut len_buf = vec![];
+                put_varint(&mut len_buf, frame.len() as u64);
+                self.consumed += (len_buf.len() + frame.len()) as u64;
+                Ok(Some(snap))
+            }
+        }
+    }
+}
+
+impl<R: Read> Iterator for RecordingReader<R> {
+    type Item = Result<StatSnapshot, SourceError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_frame().transpose()
+    }
+}
+
+pub struct Recorder {
+    out: BufWriter<File>,
+    state: CodecState,
+}
+
+impl Recorder {
+    /// Start a new recording, replacing the file if it exists
+    pub fn create(path: &str) -> std::io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(MAGIC)?;
+        out.flush()?;
+        Ok(Recorder {
+            out,
+            state: Default::default(),
+        })
+    }
+
+    /// Continue an existing recording, or start a new one if there is none.
+    /// A partially written last frame is cut off.
+    pub fn append(path: &str) -> std::io::Result<Self> {
+        let existing = match File::open(path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Recorder::create(path),
+            Err(e) => return Err(e),
+        };
+        let mut reader = RecordingReader::new(BufReader::new(existing)).map_err(|e| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{:?}", e))
+        })?;
+        while let Some(res) = reader.next_frame().transpose() {
+            res.map_err(|e| {
+                std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{:?}", e))
+            })?;
+        }
+        let mut f = OpenOptions::new().write(true).open(path)?;
+        f.set_len(reader.consumed)?;
+        f.seek(SeekFrom::End(0))?;
+        Ok(Recorder {
+            out: BufWriter::new(f),
+            state: reader.state,
+        })
+    }
+
+    pub fn record(&mut self, snap: &StatSnapshot) -> std::io::Result<()> {
+        let frame = self.state.encode_frame(snap);
+        let mut len_buf = vec![];
+        put_varint(&mut len_buf, frame.len() as u64);
+        self.out.write_all(&len_buf)?;
+        self.out.write_all(&frame)?;
+        self.out.flush()
+    }
+}
+
+/// Plays a recording back through the same interface as the live client.
+/// With the speed of 1.0 the frames come at the pace they were recorded,
+/// 2.0 is twice as fast, and 0.0 gives the next frame on every fetch.
+pub struct Replay {
+    reader: RecordingReader<BufReader<File>>,
+    speed: f64,
+    start: Option<(Instant, f64)>,
+    current: Option<StatSnapshot>,
+    pending: Option<StatSnapshot>,
+    filter: PatternFilter,
+}
+
+impl Replay {
+    pub fn open(path: &str) -> Result<Self, SourceError> {
+        Ok(Replay {
+            reader: RecordingReader::open(path)?,
+            speed: 1.0,
+            start: None,
+            current: None,
+            pending: None,
+            filter: Default::default(),
+        })
+    }
+
+    pub fn with_speed(mut self, speed: f64) -> Self {
+        self.speed = speed;
+        self
+    }
+
+    fn next_frame(&mut self) -> Result<Option<StatSnapshot>, SourceError> {
+        match self.pending.take() {
+            Some(frame) => Ok(Some(frame)),
+            None => self.reader.next_frame(),
+        }
+    }
+
+    /* Move to the latest frame that is due, waiting for one if none is */
+    fn advance(&mut self) -> Result<(), SourceError> {
+        let mut advanced = false;
+        while let Some(frame) = self.next_frame()? {
+            if self.speed <= 0.0 {
+                self.current = Some(frame);
+                return Ok(());
+            }
+            let (t0, ts0) = *self.start.get_or_insert((Instant::now(), frame.timestamp));
+            let due = (frame.timestamp - ts0) / self.speed;
+            let now = t0.elapsed().as_secs_f64();
+            if due > now {
+                if advanced {
+                    self.pending = Some(frame);
+                    return Ok(());
+                }
+                std::thread::sleep(Duration::from_secs_f64(due - now));
+            }
+            self.current = Some(frame);
+            advanced = true;
+        }
+        if advanced {
+            Ok(())
+        } else {
+            Err(SourceError::EndOfRecording)
+        }
+    }
+}
+
+impl StatSource for Replay {
+    fn heartbeat(&self) -> f64 {
+        self.current.as_ref().map(|s| s.heartbeat).unwrap_or(0.0)
+    }
+
+    fn fetch(&mut self, patterns: &[String]) -> Result<StatSnapshot, SourceError> {
+        self.advance()?;
+        if !self.filter.is_for(patterns) {
+            self.filter = PatternFilter::new(patterns);
+        }
+        Ok(self.filter.apply(self.current.as_ref().unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::SnapshotBuilder;
+
+    fn snap(ts: f64, drops: u64, rx: u64, names: &[&str]) -> StatSnapshot {
+        SnapshotBuilder::new(ts)
+            .with_interfaces(names)
+            .with_simple("/if/drops", vec![vec![0, drops], vec![drops]])
+            .with_combined("/if/rx", vec![vec![(rx, rx.wrapping_mul(64))]])
+            .with_scalar("/sys/vector_rate", ts / 2.0)
+            .build()
+    }
+
+    fn temp_path(name: &str) -> String {
+        let dir = std::env::temp_dir();
+        format!(
+            "{}/vpp-stat-client-{}-{}.rec",
+            dir.display(),
+            name,
+            std::process::id()
+        )
+    }
+
+    #[test]
+    fn record_and_read_back() {
+        let path = temp_path("roundtrip");
+        let frames = vec![
+            snap(100.0, 5, 10, &["local0"]),
+            snap(101.0, 7, 5, &["local0"]),
+            snap(102.5, 7, u64::MAX, &["local0", "eth0"]),
+        ];
+        {
+            let mut rec = Recorder::create(&path).unwrap();
+            rec.record(&frames[0]).unwrap();
+        }
+        {
+            /* appending picks up the names and the deltas where they were left */
+            let mut rec = Recorder::append(&path).unwrap();
+            rec.record(&frames[1]).unwrap();
+            rec.record(&frames[2]).unwrap();
+        }
+        let read: Vec<StatSnapshot> = RecordingReader::open(&path)
+            .unwrap()
+            .map(|f| f.unwrap())
+            .collect();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(read, frames);
+    }
+
+    #[test]
+    fn replay_filters_and_ends() {
+        let path = temp_path("replay");
+        {
+            let mut rec = Recorder::create(&path).unwrap();
+            rec.record(&snap(1.0, 1, 1, &["local0"])).unwrap();
+            rec.record(&snap(2.0, 2, 2, &["local0"])).unwrap();
+        }
+        let mut replay = Replay::open(&path).unwrap().with_speed(0.0);
+        let first = replay.fetch(&["^/if/drops$".to_string()]).unwrap();
+        assert_eq!(first.entries.len(), 1);
+        assert_eq!(replay.heartbeat(), 1.0);
+        let second = replay.fetch(&[]).unwrap();
+        assert_eq!(second.entries.len(), 4);
+        assert_eq!(replay.fetch(&[]).unwrap_err(), SourceError::EndOfRecording);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,287 @@
+/*
+ * Prometheus remote_write push mode, for the boxes which can not be scraped.
+ *
+ * Each snapshot is encoded as a snappy-compressed WriteRequest protobuf
+ * and queued; the queue is bounded, so during a long outage the oldest
+ * batches get dropped rather than eating all the memory.
+ *
+ * As the Sink of a Pipeline, the batches are sent straight away and it is
+ * the pipeline which queues them.
+ */
+
+use crate::pipeline::{Batch, Sink, SinkError};
+use crate::prometheus::{families, PromFamily};
+use crate::protobuf::ProtoWriter;
+use crate::snapshot::StatSnapshot;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+pub const DEFAULT_MAX_QUEUE: usize = 100;
+pub const DEFAULT_RETRIES: usize = 3;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RemoteWriteError {
+    CompressionFailed(String),
+    RequestFailed(String),
+    HttpStatus(i32),
+}
+
+impl RemoteWriteError {
+    /* The server will not accept the batch no matter how many times we send it */
+    fn is_permanent(&self) -> bool {
+        match self {
+            RemoteWriteError::CompressionFailed(_) => true,
+            RemoteWriteError::RequestFailed(_) => false,
+            RemoteWriteError::HttpStatus(code) => *code != 429 && (400..500).contains(code),
+        }
+    }
+}
+
+/// Encode the families as a WriteRequest, all samples sharing the same timestamp
+pub fn encode_write_request(
+    families: &[PromFamily],
+    external_labels: &[(String, String)],
+    timestamp_ms: i64,
+) -> Vec<u8> {
+    let mut req = ProtoWriter::new();
+    for family in families {
+        for sample in &family.samples {
+            let mut labels: Vec<(&str, &str)> = vec![("__name__", family.name.as_str())];
+            labels.extend(sample.labels.iter().map(|(k, v)| (k.as_str(), v.as_str())));
+            labels.extend(
+                external_labels
+                    .iter()
+                    .map(|(k, v)| (k.as_str(), v.as_str())),
+            );
+            /* remote_write requires the labels sorted by name */
+            labels.sort_by(|a, b| a.0.cmp(b.0));
+            req.message(1, |ts| {
+                for (name, value) in &labels {
+                    ts.message(1, |l| {
+                        l.string(1, name);
+                        l.string(2, value);
+                    });
+                }
+                ts.message(2, |s| {
+                    s.double(1, sample.value.as_f64());
+                    s.int64(2, timestamp_ms);
+                });
+            });
+        }
+    }
+    req.into_bytes()
+}
+
+pub struct RemoteWriter {
+    url: String,
+    external_labels: Vec<(String, String)>,
+    queue: VecDeque<Vec<u8>>,
+    max_queue: usize,
+    retries: usize,
+    timeout: u64,
+    dropped: u64,
+    /* As a Sink, the batches up to it went out, even if the ones after failed */
+    sent_until: f64,
+}
+
+impl RemoteWriter {
+    pub fn new(url: &str) -> Self {
+        RemoteWriter {
+            url: url.to_string(),
+            external_labels: vec![],
+            queue: VecDeque::new(),
+            max_queue: DEFAULT_MAX_QUEUE,
+            retries: DEFAULT_RETRIES,
+            timeout: 10,
+            dropped: 0,
+            sent_until: f64::MIN,
+        }
+    }
+
+    /// A label added to every series, e.g. "instance"
+    pub fn with_external_label(mut self, name: &str, value: &str) -> Self {
+        self.external_labels
+            .push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// How many batches to hold while the remote end is not reachable
+    pub fn with_max_queue(mut self, max_queue: usize) -> Self {
+        self.max_queue = max_queue.max(1);
+        self
+    }
+
+    pub fn with_retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    pub fn with_timeout(mut self, seconds: u64) -> Self {
+        self.timeout = seconds;
+        self
+    }
+
+    pub fn queue_len(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Number of batches discarded because the queue was full or the server rejected them
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    pub fn enqueue(&mut self, snap: &StatSnapshot) -> Result<(), RemoteWriteError> {
+        self.enqueue_families(&families(snap), snap.timestamp)
+    }
+
+    /// Queue the already prepared families, e.g. after the relabeling
+    pub fn enqueue_families(
+        &mut self,
+        families: &[PromFamily],
+        timestamp: f64,
+    ) -> Result<(), RemoteWriteError> {
+        let compressed = self.compress(families, timestamp)?;
+        while self.queue.len() >= self.max_queue {
+            self.queue.pop_front();
+            self.dropped += 1;
+        }
+        self.queue.push_back(compressed);
+        Ok(())
+    }
+
+    fn compress(
+        &self,
+        families: &[PromFamily],
+        timestamp: f64,
+    ) -> Result<Vec<u8>, RemoteWriteError> {
+        let timestamp_ms = (timestamp * 1000.0) as i64;
+        let body = encode_write_request(families, &self.external_labels, timestamp_ms);
+        snap::raw::Encoder::new()
+            .compress_vec(&body)
+            .map_err(|e| RemoteWriteError::CompressionFailed(e.to_string()))
+    }
+
+    fn send(&self, body: &[u8]) -> Result<(), RemoteWriteError> {
+        let resp = minreq::post(self.url.as_str())
+            .with_header("Content-Type", "application/x-protobuf")
+            .with_header("Content-Encoding", "snappy")
+            .with_header("X-Prometheus-Remote-Write-Version", "0.1.0")
+            .with_body(body.to_vec())
+            .with_timeout(self.timeout)
+            .send()
+            .map_err(|e| RemoteWriteError::RequestFailed(e.to_string()))?;
+        if (200..300).contains(&resp.status_code) {
+            Ok(())
+        } else {
+            Err(RemoteWriteError::HttpStatus(resp.status_code))
+        }
+    }
+
+    /// Send the queued batches, oldest first. On a persistent failure
+    /// the remaining batches stay queued for the next attempt.
+    pub fn flush(&mut self) -> Result<usize, RemoteWriteError> {
+        let mut sent = 0;
+        while let Some(body) = self.queue.front() {
+            match self.send_retrying(body) {
+                Ok(()) => {
+                    self.queue.pop_front();
+                    sent += 1;
+                }
+                Err(e) => {
+                    if e.is_permanent() {
+                        self.queue.pop_front();
+                        self.dropped += 1;
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(sent)
+    }
+
+    fn send_retrying(&self, body: &[u8]) -> Result<(), RemoteWriteError> {
+        let mut attempt = 0;
+        loop {
+            match self.send(body) {
+                Ok(()) => return Ok(()),
+                Err(e) if e.is_permanent() || attempt >= self.retries => return Err(e),
+                Err(_) => {
+                    attempt += 1;
+                    std::thread::sleep(Duration::from_millis(500 << attempt.min(5)));
+                }
+            }
+        }
+    }
+}
+
+impl Sink for RemoteWriter {
+    fn send(&mut self, batches: &[Batch]) -> Result<(), SinkError> {
+        let sent_until = self.sent_until;
+        for batch in batches.iter().filter(|b| b.timestamp > sent_until) {
+            let res = self
+                .compress(&batch.families, batch.timestamp)
+                .and_then(|body| self.send_retrying(&body));
+            match res {
+                Ok(()) => self.sent_until = batch.timestamp,
+                Err(e) if e.is_permanent() => return Err(SinkError::Permanent(format!("{:?}", e))),
+                Err(e) => return Err(SinkError::Temporary(format!("{:?}", e))),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prometheus::{MetricType, PromSample, SampleValue};
+    use crate::snapshot::SnapshotBuilder;
+
+    #[test]
+    fn write_request_labels_are_sorted() {
+        let families = vec![PromFamily {
+            name: "_if_drops".to_string(),
+            metric_type: MetricType::Counter,
+            samples: vec![PromSample {
+                labels: vec![
+                    ("thread".to_string(), "0".to_string()),
+                    ("interface".to_string(), "1".to_string()),
+                ],
+                value: SampleValue::Int(5),
+            }],
+        }];
+        let external = vec![("instance".to_string(), "vpp1".to_string())];
+        let encoded = encode_write_request(&families, &external, 1000);
+
+        let mut expected = ProtoWriter::new();
+        expected.message(1, |ts| {
+            for (name, value) in [
+                ("__name__", "_if_drops"),
+                ("instance", "vpp1"),
+                ("interface", "1"),
+                ("thread", "0"),
+            ] {
+                ts.message(1, |l| {
+                    l.string(1, name);
+                    l.string(2, value);
+                });
+            }
+            ts.message(2, |s| {
+                s.double(1, 5.0);
+                s.int64(2, 1000);
+            });
+        });
+        assert_eq!(encoded, expected.into_bytes());
+    }
+
+    #[test]
+    fn queue_is_bounded() {
+        let snap = SnapshotBuilder::new(1.0).build();
+        let mut writer = RemoteWriter::new("http://127.0.0.1:1/api/v1/write").with_max_queue(2);
+        for _i in 0..5 {
+            writer.enqueue(&snap).unwrap();
+        }
+        assert_eq!(writer.queue_len(), 2);
+        assert_eq!(writer.dropped(), 3);
+    }
+}
@@ -0,0 +1,160 @@
+/*
+ * Offline access to the stats segment: an image saved from a live system
+ * (or any fd holding the segment) is mapped read-only and handed over
+ * to the C client library in place of the one received over the socket.
+ *
+ * The pointers within the segment are in the VPP address space,
+ * the client translates them using the "base" in the shared header,
+ * so the image can be mapped anywhere.
+ */
+
+use crate::sys::{stat_client_get, stat_segment_shared_header_t};
+use crate::{VppStatClient, VppStatError};
+use std::fs::File;
+use std::io::Write;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr;
+use std::sync::atomic::{fence, Ordering};
+use std::time::Duration;
+
+/* How many times to retry copying the segment while the directory is being changed */
+pub const SAVE_RETRIES: usize = 1000;
+
+fn pointee_size<T>(_p: *mut T) -> usize {
+    std::mem::size_of::<T>()
+}
+
+/* Translate a pointer from the VPP address space into the local mapping */
+fn segment_pointer(
+    header: *const stat_segment_shared_header_t,
+    size: usize,
+    p: *const u8,
+) -> Option<*const u8> {
+    let base = unsafe { (*header).base } as usize;
+    let offset = (p as usize).wrapping_sub(base);
+    if offset < size {
+        Some((header as usize + offset) as *const u8)
+    } else {
+        None
+    }
+}
+
+impl VppStatClient {
+    /// Open a segment image saved by save_segment(), for the offline analysis
+    pub fn open_segment(path: &str) -> Result<Self, VppStatError> {
+        let file = File::open(path).map_err(|_| VppStatError::CouldNotOpenFile)?;
+        /* The mapping stays valid after the file is closed */
+        VppStatClient::from_segment_fd(file.as_raw_fd())
+    }
+
+    /// Map the segment from an already open fd. The fd is not taken over,
+    /// the caller can close it once this returns.
+    pub fn from_segment_fd(fd: RawFd) -> Result<Self, VppStatError> {
+        use crate::VppStatError::*;
+
+        VppStatClient::init_default();
+
+        let mut st: libc::stat = unsafe { std::mem::zeroed() };
+        if unsafe { libc::fstat(fd, &mut st) } != 0 {
+            return Err(MmapFstatFailed);
+        }
+        let size = st.st_size as usize;
+        if size == 0 {
+            return Err(SegmentTooSmall);
+        }
+        let mem = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                fd,
+                0,
+            )
+        };
+        if mem == libc::MAP_FAILED {
+            return Err(MmapMapFailed);
+        }
+
+        /* Checked before the client is made, so that nothing but the mapping needs undoing */
+        let header = mem as *mut stat_segment_shared_header_t;
+        let checked = if size < pointee_size(header) {
+            Err(SegmentTooSmall)
+        } else if unsafe { ptr::read_volatile(&(*header).in_progress) } != 0 {
+            /* The C client would wait forever for the update to finish */
+            Err(SegmentUpdateInProgress)
+        } else {
+            let dir = unsafe { (*header).directory_vector } as *const u8;
+            segment_pointer(header, size, dir).ok_or(BadDirectoryPointer)
+        };
+        let dir = match checked {
+            Ok(dir) => dir,
+            Err(e) => {
+                unsafe { libc::munmap(mem, size) };
+                return Err(e);
+            }
+        };
+
+        let sc = unsafe { stat_client_get() };
+        unsafe {
+            (*sc).shared_header = header;
+            (*sc).memory_size = size as _;
+            (*sc).directory_vector = dir as _;
+        }
+        Ok(VppStatClient {
+            stat_client_ptr: sc,
+            read_stats: Default::default(),
+            from_file: true,
+        })
+    }
+
+    /// Save a copy of the mapped segment, e.g. for a bug report. The copy is retried
+    /// until no directory update happened while it was being made, and the file
+    /// is written under a temporary name and then renamed into place.
+    pub fn save_segment(&self, path: &str) -> std::io::Result<()> {
+        let sm = unsafe { &*self.stat_client_ptr };
+        let header = sm.shared_header;
+        let size = sm.memory_size as usize;
+        let mut copy = vec![0u8; size];
+
+        let state = || unsafe {
+            (
+                ptr::read_volatile(&(*header).epoch),
+                ptr::read_volatile(&(*header).in_progress) != 0,
+            )
+        };
+
+        let mut consistent = false;
+        for _i in 0..SAVE_RETRIES {
+            let (epoch, busy) = state();
+            if !busy {
+                fence(Ordering::Acquire);
+                unsafe { ptr::copy_nonoverlapping(header as *const u8, copy.as_mut_ptr(), size) };
+                fence(Ordering::Acquire);
+                if state() == (epoch, false) {
+                    consistent = true;
+                    break;
+                }
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        if !consistent {
+            return Err(std::io::Error::other(
+                "the stats segment directory kept changing",
+            ));
+        }
+
+        let tmp_path = format!("{}.tmp", path);
+        let res = File::create(&tmp_path).and_then(|mut f| {
+            f.write_all(&copy)?;
+            f.sync_all()
+        });
+        match res {
+            Ok(()) => std::fs::rename(&tmp_path, path),
+            Err(e) => {
+                let _ = std::fs::remove_file(&tmp_path);
+                Err(e)
+            }
+        }
+    }
+}
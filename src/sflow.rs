@@ -0,0 +1,240 @@
+/*
+ * sFlow v5 counter samples with the "generic interface counters" records,
+ * built from the "/if/..." counters and sent to a collector over UDP.
+ *
+ * The ifIndex reported is sw_if_index + 1, since ifIndex 0 is not valid.
+ */
+
+use crate::interfaces::InterfaceCounters;
+use crate::snapshot::StatSnapshot;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::time::Instant;
+
+pub const SFLOW_DEFAULT_PORT: u16 = 6343;
+
+const SFLOW_VERSION: u32 = 5;
+const SAMPLE_FORMAT_COUNTERS: u32 = 2;
+const RECORD_FORMAT_GENERIC_INTERFACE: u32 = 1;
+const GENERIC_INTERFACE_RECORD_LEN: u32 = 88;
+const IF_TYPE_ETHERNET_CSMACD: u32 = 6;
+/* The stats segment does not carry the link state, report admin and oper up */
+const IF_STATUS_UP: u32 = 3;
+/* Keep the datagrams well under the usual MTU */
+const MAX_DATAGRAM_SIZE: usize = 1400;
+
+pub fn sflow_if_index(sw_if_index: u32) -> u32 {
+    sw_if_index + 1
+}
+
+struct Xdr {
+    buf: Vec<u8>,
+}
+
+impl Xdr {
+    fn new() -> Self {
+        Xdr { buf: vec![] }
+    }
+    fn u32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+    }
+    /* The 32-bit counters simply wrap, like SNMP Counter32 */
+    fn c32(&mut self, v: u64) {
+        self.u32(v as u32);
+    }
+    fn u64(&mut self, v: u64) {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+    }
+}
+
+pub struct SflowAgent {
+    socket: UdpSocket,
+    collector: SocketAddr,
+    agent_address: IpAddr,
+    sub_agent_id: u32,
+    datagram_seq: u32,
+    sample_seq: HashMap<u32, u32>,
+    start: Instant,
+}
+
+impl SflowAgent {
+    /// The agent address identifies this VPP instance to the collector
+    pub fn new(collector: SocketAddr, agent_address: IpAddr) -> std::io::Result<Self> {
+        let bind_addr = if collector.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        Ok(SflowAgent {
+            socket: UdpSocket::bind(bind_addr)?,
+            collector,
+            agent_address,
+            sub_agent_id: 0,
+            datagram_seq: 0,
+            sample_seq: HashMap::new(),
+            start: Instant::now(),
+        })
+    }
+
+    pub fn with_sub_agent_id(mut self, sub_agent_id: u32) -> Self {
+        self.sub_agent_id = sub_agent_id;
+        self
+    }
+
+    fn encode_sample(&mut self, ifc: &InterfaceCounters) -> Vec<u8> {
+        let if_index = sflow_if_index(ifc.sw_if_index);
+        let seq = self.sample_seq.entry(if_index).or_insert(0);
+        *seq = seq.wrapping_add(1);
+
+        let mut rec = Xdr::new();
+        rec.u32(if_index);
+        rec.u32(IF_TYPE_ETHERNET_CSMACD);
+        rec.u64(0); /* ifSpeed unknown */
+        rec.u32(0); /* ifDirection unknown */
+        rec.u32(IF_STATUS_UP);
+        rec.u64(ifc.rx.bytes);
+        rec.c32(ifc.rx_unicast.packets);
+        rec.c32(ifc.rx_multicast.packets);
+        rec.c32(ifc.rx_broadcast.packets);
+        rec.c32(ifc.drops);
+        rec.c32(ifc.rx_error);
+        rec.u32(0); /* ifInUnknownProtos */
+        rec.u64(ifc.tx.bytes);
+        rec.c32(ifc.tx_unicast.packets);
+        rec.c32(ifc.tx_multicast.packets);
+        rec.c32(ifc.tx_broadcast.packets);
+        rec.u32(0); /* ifOutDiscards */
+        rec.c32(ifc.tx_error);
+        rec.u32(0); /* ifPromiscuousMode */
+
+        let mut sample = Xdr::new();
+        sample.u32(*seq);
+        sample.u32(if_index); /* source id type 0 - ifIndex */
+        sample.u32(1);
+        sample.u32(RECORD_FORMAT_GENERIC_INTERFACE);
+        sample.u32(GENERIC_INTERFACE_RECORD_LEN);
+        sample.buf.extend_from_slice(&rec.buf);
+
+        let mut out = Xdr::new();
+        out.u32(SAMPLE_FORMAT_COUNTERS);
+        out.u32(sample.buf.len() as u32);
+        out.buf.extend_from_slice(&sample.buf);
+        out.buf
+    }
+
+    fn encode_datagram(&mut self, samples: &[Vec<u8>]) -> Vec<u8> {
+        self.datagram_seq = self.datagram_seq.wrapping_add(1);
+        let mut d = Xdr::new();
+        d.u32(SFLOW_VERSION);
+        match self.agent_address {
+            IpAddr::V4(a) => {
+                d.u32(1);
+                d.buf.extend_from_slice(&a.octets());
+            }
+            IpAddr::V6(a) => {
+                d.u32(2);
+                d.buf.extend_from_slice(&a.octets());
+            }
+        }
+        d.u32(self.sub_agent_id);
+        d.u32(self.datagram_seq);
+        d.u32(self.start.elapsed().as_millis() as u32);
+        d.u32(samples.len() as u32);
+        for s in samples {
+            d.buf.extend_from_slice(s);
+        }
+        d.buf
+    }
+
+    /// Encode the counters, splitting them into as many datagrams as needed
+    pub fn encode(&mut self, counters: &[InterfaceCounters]) -> Vec<Vec<u8>> {
+        let mut datagrams = vec![];
+        let mut samples: Vec<Vec<u8>> = vec![];
+        let mut size = 0;
+        for ifc in counters {
+            let sample = self.encode_sample(ifc);
+            if !samples.is_empty() && size + sample.len() > MAX_DATAGRAM_SIZE - 64 {
+                datagrams.push(self.encode_datagram(&samples));
+                samples.clear();
+                size = 0;
+            }
+            size += sample.len();
+            samples.push(sample);
+        }
+        if !samples.is_empty() {
+            datagrams.push(self.encode_datagram(&samples));
+        }
+        datagrams
+    }
+
+    /// Send the counter samples of all the interfaces in the snapshot
+    pub fn send(&mut self, snap: &StatSnapshot) -> std::io::Result<usize> {
+        let counters = InterfaceCounters::collect(snap);
+        for datagram in self.encode(&counters) {
+            self.socket.send_to(&datagram, self.collector)?;
+        }
+        Ok(counters.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::CombinedCounter;
+
+    fn be32(b: &[u8], off: usize) -> u32 {
+        u32::from_be_bytes([b[off], b[off + 1], b[off + 2], b[off + 3]])
+    }
+
+    #[test]
+    fn counter_sample_layout() {
+        let collector: SocketAddr = "127.0.0.1:6343".parse().unwrap();
+        let mut agent = SflowAgent::new(collector, "192.0.2.1".parse().unwrap()).unwrap();
+        let ifc = InterfaceCounters {
+            sw_if_index: 1,
+            rx: CombinedCounter {
+                packets: 10,
+                bytes: 1000,
+            },
+            rx_unicast: CombinedCounter {
+                packets: 10,
+                bytes: 1000,
+            },
+            drops: 2,
+            ..Default::default()
+        };
+        let datagrams = agent.encode(&[ifc.clone(), ifc]);
+        assert_eq!(datagrams.len(), 1);
+        let d = &datagrams[0];
+        /* header 28 bytes, then two samples of 8 + 12 + 8 + 88 bytes */
+        assert_eq!(d.len(), 28 + 2 * 116);
+        assert_eq!(be32(d, 0), 5);
+        assert_eq!(&d[8..12], &[192, 0, 2, 1]);
+        assert_eq!(be32(d, 24), 2);
+        assert_eq!(be32(d, 28), SAMPLE_FORMAT_COUNTERS);
+        assert_eq!(be32(d, 32), 108);
+        /* second sample from the same source has the next sequence number */
+        assert_eq!(be32(d, 28 + 116 + 8), 2);
+        let rec = 28 + 28;
+        assert_eq!(be32(d, rec), 2); /* ifIndex */
+        assert_eq!(be32(d, rec + 32), 10); /* ifInUcastPkts */
+        assert_eq!(be32(d, rec + 44), 2); /* ifInDiscards */
+    }
+
+    #[test]
+    fn many_interfaces_are_split() {
+        let collector: SocketAddr = "127.0.0.1:6343".parse().unwrap();
+        let mut agent = SflowAgent::new(collector, "192.0.2.1".parse().unwrap()).unwrap();
+        let counters: Vec<InterfaceCounters> = (0..30)
+            .map(|i| InterfaceCounters {
+                sw_if_index: i,
+                ..Default::default()
+            })
+            .collect();
+        let datagrams = agent.encode(&counters);
+        assert!(datagrams.len() > 1);
+        assert!(datagrams.iter().all(|d| d.len() <= MAX_DATAGRAM_SIZE));
+        let total: u32 = datagrams.iter().map(|d| be32(d, 24)).sum();
+        assert_eq!(total, 30);
+    }
+}
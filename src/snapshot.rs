@@ -0,0 +1,312 @@
+/*
+ * Owned copies of the stat segment data.
+ *
+ * The data returned by dump() points straight into the shared memory and
+ * into the vectors allocated by the C library, so it can not outlive the
+ * directory it came from. The snapshot copies the values out, such that
+ * they can be kept around, compared with each other and handed to the
+ * various exporters.
+ */
+
+use crate::sys::vlib_counter_t;
+use crate::{StatValue, VppStatClient, VppStatData, VppStatDir, VppStatDumpError, VppStringVec};
+use serde::{Deserialize, Serialize};
+use std::ffi::CStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/* How many times to redo the ls if the directory changes under our feet */
+pub const SNAPSHOT_RETRIES: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct CombinedCounter {
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+impl From<&vlib_counter_t> for CombinedCounter {
+    fn from(c: &vlib_counter_t) -> Self {
+        CombinedCounter {
+            packets: c.packets,
+            bytes: c.bytes,
+        }
+    }
+}
+
+/// The value of a single stat entry. The vectors are indexed
+/// by thread first, and then by the object index (e.g. sw_if_index).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum SnapshotValue {
+    Scalar(f64),
+    Simple(Vec<Vec<u64>>),
+    Combined(Vec<Vec<CombinedCounter>>),
+    /* The slots of deleted objects have no name */
+    Names(Vec<Option<String>>),
+    Empty,
+}
+
+impl SnapshotValue {
+    fn from_stat_value(value: &StatValue<'_>) -> Self {
+        use crate::StatValue::*;
+        match value {
+            ScalarIndex(val) => SnapshotValue::Scalar(*val),
+            CounterVectorSimple(cvs) => {
+                SnapshotValue::Simple((0..cvs.len()).map(|k| cvs[k].to_vec()).collect())
+            }
+            CounterVectorCombined(cvc) => SnapshotValue::Combined(
+                (0..cvc.len())
+                    .map(|k| cvc[k].iter().map(CombinedCounter::from).collect())
+                    .collect(),
+            ),
+            NameVector(nv) => SnapshotValue::Names(
+                nv.vector_ptr
+                    .iter()
+                    .map(|&p| {
+                        if p.is_null() {
+                            None
+                        } else {
+                            let c_str = unsafe { CStr::from_ptr(p as *const libc::c_char) };
+                            Some(c_str.to_string_lossy().into_owned())
+                        }
+                    })
+                    .collect(),
+            ),
+            Illegal | Empty | Symlink => SnapshotValue::Empty,
+        }
+    }
+
+    /// Sum of a simple counter across all the threads
+    pub fn simple_total(&self, index: usize) -> Option<u64> {
+        match self {
+            SnapshotValue::Simple(v) => Some(
+                v.iter()
+                    .filter_map(|t| t.get(index))
+                    .fold(0u64, |acc, x| acc.wrapping_add(*x)),
+            ),
+            _ => None,
+        }
+    }
+
+    /// Sum of a combined counter across all the threads
+    pub fn combined_total(&self, index: usize) -> Option<CombinedCounter> {
+        match self {
+            SnapshotValue::Combined(v) => Some(v.iter().filter_map(|t| t.get(index)).fold(
+                CombinedCounter::default(),
+                |acc, x| CombinedCounter {
+                    packets: acc.packets.wrapping_add(x.packets),
+                    bytes: acc.bytes.wrapping_add(x.bytes),
+                },
+            )),
+            _ => None,
+        }
+    }
+
+    /// Number of the objects in a vector value (the widest thread wins)
+    pub fn index_count(&self) -> usize {
+        match self {
+            SnapshotValue::Simple(v) => v.iter().map(|t| t.len()).max().unwrap_or(0),
+            SnapshotValue::Combined(v) => v.iter().map(|t| t.len()).max().unwrap_or(0),
+            SnapshotValue::Names(v) => v.len(),
+            SnapshotValue::Scalar(_) | SnapshotValue::Empty => 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotEntry {
+    pub name: String,
+    pub value: SnapshotValue,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StatSnapshot {
+    /* Seconds since the UNIX epoch when the data was copied */
+    pub timestamp: f64,
+    pub heartbeat: f64,
+    pub entries: Vec<SnapshotEntry>,
+}
+
+pub fn unix_time_now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or(0.0)
+}
+
+impl StatSnapshot {
+    pub fn from_data(data: &VppStatData<'_>, heartbeat: f64) -> Self {
+        let entries = data
+            .iter()
+            .map(|item| SnapshotEntry {
+                name: item.name.to_string(),
+                value: SnapshotValue::from_stat_value(&item.value),
+            })
+            .collect();
+        StatSnapshot {
+            timestamp: unix_time_now(),
+            heartbeat,
+            entries,
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&SnapshotValue> {
+        self.entries
+            .iter()
+            .find(|e| e.name == name)
+            .map(|e| &e.value)
+    }
+
+    pub fn scalar(&self, name: &str) -> Option<f64> {
+        match self.get(name) {
+            Some(SnapshotValue::Scalar(v)) => Some(*v),
+            _ => None,
+        }
+    }
+
+    /// The interface names from "/if/names", indexed by sw_if_index.
+    /// This requires "/if/names" to be matched by the patterns.
+    pub fn interface_names(&self) -> &[Option<String>] {
+        match self.get("/if/names") {
+            Some(SnapshotValue::Names(names)) => names,
+            _ => &[],
+        }
+    }
+
+    pub fn interface_name(&self, sw_if_index: usize) -> Option<&str> {
+        self.interface_names()
+            .get(sw_if_index)
+            .and_then(|n| n.as_deref())
+    }
+}
+
+/// Statistics under "/if/" are indexed by sw_if_index, so they can be joined with "/if/names"
+pub fn is_interface_stat(name: &str) -> bool {
+    name.starts_with("/if/") && name != "/if/names"
+}
+
+impl<'a> VppStatDir<'a> {
+    pub fn snapshot(&'a self) -> Result<StatSnapshot, VppStatDumpError> {
+        let data = self.dump()?;
+        Ok(StatSnapshot::from_data(&data, self.client.heartbeat()))
+    }
+}
+
+impl VppStatClient {
+    /// Do the ls and dump, redoing the ls if the directory layout changes meanwhile
+    pub fn snapshot(
+        &self,
+        patterns: Option<&VppStringVec>,
+    ) -> Result<StatSnapshot, VppStatDumpError> {
+        let mut res = Err(VppStatDumpError::ObsoleteDirData);
+        for _i in 0..SNAPSHOT_RETRIES {
+            let dir = self.ls(patterns);
+            res = dir.snapshot();
+            if res.is_ok() {
+                break;
+            }
+            self.count_read(|s| s.obsolete_retries += 1);
+        }
+        res
+    }
+}
+
+/// Builds the snapshots for the unit tests; the heartbeat is the timestamp unless set
+#[cfg(test)]
+pub(crate) struct SnapshotBuilder(StatSnapshot);
+
+#[cfg(test)]
+impl SnapshotBuilder {
+    pub fn new(timestamp: f64) -> Self {
+        SnapshotBuilder(StatSnapshot {
+            timestamp,
+            heartbeat: timestamp,
+            entries: vec![],
+        })
+    }
+
+    pub fn with_heartbeat(mut self, heartbeat: f64) -> Self {
+        self.0.heartbeat = heartbeat;
+        self
+    }
+
+    fn with(mut self, name: &str, value: SnapshotValue) -> Self {
+        self.0.entries.push(SnapshotEntry {
+            name: name.to_string(),
+            value,
+        });
+        self
+    }
+
+    pub fn with_scalar(self, name: &str, value: f64) -> Self {
+        self.with(name, SnapshotValue::Scalar(value))
+    }
+
+    pub fn with_simple(self, name: &str, threads: Vec<Vec<u64>>) -> Self {
+        self.with(name, SnapshotValue::Simple(threads))
+    }
+
+    /// The counters given as (packets, bytes)
+    pub fn with_combined(self, name: &str, threads: Vec<Vec<(u64, u64)>>) -> Self {
+        let threads = threads
+            .into_iter()
+            .map(|t| {
+                t.into_iter()
+                    .map(|(packets, bytes)| CombinedCounter { packets, bytes })
+                    .collect()
+            })
+            .collect();
+        self.with(name, SnapshotValue::Combined(threads))
+    }
+
+    /// An empty name is a deleted slot
+    pub fn with_names(self, name: &str, names: &[&str]) -> Self {
+        let names = names
+            .iter()
+            .map(|n| Some(n.to_string()).filter(|n| !n.is_empty()))
+            .collect();
+        self.with(name, SnapshotValue::Names(names))
+    }
+
+    /// "/if/names"
+    pub fn with_interfaces(self, names: &[&str]) -> Self {
+        self.with_names("/if/names", names)
+    }
+
+    pub fn build(self) -> StatSnapshot {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn totals_across_threads() {
+        let simple = SnapshotValue::Simple(vec![vec![1, 2], vec![10, 20, 30]]);
+        assert_eq!(simple.simple_total(1), Some(22));
+        assert_eq!(simple.simple_total(2), Some(30));
+        assert_eq!(simple.index_count(), 3);
+
+        let c = |packets, bytes| CombinedCounter { packets, bytes };
+        let combined = SnapshotValue::Combined(vec![vec![c(1, 100)], vec![c(2, 200)]]);
+        assert_eq!(combined.combined_total(0), Some(c(3, 300)));
+        assert_eq!(combined.simple_total(0), None);
+    }
+
+    #[test]
+    fn interface_name_lookup() {
+        let snap = StatSnapshot {
+            timestamp: 0.0,
+            heartbeat: 1.0,
+            entries: vec![SnapshotEntry {
+                name: "/if/names".to_string(),
+                value: SnapshotValue::Names(vec![Some("local0".to_string()), None]),
+            }],
+        };
+        assert_eq!(snap.interface_name(0), Some("local0"));
+        assert_eq!(snap.interface_name(1), None);
+        assert_eq!(snap.interface_name(2), None);
+        assert!(is_interface_stat("/if/rx"));
+        assert!(!is_interface_stat("/if/names"));
+    }
+}
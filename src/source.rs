@@ -0,0 +1,181 @@
+/*
+ * The read side of the stats, shared by the live client and the recordings,
+ * so the same consumers can run with or without VPP being there.
+ */
+
+use crate::snapshot::StatSnapshot;
+use crate::{VppStatClient, VppStatDumpError, VppStringVec};
+use regex::Regex;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SourceError {
+    /// The live dump failed
+    Dump(VppStatDumpError),
+    /// The replay is past the last frame
+    EndOfRecording,
+    BadRecording(String),
+}
+
+impl From<VppStatDumpError> for SourceError {
+    fn from(e: VppStatDumpError) -> Self {
+        SourceError::Dump(e)
+    }
+}
+
+/// Running totals of how the reads went, for the self-monitoring
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReadStats {
+    /// Directory listings done
+    pub ls_count: u64,
+    /// Dumps redone because the directory changed while reading it
+    pub obsolete_retries: u64,
+}
+
+pub trait StatSource {
+    fn heartbeat(&self) -> f64;
+
+    /// Copy out the entries whose names match any of the patterns,
+    /// the same way as the ls followed by the dump does.
+    fn fetch(&mut self, patterns: &[String]) -> Result<StatSnapshot, SourceError>;
+
+    /// Just the names of the matching entries, without copying the values
+    fn names(&mut self, patterns: &[String]) -> Result<Vec<String>, SourceError> {
+        let snap = self.fetch(patterns)?;
+        Ok(snap.entries.into_iter().map(|e| e.name).collect())
+    }
+
+    /// The totals since the source was opened; all zero if it does not keep them
+    fn read_stats(&self) -> ReadStats {
+        ReadStats::default()
+    }
+}
+
+fn to_vpp_patterns(patterns: &[String]) -> Option<VppStringVec> {
+    if patterns.is_empty() {
+        return None;
+    }
+    let mut vpatterns = VppStringVec::new();
+    for p in patterns {
+        vpatterns.push(p);
+    }
+    Some(vpatterns)
+}
+
+impl StatSource for VppStatClient {
+    fn heartbeat(&self) -> f64 {
+        VppStatClient::heartbeat(self)
+    }
+
+    fn fetch(&mut self, patterns: &[String]) -> Result<StatSnapshot, SourceError> {
+        Ok(self.snapshot(to_vpp_patterns(patterns).as_ref())?)
+    }
+
+    fn names(&mut self, patterns: &[String]) -> Result<Vec<String>, SourceError> {
+        let vpatterns = to_vpp_patterns(patterns);
+        let dir = self.ls(vpatterns.as_ref());
+        Ok(dir.names().collect())
+    }
+
+    fn read_stats(&self) -> ReadStats {
+        self.read_stats.get()
+    }
+}
+
+/// The pattern matching just the given stat name. The C library compiles
+/// the patterns with the POSIX regcomp(), where e.g. "\(" and "\+" are the
+/// operators, so the characters which are special in any of the dialects are
+/// put in the brackets instead, which reads the same in POSIX and in regex.
+pub fn exact_pattern(name: &str) -> String {
+    let mut out = String::from("^");
+    for c in name.chars() {
+        match c {
+            '.' | '[' | '\\' | '*' | '^' | '$' => {
+                out.push('\\');
+                out.push(c);
+            }
+            '+' | '?' | '(' | ')' | '{' | '}' | '|' | ']' => {
+                out.push('[');
+                out.push(c);
+                out.push(']');
+            }
+            _ => out.push(c),
+        }
+    }
+    out.push('$');
+    out
+}
+
+/// Name matching for the sources which are not backed by the stat segment,
+/// mirroring the regex search done by the C library for the ls.
+#[derive(Debug, Clone, Default)]
+pub struct PatternFilter {
+    patterns: Vec<String>,
+    regexes: Vec<Regex>,
+}
+
+impl PatternFilter {
+    pub fn new(patterns: &[String]) -> Self {
+        PatternFilter {
+            patterns: patterns.to_vec(),
+            /* The patterns that do not compile can not match anything */
+            regexes: patterns.iter().filter_map(|p| Regex::new(p).ok()).collect(),
+        }
+    }
+
+    pub fn is_for(&self, patterns: &[String]) -> bool {
+        self.patterns == patterns
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        self.patterns.is_empty() || self.regexes.iter().any(|r| r.is_match(name))
+    }
+
+    pub fn apply(&self, snap: &StatSnapshot) -> StatSnapshot {
+        StatSnapshot {
+            timestamp: snap.timestamp,
+            heartbeat: snap.heartbeat,
+            entries: snap
+                .entries
+                .iter()
+                .filter(|e| self.matches(&e.name))
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* The same way as the C library does it */
+    fn posix_match(pattern: &str, name: &str) -> bool {
+        let pattern = std::ffi::CString::new(pattern).unwrap();
+        let name = std::ffi::CString::new(name).unwrap();
+        unsafe {
+            let mut re: libc::regex_t = std::mem::zeroed();
+            assert_eq!(libc::regcomp(&mut re, pattern.as_ptr(), 0), 0);
+            let rv = libc::regexec(&re, name.as_ptr(), 0, std::ptr::null_mut(), 0);
+            libc::regfree(&mut re);
+            rv == 0
+        }
+    }
+
+    #[test]
+    fn exact_patterns() {
+        let names = [
+            "/err/ip4-input/ip4 ttl <= 1",
+            "/err/ip6-icmp-input/unknown type (ICMPv6)",
+            "/err/x/a+b? {1} [c] d|e $f ^g \\h *.",
+        ];
+        for name in names {
+            let re = Regex::new(&exact_pattern(name)).unwrap();
+            assert!(re.is_match(name));
+            assert!(!re.is_match(&format!("{}x", name)));
+            assert!(posix_match(&exact_pattern(name), name));
+            assert!(!posix_match(&exact_pattern(name), &format!("{}x", name)));
+        }
+        assert_eq!(exact_pattern("/a (b+)"), "^/a [(]b[+][)]$");
+        assert_eq!(exact_pattern("/a.b"), "^/a\\.b$");
+    }
+}
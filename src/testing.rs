@@ -0,0 +1,334 @@
+/*
+ * Helpers for the integration tests which check how the counters moved:
+ *
+ *   let baseline = StatBaseline::take(&mut client, &["^/err/ip4-input/", "^/if/"])?;
+ *   ... send the packets ...
+ *   let mut deltas = baseline.deltas(&mut client)?;
+ *   deltas.counter("/err/ip4-input/ip4 ttl <= 1").exactly(100);
+ *   deltas.counter("/if/drops").interface("pg0").unchanged();
+ *   deltas.counter("/if/rx").interface("pg1").bytes().between(6400, 7000);
+ *   deltas.assert_ok();
+ *
+ * All the failed checks are reported together, with the counter values.
+ */
+
+use crate::delta::CounterField;
+use crate::snapshot::{SnapshotValue, StatSnapshot};
+use crate::source::{SourceError, StatSource};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatBaseline {
+    patterns: Vec<String>,
+    before: StatSnapshot,
+}
+
+impl StatBaseline {
+    /// Snapshot the matching counters before the test. "/if/names" is always
+    /// included, so the interfaces can be referred to by their names.
+    pub fn take(source: &mut dyn StatSource, patterns: &[&str]) -> Result<Self, SourceError> {
+        let mut patterns: Vec<String> = patterns.iter().map(|p| p.to_string()).collect();
+        patterns.push("^/if/names$".to_string());
+        let before = source.fetch(&patterns)?;
+        Ok(StatBaseline { patterns, before })
+    }
+
+    /// Snapshot the same counters again after the test, to check the deltas
+    pub fn deltas(&self, source: &mut dyn StatSource) -> Result<CounterDeltas, SourceError> {
+        let after = source.fetch(&self.patterns)?;
+        Ok(CounterDeltas::new(self.before.clone(), after))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CounterAssertionError {
+    pub failures: Vec<String>,
+}
+
+impl fmt::Display for CounterAssertionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} counter check(s) failed:", self.failures.len())?;
+        for failure in &self.failures {
+            writeln!(f, "  {}", failure)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for CounterAssertionError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CounterDeltas {
+    pub before: StatSnapshot,
+    pub after: StatSnapshot,
+    failures: Vec<String>,
+}
+
+/* Which object of a vector counter to look at */
+#[derive(Debug, Clone, PartialEq)]
+enum Selector {
+    All,
+    Index(usize),
+    Interface(String),
+}
+
+impl CounterDeltas {
+    pub fn new(before: StatSnapshot, after: StatSnapshot) -> Self {
+        CounterDeltas {
+            before,
+            after,
+            failures: vec![],
+        }
+    }
+
+    /// Start a check of the counter with this exact name. Without the further
+    /// selection the vector counters are summed across all the indices.
+    pub fn counter(&mut self, name: &str) -> CounterCheck<'_> {
+        CounterCheck {
+            deltas: self,
+            name: name.to_string(),
+            selector: Selector::All,
+            field: CounterField::Packets,
+        }
+    }
+
+    pub fn failures(&self) -> &[String] {
+        &self.failures
+    }
+
+    pub fn verify(&self) -> Result<(), CounterAssertionError> {
+        if self.failures.is_empty() {
+            Ok(())
+        } else {
+            Err(CounterAssertionError {
+                failures: self.failures.clone(),
+            })
+        }
+    }
+
+    /// Panic with all the failed checks, for use within the tests
+    pub fn assert_ok(&self) {
+        if let Err(e) = self.verify() {
+            panic!("{}", e);
+        }
+    }
+}
+
+/* The counters are compared as the integers, so they are exact all the way
+ * up; the scalars are rounded */
+fn value_of(
+    snap: &StatSnapshot,
+    name: &str,
+    index: Option<usize>,
+    field: CounterField,
+) -> Option<i128> {
+    let value = snap.get(name)?;
+    let count = value.index_count();
+    let indices: Vec<usize> = match index {
+        Some(i) => vec![i],
+        None => (0..count).collect(),
+    };
+    match value {
+        SnapshotValue::Scalar(v) => Some(v.round() as i128),
+        SnapshotValue::Simple(_) => Some(
+            indices
+                .iter()
+                .map(|i| value.simple_total(*i).unwrap_or(0) as i128)
+                .sum(),
+        ),
+        SnapshotValue::Combined(_) => Some(
+            indices
+                .iter()
+                .map(|i| {
+                    let c = value.combined_total(*i).unwrap_or_default();
+                    match field {
+                        CounterField::Bytes => c.bytes as i128,
+                        _ => c.packets as i128,
+                    }
+                })
+                .sum(),
+        ),
+        SnapshotValue::Names(_) | SnapshotValue::Empty => None,
+    }
+}
+
+pub struct CounterCheck<'a> {
+    deltas: &'a mut CounterDeltas,
+    name: String,
+    selector: Selector,
+    field: CounterField,
+}
+
+impl<'a> CounterCheck<'a> {
+    /// Look only at the interface with this name in "/if/names"
+    pub fn interface(mut self, name: &str) -> Self {
+        self.selector = Selector::Interface(name.to_string());
+        self
+    }
+
+    pub fn index(mut self, index: usize) -> Self {
+        self.selector = Selector::Index(index);
+        self
+    }
+
+    /// For the combined counters, check the packets (the default)
+    pub fn packets(mut self) -> Self {
+        self.field = CounterField::Packets;
+        self
+    }
+
+    /// For the combined counters, check the bytes rather than the packets
+    pub fn bytes(mut self) -> Self {
+        self.field = CounterField::Bytes;
+        self
+    }
+
+    fn description(&self) -> String {
+        let field = match self.field {
+            CounterField::Bytes => " bytes",
+            _ => "",
+        };
+        match &self.selector {
+            Selector::All => format!("{}{}", self.name, field),
+            Selector::Index(i) => format!("{}[{}]{}", self.name, i, field),
+            Selector::Interface(n) => format!("{}[{}]{}", self.name, n, field),
+        }
+    }
+
+    /// The values before and after, or the reason why they are not there
+    fn values(&self) -> Result<(i128, i128), String> {
+        let index = match &self.selector {
+            Selector::All => None,
+            Selector::Index(i) => Some(*i),
+            Selector::Interface(name) => {
+                let names = self.deltas.after.interface_names();
+                if names.is_empty() {
+                    return Err("\"/if/names\" is not in the snapshot".to_string());
+                }
+                match names
+                    .iter()
+                    .position(|n| n.as_deref() == Some(name.as_str()))
+                {
+                    Some(i) => Some(i),
+                    None => return Err(format!("no interface named {}", name)),
+                }
+            }
+        };
+        let after = value_of(&self.deltas.after, &self.name, index, self.field)
+            .ok_or_else(|| "no such counter".to_string())?;
+        /* The counter might have been created during the test */
+        let before = value_of(&self.deltas.before, &self.name, index, self.field).unwrap_or(0);
+        Ok((before, after))
+    }
+
+    fn check(self, expectation: &str, ok: impl Fn(i128) -> bool) -> bool {
+        let desc = self.description();
+        let failure = match self.values() {
+            Err(reason) => Some(format!(
+                "{}: expected {}, but {}",
+                desc, expectation, reason
+            )),
+            Ok((before, after)) if !ok(after - before) => Some(format!(
+                "{}: expected {}, was {}, now {} ({:+})",
+                desc,
+                expectation,
+                before,
+                after,
+                after - before
+            )),
+            Ok(_) => None,
+        };
+        match failure {
+            Some(f) => {
+                self.deltas.failures.push(f);
+                false
+            }
+            None => true,
+        }
+    }
+
+    /// The counter has increased by exactly this much
+    pub fn exactly(self, delta: u64) -> bool {
+        self.check(&format!("an increase of {}", delta), |d| d == delta as i128)
+    }
+
+    /// The increase is within the inclusive range
+    pub fn between(self, min: u64, max: u64) -> bool {
+        self.check(&format!("an increase between {} and {}", min, max), |d| {
+            d >= min as i128 && d <= max as i128
+        })
+    }
+
+    pub fn at_least(self, min: u64) -> bool {
+        self.check(&format!("an increase of at least {}", min), |d| {
+            d >= min as i128
+        })
+    }
+
+    pub fn unchanged(self) -> bool {
+        self.check("no change", |d| d == 0)
+    }
+
+    pub fn increased(self) -> bool {
+        self.check("an increase", |d| d > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::SnapshotBuilder;
+
+    fn snap(ttl: u64, drops: u64, rx: u64) -> StatSnapshot {
+        SnapshotBuilder::new(0.0)
+            .with_interfaces(&["local0", "pg0"])
+            .with_simple("/if/drops", vec![vec![1, drops], vec![0, 1]])
+            .with_combined("/if/rx", vec![vec![(0, 0), (rx, rx * 64)]])
+            .with_simple("/err/ip4-input/ip4 ttl <= 1", vec![vec![ttl], vec![ttl]])
+            .build()
+    }
+
+    #[test]
+    fn passing_checks() {
+        let mut d = CounterDeltas::new(snap(10, 5, 0), snap(60, 5, 100));
+        assert!(d.counter("/err/ip4-input/ip4 ttl <= 1").exactly(100));
+        assert!(d.counter("/if/drops").interface("pg0").unchanged());
+        assert!(d
+            .counter("/if/rx")
+            .interface("pg0")
+            .bytes()
+            .between(6400, 6400));
+        assert!(d.counter("/if/rx").index(1).packets().at_least(50));
+        d.assert_ok();
+
+        /* Past 2^53 the f64 would see no change at all */
+        let big = 1u64 << 53;
+        let mut d = CounterDeltas::new(snap(big, 5, 0), snap(big + 1, 5, 0));
+        let delta: u64 = 2;
+        assert!(d.counter("/err/ip4-input/ip4 ttl <= 1").exactly(delta));
+        d.assert_ok();
+    }
+
+    #[test]
+    fn failure_messages() {
+        let mut d = CounterDeltas::new(snap(10, 5, 0), snap(20, 7, 0));
+        d.counter("/err/ip4-input/ip4 ttl <= 1").exactly(100);
+        d.counter("/if/drops").interface("pg0").unchanged();
+        d.counter("/if/drops").interface("pg9").unchanged();
+        d.counter("/if/nosuch").increased();
+        assert_eq!(
+            d.failures(),
+            &[
+                "/err/ip4-input/ip4 ttl <= 1: expected an increase of 100, was 20, now 40 (+20)",
+                "/if/drops[pg0]: expected no change, was 6, now 8 (+2)",
+                "/if/drops[pg9]: expected no change, but no interface named pg9",
+                "/if/nosuch: expected an increase, but no such counter",
+            ]
+        );
+        assert!(d
+            .verify()
+            .unwrap_err()
+            .to_string()
+            .starts_with("4 counter check(s) failed:"));
+    }
+}
@@ -0,0 +1,347 @@
+/*
+ * The tables for a top-style live view: interface rates, graph node
+ * runtime per worker and the error counters that are incrementing,
+ * all computed from the deltas between two consecutive snapshots, and
+ * the peak interface rates over the last few minutes of the history.
+ */
+
+use crate::delta::{deltas, CounterField, SeriesDelta, SeriesKey};
+use crate::history::{History, Measure};
+use crate::interfaces::InterfaceCounters;
+use crate::snapshot::{SnapshotValue, StatSnapshot};
+use regex::Regex;
+use serde::Serialize;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+
+/// The patterns needed to fill in all the tables
+pub const TOP_PATTERNS: &[&str] = &["^/if/", "^/sys/node/", "^/err/"];
+
+/// The stats the history needs to keep for the peaks table
+pub const PEAK_PATTERNS: &[&str] = &["^/if/rx$", "^/if/tx$", "^/if/drops$"];
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TableRow {
+    pub label: String,
+    pub values: Vec<f64>,
+}
+
+/// The first column holds the labels, the rest are the values
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Table {
+    pub columns: Vec<&'static str>,
+    pub rows: Vec<TableRow>,
+}
+
+impl Table {
+    /// Keep only the rows whose label matches
+    pub fn filter(&mut self, re: &Regex) {
+        self.rows.retain(|r| re.is_match(&r.label));
+    }
+
+    /// Sort by the label for the column 0, or by the value in the given column.
+    /// The ties keep the label order so that the rows do not jump around.
+    pub fn sort_by(&mut self, column: usize, descending: bool) {
+        self.rows.sort_by(|a, b| {
+            let ord = if column == 0 {
+                a.label.cmp(&b.label)
+            } else {
+                let va = a.values.get(column - 1).copied().unwrap_or(0.0);
+                let vb = b.values.get(column - 1).copied().unwrap_or(0.0);
+                va.partial_cmp(&vb).unwrap_or(Ordering::Equal)
+            };
+            let ord = if descending { ord.reverse() } else { ord };
+            ord.then_with(|| a.label.cmp(&b.label))
+        });
+    }
+}
+
+struct Rates(HashMap<SeriesKey, SeriesDelta>);
+
+impl Rates {
+    fn new(prev: &StatSnapshot, cur: &StatSnapshot, per_thread: bool) -> Self {
+        Rates(
+            deltas(prev, cur, per_thread)
+                .into_iter()
+                .map(|d| (d.key.clone(), d))
+                .collect(),
+        )
+    }
+
+    fn get(
+        &self,
+        name: &str,
+        thread: Option<usize>,
+        index: usize,
+        field: CounterField,
+    ) -> Option<&SeriesDelta> {
+        self.0.get(&SeriesKey {
+            name: name.to_string(),
+            thread,
+            index: Some(index),
+            field,
+        })
+    }
+
+    fn rate(&self, name: &str, index: usize, field: CounterField) -> f64 {
+        self.get(name, None, index, field)
+            .map(|d| d.rate)
+            .unwrap_or(0.0)
+    }
+}
+
+/// Packet and bit rates per interface
+pub fn interfaces_table(prev: &StatSnapshot, cur: &StatSnapshot) -> Table {
+    let rates = Rates::new(prev, cur, false);
+    let rows = InterfaceCounters::collect(cur)
+        .iter()
+        .map(|ifc| {
+            let i = ifc.sw_if_index as usize;
+            TableRow {
+                label: ifc.display_name(),
+                values: vec![
+                    rates.rate("/if/rx", i, CounterField::Packets),
+                    rates.rate("/if/rx", i, CounterField::Bytes) * 8.0,
+                    rates.rate("/if/tx", i, CounterField::Packets),
+                    rates.rate("/if/tx", i, CounterField::Bytes) * 8.0,
+                    rates.rate("/if/drops", i, CounterField::Value),
+                ],
+            }
+        })
+        .collect();
+    Table {
+        columns: vec![
+            "Interface",
+            "Rx pps",
+            "Rx bps",
+            "Tx pps",
+            "Tx bps",
+            "Drops/s",
+        ],
+        rows,
+    }
+}
+
+/// The 95th percentile and the maximum of the per-interface rates over the
+/// window, with the interfaces of the current snapshot
+pub fn peaks_table(history: &History, cur: &StatSnapshot, start: f64, end: f64) -> Table {
+    let rates = |name: &str, index: usize, field| {
+        let key = SeriesKey {
+            name: name.to_string(),
+            thread: None,
+            index: Some(index),
+            field,
+        };
+        let p95 = history.percentile(&key, start, end, Measure::Rate, 95.0);
+        let max = history
+            .summary(&key, start, end, Measure::Rate)
+            .map(|s| s.max);
+        (p95.unwrap_or(0.0), max.unwrap_or(0.0))
+    };
+    let rows = InterfaceCounters::collect(cur)
+        .iter()
+        .map(|ifc| {
+            let i = ifc.sw_if_index as usize;
+            let rx = rates("/if/rx", i, CounterField::Packets);
+            let tx = rates("/if/tx", i, CounterField::Packets);
+            let drops = rates("/if/drops", i, CounterField::Value);
+            TableRow {
+                label: ifc.display_name(),
+                values: vec![rx.0, rx.1, tx.0, tx.1, drops.1],
+            }
+        })
+        .collect();
+    Table {
+        columns: vec![
+            "Interface",
+            "Rx pps p95",
+            "Rx pps max",
+            "Tx pps p95",
+            "Tx pps max",
+            "Drops/s max",
+        ],
+        rows,
+    }
+}
+
+/// Runtime of the graph nodes which were called, per worker thread,
+/// the same numbers as "show runtime" gives over the interval.
+pub fn nodes_table(prev: &StatSnapshot, cur: &StatSnapshot) -> Table {
+    let rates = Rates::new(prev, cur, true);
+    let names = match cur.get("/sys/node/names") {
+        Some(SnapshotValue::Names(names)) => names.as_slice(),
+        _ => &[],
+    };
+    let threads = match cur.get("/sys/node/calls") {
+        Some(SnapshotValue::Simple(v)) => v.len(),
+        _ => 0,
+    };
+    let mut rows = vec![];
+    for t in 0..threads {
+        for (i, name) in names.iter().enumerate() {
+            let name = match name {
+                Some(n) => n,
+                None => continue,
+            };
+            let delta = |stat| {
+                rates
+                    .get(stat, Some(t), i, CounterField::Value)
+                    .map(|d| d.delta)
+                    .unwrap_or(0.0)
+            };
+            let calls = delta("/sys/node/calls");
+            if calls == 0.0 {
+                continue;
+            }
+            let vectors = delta("/sys/node/vectors");
+            let clocks = delta("/sys/node/clocks");
+            let elapsed = cur.timestamp - prev.timestamp;
+            let per_sec = |v: f64| if elapsed > 0.0 { v / elapsed } else { 0.0 };
+            rows.push(TableRow {
+                label: name.clone(),
+                values: vec![
+                    t as f64,
+                    per_sec(calls),
+                    per_sec(vectors),
+                    vectors / calls,
+                    if vectors > 0.0 { clocks / vectors } else { 0.0 },
+                ],
+            });
+        }
+    }
+    Table {
+        columns: vec![
+            "Node",
+            "Thread",
+            "Calls/s",
+            "Vectors/s",
+            "Vectors/call",
+            "Clocks/vector",
+        ],
+        rows,
+    }
+}
+
+/// The error counters which have incremented, summed across the threads
+pub fn errors_table(prev: &StatSnapshot, cur: &StatSnapshot) -> Table {
+    let mut sums: BTreeMap<&str, (f64, f64, f64)> = BTreeMap::new();
+    let all = deltas(prev, cur, false);
+    for d in all.iter().filter(|d| d.key.name.starts_with("/err/")) {
+        let e = sums.entry(&d.key.name).or_default();
+        e.0 += d.new.as_f64();
+        e.1 += d.delta;
+        e.2 += d.rate;
+    }
+    let rows = sums
+        .into_iter()
+        .filter(|(_, (_, delta, _))| *delta > 0.0)
+        .map(|(name, (count, delta, rate))| TableRow {
+            label: name.to_string(),
+            values: vec![count, delta, rate],
+        })
+        .collect();
+    Table {
+        columns: vec!["Counter", "Count", "Increase", "Rate/s"],
+        rows,
+    }
+}
+
+/// Short form of a number with the SI suffix, e.g. "12.3M"
+pub fn human(v: f64) -> String {
+    let suffixes = ["", "k", "M", "G", "T"];
+    let mut v = v;
+    let mut i = 0;
+    while v.abs() >= 1000.0 && i < suffixes.len() - 1 {
+        v /= 1000.0;
+        i += 1;
+    }
+    if i == 0 && v.fract() == 0.0 {
+        format!("{}", v)
+    } else {
+        format!("{:.1}{}", v, suffixes[i])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::SnapshotBuilder;
+    use std::time::Duration;
+
+    fn snap(timestamp: f64, calls: u64, vectors: u64, errors: u64) -> StatSnapshot {
+        SnapshotBuilder::new(timestamp)
+            .with_names("/sys/node/names", &["ip4-lookup", "drop"])
+            .with_simple("/sys/node/calls", vec![vec![0, 0], vec![calls, 7]])
+            .with_simple("/sys/node/vectors", vec![vec![0, 0], vec![vectors, 7]])
+            .with_simple("/sys/node/clocks", vec![vec![0, 0], vec![vectors * 50, 7]])
+            .with_simple("/err/ip4-input/ttl expired", vec![vec![errors], vec![1]])
+            .with_simple("/err/ip4-input/bad checksum", vec![vec![3]])
+            .build()
+    }
+
+    #[test]
+    fn node_runtime() {
+        let t = nodes_table(&snap(1.0, 10, 100, 0), &snap(3.0, 30, 500, 0));
+        assert_eq!(t.rows.len(), 1);
+        assert_eq!(t.rows[0].label, "ip4-lookup");
+        assert_eq!(t.rows[0].values, vec![1.0, 10.0, 200.0, 20.0, 50.0]);
+    }
+
+    #[test]
+    fn incrementing_errors() {
+        let mut t = errors_table(&snap(1.0, 0, 0, 5), &snap(2.0, 0, 0, 9));
+        assert_eq!(t.rows.len(), 1);
+        assert_eq!(t.rows[0].label, "/err/ip4-input/ttl expired");
+        assert_eq!(t.rows[0].values, vec![10.0, 4.0, 4.0]);
+
+        t.filter(&Regex::new("checksum").unwrap());
+        assert!(t.rows.is_empty());
+    }
+
+    #[test]
+    fn interface_peaks() {
+        let mut history = History::new(Duration::from_secs(1), Duration::from_secs(60))
+            .with_patterns(
+                &PEAK_PATTERNS
+                    .iter()
+                    .map(|p| p.to_string())
+                    .collect::<Vec<_>>(),
+            );
+        let mut last = None;
+        for (t, rx) in [(0, 0), (1, 10), (2, 110), (3, 120), (4, 130)] {
+            let snap = SnapshotBuilder::new(t as f64)
+                .with_interfaces(&["eth0"])
+                .with_combined("/if/rx", vec![vec![(rx, rx * 100)]])
+                .build();
+            history.record(&snap);
+            last = Some(snap);
+        }
+        let t = peaks_table(&history, &last.unwrap(), 0.0, 4.0);
+        assert_eq!(t.rows.len(), 1);
+        assert_eq!(t.rows[0].label, "eth0");
+        assert_eq!(t.rows[0].values, vec![100.0, 100.0, 0.0, 0.0, 0.0]);
+        let empty = SnapshotBuilder::new(4.0).build();
+        let t = peaks_table(&history, &empty, 0.0, 4.0);
+        assert!(t.rows.is_empty());
+    }
+
+    #[test]
+    fn sorting_and_formatting() {
+        let row = |label: &str, v| TableRow {
+            label: label.to_string(),
+            values: vec![v],
+        };
+        let mut t = Table {
+            columns: vec!["Name", "Value"],
+            rows: vec![row("b", 1.0), row("a", 2.0), row("c", 1.0)],
+        };
+        t.sort_by(1, true);
+        let labels: Vec<&str> = t.rows.iter().map(|r| r.label.as_str()).collect();
+        assert_eq!(labels, vec!["a", "b", "c"]);
+        t.sort_by(0, true);
+        assert_eq!(t.rows[0].label, "c");
+
+        assert_eq!(human(999.0), "999");
+        assert_eq!(human(12345678.0), "12.3M");
+    }
+}